@@ -0,0 +1,33 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Bake the git commit into the binary for `tunnelto version --verbose`
+fn main() {
+    let commit = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TUNNELTO_GIT_COMMIT={}", commit);
+
+    // HEAD itself only changes on checkout, a new commit moves the branch it points to
+    // (a loose ref, or `packed-refs` after a gc)
+    println!("cargo:rerun-if-changed=build.rs");
+    let mut watched = vec!["HEAD".to_string(), "packed-refs".to_string()];
+    watched.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for name in watched {
+        if let Some(path) = git(&["rev-parse", "--git-path", &name]) {
+            // a missing path would rerun every build
+            if Path::new(&path).exists() {
+                println!("cargo:rerun-if-changed={}", path);
+            }
+        }
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+        .filter(|output| !output.is_empty())
+}
@@ -1,24 +1,54 @@
+use std::io::{IsTerminal, Write};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
-use crate::Config;
+use crate::{Config, OutputFormat};
+use chrono::TimeZone;
 use cli_table::format::Padding;
 use cli_table::{format::Justify, print_stderr, Cell, Table};
 use colored::Colorize;
 use indicatif::{ProgressBar, ProgressStyle};
 
 pub struct CliInterface {
-    spinner: ProgressBar,
+    spinner: Option<ProgressBar>,
+    /// where status lines go instead of the spinner, with `--no-spinner`
+    status_lines: Mutex<Box<dyn Write + Send>>,
     config: Config,
     introspect: SocketAddr,
 }
 impl CliInterface {
     pub fn start(config: Config, introspect: SocketAddr) -> Self {
-        let spinner = new_spinner("Opening remote tunnel...");
-        Self {
+        Self::with_status_lines(config, introspect, Box::new(std::io::stderr()))
+    }
+
+    fn with_status_lines(
+        config: Config,
+        introspect: SocketAddr,
+        status_lines: Box<dyn Write + Send>,
+    ) -> Self {
+        let message = "Opening remote tunnel...";
+        let spinner = if config.no_spinner {
+            None
+        } else {
+            Some(new_spinner(message))
+        };
+        let interface = Self {
             spinner,
+            status_lines: Mutex::new(status_lines),
             config,
             introspect,
+        };
+        if interface.spinner.is_none() {
+            interface.status_line(message);
         }
+        interface
+    }
+
+    fn status_line(&self, message: &str) {
+        let mut out = self.status_lines.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(out, "{}", message);
     }
 
     fn get_sub_domain_notice(&self, sub_domain: &str) -> Option<String> {
@@ -37,19 +67,74 @@ impl CliInterface {
         }
     }
 
-    pub fn did_connect(&self, sub_domain: &str, full_hostname: &str) {
-        self.spinner
-            .finish_with_message("Success! Remote tunnel is now open.\n".green().as_ref());
+    pub fn did_connect(
+        &self,
+        sub_domain: &str,
+        full_hostname: &str,
+        reserved_until: Option<u64>,
+        remote_port: Option<u16>,
+        served_by: &ServedBy,
+    ) {
+        let message = "Success! Remote tunnel is now open.\n".green();
+        match &self.spinner {
+            Some(spinner) => spinner.finish_with_message(message.as_ref()),
+            None => self.status_line(&message.to_string()),
+        }
 
         if !self.config.first_run {
             return;
         }
 
-        let public_url = self.config.activation_url(&full_hostname).bold().green();
+        let public_url = match remote_port {
+            Some(port) => format!("tcp://{}:{}", full_hostname, port),
+            None => self.config.activation_url(full_hostname),
+        };
         let forward_url = self.config.forward_url();
-        let inspect = format!("http://localhost:{}", self.introspect.port());
+        let inspect = self.config.dashboard_url(self.introspect.port());
 
-        let table = vec![
+        // raw tcp urls aren't for browsers, and with several tunnels `main` opens the dashboard
+        if self.config.open && remote_port.is_none() {
+            open_in_browser(&public_url);
+        }
+        if self.config.open_dashboard && self.config.forwards.is_empty() {
+            open_in_browser(&inspect);
+        }
+
+        if self.config.output == OutputFormat::Json {
+            let status = serde_json::json!({
+                "public_url": public_url,
+                "forward_url": forward_url,
+                "dashboard": inspect,
+                "instance_id": served_by.instance_id,
+                "region": served_by.region,
+            });
+            println!("{}", status);
+            return;
+        }
+
+        let public_url = public_url.bold().green();
+
+        // one row per tunnel when running several
+        if !self.config.forwards.is_empty() {
+            let table = vec![vec![
+                "Public tunnel URL".green().cell(),
+                public_url
+                    .green()
+                    .cell()
+                    .padding(Padding::builder().left(4).right(4).build())
+                    .justify(Justify::Left),
+                format!("=> {}", forward_url)
+                    .cell()
+                    .padding(Padding::builder().left(4).build())
+                    .justify(Justify::Left),
+            ]];
+            print_stderr(table.table())
+                .expect("failed to generate starting terminal user interface");
+            self.print_notices(sub_domain, reserved_until, served_by);
+            return;
+        }
+
+        let mut table = vec![
             vec![
                 "Public tunnel URL".green().cell(),
                 public_url
@@ -75,15 +160,116 @@ impl CliInterface {
             ],
         ];
 
+        if self.config.region.is_some() || self.config.verbose {
+            table.push(vec![
+                "Served by".cell(),
+                served_by
+                    .to_string()
+                    .cell()
+                    .padding(Padding::builder().left(4).build())
+                    .justify(Justify::Left),
+            ]);
+        }
+
+        if self.config.show_resolved && self.config.unix_socket.is_none() {
+            table.push(vec![
+                "Resolved local address".cell(),
+                self.config
+                    .resolved_local_addr()
+                    .cell()
+                    .padding(Padding::builder().left(4).build())
+                    .justify(Justify::Left),
+            ]);
+        }
+
         let table = table.table();
         print_stderr(table).expect("failed to generate starting terminal user interface");
 
+        self.print_notices(sub_domain, reserved_until, served_by);
+    }
+
+    fn print_notices(&self, sub_domain: &str, reserved_until: Option<u64>, served_by: &ServedBy) {
+        if let Some(reservation) = reservation_notice(reserved_until) {
+            eprintln!("\n{}", reservation.cyan());
+        }
+
+        if let Some(wanted) = self.config.region.as_ref() {
+            let placed = served_by.region.as_ref();
+            if !placed.map_or(false, |region| region.eq_ignore_ascii_case(wanted)) {
+                let notice = format!("Requested region {} but served by {}", wanted, served_by);
+                eprintln!("\n{}: {}\n", ">>> Notice".yellow(), notice);
+            }
+        }
+
         if let Some(notice) = self.get_sub_domain_notice(sub_domain) {
             eprintln!("\n{}: {}\n", ">>> Notice".yellow(), notice);
         }
     }
 }
 
+/// Open a url in the user's browser, quietly doing nothing where there is none (i.e. ci, ssh)
+pub fn open_in_browser(url: &str) {
+    if std::env::var_os("CI").is_some() || !std::io::stdout().is_terminal() {
+        log::debug!("not opening {} without an interactive terminal", url);
+        return;
+    }
+
+    if let Err(e) = webbrowser::open(url) {
+        log::debug!("failed to open {} in a browser: {:?}", url, e);
+    }
+}
+
+/// The server instance serving a tunnel, see `ServerHello::Success`
+#[derive(Debug, Clone, Default)]
+pub struct ServedBy {
+    pub instance_id: Option<String>,
+    pub region: Option<String>,
+}
+
+impl std::fmt::Display for ServedBy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let region = self.region.as_deref().unwrap_or("an unknown region");
+        match self.instance_id.as_ref() {
+            Some(instance_id) => write!(f, "{} ({})", region, instance_id),
+            None => write!(f, "{}", region),
+        }
+    }
+}
+
+/// Round trip times past this are reported as a laggy tunnel
+const LAGGY_RTT: Duration = Duration::from_secs(1);
+
+static REPORTED_RTT: AtomicBool = AtomicBool::new(false);
+
+/// Print the tunnel's round trip time, measured by probes to the server:
+/// the first one, then only the laggy ones
+pub fn report_rtt(config: &Config, rtt: Duration) {
+    log::debug!("tunnel rtt: {}ms", rtt.as_millis());
+
+    let first = !REPORTED_RTT.swap(true, Ordering::Relaxed);
+    if config.output == OutputFormat::Json {
+        return;
+    }
+
+    if rtt >= LAGGY_RTT {
+        let message = format!("Tunnel is laggy: {}ms round trip", rtt.as_millis());
+        eprintln!("{}", message.yellow());
+    } else if first {
+        eprintln!("Tunnel latency: {}ms", rtt.as_millis());
+    }
+}
+
+/// Describe when a time-limited sub-domain reservation expires, in local time
+fn reservation_notice(reserved_until: Option<u64>) -> Option<String> {
+    let expires = chrono::Local
+        .timestamp_opt(reserved_until? as i64, 0)
+        .single()?;
+    Some(format!(
+        "subdomain reserved until {}",
+        expires.format("%Y-%m-%d %H:%M %Z")
+    ))
+}
+
 fn new_spinner(message: &str) -> ProgressBar {
     let pb = ProgressBar::new_spinner();
     pb.enable_steady_tick(150);
@@ -96,3 +282,79 @@ fn new_spinner(message: &str) -> ProgressBar {
     pb.set_message(message);
     pb
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Status lines written to a buffer shared with the test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reservation_expiry() {
+        assert_eq!(reservation_notice(None), None);
+
+        // 2023-11-14 22:13 UTC, the 14th or 15th in local time
+        let reserved_until = 1_700_000_000;
+        let notice = reservation_notice(Some(reserved_until)).unwrap();
+        assert!(
+            notice.starts_with("subdomain reserved until 2023-11-1"),
+            "{}",
+            notice
+        );
+        let expected = chrono::Local
+            .timestamp_opt(reserved_until as i64, 0)
+            .unwrap();
+        assert_eq!(
+            reservation_notice(Some(reserved_until)),
+            Some(format!(
+                "subdomain reserved until {}",
+                expected.format("%Y-%m-%d %H:%M %Z")
+            ))
+        );
+
+        // out of chrono's range
+        assert_eq!(reservation_notice(Some(u64::MAX / 2)), None);
+    }
+
+    #[test]
+    fn no_spinner_prints_plain_lines() {
+        let mut config = Config::from_args(&["tunnelto", "--no-spinner"]);
+        config.first_run = false;
+        let captured = Captured::default();
+        let interface = CliInterface::with_status_lines(
+            config,
+            "127.0.0.1:0".parse().unwrap(),
+            Box::new(captured.clone()),
+        );
+        assert!(interface.spinner.is_none());
+        interface.did_connect("foo", "foo.tunnelto.dev", None, None, &ServedBy::default());
+
+        let out = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(out.contains("Opening remote tunnel...\n"), "{:?}", out);
+        assert!(
+            out.contains("Success! Remote tunnel is now open."),
+            "{:?}",
+            out
+        );
+
+        // no carriage returns or cursor movement, colors are fine
+        assert!(!out.contains('\r'), "{:?}", out);
+        for sequence in out.split('\x1b').skip(1) {
+            let end = sequence.find(|c: char| c.is_ascii_alphabetic()).unwrap();
+            assert_eq!(&sequence[end..=end], "m", "{:?}", out);
+        }
+    }
+}
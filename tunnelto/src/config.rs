@@ -1,6 +1,10 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::io::IsTerminal;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 
 use super::*;
+use serde::Deserialize;
 use structopt::StructOpt;
 
 const HOST_ENV: &'static str = "CTRL_HOST";
@@ -13,6 +17,10 @@ const DEFAULT_CONTROL_PORT: &'static str = "10001";
 
 const SETTINGS_DIR: &'static str = ".tunnelto";
 const SECRET_KEY_FILE: &'static str = "key.token";
+const CONFIG_FILE: &'static str = "config.toml";
+
+const DEFAULT_LOCAL_HOST: &'static str = "localhost";
+const DEFAULT_LOCAL_PORT: u16 = 8000;
 
 /// Command line arguments
 #[derive(Debug, StructOpt)]
@@ -29,29 +37,175 @@ struct Opts {
     #[structopt(subcommand)]
     command: Option<SubCommand>,
 
+    /// Read default options from this file instead of `~/.tunnelto/config.toml`
+    #[structopt(long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Validate the config file and exit, with a non-zero status if it has problems
+    #[structopt(long = "config-check")]
+    config_check: bool,
+
     /// Sets an API authentication key to use for this tunnel
     #[structopt(short = "k", long = "key")]
     key: Option<String>,
 
+    /// Read the API authentication key from this file instead of ~/.tunnelto/key.token
+    #[structopt(long = "key-file", env = "TUNNELTO_KEY_FILE", parse(from_os_str))]
+    key_file: Option<PathBuf>,
+
     /// Specify a sub-domain for this tunnel
     #[structopt(short = "s", long = "subdomain")]
     sub_domain: Option<String>,
 
-    /// Sets the HOST (i.e. localhost) to forward incoming tunnel traffic to
-    #[structopt(long = "host", default_value = "localhost")]
-    local_host: String,
+    /// Sets the HOST (i.e. localhost) to forward incoming tunnel traffic to [default: localhost]
+    #[structopt(long = "host")]
+    local_host: Option<String>,
 
     /// Sets the protocol for local forwarding (i.e. https://localhost) to forward incoming tunnel traffic to
     #[structopt(long = "use-tls", short = "t")]
     use_tls: bool,
 
-    /// Sets the port to forward incoming tunnel traffic to on the target host
-    #[structopt(short = "p", long = "port", default_value = "8000")]
-    port: u16,
+    /// Sets the SCHEME (i.e. http or https) to forward incoming tunnel traffic to
+    #[structopt(long = "scheme")]
+    scheme: Option<String>,
+
+    /// Shut down with an error if the local service answers plain HTTP while `--use-tls` is set
+    #[structopt(long = "tls-fail-fast")]
+    tls_fail_fast: bool,
+
+    /// Forward incoming tunnel traffic to a unix domain socket instead of a TCP port
+    #[structopt(long = "unix-socket", parse(from_os_str))]
+    unix_socket: Option<PathBuf>,
+
+    /// Sets the port to forward incoming tunnel traffic to on the target host [default: 8000]
+    #[structopt(short = "p", long = "port")]
+    port: Option<u16>,
+
+    /// Open a tunnel for each `[subdomain:]port` mapping, can be used multiple times
+    /// (replaces `--port` and `--subdomain`)
+    #[structopt(long = "forward", parse(try_from_str = parse_forward))]
+    forwards: Vec<(Option<String>, u16)>,
 
     /// Sets the address of the local introspection dashboard
     #[structopt(long = "dashboard-port")]
     dashboard_port: Option<u16>,
+
+    /// Sets the IP the local introspection dashboard listens on, i.e. `127.0.0.1` to only
+    /// allow this machine or a LAN IP to share it. Defaults to all interfaces.
+    #[structopt(long = "dashboard-host", default_value = "::")]
+    dashboard_host: IpAddr,
+
+    /// How to set the Host header of requests to the local service: `preserve` the public
+    /// host (i.e. `foo.tunnelto.dev`, the default), `localhost` (or `rewrite`) to use `host:port`,
+    /// or any explicit value. Rewriting fixes local servers that route by virtual host,
+    /// or build redirects and check CSRF origins from it.
+    #[structopt(long = "host-header", default_value = "preserve", parse(from_str = HostHeader::parse))]
+    host_header: HostHeader,
+
+    /// Compress large data packets over the tunnel, if the server supports it.
+    /// Saves bandwidth for text-heavy traffic at some CPU cost.
+    #[structopt(long = "compress")]
+    compress: bool,
+
+    /// Tunnel raw tcp instead of http: the server gives this tunnel its own public port
+    #[structopt(long = "tcp")]
+    tcp: bool,
+
+    /// The public port to ask for with `--tcp`, any free one if not set
+    #[structopt(long = "remote-port")]
+    remote_port: Option<u16>,
+
+    /// The server region to connect to (i.e. `ams`), if the server fleet has one there
+    #[structopt(long = "region")]
+    region: Option<String>,
+
+    /// Take over the sub-domain if a stale tunnel of your account (i.e. after a crash) still holds it
+    #[structopt(long = "takeover")]
+    takeover: bool,
+
+    /// Require visitors to log in with HTTP basic auth (`user:pass`) before reaching the local service
+    #[structopt(long = "basic-auth", parse(try_from_str = parse_basic_auth))]
+    basic_auth: Option<String>,
+
+    /// Only forward requests with these methods (i.e. `GET,HEAD` for a read-only demo),
+    /// others are answered with a 405 without reaching the local service
+    #[structopt(long = "tunnel-only-methods", use_delimiter = true)]
+    tunnel_only_methods: Vec<String>,
+
+    /// Respond with a 504 if the local service hasn't started responding within this many seconds
+    #[structopt(long = "local-response-timeout")]
+    local_response_timeout: Option<u64>,
+
+    /// When the local service refuses a stream, poll this path (i.e. `/health`) on it
+    /// and report once it's back up
+    #[structopt(long = "local-path-health")]
+    local_path_health: Option<String>,
+
+    /// Refuse a stream if connecting to the local service takes longer than this many milliseconds
+    #[structopt(long = "connect-timeout", default_value = "5000")]
+    connect_timeout: u64,
+
+    /// On Ctrl-C, stop accepting streams and wait up to this many seconds for in-flight requests to finish
+    #[structopt(long = "shutdown-grace", alias = "drain-timeout", default_value = "0")]
+    shutdown_grace: u64,
+
+    /// Print plain status lines instead of an animated spinner (default when stderr is not a terminal)
+    #[structopt(long = "no-spinner")]
+    no_spinner: bool,
+
+    /// Show the address the local host resolved to, and the alternatives that weren't picked
+    #[structopt(long = "show-resolved")]
+    show_resolved: bool,
+
+    /// Open the public url in the browser once the tunnel is up
+    #[structopt(long = "open")]
+    open: bool,
+
+    /// Open the local inspect dashboard in the browser once the tunnel is up
+    #[structopt(long = "open-dashboard")]
+    open_dashboard: bool,
+
+    /// Wait until the local service accepts connections before opening the tunnel
+    #[structopt(long = "wait-for-local")]
+    wait_for_local: bool,
+
+    /// Once connected, print a table for humans or a JSON line on stdout for scripts [human, json]
+    #[structopt(long = "output", default_value = "human")]
+    output: OutputFormat,
+
+    /// Max bytes of each request and response body kept for the dashboard, the rest is truncated
+    #[structopt(long = "max-capture-bytes", default_value = "1048576")]
+    max_capture_bytes: usize,
+
+    /// Capture request and response bodies for the dashboard [on, off].
+    /// With `off` only metadata is kept: method, path, status, headers, sizes and timing
+    #[structopt(long = "inspect-capture-bodies", default_value = "on", parse(try_from_str = parse_on_off))]
+    inspect_capture_bodies: bool,
+
+    /// Max number of requests kept for the dashboard, the oldest are evicted first
+    #[structopt(long = "max-captured-requests", default_value = "1000")]
+    max_captured_requests: usize,
+
+    /// Append captured requests to `requests.jsonl` in this directory, and restore them on startup
+    #[structopt(long = "record-dir", parse(from_os_str))]
+    record_dir: Option<PathBuf>,
+
+    /// Write a hexdump of every stream's bytes from the tunnel to the local service to this file,
+    /// for debugging protocols the dashboard doesn't understand
+    #[structopt(long = "raw-in", parse(from_os_str))]
+    raw_in: Option<PathBuf>,
+
+    /// Write a hexdump of every stream's bytes from the local service to the tunnel to this file
+    #[structopt(long = "raw-out", parse(from_os_str))]
+    raw_out: Option<PathBuf>,
+
+    /// Max stream bytes written to each of `--raw-in` and `--raw-out`
+    #[structopt(long = "raw-max-bytes", default_value = "10485760")]
+    raw_max_bytes: u64,
+
+    /// Use a random port for the dashboard if the requested `--dashboard-port` is unavailable
+    #[structopt(long = "dashboard-fallback")]
+    dashboard_fallback: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -62,6 +216,141 @@ enum SubCommand {
         #[structopt(short = "k", long = "key")]
         key: String,
     },
+    /// Check if a sub-domain is available, and suggest available alternatives
+    SubdomainCheck {
+        /// The sub-domain you'd like to use
+        name: String,
+    },
+    /// Print the version, and with `--verbose` the build info to include in bug reports
+    Version {
+        #[structopt(long = "verbose", alias = "build-info")]
+        verbose: bool,
+    },
+}
+
+/// i.e: for bug reports, everything that decides how this build talks to the server
+fn build_info() -> String {
+    format!(
+        "tunnelto {}\n\
+        commit: {}\n\
+        protocol version: {} (min {})\n\
+        features: tls (rustls), compression\n\
+        default control host: {}:{}",
+        env!("CARGO_PKG_VERSION"),
+        env!("TUNNELTO_GIT_COMMIT"),
+        PROTOCOL_VERSION,
+        MIN_PROTOCOL_VERSION,
+        DEFAULT_CONTROL_HOST,
+        DEFAULT_CONTROL_PORT,
+    )
+}
+
+/// Defaults for the command line arguments, read from `config.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    key: Option<String>,
+    subdomain: Option<String>,
+    port: Option<u16>,
+    host: Option<String>,
+    scheme: Option<String>,
+    dashboard_port: Option<u16>,
+}
+
+impl FileConfig {
+    /// The config file at `path`, or `~/.tunnelto/config.toml` if it exists
+    fn path(path: Option<&std::path::Path>) -> Option<PathBuf> {
+        match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => dirs::home_dir()
+                .map(|h| h.join(SETTINGS_DIR).join(CONFIG_FILE))
+                .filter(|path| path.exists()),
+        }
+    }
+
+    /// Load and validate the config file, printing any problems
+    fn load(path: Option<&std::path::Path>) -> Result<Self, ()> {
+        let path = match Self::path(path) {
+            Some(path) => path,
+            None => return Ok(FileConfig::default()),
+        };
+
+        let contents = std::fs::read_to_string(&path).map_err(|e| {
+            eprintln!(
+                "Error: failed to read config file {}: {}",
+                path.display(),
+                e
+            );
+        })?;
+
+        let file: FileConfig = toml::from_str(&contents).map_err(|e| {
+            eprintln!("Error: invalid config file {}: {}", path.display(), e);
+            if let Some((line, _)) = e.line_col() {
+                print_line_context(&contents, line);
+            }
+        })?;
+
+        let problems = file.problems();
+        for (key, problem) in &problems {
+            eprintln!("Error: invalid config file {}: {}", path.display(), problem);
+            if let Some(line) = contents
+                .lines()
+                .position(|l| l.trim_start().starts_with(key))
+            {
+                print_line_context(&contents, line);
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(file)
+        } else {
+            Err(())
+        }
+    }
+
+    /// Values that parse but are out of range, by key
+    fn problems(&self) -> Vec<(&'static str, String)> {
+        let mut problems = vec![];
+
+        if self.port == Some(0) {
+            problems.push(("port", "`port` must be between 1 and 65535".to_string()));
+        }
+
+        if let Some(scheme) = &self.scheme {
+            if !["http", "https"].contains(&scheme.to_lowercase().as_str()) {
+                problems.push((
+                    "scheme",
+                    format!("invalid scheme '{}', expected `http` or `https`", scheme),
+                ));
+            }
+        }
+
+        if let Some(sub_domain) = &self.subdomain {
+            if let Err(e) = sub_domain_to_ascii(sub_domain) {
+                problems.push((
+                    "subdomain",
+                    format!("invalid sub-domain '{}': {}", sub_domain, e),
+                ));
+            }
+        }
+
+        if self
+            .host
+            .as_ref()
+            .map_or(false, |host| host.trim().is_empty())
+        {
+            problems.push(("host", "`host` must not be empty".to_string()));
+        }
+
+        problems
+    }
+}
+
+/// Print a (zero-based) line of a config file, i.e: "  3 | port = 0"
+fn print_line_context(contents: &str, line: usize) {
+    if let Some(text) = contents.lines().nth(line) {
+        eprintln!("{}", format!("{:>4} | {}", line + 1, text).yellow());
+    }
 }
 
 /// Config
@@ -69,34 +358,330 @@ enum SubCommand {
 pub struct Config {
     pub client_id: ClientId,
     pub control_url: String,
+    pub control_api_url: String,
     pub use_tls: bool,
+    pub tls_fail_fast: bool,
     pub host: String,
     pub local_host: String,
     pub local_port: u16,
     pub local_addr: SocketAddr,
+    pub unix_socket: Option<PathBuf>,
     pub sub_domain: Option<String>,
+    pub sub_domain_check: Option<String>,
     pub secret_key: Option<SecretKey>,
     pub control_tls_off: bool,
     pub first_run: bool,
+    pub dashboard_host: IpAddr,
     pub dashboard_port: u16,
+    pub dashboard_fallback: bool,
+    pub max_capture_bytes: usize,
+    pub capture_bodies: bool,
+    pub max_captured_requests: usize,
+    pub record_dir: Option<PathBuf>,
+    pub raw_in: Option<PathBuf>,
+    pub raw_out: Option<PathBuf>,
+    pub raw_max_bytes: u64,
+    pub no_spinner: bool,
+    pub show_resolved: bool,
+    pub wait_for_local: bool,
+    pub open: bool,
+    pub open_dashboard: bool,
+    /// everything `local_host` resolved to, `local_addr` is the first
+    pub local_addrs: Vec<SocketAddr>,
+    pub output: OutputFormat,
+    pub shutdown_grace: u64,
+    pub local_response_timeout: Option<u64>,
+    pub connect_timeout: u64,
+    pub local_path_health: Option<String>,
+    pub host_header: HostHeader,
+    pub basic_auth: Option<String>,
+    /// uppercase, everything is forwarded when not set
+    pub allowed_methods: Option<Vec<String>>,
+    pub takeover: bool,
+    pub compress: bool,
+    pub tcp: bool,
+    pub remote_port: Option<u16>,
+    pub region: Option<String>,
     pub verbose: bool,
+    pub forwards: Vec<Forward>,
+    /// streams of this tunnel
+    pub active_streams: ActiveStreams,
+    pub reconnect_token: SharedReconnectToken,
+    /// the data payload size the server advertised, see `ServerHello::Success`
+    pub max_frame_size: Arc<AtomicUsize>,
+    /// the server takes our `ControlPacket::End`, see `feature::STREAM_END`
+    pub server_stream_end: Arc<AtomicBool>,
+}
+
+/// A tunnel mapping from `--forward`
+#[derive(Debug, Clone)]
+pub struct Forward {
+    pub sub_domain: Option<String>,
+    pub local_port: u16,
+    pub local_addr: SocketAddr,
+}
+
+/// The Host header sent to the local service from `--host-header`
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostHeader {
+    /// Forward the public host untouched, the server never rewrites it
+    Preserve,
+    /// Rewrite to the local `host:port`
+    Rewrite,
+    /// Rewrite to a custom value
+    Value(String),
+}
+
+impl HostHeader {
+    fn parse(value: &str) -> Self {
+        match value.to_lowercase().as_str() {
+            "preserve" => HostHeader::Preserve,
+            "rewrite" | "localhost" => HostHeader::Rewrite,
+            _ => HostHeader::Value(value.to_string()),
+        }
+    }
+}
+
+/// How the tunnel is reported once connected, from `--output`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// A table on stderr
+    Human,
+    /// A single JSON object on stdout
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output '{}', expected human or json", s)),
+        }
+    }
+}
+
+/// Parse a `[subdomain:]port` mapping
+fn parse_forward(forward: &str) -> Result<(Option<String>, u16), String> {
+    let (sub_domain, port) = match forward.rfind(':') {
+        Some(idx) => (Some(forward[..idx].to_string()), &forward[idx + 1..]),
+        None => (None, forward),
+    };
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("invalid port in forward '{}'", forward))?;
+    Ok((sub_domain.filter(|s| !s.is_empty()), port))
+}
+
+/// Parse an `on`/`off` switch
+fn parse_on_off(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected `on` or `off`, got '{}'", value)),
+    }
+}
+
+/// Parse `user:pass` basic auth credentials
+fn parse_basic_auth(credentials: &str) -> Result<String, String> {
+    match credentials.find(':') {
+        Some(idx) if idx > 0 => Ok(credentials.to_string()),
+        _ => Err("expected credentials as `user:pass`".to_string()),
+    }
+}
+
+/// Punycode-encode an internationalized sub-domain
+fn encode_sub_domain(sub_domain: Option<String>) -> Result<Option<String>, ()> {
+    match sub_domain {
+        Some(requested) => match sub_domain_to_ascii(&requested) {
+            Ok(sub_domain) => {
+                if let Some(notice) = normalization_notice(&requested) {
+                    eprintln!("{}", notice.yellow());
+                }
+                Ok(Some(sub_domain))
+            }
+            Err(e) => {
+                eprintln!("Error: invalid sub-domain '{}': {}", requested, e);
+                Err(())
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+/// Tell the user when `normalize_sub_domain` changed the sub-domain they asked for
+fn normalization_notice(requested: &str) -> Option<String> {
+    let normalized = normalize_sub_domain(requested);
+    if normalized == requested {
+        return None;
+    }
+    Some(format!(
+        "Note: sub-domain '{}' was normalized to '{}' (repeated hyphens are collapsed and leading/trailing ones trimmed)",
+        requested, normalized
+    ))
+}
+
+fn resolve_local_addr(local_host: &str, port: u16) -> Result<SocketAddr, ()> {
+    Ok(resolve_local_addrs(local_host, port)?[0])
+}
+
+/// Every address `local_host` resolves to, we forward to the first
+fn resolve_local_addrs(local_host: &str, port: u16) -> Result<Vec<SocketAddr>, ()> {
+    let addrs: Vec<SocketAddr> = (local_host, port)
+        .to_socket_addrs()
+        .map(|addrs| addrs.collect())
+        .unwrap_or_default();
+
+    match addrs.first() {
+        Some(addr) => {
+            debug!(
+                "resolved {}:{} to {:?}, forwarding to {}",
+                local_host, port, addrs, addr
+            );
+            Ok(addrs)
+        }
+        None => {
+            error!(
+                "An invalid local address was specified: {}:{}",
+                local_host, port
+            );
+            Err(())
+        }
+    }
+}
+
+/// `--config-check`: the config file that was found and is valid, printing any problems
+fn config_check(path: Option<&Path>) -> Result<PathBuf, ()> {
+    match FileConfig::path(path) {
+        Some(path) => FileConfig::load(Some(&path)).map(|_| path),
+        None => {
+            eprintln!("Error: no config file found, use `--config <path>`");
+            Err(())
+        }
+    }
+}
+
+fn init_logger(verbose: bool) {
+    logger_builder(verbose, env::var("RUST_LOG").ok().as_deref()).init();
+}
+
+/// `--verbose` logs our crate at debug, on top of (and never overriding) filters from `RUST_LOG`
+fn logger_builder(verbose: bool, rust_log: Option<&str>) -> pretty_env_logger::env_logger::Builder {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if verbose {
+        builder.filter_module("tunnelto", log::LevelFilter::Debug);
+    }
+
+    // parsed last: a user's directive for the same module replaces ours
+    if let Some(filters) = rust_log {
+        builder.parse_filters(filters);
+    }
+
+    builder
+}
+
+/// Pick the key by precedence: `--key`, `--key-file`, the config file,
+/// the `TUNNELTO_KEY` env var, then the one stored on disk
+fn resolve_key(
+    key: Option<String>,
+    key_file: Option<&Path>,
+    file_key: Option<String>,
+    env_key: Option<String>,
+    stored_key: impl FnOnce() -> Option<String>,
+) -> Result<Option<String>, ()> {
+    if key.is_some() {
+        return Ok(key);
+    }
+
+    if let Some(path) = key_file {
+        return match std::fs::read_to_string(path) {
+            Ok(key) => Ok(Some(key.trim().to_string())),
+            Err(e) => {
+                eprintln!(
+                    "Error: {}",
+                    format!("could not read key file {}: {}", path.display(), e).red()
+                );
+                Err(())
+            }
+        };
+    }
+
+    if file_key.is_some() {
+        return Ok(file_key);
+    }
+
+    if let Some(key) = env_key {
+        if !key.trim().is_empty() {
+            return Ok(Some(key.trim().to_string()));
+        }
+    }
+
+    Ok(stored_key())
+}
+
+/// The key stored on disk with `set-auth`
+fn stored_key() -> Option<String> {
+    dirs::home_dir()
+        .map(|h| h.join(SETTINGS_DIR).join(SECRET_KEY_FILE))
+        .map(|path| {
+            if path.exists() {
+                std::fs::read_to_string(path)
+                    .map_err(|e| error!("Error reading authentication token: {:?}", e))
+                    .ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or(None)
 }
 
 impl Config {
     /// Parse the URL to use to connect to the wormhole control server
     pub fn get() -> Result<Config, ()> {
-        // parse the opts
+        // parse the opts, command line flags take precedence over the config file
         let opts: Opts = Opts::from_args();
 
-        if opts.verbose {
-            std::env::set_var("RUST_LOG", "tunnelto=debug");
+        if opts.config_check {
+            match config_check(opts.config.as_deref()) {
+                Ok(path) => {
+                    eprintln!("Config file {} is valid.", path.display());
+                    std::process::exit(0);
+                }
+                Err(()) => std::process::exit(1),
+            }
         }
 
-        pretty_env_logger::init();
+        // needs no config, and should work even with a broken one
+        if let Some(SubCommand::Version { verbose }) = opts.command {
+            if verbose {
+                println!("{}", build_info());
+            } else {
+                println!("tunnelto {}", env!("CARGO_PKG_VERSION"));
+            }
+            std::process::exit(0);
+        }
 
-        let (secret_key, sub_domain) = match opts.command {
+        init_logger(opts.verbose);
+        Self::from_opts(opts)
+    }
+
+    fn from_opts(mut opts: Opts) -> Result<Config, ()> {
+        let file = FileConfig::load(opts.config.as_deref())?;
+        let file_key = file.key;
+        opts.sub_domain = opts.sub_domain.or(file.subdomain);
+        opts.scheme = opts.scheme.or(file.scheme);
+        opts.dashboard_port = opts.dashboard_port.or(file.dashboard_port);
+        let local_host = opts
+            .local_host
+            .or(file.host)
+            .unwrap_or(DEFAULT_LOCAL_HOST.to_string());
+        let local_port = opts.port.or(file.port).unwrap_or(DEFAULT_LOCAL_PORT);
+
+        let (secret_key, sub_domain, sub_domain_check) = match opts.command {
             Some(SubCommand::SetAuth { key }) => {
-                let key = opts.key.unwrap_or(key);
+                let key = opts.key.or(file_key).unwrap_or(key);
                 let settings_dir = match dirs::home_dir().map(|h| h.join(SETTINGS_DIR)) {
                     Some(path) => path,
                     None => {
@@ -111,48 +696,91 @@ impl Config {
                 eprintln!("Authentication key stored successfully!");
                 std::process::exit(0);
             }
-            None => {
-                let key = opts.key;
-                let sub_domain = opts.sub_domain;
-                (
-                    match key {
-                        Some(key) => Some(key),
-                        None => dirs::home_dir()
-                            .map(|h| h.join(SETTINGS_DIR).join(SECRET_KEY_FILE))
-                            .map(|path| {
-                                if path.exists() {
-                                    std::fs::read_to_string(path)
-                                        .map_err(|e| {
-                                            error!("Error reading authentication token: {:?}", e)
-                                        })
-                                        .ok()
-                                } else {
-                                    None
-                                }
-                            })
-                            .unwrap_or(None),
-                    },
-                    sub_domain,
-                )
-            }
+            Some(SubCommand::Version { .. }) => unreachable!("handled before loading the config"),
+            Some(SubCommand::SubdomainCheck { name }) => (
+                resolve_key(
+                    opts.key,
+                    opts.key_file.as_deref(),
+                    file_key,
+                    env::var("TUNNELTO_KEY").ok(),
+                    stored_key,
+                )?,
+                None,
+                Some(name),
+            ),
+            None => (
+                resolve_key(
+                    opts.key,
+                    opts.key_file.as_deref(),
+                    file_key,
+                    env::var("TUNNELTO_KEY").ok(),
+                    stored_key,
+                )?,
+                opts.sub_domain,
+                None,
+            ),
         };
 
-        let local_addr = match (opts.local_host.as_str(), opts.port)
-            .to_socket_addrs()
-            .unwrap_or(vec![].into_iter())
-            .next()
-        {
-            Some(addr) => addr,
-            None => {
-                error!(
-                    "An invalid local address was specified: {}:{}",
-                    opts.local_host.as_str(),
-                    opts.port
+        let scheme = opts.scheme.as_ref().map(|s| s.to_lowercase());
+        let use_tls = match (scheme.as_deref(), opts.use_tls) {
+            (None, use_tls) => use_tls,
+            (Some("https"), _) => true,
+            (Some("http"), false) => false,
+            (Some("http"), true) => {
+                eprintln!("Error: `--use-tls` conflicts with `--scheme http`");
+                return Err(());
+            }
+            (Some(scheme), _) => {
+                eprintln!(
+                    "Error: invalid scheme '{}', expected `http` or `https`",
+                    scheme
                 );
                 return Err(());
             }
         };
 
+        if use_tls && opts.unix_socket.is_some() {
+            eprintln!("Error: TLS cannot be used when forwarding to `--unix-socket`");
+            return Err(());
+        }
+
+        if opts.tcp && opts.basic_auth.is_some() {
+            eprintln!("Error: `--basic-auth` cannot be used with raw `--tcp` tunnels");
+            return Err(());
+        }
+
+        if opts.tcp && !opts.tunnel_only_methods.is_empty() {
+            eprintln!("Error: `--tunnel-only-methods` cannot be used with raw `--tcp` tunnels");
+            return Err(());
+        }
+
+        // sent as a header value
+        let region = opts.region.map(|r| r.trim().to_lowercase());
+        if let Some(region) = region.as_ref() {
+            if region.is_empty() || !region.chars().all(|c| c.is_ascii_alphanumeric()) {
+                eprintln!("Error: invalid `--region` {:?}", region);
+                return Err(());
+            }
+        }
+
+        if opts.remote_port.is_some() && !opts.tcp {
+            eprintln!("Error: `--remote-port` requires `--tcp`");
+            return Err(());
+        }
+
+        let sub_domain = encode_sub_domain(sub_domain)?;
+        let local_addrs = resolve_local_addrs(&local_host, local_port)?;
+        let local_addr = local_addrs[0];
+
+        let mut forwards = vec![];
+        for (sub_domain, local_port) in opts.forwards {
+            forwards.push(Forward {
+                sub_domain: encode_sub_domain(sub_domain)?,
+                local_port,
+                local_addr: resolve_local_addr(&local_host, local_port)?,
+            });
+        }
+
         // get the host url
         let tls_off = env::var(TLS_OFF_ENV).is_ok();
         let host = env::var(HOST_ENV).unwrap_or(format!("{}", DEFAULT_HOST));
@@ -164,25 +792,103 @@ impl Config {
         let scheme = if tls_off { "ws" } else { "wss" };
         let control_url = format!("{}://{}:{}/wormhole", scheme, control_host, port);
 
+        let api_scheme = if tls_off { "http" } else { "https" };
+        let control_api_url = format!("{}://{}:{}", api_scheme, control_host, port);
+
         info!("Control Server URL: {}", &control_url);
 
         Ok(Config {
             client_id: ClientId::generate(),
-            local_host: opts.local_host,
-            use_tls: opts.use_tls,
+            local_host,
+            use_tls,
+            tls_fail_fast: opts.tls_fail_fast,
             control_url,
+            control_api_url,
             host,
-            local_port: opts.port,
+            local_port,
             local_addr,
+            unix_socket: opts.unix_socket,
             sub_domain,
+            sub_domain_check,
+            dashboard_host: opts.dashboard_host,
             dashboard_port: opts.dashboard_port.unwrap_or(0),
+            dashboard_fallback: opts.dashboard_fallback,
+            max_capture_bytes: opts.max_capture_bytes,
+            capture_bodies: opts.inspect_capture_bodies,
+            max_captured_requests: opts.max_captured_requests,
+            record_dir: opts.record_dir,
+            raw_in: opts.raw_in,
+            raw_out: opts.raw_out,
+            raw_max_bytes: opts.raw_max_bytes,
+            shutdown_grace: opts.shutdown_grace,
+            local_response_timeout: opts.local_response_timeout,
+            connect_timeout: opts.connect_timeout,
+            local_path_health: opts.local_path_health,
+            host_header: opts.host_header,
+            basic_auth: opts.basic_auth,
+            allowed_methods: Some(opts.tunnel_only_methods)
+                .filter(|methods| !methods.is_empty())
+                .map(|methods| {
+                    methods
+                        .iter()
+                        .map(|m| m.trim().to_uppercase())
+                        .filter(|m| !m.is_empty())
+                        .collect()
+                }),
+            takeover: opts.takeover,
+            compress: opts.compress,
+            tcp: opts.tcp,
+            remote_port: opts.remote_port,
+            region,
+            no_spinner: opts.no_spinner || !std::io::stderr().is_terminal(),
+            output: opts.output,
+            show_resolved: opts.show_resolved,
+            wait_for_local: opts.wait_for_local,
+            open: opts.open,
+            open_dashboard: opts.open_dashboard,
+            local_addrs,
             verbose: opts.verbose,
             secret_key: secret_key.map(|s| SecretKey(s)),
             control_tls_off: tls_off,
             first_run: true,
+            forwards,
+            active_streams: Arc::new(RwLock::new(HashMap::new())),
+            reconnect_token: Arc::new(Mutex::new(None)),
+            max_frame_size: Arc::new(AtomicUsize::new(MAX_FRAME_SIZE)),
+            server_stream_end: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// The config for these command line arguments, i.e: `&["tunnelto", "--port", "3000"]`
+    #[cfg(test)]
+    pub fn from_args(args: &[&str]) -> Config {
+        Self::from_opts(Opts::from_iter(args)).unwrap()
+    }
+
+    /// One config per tunnel to open: for each `--forward`, or just this one
+    pub fn tunnels(&self) -> Vec<Config> {
+        if self.forwards.is_empty() {
+            return vec![self.clone()];
+        }
+
+        self.forwards
+            .iter()
+            .map(|forward| Config {
+                sub_domain: forward.sub_domain.clone(),
+                local_port: forward.local_port,
+                local_addr: forward.local_addr,
+                local_addrs: vec![forward.local_addr],
+                // concurrent spinners would garble the terminal
+                no_spinner: true,
+                active_streams: Arc::new(RwLock::new(HashMap::new())),
+                reconnect_token: Arc::new(Mutex::new(None)),
+                max_frame_size: Arc::new(AtomicUsize::new(MAX_FRAME_SIZE)),
+                server_stream_end: Arc::new(AtomicBool::new(false)),
+                ..self.clone()
+            })
+            .collect()
+    }
+
     pub fn activation_url(&self, full_hostname: &str) -> String {
         format!(
             "{}://{}",
@@ -196,11 +902,243 @@ impl Config {
     }
 
     pub fn forward_url(&self) -> String {
-        let scheme = if self.use_tls { "https" } else { "http" };
+        if let Some(path) = &self.unix_socket {
+            return format!("unix:{}", path.display());
+        }
+
+        let scheme = match (self.tcp, self.use_tls) {
+            (true, _) => "tcp",
+            (false, true) => "https",
+            (false, false) => "http",
+        };
         format!("{}://{}:{}", &scheme, &self.local_host, &self.local_port)
     }
+    /// i.e: "127.0.0.1:8000 (also resolved: [::1]:8000)"
+    pub fn resolved_local_addr(&self) -> String {
+        let others: Vec<String> = self
+            .local_addrs
+            .iter()
+            .filter(|addr| **addr != self.local_addr)
+            .map(|addr| addr.to_string())
+            .collect();
+
+        if others.is_empty() {
+            self.local_addr.to_string()
+        } else {
+            format!("{} (also resolved: {})", self.local_addr, others.join(", "))
+        }
+    }
+
+    /// The url to open the introspection dashboard listening on `port` at
+    pub fn dashboard_url(&self, port: u16) -> String {
+        if self.dashboard_host.is_unspecified() {
+            format!("http://localhost:{}", port)
+        } else {
+            format!("http://{}", SocketAddr::new(self.dashboard_host, port))
+        }
+    }
+
+    /// The Host header to rewrite requests to the local service with, if any
+    pub fn host_header_value(&self) -> Option<String> {
+        match &self.host_header {
+            HostHeader::Preserve => None,
+            HostHeader::Rewrite => Some(format!("{}:{}", &self.local_host, &self.local_port)),
+            HostHeader::Value(value) => Some(value.clone()),
+        }
+    }
+
     pub fn ws_forward_url(&self) -> String {
         let scheme = if self.use_tls { "wss" } else { "ws" };
         format!("{}://{}:{}", scheme, &self.local_host, &self.local_port)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_has_the_protocol_version() {
+        let info = build_info();
+        assert!(info.contains(env!("CARGO_PKG_VERSION")));
+        assert!(info.contains(&format!(
+            "protocol version: {} (min {})",
+            PROTOCOL_VERSION, MIN_PROTOCOL_VERSION
+        )));
+    }
+
+    #[test]
+    fn sub_domain_normalization_notice() {
+        assert_eq!(normalization_notice("my-app"), None);
+        assert_eq!(normalization_notice("café"), None);
+
+        let notice = normalization_notice("-my--app-").unwrap();
+        assert!(
+            notice.contains("'-my--app-' was normalized to 'my-app'"),
+            "{}",
+            notice
+        );
+
+        assert_eq!(
+            encode_sub_domain(Some("-my--app-".to_string())),
+            Ok(Some("my-app".to_string()))
+        );
+        assert_eq!(encode_sub_domain(Some("a..b".to_string())), Err(()));
+    }
+
+    #[test]
+    fn key_precedence() {
+        let key = |s: &str| Some(s.to_string());
+        let key_file = TempFile::new("key-file", " from-key-file\n");
+        let stored = || key("stored");
+
+        assert_eq!(
+            resolve_key(
+                key("flag"),
+                Some(&key_file.0),
+                key("file"),
+                key("env"),
+                stored
+            ),
+            Ok(key("flag"))
+        );
+        assert_eq!(
+            resolve_key(None, Some(&key_file.0), key("file"), key("env"), stored),
+            Ok(key("from-key-file"))
+        );
+        assert_eq!(
+            resolve_key(None, None, key("file"), key("env"), stored),
+            Ok(key("file"))
+        );
+        assert_eq!(
+            resolve_key(None, None, None, key(" env "), stored),
+            Ok(key("env"))
+        );
+
+        // a blank env var is as good as none
+        assert_eq!(
+            resolve_key(None, None, None, key(" "), stored),
+            Ok(key("stored"))
+        );
+        assert_eq!(resolve_key(None, None, None, None, || None), Ok(None));
+
+        let missing = std::env::temp_dir().join("tunnelto-missing-key.token");
+        assert_eq!(
+            resolve_key(key("flag"), Some(&missing), None, None, stored),
+            Ok(key("flag"))
+        );
+        assert_eq!(
+            resolve_key(None, Some(&missing), None, None, stored),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn verbose_keeps_rust_log_filters() {
+        use log::{Level, Log, Metadata};
+
+        let enabled = |verbose: bool, rust_log: Option<&str>, target: &str, level: Level| {
+            let logger = logger_builder(verbose, rust_log).build();
+            logger.enabled(&Metadata::builder().target(target).level(level).build())
+        };
+
+        assert!(enabled(true, None, "tunnelto::local", Level::Debug));
+        assert!(!enabled(false, None, "tunnelto::local", Level::Debug));
+
+        // the user's filters for other modules are kept next to ours
+        assert!(enabled(
+            true,
+            Some("hyper=trace"),
+            "hyper::proto",
+            Level::Trace
+        ));
+        assert!(enabled(
+            true,
+            Some("hyper=trace"),
+            "tunnelto::local",
+            Level::Debug
+        ));
+        assert!(enabled(
+            false,
+            Some("hyper=trace"),
+            "hyper::proto",
+            Level::Trace
+        ));
+
+        // and theirs for our crate win over `--verbose`
+        assert!(!enabled(
+            true,
+            Some("tunnelto=warn"),
+            "tunnelto::local",
+            Level::Debug
+        ));
+        assert!(enabled(
+            true,
+            Some("tunnelto=warn"),
+            "tunnelto::local",
+            Level::Warn
+        ));
+        assert!(enabled(
+            true,
+            Some("tunnelto=trace"),
+            "tunnelto::local",
+            Level::Trace
+        ));
+    }
+
+    #[test]
+    fn resolved_local_addr_display() {
+        let mut config = Config::from_args(&["tunnelto", "--host", "127.0.0.1", "--port", "3000"]);
+        assert_eq!(config.local_addrs, vec![config.local_addr]);
+        assert_eq!(config.resolved_local_addr(), "127.0.0.1:3000");
+
+        // i.e: `localhost` resolving both ways, we forward to the first
+        config.local_addrs = vec![
+            config.local_addr,
+            "[::1]:3000".parse().unwrap(),
+            "10.0.0.1:3000".parse().unwrap(),
+        ];
+        assert_eq!(
+            config.resolved_local_addr(),
+            "127.0.0.1:3000 (also resolved: [::1]:3000, 10.0.0.1:3000)"
+        );
+
+        assert_eq!(resolve_local_addrs("", 3000), Err(()));
+    }
+
+    /// A temp file with these contents, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("tunnelto-{}-{}.toml", name, std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn config_check_valid_file() {
+        let file = TempFile::new("valid", "port = 3000\nscheme = \"https\"\n");
+        assert_eq!(config_check(Some(&file.0)), Ok(file.0.clone()));
+    }
+
+    #[test]
+    fn config_check_problems() {
+        let out_of_range = TempFile::new("out-of-range", "port = 0\n");
+        assert_eq!(config_check(Some(&out_of_range.0)), Err(()));
+
+        let unknown_key = TempFile::new("unknown-key", "prot = 3000\n");
+        assert_eq!(config_check(Some(&unknown_key.0)), Err(()));
+
+        let missing = std::env::temp_dir().join("tunnelto-missing-config.toml");
+        assert_eq!(config_check(Some(&missing)), Err(()));
+    }
+}
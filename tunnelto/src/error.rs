@@ -1,4 +1,5 @@
 use thiserror::Error;
+use tunnelto_lib::CONTROL_SUBPROTOCOL;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -8,6 +9,9 @@ pub enum Error {
     #[error("Server denied the connection.")]
     AuthenticationFailed,
 
+    #[error("Server denied the connection: {0}")]
+    AuthenticationRefused(String),
+
     #[error("Server sent a malformed message.")]
     MalformedMessageFromServer,
 
@@ -17,6 +21,16 @@ pub enum Error {
     #[error("Cannot use this sub-domain, it is already taken.")]
     SubDomainInUse,
 
+    #[error("This client speaks protocol version {ours}, but the server supports versions {min} to {max}. Please upgrade tunnelto: https://tunnelto.dev")]
+    IncompatibleVersion { ours: u8, min: u8, max: u8 },
+
+    #[error("This client speaks protocol version {ours}, but the server requires version {min} or newer. Please upgrade tunnelto: {upgrade_url}")]
+    OutdatedClient {
+        ours: u8,
+        min: u8,
+        upgrade_url: String,
+    },
+
     #[error("{0}")]
     ServerError(String),
 
@@ -26,6 +40,12 @@ pub enum Error {
     #[error("The server did not respond to our client_hello.")]
     NoResponseFromServer,
 
+    #[error("The server speaks websocket subprotocol {0}, but this client speaks {}. Please upgrade tunnelto: https://tunnelto.dev", CONTROL_SUBPROTOCOL)]
+    SubprotocolMismatch(String),
+
+    #[error("The server rejected the connection during the handshake.")]
+    HandshakeClosed,
+
     #[error("The server timed out sending us something.")]
     Timeout,
 }
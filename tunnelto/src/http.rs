@@ -0,0 +1,788 @@
+/// How much of the current HTTP message body is left to pass through
+#[derive(Debug, Clone, Copy)]
+pub enum BodyLength {
+    Remaining(usize),
+    Chunked,
+    UntilClose,
+}
+
+/// Upper bound on a chunk size or trailer line before we stop looking for the end of the body
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// The framing of a chunked body is broken, so its end can't be found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidChunk;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkState {
+    /// the size line of the next chunk
+    Size,
+    /// bytes left of the current chunk
+    Data(u64),
+    /// the line break after a chunk's data
+    DataEnd,
+    /// trailer lines after the last chunk, up to an empty one
+    Trailer,
+}
+
+/// Finds where a chunked body ends, no matter how it's split across reads
+#[derive(Debug, Clone)]
+pub struct ChunkedBody {
+    state: ChunkState,
+    line: Vec<u8>,
+}
+
+impl Default for ChunkedBody {
+    fn default() -> Self {
+        Self {
+            state: ChunkState::Size,
+            line: vec![],
+        }
+    }
+}
+
+impl ChunkedBody {
+    /// Feed body bytes, returns how many of them the body took if it ended within them
+    pub fn feed(&mut self, data: &[u8]) -> Result<Option<usize>, InvalidChunk> {
+        let mut read = 0;
+
+        while read < data.len() {
+            if let ChunkState::Data(remaining) = self.state {
+                let n = remaining.min((data.len() - read) as u64);
+                read += n as usize;
+                self.state = match remaining - n {
+                    0 => ChunkState::DataEnd,
+                    remaining => ChunkState::Data(remaining),
+                };
+                continue;
+            }
+
+            // the other states are a line each
+            match data[read..].iter().position(|b| *b == b'\n') {
+                Some(end) => {
+                    self.line.extend_from_slice(&data[read..read + end + 1]);
+                    read += end + 1;
+                }
+                None => {
+                    self.line.extend_from_slice(&data[read..]);
+                    read = data.len();
+                }
+            }
+            if self.line.len() > MAX_CHUNK_LINE {
+                return Err(InvalidChunk);
+            }
+            if self.line.last() != Some(&b'\n') {
+                break;
+            }
+
+            let line = std::mem::take(&mut self.line);
+            let empty = line == b"\r\n";
+            match self.state {
+                ChunkState::Size => match httparse::parse_chunk_size(&line) {
+                    Ok(httparse::Status::Complete((_, 0))) => self.state = ChunkState::Trailer,
+                    Ok(httparse::Status::Complete((_, size))) => {
+                        self.state = ChunkState::Data(size)
+                    }
+                    _ => return Err(InvalidChunk),
+                },
+                ChunkState::DataEnd if empty => self.state = ChunkState::Size,
+                ChunkState::DataEnd => return Err(InvalidChunk),
+                ChunkState::Trailer if empty => {
+                    *self = Self::default();
+                    return Ok(Some(read));
+                }
+                ChunkState::Trailer => {}
+                ChunkState::Data(_) => unreachable!(),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Best-effort tracking of where HTTP responses from the local service end,
+/// so we can mark message boundaries in the tunnel stream.
+#[derive(Debug, Default)]
+pub struct ResponseBoundary {
+    head: Vec<u8>,
+    body: Option<BodyLength>,
+    chunks: ChunkedBody,
+}
+
+impl ResponseBoundary {
+    /// Feed bytes read from the local service, returns true if a response ended within them
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        let mut pending = data.to_vec();
+        let mut ended = false;
+
+        while !pending.is_empty() {
+            match self.body {
+                None => {
+                    self.head.append(&mut pending);
+
+                    let mut headers = [httparse::EMPTY_HEADER; 100];
+                    let mut response = httparse::Response::new(&mut headers);
+                    let head_len = match response.parse(&self.head) {
+                        Ok(httparse::Status::Complete(len)) => len,
+                        Ok(httparse::Status::Partial) => break,
+                        Err(_) => {
+                            // not http: no boundaries to find
+                            self.head.clear();
+                            self.body = Some(BodyLength::UntilClose);
+                            break;
+                        }
+                    };
+
+                    let header = |name: &str| {
+                        response
+                            .headers
+                            .iter()
+                            .find(|h| h.name.eq_ignore_ascii_case(name))
+                            .and_then(|h| std::str::from_utf8(h.value).ok())
+                    };
+                    let chunked = header("transfer-encoding")
+                        .map_or(false, |v| v.to_lowercase().contains("chunked"));
+                    let content_length =
+                        header("content-length").and_then(|v| v.trim().parse::<usize>().ok());
+
+                    let body = match (response.code, chunked, content_length) {
+                        (Some(100..=199), _, _) | (Some(204), _, _) | (Some(304), _, _) => {
+                            BodyLength::Remaining(0)
+                        }
+                        (_, true, _) => BodyLength::Chunked,
+                        (_, false, Some(len)) => BodyLength::Remaining(len),
+                        (_, false, None) => BodyLength::UntilClose,
+                    };
+
+                    pending = self.head.split_off(head_len);
+                    self.head.clear();
+
+                    if let BodyLength::Remaining(0) = body {
+                        ended = true;
+                    } else {
+                        self.chunks = ChunkedBody::default();
+                        self.body = Some(body);
+                    }
+                }
+                Some(BodyLength::Remaining(len)) => {
+                    if pending.len() >= len {
+                        pending.drain(..len);
+                        self.body = None;
+                        ended = true;
+                    } else {
+                        self.body = Some(BodyLength::Remaining(len - pending.len()));
+                        pending.clear();
+                    }
+                }
+                Some(BodyLength::Chunked) => match self.chunks.feed(&pending) {
+                    Ok(Some(len)) => {
+                        pending.drain(..len);
+                        self.body = None;
+                        ended = true;
+                    }
+                    Ok(None) => pending.clear(),
+                    Err(InvalidChunk) => {
+                        self.body = Some(BodyLength::UntilClose);
+                        pending.clear();
+                    }
+                },
+                Some(BodyLength::UntilClose) => pending.clear(),
+            }
+        }
+
+        ended
+    }
+}
+
+/// Upper bound on a buffered request head before we give up and pass it through
+const MAX_REQUEST_HEAD: usize = 64 * 1024;
+
+/// Rewrites the parsed head of each HTTP request written to the local service,
+/// passing bodies and upgraded (i.e. WebSocket) streams through untouched.
+#[derive(Debug)]
+pub struct RequestRewriter {
+    host: String,
+    head: Vec<u8>,
+    body: Option<BodyLength>,
+    chunks: ChunkedBody,
+}
+
+impl RequestRewriter {
+    /// Replace the `Host` header of each request with `host`
+    pub fn new(host: String) -> Self {
+        Self {
+            host,
+            head: vec![],
+            body: None,
+            chunks: ChunkedBody::default(),
+        }
+    }
+
+    /// Feed bytes headed to the local service, returns the bytes to write now
+    pub fn rewrite(&mut self, data: Vec<u8>) -> Vec<u8> {
+        let mut pending = data;
+        let mut out = vec![];
+
+        while !pending.is_empty() {
+            match self.body {
+                None => {
+                    self.head.append(&mut pending);
+
+                    let mut headers = [httparse::EMPTY_HEADER; 100];
+                    let mut request = httparse::Request::new(&mut headers);
+                    let head_len = match request.parse(&self.head) {
+                        Ok(httparse::Status::Complete(len)) => len,
+                        Ok(httparse::Status::Partial) if self.head.len() < MAX_REQUEST_HEAD => {
+                            break
+                        }
+                        _ => {
+                            // not http (or too large): stop rewriting
+                            out.append(&mut self.head);
+                            self.body = Some(BodyLength::UntilClose);
+                            break;
+                        }
+                    };
+
+                    let header = |name: &str| {
+                        request
+                            .headers
+                            .iter()
+                            .find(|h| h.name.eq_ignore_ascii_case(name))
+                            .and_then(|h| std::str::from_utf8(h.value).ok())
+                    };
+                    let body = if header("upgrade").is_some() {
+                        BodyLength::UntilClose
+                    } else if header("transfer-encoding")
+                        .map_or(false, |v| v.to_lowercase().contains("chunked"))
+                    {
+                        BodyLength::Chunked
+                    } else {
+                        BodyLength::Remaining(
+                            header("content-length")
+                                .and_then(|v| v.trim().parse::<usize>().ok())
+                                .unwrap_or(0),
+                        )
+                    };
+
+                    pending = self.head.split_off(head_len);
+                    out.extend(self.rewrite_head(&self.head));
+                    self.head.clear();
+
+                    if let BodyLength::Remaining(0) = body {
+                        self.body = None;
+                    } else {
+                        self.chunks = ChunkedBody::default();
+                        self.body = Some(body);
+                    }
+                }
+                Some(BodyLength::Remaining(len)) => {
+                    if pending.len() >= len {
+                        out.extend(pending.drain(..len));
+                        self.body = None;
+                    } else {
+                        self.body = Some(BodyLength::Remaining(len - pending.len()));
+                        out.append(&mut pending);
+                    }
+                }
+                Some(BodyLength::Chunked) => match self.chunks.feed(&pending) {
+                    Ok(Some(len)) => {
+                        out.extend(pending.drain(..len));
+                        self.body = None;
+                    }
+                    Ok(None) => out.append(&mut pending),
+                    Err(InvalidChunk) => {
+                        // its end can't be found, so neither can the next request's head
+                        out.append(&mut pending);
+                        self.body = Some(BodyLength::UntilClose);
+                    }
+                },
+                Some(BodyLength::UntilClose) => out.append(&mut pending),
+            }
+        }
+
+        out
+    }
+
+    /// Only `Host` is replaced: the upgrade headers in `PRESERVED_HEADERS` always pass as-is
+    fn rewrite_head(&self, head: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(head.len());
+        for (i, line) in head.split_inclusive(|b| *b == b'\n').enumerate() {
+            let name = match line.iter().position(|b| *b == b':') {
+                Some(end) if i > 0 => std::str::from_utf8(&line[..end]).unwrap_or_default(),
+                _ => "",
+            };
+
+            if tunnelto_lib::is_preserved_header(name.trim()) {
+                out.extend_from_slice(line);
+            } else if name.trim().eq_ignore_ascii_case("host") {
+                out.extend_from_slice(format!("Host: {}\r\n", self.host).as_bytes());
+            } else {
+                out.extend_from_slice(line);
+            }
+        }
+        out
+    }
+}
+
+/// Does the request head in `data` carry `Authorization: Basic` with exactly `credentials` (`user:pass`)
+pub fn has_basic_auth(data: &[u8], credentials: &str) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 100];
+    let mut request = httparse::Request::new(&mut headers);
+    if request.parse(data).is_err() {
+        return false;
+    }
+
+    let value = match request
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("authorization"))
+    {
+        Some(header) => header.value,
+        None => return false,
+    };
+
+    if value.len() < 6 || !value[..6].eq_ignore_ascii_case(b"basic ") {
+        return false;
+    }
+
+    let expected = base64::encode(credentials);
+    constant_time_eq(&value[6..], expected.as_bytes())
+}
+
+/// Compare without short-circuiting, so timing doesn't leak how much of a secret matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Upper bound on a buffered upgrade head before we stop logging frames
+const MAX_UPGRADE_HEAD: usize = 64 * 1024;
+
+#[derive(Debug)]
+enum FrameState {
+    /// buffering the HTTP upgrade request or response
+    Head(Vec<u8>),
+    /// in the frames: a partial frame header, or the payload of the current frame
+    Frames {
+        header: Vec<u8>,
+        payload: Option<FramePayload>,
+    },
+    Disabled,
+}
+
+#[derive(Debug)]
+struct FramePayload {
+    frame: WebSocketFrame,
+    mask: Option<[u8; 4]>,
+    read: u64,
+}
+
+/// Bytes of each frame payload kept for previews
+const FRAME_PREVIEW_LEN: usize = 128;
+
+/// A WebSocket frame, with the start of its unmasked payload
+#[derive(Debug, Clone)]
+pub struct WebSocketFrame {
+    pub opcode: u8,
+    pub len: u64,
+    pub preview: Vec<u8>,
+}
+
+/// Splits one direction of an upgraded stream into WebSocket frames
+#[derive(Debug)]
+pub struct WebSocketFrameParser {
+    state: FrameState,
+}
+
+impl WebSocketFrameParser {
+    pub fn new() -> Self {
+        Self {
+            state: FrameState::Head(vec![]),
+        }
+    }
+
+    /// Feed bytes of this direction, starting with the upgrade head,
+    /// returning the frames completed by them
+    pub fn feed(&mut self, data: &[u8]) -> Vec<WebSocketFrame> {
+        let mut frames = vec![];
+        self.feed_into(data, &mut frames);
+        frames
+    }
+
+    fn feed_into(&mut self, data: &[u8], frames: &mut Vec<WebSocketFrame>) {
+        let mut pending = data;
+
+        while !pending.is_empty() {
+            match &mut self.state {
+                FrameState::Disabled => return,
+                FrameState::Head(head) => {
+                    head.extend_from_slice(pending);
+                    pending = &[];
+
+                    match head.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(idx) => {
+                            let rest = head.split_off(idx + 4);
+                            self.state = FrameState::Frames {
+                                header: vec![],
+                                payload: None,
+                            };
+                            self.feed_into(&rest, frames);
+                        }
+                        None if head.len() > MAX_UPGRADE_HEAD => self.state = FrameState::Disabled,
+                        None => {}
+                    }
+                }
+                FrameState::Frames {
+                    payload: Some(current),
+                    ..
+                } => {
+                    let remaining = current.frame.len - current.read;
+                    let n = (remaining.min(pending.len() as u64)) as usize;
+
+                    for (i, b) in pending[..n].iter().enumerate() {
+                        let offset = current.read as usize + i;
+                        if offset >= FRAME_PREVIEW_LEN {
+                            break;
+                        }
+                        let b = match current.mask {
+                            Some(mask) => b ^ mask[offset % 4],
+                            None => *b,
+                        };
+                        current.frame.preview.push(b);
+                    }
+
+                    current.read += n as u64;
+                    pending = &pending[n..];
+
+                    if current.read == current.frame.len {
+                        if let FrameState::Frames { payload, .. } = &mut self.state {
+                            if let Some(done) = payload.take() {
+                                frames.push(done.frame);
+                            }
+                        }
+                    }
+                }
+                FrameState::Frames { header, payload } => {
+                    header.push(pending[0]);
+                    pending = &pending[1..];
+
+                    if let Some((opcode, len, mask)) = parse_frame_header(header) {
+                        header.clear();
+                        let frame = WebSocketFrame {
+                            opcode,
+                            len,
+                            preview: vec![],
+                        };
+                        if len == 0 {
+                            frames.push(frame);
+                        } else {
+                            *payload = Some(FramePayload {
+                                frame,
+                                mask,
+                                read: 0,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Logs the WebSocket frames (opcode and size) of one direction of an upgraded stream
+#[derive(Debug)]
+pub struct WebSocketFrameLog {
+    direction: &'static str,
+    parser: WebSocketFrameParser,
+}
+
+impl WebSocketFrameLog {
+    pub fn new(direction: &'static str) -> Self {
+        Self {
+            direction,
+            parser: WebSocketFrameParser::new(),
+        }
+    }
+
+    /// Feed bytes of this direction, starting with the upgrade head
+    pub fn feed(&mut self, data: &[u8]) {
+        for frame in self.parser.feed(data) {
+            log::debug!(
+                "{}",
+                frame_log_line(self.direction, frame.opcode, frame.len)
+            );
+        }
+    }
+}
+
+/// The opcode, payload length and masking key of a complete frame header
+fn parse_frame_header(header: &[u8]) -> Option<(u8, u64, Option<[u8; 4]>)> {
+    if header.len() < 2 {
+        return None;
+    }
+
+    let opcode = header[0] & 0x0F;
+    let mask_len = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    let (len, len_size) = match header[1] & 0x7F {
+        126 => {
+            if header.len() < 4 {
+                return None;
+            }
+            (u16::from_be_bytes([header[2], header[3]]) as u64, 2)
+        }
+        127 => {
+            if header.len() < 10 {
+                return None;
+            }
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&header[2..10]);
+            (u64::from_be_bytes(len), 8)
+        }
+        len => (len as u64, 0),
+    };
+
+    if header.len() < 2 + len_size + mask_len {
+        return None;
+    }
+
+    let mask = if mask_len > 0 {
+        let start = 2 + len_size;
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&header[start..start + 4]);
+        Some(mask)
+    } else {
+        None
+    };
+
+    Some((opcode, len, mask))
+}
+
+/// i.e: "text", "binary" or "close"
+pub fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        0x0 => "continuation".to_string(),
+        0x1 => "text".to_string(),
+        0x2 => "binary".to_string(),
+        0x8 => "close".to_string(),
+        0x9 => "ping".to_string(),
+        0xA => "pong".to_string(),
+        other => format!("reserved(0x{:x})", other),
+    }
+}
+
+/// i.e: "websocket frame (remote -> local): text, 42 bytes"
+fn frame_log_line(direction: &str, opcode: u8, len: u64) -> String {
+    format!(
+        "websocket frame ({}): {}, {} bytes",
+        direction,
+        opcode_name(opcode),
+        len
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(rewriter: &mut RequestRewriter, data: &[u8]) -> String {
+        String::from_utf8(rewriter.rewrite(data.to_vec())).unwrap()
+    }
+
+    /// The frame log lines of a stream arriving in these reads, after an upgrade head
+    fn frame_log_lines(reads: &[&[u8]]) -> Vec<Vec<String>> {
+        let mut parser = WebSocketFrameParser::new();
+        parser.feed(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n");
+        reads
+            .iter()
+            .map(|data| {
+                parser
+                    .feed(data)
+                    .iter()
+                    .map(|frame| frame_log_line("local -> remote", frame.opcode, frame.len))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_log_line_text_and_binary() {
+        // a masked text frame, then a binary one with a 16-bit length
+        let mut data = vec![0x81, 0x85, 1, 2, 3, 4];
+        data.extend(
+            b"hello"
+                .iter()
+                .zip([1, 2, 3, 4].iter().cycle())
+                .map(|(b, m)| b ^ m),
+        );
+        data.extend_from_slice(&[0x82, 126, 0x01, 0x2C]);
+        data.extend_from_slice(&[0; 300]);
+
+        assert_eq!(
+            frame_log_lines(&[&data]),
+            vec![vec![
+                "websocket frame (local -> remote): text, 5 bytes".to_string(),
+                "websocket frame (local -> remote): binary, 300 bytes".to_string(),
+            ]]
+        );
+        assert_eq!(
+            frame_log_line("remote -> local", 0x3, 0),
+            "websocket frame (remote -> local): reserved(0x3), 0 bytes"
+        );
+    }
+
+    #[test]
+    fn frame_log_line_once_a_truncated_frame_completes() {
+        // the header split in the middle of its length, then the payload split too
+        let lines = frame_log_lines(&[&[0x82, 126], &[0x00], &[0x04, b'a', b'b'], b"cd"]);
+        assert_eq!(
+            lines,
+            vec![
+                vec![],
+                vec![],
+                vec![],
+                vec!["websocket frame (local -> remote): binary, 4 bytes".to_string()],
+            ]
+        );
+
+        // and none for a frame cut off by the end of the stream
+        assert_eq!(
+            frame_log_lines(&[&[0x81, 0x05, b'h', b'e']]),
+            vec![Vec::<String>::new()]
+        );
+    }
+
+    const CHUNKED_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\n0\r\n\r\n\r\nA\r\n0123456789\r\n0\r\nExpires: never\r\n\r\n";
+
+    #[test]
+    fn chunked_body_ends_after_trailers() {
+        let body = b"3\r\nabc\r\n0\r\nX-Trailer: 1\r\n\r\nHTTP/1.1";
+        assert_eq!(ChunkedBody::default().feed(body), Ok(Some(body.len() - 8)));
+
+        // without trailers
+        assert_eq!(ChunkedBody::default().feed(b"0\r\n\r\n"), Ok(Some(5)));
+        assert_eq!(ChunkedBody::default().feed(b"0\r\n"), Ok(None));
+    }
+
+    #[test]
+    fn chunked_body_rejects_broken_framing() {
+        assert_eq!(ChunkedBody::default().feed(b"zz\r\n"), Err(InvalidChunk));
+        assert_eq!(
+            ChunkedBody::default().feed(b"3\r\nabcdef\r\n"),
+            Err(InvalidChunk)
+        );
+        assert_eq!(
+            ChunkedBody::default().feed(&[b'1'; MAX_CHUNK_LINE + 1]),
+            Err(InvalidChunk)
+        );
+    }
+
+    #[test]
+    fn boundary_of_a_chunked_response_split_across_reads() {
+        // whatever the reads, the response only ends at its very last byte, not at the
+        // "0\r\n\r\n" in the first chunk's data
+        for split in 1..CHUNKED_RESPONSE.len() {
+            let mut boundary = ResponseBoundary::default();
+            assert!(
+                !boundary.feed(&CHUNKED_RESPONSE[..split]),
+                "split {}",
+                split
+            );
+            assert!(boundary.feed(&CHUNKED_RESPONSE[split..]), "split {}", split);
+        }
+
+        let mut boundary = ResponseBoundary::default();
+        for byte in CHUNKED_RESPONSE.iter().take(CHUNKED_RESPONSE.len() - 1) {
+            assert!(!boundary.feed(&[*byte]));
+        }
+        assert!(boundary.feed(b"\n"));
+
+        // the next response on the connection is tracked on its own
+        assert!(boundary.feed(b"HTTP/1.1 204 No Content\r\n\r\n"));
+    }
+
+    #[test]
+    fn rewriter_replaces_host() {
+        let mut rewriter = RequestRewriter::new("localhost:3000".to_string());
+        assert_eq!(
+            rewrite(
+                &mut rewriter,
+                b"GET / HTTP/1.1\r\nhost: foo.tunnelto.dev\r\nAccept: */*\r\n\r\n"
+            ),
+            "GET / HTTP/1.1\r\nHost: localhost:3000\r\nAccept: */*\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn rewriter_keeps_preserved_headers() {
+        let mut rewriter = RequestRewriter::new("localhost:3000".to_string());
+        let head = "GET /ws HTTP/1.1\r\nHost: foo.tunnelto.dev\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: k\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n";
+        assert_eq!(
+            rewrite(&mut rewriter, head.as_bytes()),
+            head.replace("Host: foo.tunnelto.dev", "Host: localhost:3000")
+        );
+
+        // the upgraded stream passes through untouched
+        assert_eq!(rewriter.rewrite(b"\x81\x02hi".to_vec()), b"\x81\x02hi");
+    }
+
+    #[test]
+    fn rewriter_finds_the_request_after_a_split_chunked_body() {
+        let request = b"POST /a HTTP/1.1\r\nHost: foo.tunnelto.dev\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n0\r\n\r\n\r\n0\r\nX-Sum: 1\r\n\r\n";
+        let next = b"GET /b HTTP/1.1\r\nHost: foo.tunnelto.dev\r\n\r\n";
+        let stream = [request.as_ref(), next].concat();
+        let expected = String::from_utf8(stream.clone())
+            .unwrap()
+            .replace("foo.tunnelto.dev", "localhost:3000");
+
+        for split in 1..stream.len() {
+            let mut rewriter = RequestRewriter::new("localhost:3000".to_string());
+            let mut out = rewrite(&mut rewriter, &stream[..split]);
+            out.push_str(&rewrite(&mut rewriter, &stream[split..]));
+            assert_eq!(out, expected, "split {}", split);
+        }
+    }
+
+    #[test]
+    fn basic_auth_matches_exact_credentials() {
+        // "user:pass"
+        let head = b"GET / HTTP/1.1\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        assert!(has_basic_auth(head, "user:pass"));
+        assert!(!has_basic_auth(head, "user:other"));
+        assert!(!has_basic_auth(head, "user:pas"));
+    }
+
+    #[test]
+    fn basic_auth_scheme_is_case_insensitive() {
+        let head = b"GET / HTTP/1.1\r\nauthorization: BASIC dXNlcjpwYXNz\r\n\r\n";
+        assert!(has_basic_auth(head, "user:pass"));
+    }
+
+    #[test]
+    fn basic_auth_rejects_missing_or_other_schemes() {
+        assert!(!has_basic_auth(b"GET / HTTP/1.1\r\n\r\n", "user:pass"));
+        assert!(!has_basic_auth(
+            b"GET / HTTP/1.1\r\nAuthorization: Bearer dXNlcjpwYXNz\r\n\r\n",
+            "user:pass"
+        ));
+        assert!(!has_basic_auth(
+            b"GET / HTTP/1.1\r\nAuthorization: Basic\r\n\r\n",
+            "user:pass"
+        ));
+        assert!(!has_basic_auth(b"\x00garbage", "user:pass"));
+    }
+
+    #[test]
+    fn basic_auth_needs_the_header_in_the_data() {
+        // the head isn't complete, the header hasn't arrived yet
+        assert!(!has_basic_auth(
+            b"GET / HTTP/1.1\r\nHost: a\r\n",
+            "user:pass"
+        ));
+    }
+}
@@ -4,6 +4,14 @@ pub fn connect_failed() {
     eprintln!("{}", "CONNECTION REFUSED".red())
 }
 
+pub fn tls_plaintext_failed() {
+    eprintln!(
+        "{} {}",
+        "TLS HANDSHAKE FAILED".red(),
+        "(the local service appears to speak plain HTTP, try `--scheme http`)".yellow()
+    )
+}
+
 pub fn log(request: &httparse::Request, response: &httparse::Response) {
     let out = match response.code {
         Some(code @ 200..=299) => format!("{}", code).green(),
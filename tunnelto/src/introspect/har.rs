@@ -0,0 +1,204 @@
+use super::Request;
+use chrono::TimeZone;
+use serde::Serialize;
+
+/// A HAR 1.2 archive of captured requests, see http://www.softwareishard.com/blog/har-12-spec/
+#[derive(Debug, Serialize)]
+pub struct Har {
+    log: Log,
+}
+
+#[derive(Debug, Serialize)]
+struct Log {
+    version: &'static str,
+    creator: Creator,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: i64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: Cache,
+    timings: Timings,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: &'static str,
+    cookies: Vec<Header>,
+    headers: Vec<Header>,
+    query_string: Vec<Header>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<Content>,
+    headers_size: i64,
+    body_size: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: &'static str,
+    cookies: Vec<Header>,
+    headers: Vec<Header>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: usize,
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Cache {}
+
+#[derive(Debug, Serialize)]
+struct Timings {
+    send: i64,
+    wait: i64,
+    receive: i64,
+}
+
+impl Har {
+    pub fn new(requests: &[Request]) -> Self {
+        Har {
+            log: Log {
+                version: "1.2",
+                creator: Creator {
+                    name: "tunnelto",
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries: requests.iter().map(Entry::from).collect(),
+            },
+        }
+    }
+}
+
+impl From<&Request> for Entry {
+    fn from(request: &Request) -> Self {
+        let time = (request.completed - request.started).num_milliseconds();
+        let started_date_time = chrono::Local
+            .from_local_datetime(&request.started)
+            .single()
+            .map(|started| started.to_rfc3339())
+            .unwrap_or_else(|| request.started.format("%Y-%m-%dT%H:%M:%S%.3f").to_string());
+
+        let path = request.path.clone().unwrap_or_default();
+        let host = header_value(&request.headers, "host").unwrap_or("localhost");
+        let query_string = path
+            .split_once('?')
+            .map(|(_, query)| {
+                serde_urlencoded::from_str::<Vec<(String, String)>>(query).unwrap_or_default()
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| Header { name, value })
+            .collect();
+
+        let post_data = if request.body_data.is_empty() {
+            None
+        } else {
+            Some(content(&request.headers, &request.body_data))
+        };
+
+        Entry {
+            started_date_time,
+            time,
+            request: HarRequest {
+                method: request.method.clone().unwrap_or_default(),
+                url: format!("http://{}{}", host, path),
+                http_version: "HTTP/1.1",
+                cookies: vec![],
+                headers: headers(&request.headers),
+                query_string,
+                post_data,
+                headers_size: -1,
+                body_size: request.body_data.len(),
+            },
+            response: HarResponse {
+                status: request.status,
+                status_text: String::new(),
+                http_version: "HTTP/1.1",
+                cookies: vec![],
+                headers: headers(&request.response_headers),
+                content: content(&request.response_headers, &request.response_data),
+                redirect_url: header_value(&request.response_headers, "location")
+                    .unwrap_or_default()
+                    .to_string(),
+                headers_size: -1,
+                body_size: request.response_data.len(),
+            },
+            cache: Cache {},
+            timings: Timings {
+                send: 0,
+                wait: time,
+                receive: 0,
+            },
+        }
+    }
+}
+
+fn headers(headers: &[(String, String)]) -> Vec<Header> {
+    headers
+        .iter()
+        .map(|(name, value)| Header {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect()
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Bodies are stored as text if they are UTF-8, otherwise base64 encoded
+fn content(headers: &[(String, String)], data: &[u8]) -> Content {
+    let mime_type = header_value(headers, "content-type")
+        .unwrap_or_default()
+        .to_string();
+
+    let (text, encoding) = match std::str::from_utf8(data) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (base64::encode(data), Some("base64")),
+    };
+
+    Content {
+        size: data.len(),
+        mime_type,
+        text,
+        encoding,
+    }
+}
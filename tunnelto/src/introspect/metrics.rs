@@ -0,0 +1,194 @@
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Upper bounds of the status code buckets: informational, success, redirect, client and server errors
+const STATUS_BUCKETS: [u16; 5] = [199, 299, 399, 499, 599];
+
+lazy_static::lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+/// Totals over every request captured since start. They only ever go up,
+/// requests evicted from (or cleared out of) the inspector still count.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: u64,
+    /// requests with a status up to each of `STATUS_BUCKETS`, the last one counts the rest
+    status_buckets: [u64; STATUS_BUCKETS.len() + 1],
+    status_sum: u64,
+    request_bytes: u64,
+    response_bytes: u64,
+}
+
+impl Metrics {
+    /// Count a captured request, its response status (0 if there was none), and
+    /// the bytes each way including heads
+    pub fn record(&mut self, status: u16, request_bytes: usize, response_bytes: usize) {
+        self.requests += 1;
+        let bucket = STATUS_BUCKETS
+            .iter()
+            .position(|le| status <= *le)
+            .unwrap_or(STATUS_BUCKETS.len());
+        self.status_buckets[bucket] += 1;
+        self.status_sum += status as u64;
+        self.request_bytes += request_bytes as u64;
+        self.response_bytes += response_bytes as u64;
+    }
+
+    /// Prometheus text exposition
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "tunnelto_requests_total",
+            "counter",
+            "Requests captured by the inspector",
+        );
+        let _ = writeln!(out, "tunnelto_requests_total {}", self.requests);
+
+        header(
+            &mut out,
+            "tunnelto_response_status",
+            "histogram",
+            "Response status codes of the captured requests",
+        );
+        let mut cumulative = 0;
+        for (le, count) in STATUS_BUCKETS.iter().zip(self.status_buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "tunnelto_response_status_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "tunnelto_response_status_bucket{{le=\"+Inf\"}} {}",
+            self.requests
+        );
+        let _ = writeln!(out, "tunnelto_response_status_sum {}", self.status_sum);
+        let _ = writeln!(out, "tunnelto_response_status_count {}", self.requests);
+
+        header(
+            &mut out,
+            "tunnelto_request_bytes_total",
+            "counter",
+            "Bytes of the captured requests, including heads",
+        );
+        let _ = writeln!(out, "tunnelto_request_bytes_total {}", self.request_bytes);
+
+        header(
+            &mut out,
+            "tunnelto_response_bytes_total",
+            "counter",
+            "Bytes of the captured responses, including heads",
+        );
+        let _ = writeln!(out, "tunnelto_response_bytes_total {}", self.response_bytes);
+
+        out
+    }
+}
+
+/// Count a request as it's captured, see `Metrics::record`
+pub fn record(status: u16, request_bytes: usize, response_bytes: usize) {
+    METRICS
+        .lock()
+        .unwrap()
+        .record(status, request_bytes, response_bytes);
+}
+
+pub fn render() -> String {
+    METRICS.lock().unwrap().render()
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    /// The value of the sample named (with its labels) exactly `name`
+    fn sample(exposition: &str, name: &str) -> Option<u64> {
+        exposition
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .find_map(|line| {
+                let (sample, value) = line.rsplit_once(' ')?;
+                if sample == name {
+                    value.parse().ok()
+                } else {
+                    None
+                }
+            })
+    }
+
+    #[test]
+    fn exposition_format() {
+        let mut metrics = Metrics::default();
+        metrics.record(200, 100, 1000);
+        metrics.record(204, 50, 20);
+        metrics.record(404, 10, 30);
+        metrics.record(502, 5, 0);
+        let out = metrics.render();
+
+        // every sample belongs to the family declared right before it
+        let mut family = None;
+        for line in out.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(kind == "counter" || kind == "histogram", "{}", line);
+                family = Some(name.to_string());
+            } else if !line.starts_with("# HELP ") {
+                let family = family.as_deref().expect("a sample before any # TYPE");
+                assert!(line.starts_with(family), "{} outside {}", line, family);
+                let value = line.rsplit(' ').next().unwrap();
+                assert!(value.parse::<u64>().is_ok(), "{}", line);
+            }
+        }
+
+        assert_eq!(sample(&out, "tunnelto_requests_total"), Some(4));
+        assert_eq!(sample(&out, "tunnelto_request_bytes_total"), Some(165));
+        assert_eq!(sample(&out, "tunnelto_response_bytes_total"), Some(1050));
+
+        // buckets are cumulative, with +Inf the count of everything
+        let bucket = |le: &str| {
+            sample(
+                &out,
+                &format!("tunnelto_response_status_bucket{{le=\"{}\"}}", le),
+            )
+        };
+        assert_eq!(bucket("199"), Some(0));
+        assert_eq!(bucket("299"), Some(2));
+        assert_eq!(bucket("399"), Some(2));
+        assert_eq!(bucket("499"), Some(3));
+        assert_eq!(bucket("599"), Some(4));
+        assert_eq!(bucket("+Inf"), Some(4));
+        assert_eq!(sample(&out, "tunnelto_response_status_count"), Some(4));
+        assert_eq!(
+            sample(&out, "tunnelto_response_status_sum"),
+            Some(200 + 204 + 404 + 502)
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_counts_captured_requests() {
+        let route = warp::path("metrics").and_then(crate::introspect::export_metrics);
+        let scrape = || async {
+            let res = warp::test::request().path("/metrics").reply(&route).await;
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.headers()["content-type"], "text/plain; version=0.0.4");
+            String::from_utf8(res.body().to_vec()).unwrap()
+        };
+
+        let before = sample(&scrape().await, "tunnelto_requests_total").unwrap();
+        record(201, 10, 10);
+
+        // other tests may capture requests meanwhile, so at least ours
+        let after = sample(&scrape().await, "tunnelto_requests_total").unwrap();
+        assert!(after > before, "{} then {}", before, after);
+    }
+}
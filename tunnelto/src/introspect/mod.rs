@@ -1,16 +1,22 @@
 pub mod console_log;
 pub use self::console_log::*;
+mod har;
+mod metrics;
+pub mod raw;
+mod websocket;
 use super::*;
 
+use colored::Colorize;
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::StreamExt;
 use hyper::Uri;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::vec;
 use uuid::Uuid;
 use warp::Filter;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Request {
     id: String,
     status: u16,
@@ -24,9 +30,22 @@ pub struct Request {
     started: chrono::NaiveDateTime,
     completed: chrono::NaiveDateTime,
     entire_request: Vec<u8>,
+    /// captured body sizes, kept when the bodies themselves aren't (`--inspect-capture-bodies off`)
+    #[serde(default)]
+    body_len: Option<usize>,
+    #[serde(default)]
+    response_len: Option<usize>,
 }
 
 impl Request {
+    pub fn body_len(&self) -> usize {
+        self.body_len.unwrap_or(self.body_data.len())
+    }
+
+    pub fn response_len(&self) -> usize {
+        self.response_len.unwrap_or(self.response_data.len())
+    }
+
     pub fn elapsed(&self) -> String {
         let duration = self.completed - self.started;
         if duration.num_seconds() == 0 {
@@ -37,12 +56,54 @@ impl Request {
     }
 }
 
+/// A captured request as pushed live to the dashboard
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RequestSummary {
+    id: String,
+    completed: String,
+    elapsed: String,
+    status: u16,
+    method: String,
+    path: String,
+    in_kb: usize,
+    out_kb: usize,
+}
+
+impl From<&Request> for RequestSummary {
+    fn from(request: &Request) -> Self {
+        RequestSummary {
+            id: request.id.clone(),
+            completed: request.completed.format("%H:%M:%S").to_string(),
+            elapsed: request.elapsed(),
+            status: request.status,
+            method: request.method.clone().unwrap_or_default(),
+            path: request.path.clone().unwrap_or_default(),
+            in_kb: request.body_len() / 1024,
+            out_kb: request.response_len() / 1024,
+        }
+    }
+}
+
 lazy_static::lazy_static! {
     pub static ref REQUESTS:Arc<RwLock<HashMap<String, Request>>> = Arc::new(RwLock::new(HashMap::new()));
+    pub static ref REQUEST_EVENTS: tokio::sync::broadcast::Sender<RequestSummary> = tokio::sync::broadcast::channel(64).0;
 }
 
-pub fn start_introspect_web_dashboard(config: Config) -> SocketAddr {
-    let dash_addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], config.dashboard_port));
+/// Start the dashboard, returning `None` if it could not bind its port
+pub fn start_introspect_web_dashboard(config: Config) -> Option<SocketAddr> {
+    let dashboard_port = config.dashboard_port;
+    let dashboard_fallback = config.dashboard_fallback;
+    if let Some(record_dir) = &config.record_dir {
+        load_recorded_requests(record_dir, config.max_captured_requests);
+    }
+
+    let dashboard_host = config.dashboard_host;
+    let curl_base_url = if config.unix_socket.is_some() {
+        format!("http://{}", &config.local_host)
+    } else {
+        config.forward_url()
+    };
+    let dash_addr = SocketAddr::new(dashboard_host, dashboard_port);
 
     let css = warp::get().and(warp::path!("static" / "css" / "styles.css").map(|| {
         let mut res = warp::http::Response::new(warp::hyper::Body::from(include_str!(
@@ -67,23 +128,87 @@ pub fn start_introspect_web_dashboard(config: Config) -> SocketAddr {
 
     let web_explorer = warp::get()
         .and(warp::path::end())
+        .and(warp::query::<RequestFilter>())
         .and_then(inspector)
         .or(warp::get()
             .and(warp::path("detail"))
             .and(warp::path::param())
-            .and_then(request_detail))
+            .and_then(move |id| request_detail(id, curl_base_url.clone())))
+        .or(warp::get()
+            .and(warp::path("edit"))
+            .and(warp::path::param())
+            .and_then(edit_request))
         .or(warp::post()
             .and(warp::path("replay"))
             .and(warp::path::param())
-            .and_then(move |id| replay_request(id, config.clone())))
+            .and(warp::body::form())
+            .and_then(move |id, form| replay_request(id, form, config.clone())))
+        .or(warp::get()
+            .and(warp::path("events"))
+            .and(warp::path::end())
+            .map(request_events))
+        .or(warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::path::end())
+            .and_then(export_metrics))
+        .or(warp::get()
+            .and(warp::path("export.har"))
+            .and(warp::path::end())
+            .and_then(export_har))
+        .or(warp::get()
+            .and(warp::path("websockets"))
+            .and(warp::path::end())
+            .and_then(websocket_sessions))
+        .or(warp::get()
+            .and(warp::path("websockets"))
+            .and(warp::path::param())
+            .and_then(websocket_session_detail))
+        .or(warp::post()
+            .and(warp::path("clear"))
+            .and(warp::path::end())
+            .and_then(clear_requests))
         .or(css)
         .or(logo);
 
-    let (web_explorer_address, explorer_server) =
-        warp::serve(web_explorer).bind_ephemeral(dash_addr);
+    let (web_explorer_address, explorer_server) = match warp::serve(web_explorer.clone())
+        .try_bind_ephemeral(dash_addr)
+    {
+        Ok(bound) => bound,
+        Err(e) if dashboard_fallback && dashboard_port != 0 => {
+            warn!("dashboard port {} unavailable: {}", dashboard_port, e);
+            eprintln!(
+                "{}",
+                format!(
+                    "Dashboard port {} is in use, using a random port instead.",
+                    dashboard_port
+                )
+                .yellow()
+            );
+            let fallback_addr = SocketAddr::new(dashboard_host, 0);
+            match warp::serve(web_explorer).try_bind_ephemeral(fallback_addr) {
+                Ok(bound) => bound,
+                Err(e) => {
+                    eprintln!("Error: {}", format!("{}", e).red());
+                    return None;
+                }
+            }
+        }
+        Err(e) => {
+            error!("failed to bind dashboard: {}", e);
+            eprintln!(
+                    "Error: {}",
+                    format!(
+                        "Dashboard port {} is in use. Pick another `--dashboard-port` or pass `--dashboard-fallback`.",
+                        dashboard_port
+                    )
+                    .red()
+                );
+            return None;
+        }
+    };
     tokio::spawn(explorer_server);
 
-    web_explorer_address
+    Some(web_explorer_address)
 }
 
 #[derive(Debug, Clone)]
@@ -92,12 +217,29 @@ pub struct IntrospectChannels {
     pub response: UnboundedSender<Vec<u8>>,
 }
 
-pub fn introspect_stream() -> IntrospectChannels {
+/// Collect a stream for the dashboard, keeping at most `max_capture_bytes` of each direction
+/// (or only the heads, without `capture_bodies`) and evicting the oldest requests past `max_captured_requests`
+pub fn introspect_stream(config: &Config) -> IntrospectChannels {
     let id = Uuid::new_v4();
     let (request_tx, request_rx) = unbounded::<Vec<u8>>();
     let (response_tx, response_rx) = unbounded::<Vec<u8>>();
+    let max_capture_bytes = config.max_capture_bytes;
+    let max_requests = config.max_captured_requests;
+    let record_dir = config.record_dir.clone();
+    let capture_bodies = config.capture_bodies;
 
-    tokio::spawn(async move { collect_stream(id, request_rx, response_rx).await });
+    tokio::spawn(async move {
+        collect_stream(
+            id,
+            request_rx,
+            response_rx,
+            max_capture_bytes,
+            max_requests,
+            record_dir,
+            capture_bodies,
+        )
+        .await
+    });
 
     IntrospectChannels {
         request: request_tx,
@@ -107,33 +249,74 @@ pub fn introspect_stream() -> IntrospectChannels {
 
 async fn collect_stream(
     id: Uuid,
-    mut request_rx: UnboundedReceiver<Vec<u8>>,
-    mut response_rx: UnboundedReceiver<Vec<u8>>,
+    request_rx: UnboundedReceiver<Vec<u8>>,
+    response_rx: UnboundedReceiver<Vec<u8>>,
+    max_capture_bytes: usize,
+    max_requests: usize,
+    record_dir: Option<PathBuf>,
+    capture_bodies: bool,
 ) {
     let started = chrono::Local::now().naive_local();
     let mut collected_request: Vec<u8> = vec![];
     let mut collected_response: Vec<u8> = vec![];
+    let mut request_truncated = false;
+    let mut response_truncated = false;
+    // everything that went through, past the cap too
+    let mut request_bytes = 0;
+    let mut response_bytes = 0;
+
+    // read both directions as they happen, so websocket frames are captured live
+    let requests = request_rx.map(|data| (true, data));
+    let responses = response_rx.map(|data| (false, data));
+    let mut directions = futures::stream::select(requests, responses);
+    let mut websocket: Option<websocket::SessionCapture> = None;
+
+    // keep draining the channels past the cap, but stop storing
+    while let Some((is_request, next)) = directions.next().await {
+        // frame previews are payload too
+        if capture_bodies
+            && is_request
+            && collected_request.is_empty()
+            && StreamKind::detect(&next) == StreamKind::WebSocketUpgrade
+        {
+            websocket = Some(websocket::SessionCapture::start(
+                id.to_string(),
+                &next,
+                max_requests,
+            ));
+        }
+        if let Some(websocket) = websocket.as_mut() {
+            websocket.feed(is_request, &next);
+        }
 
-    while let Some(next) = request_rx.next().await {
-        collected_request.extend(next);
+        if is_request {
+            request_bytes += next.len();
+            request_truncated |= extend_capped(&mut collected_request, next, max_capture_bytes);
+        } else {
+            response_bytes += next.len();
+            response_truncated |= extend_capped(&mut collected_response, next, max_capture_bytes);
+        }
     }
 
-    while let Some(next) = response_rx.next().await {
-        collected_response.extend(next);
+    if let Some(websocket) = websocket {
+        websocket.finish();
     }
 
     // collect the request
     let mut request_headers = [httparse::EMPTY_HEADER; 100];
     let mut request = httparse::Request::new(&mut request_headers);
 
-    let parts_len = match request.parse(collected_request.as_slice()) {
+    let request_head_len = match request.parse(collected_request.as_slice()) {
         Ok(httparse::Status::Complete(len)) => len,
         _ => {
             warn!("incomplete request received");
             return;
         }
     };
-    let body_data = collected_request.as_slice()[parts_len..].to_vec();
+    let mut body_data = collected_request.as_slice()[request_head_len..].to_vec();
+    if request_truncated {
+        body_data.extend_from_slice(TRUNCATED_MARKER);
+    }
 
     // collect the response
     let mut response_headers = [httparse::EMPTY_HEADER; 100];
@@ -143,11 +326,22 @@ async fn collect_stream(
         Ok(httparse::Status::Complete(len)) => len,
         _ => 0,
     };
-    let response_data = collected_response.as_slice()[parts_len..].to_vec();
+    let mut response_data = collected_response.as_slice()[parts_len..].to_vec();
+    if response_truncated {
+        response_data.extend_from_slice(TRUNCATED_MARKER);
+    }
 
     console_log::log(&request, &response);
+    metrics::record(response.code.unwrap_or(0), request_bytes, response_bytes);
 
-    let stored_request = Request {
+    let body_len = body_data.len();
+    let response_len = response_data.len();
+    if !capture_bodies {
+        body_data.clear();
+        response_data.clear();
+    }
+
+    let mut stored_request = Request {
         id: id.to_string(),
         path: request.path.map(String::from),
         method: request.method.map(String::from),
@@ -178,18 +372,201 @@ async fn collect_stream(
         completed: chrono::Local::now().naive_local(),
         is_replay: false,
         entire_request: collected_request,
+        body_len: Some(body_len),
+        response_len: Some(response_len),
     };
+    if !capture_bodies {
+        stored_request.entire_request.truncate(request_head_len);
+    }
 
-    REQUESTS
-        .write()
-        .unwrap()
-        .insert(stored_request.id.clone(), stored_request);
+    // no subscribers (no open dashboard) is fine
+    let _ = REQUEST_EVENTS.send(RequestSummary::from(&stored_request));
+
+    if let Some(record_dir) = record_dir {
+        if let Err(e) = record_request(&record_dir, &stored_request).await {
+            error!("failed to record request: {:?}", e);
+        }
+    }
+
+    let mut requests = REQUESTS.write().unwrap();
+    requests.insert(stored_request.id.clone(), stored_request);
+
+    // a ring buffer: evict the oldest requests
+    while requests.len() > max_requests {
+        let oldest = match requests.values().min_by_key(|r| r.completed) {
+            Some(oldest) => oldest.id.clone(),
+            None => break,
+        };
+        requests.remove(&oldest);
+    }
+}
+
+const RECORD_FILE: &'static str = "requests.jsonl";
+
+/// Append a request as a JSON line to the record file
+async fn record_request(record_dir: &Path, request: &Request) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+
+    tokio::fs::create_dir_all(record_dir).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(record_dir.join(RECORD_FILE))
+        .await?;
+    file.write_all(&line).await
+}
+
+/// Restore the most recent recorded requests, skipping any malformed lines
+fn load_recorded_requests(record_dir: &Path, max_requests: usize) {
+    let contents = match std::fs::read_to_string(record_dir.join(RECORD_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            error!("failed to load recorded requests: {:?}", e);
+            return;
+        }
+    };
+
+    let mut recorded: Vec<Request> = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let skip = recorded.len().saturating_sub(max_requests);
+
+    let mut requests = REQUESTS.write().unwrap();
+    for request in recorded.drain(skip..) {
+        requests.insert(request.id.clone(), request);
+    }
+    info!("restored {} recorded requests", requests.len());
+}
+
+const TRUNCATED_MARKER: &'static [u8] = b"\n[truncated]";
+
+/// Append `data` up to a total of `cap` bytes, returns true if any of it was dropped
+fn extend_capped(buf: &mut Vec<u8>, data: Vec<u8>, cap: usize) -> bool {
+    let room = cap.saturating_sub(buf.len());
+    if data.len() <= room {
+        buf.extend(data);
+        return false;
+    }
+
+    buf.extend_from_slice(&data[..room]);
+    true
 }
 
 #[derive(Debug, Clone, askama::Template)]
 #[template(path = "index.html")]
 struct Inspector {
     requests: Vec<Request>,
+    filter: RequestFilter,
+    /// the active filters, each with a link to the page without it
+    active_filters: Vec<(String, String)>,
+}
+
+/// Narrow the dashboard list, e.g. `/?method=POST&status=5xx&path=/api`
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+struct RequestFilter {
+    method: Option<String>,
+    /// an exact status code, or a class like `4xx`
+    status: Option<String>,
+    /// a substring of the request path
+    path: Option<String>,
+}
+
+impl RequestFilter {
+    /// Treat empty form fields as unset
+    fn normalized(self) -> Self {
+        let set = |value: Option<String>| {
+            value
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        RequestFilter {
+            method: set(self.method),
+            status: set(self.status),
+            path: set(self.path),
+        }
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        if let Some(method) = &self.method {
+            let matches = request
+                .method
+                .as_ref()
+                .map(|m| m.eq_ignore_ascii_case(method))
+                .unwrap_or(false);
+            if !matches {
+                return false;
+            }
+        }
+
+        if let Some(status) = &self.status {
+            if !status_matches(status, request.status) {
+                return false;
+            }
+        }
+
+        if let Some(path) = &self.path {
+            let matches = request
+                .path
+                .as_ref()
+                .map(|p| p.contains(path.as_str()))
+                .unwrap_or(false);
+            if !matches {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![];
+        if let Some(method) = &self.method {
+            params.push(("method", method.clone()));
+        }
+        if let Some(status) = &self.status {
+            params.push(("status", status.clone()));
+        }
+        if let Some(path) = &self.path {
+            params.push(("path", path.clone()));
+        }
+        params
+    }
+
+    /// Label each active filter with the url that removes it
+    fn active(&self) -> Vec<(String, String)> {
+        let params = self.params();
+        params
+            .iter()
+            .map(|(name, value)| {
+                let rest: Vec<_> = params.iter().filter(|(n, _)| n != name).collect();
+                let query = serde_urlencoded::to_string(&rest).unwrap_or_default();
+                let href = if query.is_empty() {
+                    "/".to_string()
+                } else {
+                    format!("/?{}", query)
+                };
+                (format!("{}: {}", name, value), href)
+            })
+            .collect()
+    }
+}
+
+/// Match `404` exactly, or a whole class with `4xx`
+fn status_matches(filter: &str, status: u16) -> bool {
+    let filter = filter.to_lowercase();
+    if filter.len() == 3 && filter.ends_with("xx") {
+        return match filter[..1].parse::<u16>() {
+            Ok(class) => status / 100 == class,
+            Err(_) => false,
+        };
+    }
+
+    filter.parse::<u16>().map(|s| s == status).unwrap_or(false)
 }
 
 #[derive(Debug, Clone, askama::Template)]
@@ -198,12 +575,42 @@ struct InspectorDetail {
     request: Request,
     incoming: BodyData,
     response: BodyData,
+    curl: String,
+}
+
+#[derive(Debug, Clone, askama::Template)]
+#[template(path = "websockets.html")]
+struct WebSocketSessions {
+    sessions: Vec<websocket::WebSocketSession>,
+}
+
+#[derive(Debug, Clone, askama::Template)]
+#[template(path = "websocket_detail.html")]
+struct WebSocketSessionDetail {
+    session: websocket::WebSocketSession,
+}
+
+#[derive(Debug, Clone, askama::Template)]
+#[template(path = "edit.html")]
+struct InspectorEdit {
+    request: Request,
+    raw: String,
+}
+
+/// An edited raw request to replay instead of the captured one
+#[derive(Debug, Default, serde::Deserialize)]
+struct ReplayForm {
+    raw: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 struct BodyData {
     data_type: DataType,
     content: Option<String>,
+    pretty: Option<String>,
+    form: Option<Vec<(String, String)>>,
+    /// the content-encoding and a hex dump of the body before we decoded it
+    compressed: Option<(String, String)>,
     raw: String,
 }
 
@@ -216,40 +623,121 @@ impl AsRef<BodyData> for BodyData {
 #[derive(Debug, Clone)]
 enum DataType {
     Json,
+    Form,
     Unknown,
 }
 
-async fn inspector() -> Result<Page<Inspector>, warp::reject::Rejection> {
+async fn inspector(filter: RequestFilter) -> Result<Page<Inspector>, warp::reject::Rejection> {
+    let filter = filter.normalized();
     let mut requests: Vec<Request> = REQUESTS
         .read()
         .unwrap()
         .values()
+        .filter(|r| filter.matches(r))
         .map(|r| r.clone())
         .collect();
     requests.sort_by(|a, b| b.completed.cmp(&a.completed));
-    let inspect = Inspector { requests };
+    let inspect = Inspector {
+        requests,
+        active_filters: filter.active(),
+        filter,
+    };
     Ok(Page(inspect))
 }
 
-async fn request_detail(rid: String) -> Result<Page<InspectorDetail>, warp::reject::Rejection> {
+async fn request_detail(
+    rid: String,
+    curl_base_url: String,
+) -> Result<Page<InspectorDetail>, warp::reject::Rejection> {
     let request: Request = match REQUESTS.read().unwrap().get(&rid) {
         Some(r) => r.clone(),
         None => return Err(warp::reject::not_found()),
     };
 
     let detail = InspectorDetail {
-        incoming: get_body_data(&request.body_data),
-        response: get_body_data(&request.response_data),
+        incoming: get_body_data(&request.body_data, &request.headers),
+        response: get_body_data(&request.response_data, &request.response_headers),
+        curl: to_curl(&request, &curl_base_url),
         request,
     };
 
     Ok(Page(detail))
 }
 
-fn get_body_data(input: &[u8]) -> BodyData {
+/// Reconstruct a curl command that re-sends a captured request to `base_url`
+fn to_curl(req: &Request, base_url: &str) -> String {
+    let mut curl = String::new();
+
+    // binary bodies can't be inlined in a shell command
+    let body = if req.body_data.is_empty() {
+        None
+    } else {
+        match std::str::from_utf8(&req.body_data) {
+            Ok(body) => Some(shell_quote(body)),
+            Err(_) => {
+                curl.push_str(&format!(
+                    "# binary body ({} bytes): pipe it on stdin\n",
+                    req.body_data.len()
+                ));
+                Some("@-".to_string())
+            }
+        }
+    };
+
+    curl.push_str(&format!(
+        "curl -X {} {}",
+        req.method.clone().unwrap_or("GET".to_string()),
+        shell_quote(&format!(
+            "{}{}",
+            base_url,
+            req.path.clone().unwrap_or_default()
+        ))
+    ));
+
+    for (name, value) in &req.headers {
+        // curl computes the length of the body it sends
+        if name.eq_ignore_ascii_case("content-length") {
+            continue;
+        }
+        curl.push_str(&format!(
+            " \\\n  -H {}",
+            shell_quote(&format!("{}: {}", name, value))
+        ));
+    }
+
+    if let Some(body) = body {
+        curl.push_str(&format!(" \\\n  --data-binary {}", body));
+    }
+
+    curl
+}
+
+/// Single quote for a POSIX shell, i.e: it's => 'it'\''s'
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn get_body_data(input: &[u8], headers: &[(String, String)]) -> BodyData {
+    let content_encoding = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+        .map(|(_, value)| value.to_lowercase());
+
+    let decoded = content_encoding
+        .as_ref()
+        .and_then(|encoding| decode_body(input, encoding));
+    let compressed = match (&decoded, content_encoding) {
+        (Some(_), Some(encoding)) => Some((encoding, hex_dump(input))),
+        _ => None,
+    };
+    let input = decoded.as_deref().unwrap_or(input);
+
     let mut body = BodyData {
         data_type: DataType::Unknown,
         content: None,
+        pretty: None,
+        form: None,
+        compressed,
         raw: std::str::from_utf8(input)
             .map(|s| s.to_string())
             .unwrap_or("No UTF-8 Data".to_string()),
@@ -259,15 +747,156 @@ fn get_body_data(input: &[u8]) -> BodyData {
         Ok(v) => {
             body.data_type = DataType::Json;
             body.content = serde_json::to_string(&v).ok();
+            body.pretty = serde_json::to_string_pretty(&v).ok();
+            return body;
         }
         _ => {}
     }
 
+    let is_form = headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("content-type")
+            && value
+                .to_lowercase()
+                .starts_with("application/x-www-form-urlencoded")
+    });
+    if is_form && std::str::from_utf8(input).is_ok() {
+        if let Ok(form) = serde_urlencoded::from_bytes::<Vec<(String, String)>>(input) {
+            body.data_type = DataType::Form;
+            body.form = Some(form);
+        }
+    }
+
     body
 }
 
+/// Undo a `Content-Encoding` (applied in order, i.e. "gzip, br"), or `None` if
+/// unsupported or the body is malformed/truncated
+fn decode_body(input: &[u8], content_encoding: &str) -> Option<Vec<u8>> {
+    use std::io::Read;
+
+    let mut body = input.to_vec();
+    for encoding in content_encoding.split(',').rev().map(str::trim) {
+        let mut decoded = vec![];
+        match encoding {
+            "identity" => continue,
+            "gzip" | "x-gzip" => {
+                flate2::read::GzDecoder::new(body.as_slice())
+                    .read_to_end(&mut decoded)
+                    .ok()?;
+            }
+            // usually zlib wrapped, but some servers send a raw deflate stream
+            "deflate" => {
+                if flate2::read::ZlibDecoder::new(body.as_slice())
+                    .read_to_end(&mut decoded)
+                    .is_err()
+                {
+                    decoded.clear();
+                    flate2::read::DeflateDecoder::new(body.as_slice())
+                        .read_to_end(&mut decoded)
+                        .ok()?;
+                }
+            }
+            "br" => {
+                brotli::Decompressor::new(body.as_slice(), 4096)
+                    .read_to_end(&mut decoded)
+                    .ok()?;
+            }
+            _ => return None,
+        }
+        body = decoded;
+    }
+
+    Some(body)
+}
+
+fn hex_dump(input: &[u8]) -> String {
+    input
+        .chunks(16)
+        .map(|line| {
+            line.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn websocket_sessions() -> Result<Page<WebSocketSessions>, warp::reject::Rejection> {
+    let mut sessions: Vec<websocket::WebSocketSession> = websocket::SESSIONS
+        .read()
+        .unwrap()
+        .values()
+        .map(|s| s.clone())
+        .collect();
+    sessions.sort_by(|a, b| b.started.cmp(&a.started));
+    Ok(Page(WebSocketSessions { sessions }))
+}
+
+async fn websocket_session_detail(
+    sid: String,
+) -> Result<Page<WebSocketSessionDetail>, warp::reject::Rejection> {
+    let session = match websocket::SESSIONS.read().unwrap().get(&sid) {
+        Some(s) => s.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    Ok(Page(WebSocketSessionDetail { session }))
+}
+
+async fn edit_request(rid: String) -> Result<Page<InspectorEdit>, warp::reject::Rejection> {
+    let request: Request = match REQUESTS.read().unwrap().get(&rid) {
+        Some(r) => r.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
+
+    let edit = InspectorEdit {
+        raw: String::from_utf8_lossy(&request.entire_request).to_string(),
+        request,
+    };
+    Ok(Page(edit))
+}
+
+/// Turn an edited request from the browser back into HTTP: the head gets CRLF line endings
+/// and a Content-Length matching the edited body
+fn prepare_edited_request(raw: &str) -> Result<Vec<u8>, String> {
+    // browsers submit textarea newlines as CRLF
+    let raw = raw.replace("\r\n", "\n");
+    let (head, body) = match raw.find("\n\n") {
+        Some(i) => (&raw[..i], &raw[i + 2..]),
+        None => (raw.trim_end_matches('\n'), ""),
+    };
+
+    let mut request = String::with_capacity(raw.len() + 64);
+    for (i, line) in head.lines().enumerate() {
+        let is_content_length = line
+            .split(':')
+            .next()
+            .map(|name| name.trim().eq_ignore_ascii_case("content-length"))
+            .unwrap_or(false);
+
+        if i > 0 && is_content_length {
+            request.push_str(&format!("Content-Length: {}", body.len()));
+        } else {
+            request.push_str(line);
+        }
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let mut headers = [httparse::EMPTY_HEADER; 100];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(request.as_bytes()) {
+        Ok(httparse::Status::Complete(_)) => Ok(request.into_bytes()),
+        Ok(httparse::Status::Partial) => Err("the request head is incomplete".to_string()),
+        Err(e) => Err(format!("the request is not valid HTTP: {}", e)),
+    }
+}
+
 async fn replay_request(
     rid: String,
+    form: ReplayForm,
     config: Config,
 ) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
     let request: Request = match REQUESTS.read().unwrap().get(&rid) {
@@ -275,6 +904,19 @@ async fn replay_request(
         None => return Err(warp::reject::not_found()),
     };
 
+    let data = match form.raw {
+        Some(raw) => match prepare_edited_request(&raw) {
+            Ok(data) => data,
+            Err(e) => {
+                return Ok(Box::new(warp::reply::with_status(
+                    format!("Error: {}", e),
+                    warp::http::StatusCode::BAD_REQUEST,
+                )))
+            }
+        },
+        None => request.entire_request,
+    };
+
     let (tx, rx) = unbounded::<ControlPacket>();
     tokio::spawn(async move {
         // keep the rx alive
@@ -288,7 +930,7 @@ async fn replay_request(
 
     // send the data to the stream
     if let Some(mut tx) = tx {
-        let _ = tx.send(StreamMessage::Data(request.entire_request)).await;
+        let _ = tx.send(StreamMessage::Data(None, data)).await;
     } else {
         error!("failed to replay request: local tunnel could not connect");
         return Err(warp::reject::not_found());
@@ -297,6 +939,68 @@ async fn replay_request(
     Ok(Box::new(warp::redirect(Uri::from_static("/"))))
 }
 
+/// Server-sent events of each newly captured request
+fn request_events() -> impl warp::Reply {
+    let events = futures::stream::unfold(REQUEST_EVENTS.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(summary) => {
+                    let event = warp::sse::Event::default()
+                        .event("request")
+                        .data(serde_json::to_string(&summary).unwrap_or_default());
+                    return Some((Ok::<_, std::convert::Infallible>(event), rx));
+                }
+                // a slow dashboard just misses some rows until it reloads
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    warp::sse::reply(warp::sse::keep_alive().stream(events))
+}
+
+async fn export_metrics() -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    let res = warp::http::Response::builder()
+        .status(warp::http::StatusCode::OK)
+        .header(
+            warp::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )
+        .body(warp::hyper::Body::from(metrics::render()))
+        .unwrap();
+    Ok(Box::new(res))
+}
+
+async fn export_har() -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    let mut requests: Vec<Request> = REQUESTS
+        .read()
+        .unwrap()
+        .values()
+        .map(|r| r.clone())
+        .collect();
+    requests.sort_by(|a, b| a.started.cmp(&b.started));
+
+    let har = serde_json::to_vec_pretty(&har::Har::new(&requests)).unwrap_or_default();
+    let res = warp::http::Response::builder()
+        .status(warp::http::StatusCode::OK)
+        .header(warp::http::header::CONTENT_TYPE, "application/json")
+        .header(
+            warp::http::header::CONTENT_DISPOSITION,
+            "attachment; filename=\"tunnelto.har\"",
+        )
+        .body(warp::hyper::Body::from(har))
+        .unwrap();
+    Ok(Box::new(res))
+}
+
+async fn clear_requests() -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    // streams still being collected insert under the same lock once they complete
+    REQUESTS.write().unwrap().clear();
+    websocket::SESSIONS.write().unwrap().clear();
+    Ok(Box::new(warp::redirect(Uri::from_static("/"))))
+}
+
 struct Page<T>(T);
 
 impl<T> warp::reply::Reply for Page<T>
@@ -313,3 +1017,90 @@ where
             .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dashboard_config(port: u16, fallback: bool) -> Config {
+        let port = port.to_string();
+        let mut args = vec![
+            "tunnelto",
+            "--dashboard-host",
+            "127.0.0.1",
+            "--dashboard-port",
+            &port,
+        ];
+        if fallback {
+            args.push("--dashboard-fallback");
+        }
+        Config::from_args(&args)
+    }
+
+    /// Collect one exchange like `introspect_stream` does, returning what got stored
+    async fn capture(request: &[u8], response: &[u8], capture_bodies: bool) -> Request {
+        let id = Uuid::new_v4();
+        let (request_tx, request_rx) = unbounded();
+        let (response_tx, response_rx) = unbounded();
+        request_tx.unbounded_send(request.to_vec()).unwrap();
+        response_tx.unbounded_send(response.to_vec()).unwrap();
+        drop((request_tx, response_tx));
+
+        collect_stream(
+            id,
+            request_rx,
+            response_rx,
+            1024,
+            1000,
+            None,
+            capture_bodies,
+        )
+        .await;
+        REQUESTS.read().unwrap()[&id.to_string()].clone()
+    }
+
+    const POST: &[u8] = b"POST /login HTTP/1.1\r\nContent-Length: 6\r\n\r\nsecret";
+    const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\ntoken";
+
+    #[tokio::test]
+    async fn capture_bodies_off_keeps_only_metadata() {
+        let request = capture(POST, OK, false).await;
+        assert!(request.body_data.is_empty());
+        assert!(request.response_data.is_empty());
+        assert_eq!(request.entire_request, &POST[..POST.len() - 6]);
+
+        // what's left to show
+        assert_eq!(request.method.as_deref(), Some("POST"));
+        assert_eq!(request.path.as_deref(), Some("/login"));
+        assert_eq!(request.status, 200);
+        assert_eq!(
+            request.headers,
+            vec![("Content-Length".to_string(), "6".to_string())]
+        );
+        assert_eq!((request.body_len(), request.response_len()), (6, 5));
+    }
+
+    #[tokio::test]
+    async fn capture_bodies_on_keeps_them() {
+        let request = capture(POST, OK, true).await;
+        assert_eq!(request.body_data, b"secret");
+        assert_eq!(request.response_data, b"token");
+        assert_eq!(request.entire_request, POST);
+    }
+
+    #[tokio::test]
+    async fn dashboard_port_in_use() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        assert_eq!(
+            start_introspect_web_dashboard(dashboard_config(port, false)),
+            None
+        );
+
+        let addr =
+            start_introspect_web_dashboard(dashboard_config(port, true)).expect("a fallback port");
+        assert_ne!(addr.port(), port);
+        assert!(tokio::net::TcpStream::connect(addr).await.is_ok());
+    }
+}
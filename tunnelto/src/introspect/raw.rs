@@ -0,0 +1,163 @@
+use crate::StreamId;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+lazy_static::lazy_static! {
+    /// Streams of all tunnels share one capture per file
+    static ref CAPTURES: Mutex<HashMap<PathBuf, Arc<RawCapture>>> = Mutex::new(HashMap::new());
+}
+
+/// A hexdump of stream bytes in one direction, for debugging non-HTTP protocols
+pub struct RawCapture {
+    path: PathBuf,
+    max_bytes: u64,
+    state: Mutex<CaptureState>,
+}
+
+struct CaptureState {
+    file: Option<File>,
+    written: u64,
+}
+
+impl RawCapture {
+    /// The capture writing to `path`, the file is truncated when first opened
+    pub fn open(path: &Path, max_bytes: u64) -> Arc<Self> {
+        CAPTURES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let file = File::create(path)
+                    .map_err(|e| {
+                        log::error!("failed to create raw capture {}: {:?}", path.display(), e)
+                    })
+                    .ok();
+
+                Arc::new(RawCapture {
+                    path: path.to_path_buf(),
+                    max_bytes,
+                    state: Mutex::new(CaptureState { file, written: 0 }),
+                })
+            })
+            .clone()
+    }
+
+    /// Append a stream's bytes, until the capture reaches `max_bytes` of stream data
+    pub fn record(&self, stream_id: &StreamId, data: &[u8]) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let written = state.written;
+        let file = match state.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let remaining = self.max_bytes.saturating_sub(written) as usize;
+        let data = &data[..data.len().min(remaining)];
+
+        let mut out = format!(
+            "{} {} {} bytes\n",
+            chrono::Local::now().format("%H:%M:%S%.3f"),
+            stream_id.to_string(),
+            data.len()
+        );
+        out.push_str(&hexdump(data));
+        if data.len() == remaining {
+            out.push_str("-- capture reached its size limit --\n");
+        }
+
+        if let Err(e) = file.write_all(out.as_bytes()) {
+            log::error!(
+                "failed to write raw capture {}: {:?}",
+                self.path.display(),
+                e
+            );
+            state.file = None;
+            return;
+        }
+
+        state.written += data.len() as u64;
+        if state.written >= self.max_bytes {
+            state.file = None;
+        }
+    }
+}
+
+/// i.e: `00000000  47 45 54 20 2f 0d 0a  |GET /..|`
+fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|b| match b {
+                0x20..=0x7e => *b as char,
+                _ => '.',
+            })
+            .collect();
+        out.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            i * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tunnelto-raw-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn hexdump_lines() {
+        assert_eq!(
+            hexdump(b"GET / HTTP/1.1\r\nHost: a\r\n"),
+            "00000000  47 45 54 20 2f 20 48 54 54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|\n\
+             00000010  48 6f 73 74 3a 20 61 0d 0a                       |Host: a..|\n"
+        );
+        assert_eq!(hexdump(b""), "");
+    }
+
+    #[test]
+    fn records_until_the_size_limit() {
+        let path = capture_path("limit");
+        let capture = RawCapture::open(&path, 6);
+        let stream_id = StreamId::generate();
+        capture.record(&stream_id, b"abcd");
+        capture.record(&stream_id, b"efgh");
+        capture.record(&stream_id, b"ijkl");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 5, "{}", contents);
+        assert!(lines[0].ends_with(&format!("{} 4 bytes", stream_id.to_string())));
+        assert!(lines[1].ends_with("|abcd|"));
+        assert!(lines[2].ends_with(&format!("{} 2 bytes", stream_id.to_string())));
+        assert!(lines[3].ends_with("|ef|"));
+        assert_eq!(lines[4], "-- capture reached its size limit --");
+    }
+
+    #[test]
+    fn streams_share_a_capture_per_file() {
+        let path = capture_path("shared");
+        let first = RawCapture::open(&path, 1024);
+        let second = RawCapture::open(&path, 1024);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // and it isn't truncated again when the second stream opens it
+        first.record(&StreamId::generate(), b"first");
+        RawCapture::open(&path, 1024).record(&StreamId::generate(), b"second");
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(contents.contains("|first|"), "{}", contents);
+        assert!(contents.contains("|second|"), "{}", contents);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::http::{opcode_name, WebSocketFrame, WebSocketFrameParser};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Frames kept per session, the oldest are dropped first
+const MAX_SESSION_FRAMES: usize = 1000;
+
+lazy_static::lazy_static! {
+    pub static ref SESSIONS: Arc<RwLock<HashMap<String, WebSocketSession>>> = Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// An upgraded stream and the frames that crossed it
+#[derive(Debug, Clone)]
+pub struct WebSocketSession {
+    pub id: String,
+    pub path: String,
+    pub started: chrono::NaiveDateTime,
+    pub closed: Option<chrono::NaiveDateTime>,
+    pub frames: Vec<CapturedFrame>,
+    /// frames dropped past `MAX_SESSION_FRAMES`
+    pub dropped_frames: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub at: chrono::NaiveDateTime,
+    pub direction: &'static str,
+    pub opcode: String,
+    pub len: u64,
+    pub preview: String,
+}
+
+impl From<(&'static str, WebSocketFrame)> for CapturedFrame {
+    fn from((direction, frame): (&'static str, WebSocketFrame)) -> Self {
+        // text frames preview as text, anything else as hex
+        let preview = match frame.opcode {
+            0x1 | 0x8 => String::from_utf8_lossy(&frame.preview).to_string(),
+            _ => frame
+                .preview
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+
+        CapturedFrame {
+            at: chrono::Local::now().naive_local(),
+            direction,
+            opcode: opcode_name(frame.opcode),
+            len: frame.len,
+            preview,
+        }
+    }
+}
+
+/// Captures the frames of one upgraded stream into `SESSIONS` as they arrive
+pub struct SessionCapture {
+    id: String,
+    incoming: WebSocketFrameParser,
+    outgoing: WebSocketFrameParser,
+}
+
+impl SessionCapture {
+    /// Start a session from the first bytes of the upgrade request,
+    /// evicting the oldest sessions past `max_sessions`
+    pub fn start(id: String, upgrade: &[u8], max_sessions: usize) -> Self {
+        let path = upgrade
+            .split(|b| *b == b'\n')
+            .next()
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .and_then(|line| line.split(' ').nth(1))
+            .unwrap_or_default()
+            .to_string();
+
+        let mut sessions = SESSIONS.write().unwrap();
+        sessions.insert(
+            id.clone(),
+            WebSocketSession {
+                id: id.clone(),
+                path,
+                started: chrono::Local::now().naive_local(),
+                closed: None,
+                frames: vec![],
+                dropped_frames: 0,
+            },
+        );
+
+        while sessions.len() > max_sessions {
+            let oldest = match sessions.values().min_by_key(|s| s.started) {
+                Some(oldest) => oldest.id.clone(),
+                None => break,
+            };
+            sessions.remove(&oldest);
+        }
+
+        SessionCapture {
+            id,
+            incoming: WebSocketFrameParser::new(),
+            outgoing: WebSocketFrameParser::new(),
+        }
+    }
+
+    /// Feed bytes of the request (remote -> local) or response (local -> remote) direction
+    pub fn feed(&mut self, is_request: bool, data: &[u8]) {
+        let (direction, frames) = if is_request {
+            ("remote -> local", self.incoming.feed(data))
+        } else {
+            ("local -> remote", self.outgoing.feed(data))
+        };
+
+        if frames.is_empty() {
+            return;
+        }
+
+        let mut sessions = SESSIONS.write().unwrap();
+        let session = match sessions.get_mut(&self.id) {
+            Some(session) => session,
+            None => return,
+        };
+
+        session.frames.extend(
+            frames
+                .into_iter()
+                .map(|frame| CapturedFrame::from((direction, frame))),
+        );
+        if session.frames.len() > MAX_SESSION_FRAMES {
+            let excess = session.frames.len() - MAX_SESSION_FRAMES;
+            session.frames.drain(..excess);
+            session.dropped_frames += excess;
+        }
+    }
+
+    pub fn finish(self) {
+        if let Some(session) = SESSIONS.write().unwrap().get_mut(&self.id) {
+            session.closed = Some(chrono::Local::now().naive_local());
+        }
+    }
+}
@@ -5,11 +5,15 @@ use futures::{SinkExt, StreamExt};
 use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{ClientConfig, TLSError};
 use tokio_rustls::webpki::DNSNameRef;
 use tokio_rustls::TlsConnector;
 
+use crate::http::{has_basic_auth, RequestRewriter, ResponseBoundary, WebSocketFrameLog};
+use crate::introspect::raw::RawCapture;
 use crate::introspect::{self, introspect_stream, IntrospectChannels};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 pub trait AnyTcpStream: AsyncRead + AsyncWrite + Unpin + Send {}
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> AnyTcpStream for T {}
@@ -21,32 +25,54 @@ pub async fn setup_new_stream(
     stream_id: StreamId,
 ) -> Option<UnboundedSender<StreamMessage>> {
     info!("setting up local stream: {}", &stream_id.to_string());
+    let connect_timeout = Duration::from_millis(config.connect_timeout);
 
-    let local_tcp = match TcpStream::connect(config.local_addr).await {
-        Ok(s) => s,
-        Err(e) => {
-            error!("failed to connect to local service: {}", e);
-            introspect::connect_failed();
-            let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
-            return None;
-        }
-    };
+    if let Some(path) = config.unix_socket.clone() {
+        let local_unix = match with_connect_timeout(connect_timeout, connect_unix(&path)).await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("failed to connect to local unix socket: {}", e);
+                introspect::connect_failed();
+                local_health::service_down(&config);
+                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+                return None;
+            }
+        };
+        return Some(start_local_stream(
+            local_unix, &config, tunnel_tx, stream_id,
+        ));
+    }
+
+    let local_tcp =
+        match with_connect_timeout(connect_timeout, TcpStream::connect(config.local_addr)).await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("failed to connect to local service: {}", e);
+                introspect::connect_failed();
+                local_health::service_down(&config);
+                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+                return None;
+            }
+        };
 
     let local_tcp: Box<dyn AnyTcpStream> = if config.use_tls {
-        let dnsname = config.local_host;
-        let mut config = ClientConfig::new();
-        config
-            .root_store
-            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-        let config = TlsConnector::from(Arc::new(config));
-        let dnsname =
-            DNSNameRef::try_from_ascii_str(dnsname.as_str()).ok()?;
-
-        let stream = match config.connect(dnsname, local_tcp).await {
+        let dnsname = DNSNameRef::try_from_ascii_str(config.local_host.as_str()).ok()?;
+
+        let stream = match connect_tls(dnsname, local_tcp).await {
             Ok(s) => s,
+            Err(e) if is_plaintext_handshake_error(&e) => {
+                error!("failed to connect to TLS service, got plaintext: {}", e);
+                introspect::tls_plaintext_failed();
+                let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
+                if config.tls_fail_fast {
+                    crate::FAIL_FAST.notify_one();
+                }
+                return None;
+            }
             Err(e) => {
                 error!("failed to connect to TLS service: {}", e);
                 introspect::connect_failed();
+                local_health::service_down(&config);
                 let _ = tunnel_tx.send(ControlPacket::Refused(stream_id)).await;
                 return None;
             }
@@ -57,52 +83,350 @@ pub async fn setup_new_stream(
         Box::new(local_tcp)
     };
 
+    Some(start_local_stream(local_tcp, &config, tunnel_tx, stream_id))
+}
+
+async fn connect_tls(
+    dnsname: DNSNameRef<'_>,
+    local_tcp: TcpStream,
+) -> std::io::Result<TlsStream<TcpStream>> {
+    let mut tls_config = ClientConfig::new();
+    tls_config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let connector = TlsConnector::from(Arc::new(tls_config));
+    connector.connect(dnsname, local_tcp).await
+}
+
+/// Connect to the local service (without tls) just to see that it's listening
+pub async fn probe(config: &Config) -> std::io::Result<()> {
+    let timeout = Duration::from_millis(config.connect_timeout);
+    match config.unix_socket.as_ref() {
+        Some(path) => with_connect_timeout(timeout, connect_unix(path))
+            .await
+            .map(drop),
+        None => with_connect_timeout(timeout, TcpStream::connect(config.local_addr))
+            .await
+            .map(drop),
+    }
+}
+
+/// An unreachable local host can hang a connect, so time it out like a refusal
+async fn with_connect_timeout<T>(
+    timeout: Duration,
+    connect: impl std::future::Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("timed out after {}ms", timeout.as_millis()),
+        )),
+    }
+}
+
+/// Upper bound on a request head held back for `--basic-auth` or `--tunnel-only-methods`,
+/// a longer one is judged as is (i.e. without its `Authorization` header)
+const MAX_GATED_HEAD: usize = 64 * 1024;
+
+/// Do new streams wait on their request head before connecting to the local service
+pub fn is_gated(config: &Config) -> bool {
+    config.basic_auth.is_some() || config.allowed_methods.is_some()
+}
+
+/// The first packets of a new gated stream, held until its request head is complete.
+/// A head can span several data packets, the first of them alone may not have the
+/// `Authorization` header yet.
+#[derive(Debug, Default)]
+pub struct GatedHead {
+    packets: Vec<(Option<u32>, Vec<u8>)>,
+    reorder: StreamReorder<Vec<u8>>,
+    head: Vec<u8>,
+}
+
+impl GatedHead {
+    /// Hold another packet, true once the head is ready to be judged
+    pub fn push(&mut self, seq: Option<u32>, data: Vec<u8>) -> Result<bool, ProtocolError> {
+        self.packets.push((seq, data.clone()));
+        self.head.extend(self.reorder.push(seq, data)?.concat());
+
+        if self.head.len() >= MAX_GATED_HEAD {
+            return Ok(true);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 100];
+        match httparse::Request::new(&mut headers).parse(&self.head) {
+            Ok(httparse::Status::Partial) => Ok(false),
+            // complete, or not http at all
+            _ => Ok(true),
+        }
+    }
+
+    /// The in-order bytes held so far
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+
+    /// The packets as they arrived, for the new local stream to reorder itself
+    pub fn into_packets(self) -> Vec<(Option<u32>, Vec<u8>)> {
+        self.packets
+    }
+}
+
+/// Is the first request of a new stream allowed through `--basic-auth`
+pub fn is_authorized(config: &Config, data: &[u8]) -> bool {
+    match &config.basic_auth {
+        Some(credentials) => has_basic_auth(data, credentials),
+        None => true,
+    }
+}
+
+/// Is the first request of a new stream's method allowed through `--tunnel-only-methods`
+pub fn is_method_allowed(config: &Config, data: &[u8]) -> bool {
+    let allowed = match &config.allowed_methods {
+        Some(allowed) => allowed,
+        None => return true,
+    };
+
+    let method = data.split(|b| *b == b' ').next().unwrap_or_default();
+    allowed.iter().any(|m| m.as_bytes() == method)
+}
+
+/// Answer a new stream with a 401 without connecting to the local service
+pub async fn reject_unauthorized(
+    config: &Config,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+) {
+    info!("rejecting unauthorized stream: {}", &stream_id.to_string());
+    reject_stream(
+        config,
+        tunnel_tx,
+        stream_id,
+        HTTP_UNAUTHORIZED_RESPONSE.to_vec(),
+    )
+    .await;
+}
+
+/// Answer a new stream with a 405 without connecting to the local service
+pub async fn reject_method(
+    config: &Config,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+) {
+    info!(
+        "rejecting disallowed method stream: {}",
+        &stream_id.to_string()
+    );
+    let allow = config
+        .allowed_methods
+        .as_ref()
+        .map(|methods| methods.join(", "))
+        .unwrap_or_default();
+    let response = format!(
+        "HTTP/1.1 405 Method Not Allowed\r\nAllow: {}\r\nConnection: close\r\nContent-Length: 19\r\n\r\nMethod Not Allowed.",
+        allow
+    );
+    reject_stream(config, tunnel_tx, stream_id, response.into_bytes()).await;
+}
+
+/// Answer a new stream directly and end it. The rest of it is discarded until
+/// the server ends it too, servers that don't take our `End` wait for the end user
+/// to close on `Connection: close`.
+async fn reject_stream(
+    config: &Config,
+    mut tunnel_tx: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+    response: Vec<u8>,
+) {
+    let _ = tunnel_tx
+        .send(ControlPacket::Data(stream_id.clone(), None, response))
+        .await;
+    if config.server_stream_end.load(Ordering::SeqCst) {
+        let _ = tunnel_tx.send(ControlPacket::End(stream_id.clone())).await;
+    }
+
+    discard_stream(&config.active_streams, stream_id);
+}
+
+/// Swallow the rest of a stream we've already answered until the server ends it,
+/// so its packets aren't taken for a new stream
+fn discard_stream(active_streams: &ActiveStreams, stream_id: StreamId) {
+    let (tx, mut rx) = unbounded::<StreamMessage>();
+    active_streams
+        .write_or_recover()
+        .insert(stream_id.clone(), tx);
+
+    let active_streams = active_streams.clone();
+    tokio::spawn(async move {
+        while let Some(StreamMessage::Data(..)) = rx.next().await {}
+        active_streams.write_or_recover().remove(&stream_id);
+    });
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &std::path::Path) -> std::io::Result<Box<dyn AnyTcpStream>> {
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &std::path::Path) -> std::io::Result<Box<dyn AnyTcpStream>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+/// Start processing messages between the tunnel and a connected local stream
+fn start_local_stream(
+    local_tcp: Box<dyn AnyTcpStream>,
+    config: &Config,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+) -> UnboundedSender<StreamMessage> {
+    let active_streams = config.active_streams.clone();
+    let response_timeout = config.local_response_timeout.map(Duration::from_secs);
+    let rewriter = config.host_header_value().map(RequestRewriter::new);
+    let max_frame_size = config.max_frame_size.load(Ordering::SeqCst);
+    let server_stream_end = config.server_stream_end.load(Ordering::SeqCst);
+
+    // set once the first request turns out to be a websocket upgrade
+    let is_websocket = Arc::new(AtomicBool::new(false));
+
     let IntrospectChannels {
         request: introspect_request,
         response: introspect_response,
-    } = introspect_stream();
+    } = introspect_stream(config);
+
+    let raw_in = config
+        .raw_in
+        .as_ref()
+        .map(|path| RawCapture::open(path, config.raw_max_bytes));
+    let raw_out = config
+        .raw_out
+        .as_ref()
+        .map(|path| RawCapture::open(path, config.raw_max_bytes));
 
     let (stream, sink) = split(local_tcp);
 
     // Read local tcp bytes, send them tunnel
     let stream_id_clone = stream_id.clone();
+    let active_streams_clone = active_streams.clone();
+    let is_websocket_clone = is_websocket.clone();
     tokio::spawn(async move {
-        process_local_tcp(stream, tunnel_tx, stream_id_clone, introspect_response).await;
+        process_local_tcp(
+            stream,
+            active_streams_clone,
+            tunnel_tx,
+            stream_id_clone,
+            introspect_response,
+            response_timeout,
+            is_websocket_clone,
+            raw_out,
+            max_frame_size,
+            server_stream_end,
+        )
+        .await;
     });
 
     // Forward remote packets to local tcp
     let (tx, rx) = unbounded();
-    ACTIVE_STREAMS
-        .write()
-        .unwrap()
+    active_streams
+        .write_or_recover()
         .insert(stream_id.clone(), tx.clone());
 
     tokio::spawn(async move {
-        forward_to_local_tcp(sink, rx, introspect_request).await;
+        forward_to_local_tcp(
+            sink,
+            rx,
+            active_streams,
+            stream_id,
+            introspect_request,
+            rewriter,
+            is_websocket,
+            raw_in,
+        )
+        .await;
     });
 
-    Some(tx)
+    tx
+}
+
+const HTTP_UNAUTHORIZED_RESPONSE: &'static [u8] = b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"tunnelto\"\r\nConnection: close\r\nContent-Length: 13\r\n\r\nUnauthorized.";
+
+const HTTP_GATEWAY_TIMEOUT_RESPONSE: &'static [u8] =
+    b"HTTP/1.1 504 Gateway Timeout\r\nConnection: close\r\nContent-Length: 37\r\n\r\nError: Local service did not respond.";
+
+/// A plaintext HTTP reply to our TLS client hello isn't a TLS record at all,
+/// so rustls can't frame it
+fn is_plaintext_handshake_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.get_ref().and_then(|e| e.downcast_ref::<TLSError>()),
+        Some(TLSError::CorruptMessage)
+    )
 }
 
 pub async fn process_local_tcp<T>(
     mut stream: ReadHalf<T>,
+    active_streams: ActiveStreams,
     mut tunnel: UnboundedSender<ControlPacket>,
     stream_id: StreamId,
     mut introspect: UnboundedSender<Vec<u8>>,
+    response_timeout: Option<Duration>,
+    is_websocket: Arc<AtomicBool>,
+    raw_out: Option<Arc<RawCapture>>,
+    max_frame_size: usize,
+    server_stream_end: bool,
 ) where
     T: AnyTcpStream,
 {
     let mut buf = [0; 4 * 1024];
+    let mut boundary = ResponseBoundary::default();
+    let mut frame_log: Option<WebSocketFrameLog> = None;
+    let mut seq = StreamSequence::default();
+
+    // only the first response byte is bound by the timeout
+    let mut awaiting_response = response_timeout;
 
     loop {
-        let n = stream
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
+        let read = stream.read(&mut buf);
+        let n = match awaiting_response.take() {
+            Some(timeout) => match tokio::time::timeout(timeout, read).await {
+                Ok(n) => n,
+                Err(_) => {
+                    warn!("local service did not respond within {:?}", timeout);
+                    let _ = tunnel
+                        .send(ControlPacket::Data(
+                            stream_id.clone(),
+                            seq.next(),
+                            HTTP_GATEWAY_TIMEOUT_RESPONSE.to_vec(),
+                        ))
+                        .await;
+                    if server_stream_end {
+                        let _ = tunnel.send(ControlPacket::End(stream_id.clone())).await;
+                    }
+                    // also ends forwarding to the local service
+                    discard_stream(&active_streams, stream_id);
+                    return;
+                }
+            },
+            None => read.await,
+        };
+
+        // a reset by the local service ends just this stream
+        let n = match n {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("failed to read from local service: {:?}", e);
+                end_stream(&mut tunnel, &active_streams, stream_id, server_stream_end).await;
+                return;
+            }
+        };
 
         if n == 0 {
             info!("done reading from client stream");
-            ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
+            end_stream(&mut tunnel, &active_streams, stream_id, server_stream_end).await;
             return;
         }
 
@@ -112,40 +436,418 @@ pub async fn process_local_tcp<T>(
             std::str::from_utf8(&data).unwrap_or("<non utf8>")
         );
 
-        let packet = ControlPacket::Data(stream_id.clone(), data.clone());
-        tunnel
-            .send(packet)
-            .await
-            .expect("failed to tunnel packet from local tcp to tunnel");
+        if let Some(raw_out) = &raw_out {
+            raw_out.record(&stream_id, &data);
+        }
+
+        if frame_log.is_none() && is_websocket.load(Ordering::Relaxed) {
+            frame_log = Some(WebSocketFrameLog::new("local -> remote"));
+        }
+        if let Some(frame_log) = frame_log.as_mut() {
+            frame_log.feed(&data);
+        }
+
+        for packet in ControlPacket::chunk(&stream_id, &mut seq, &data, max_frame_size) {
+            if let Err(e) = tunnel.send(packet).await {
+                error!("failed to tunnel packet from local tcp to tunnel: {:?}", e);
+                active_streams.write_or_recover().remove(&stream_id);
+                return;
+            }
+        }
+
+        if boundary.feed(&data) {
+            let _ = tunnel
+                .send(ControlPacket::EndOfMessage(stream_id.clone()))
+                .await;
+        }
 
         let _ = introspect.send(data).await;
     }
 }
 
+/// The local service is done with a stream, tell the server so it closes the
+/// remote end too. Servers without `stream_end` wait for the end user to close.
+async fn end_stream(
+    tunnel: &mut UnboundedSender<ControlPacket>,
+    active_streams: &ActiveStreams,
+    stream_id: StreamId,
+    server_stream_end: bool,
+) {
+    active_streams.write_or_recover().remove(&stream_id);
+    if server_stream_end {
+        let _ = tunnel.send(ControlPacket::End(stream_id)).await;
+    }
+}
+
+/// How long a closing stream waits on packets still missing from the queue
+const END_DRAIN_TIMEOUT: Duration = Duration::from_millis(250);
+
 async fn forward_to_local_tcp<T>(
     mut sink: WriteHalf<T>,
     mut queue: UnboundedReceiver<StreamMessage>,
+    active_streams: ActiveStreams,
+    stream_id: StreamId,
     mut introspect: UnboundedSender<Vec<u8>>,
+    mut rewriter: Option<RequestRewriter>,
+    is_websocket: Arc<AtomicBool>,
+    raw_in: Option<Arc<RawCapture>>,
 ) where
     T: AnyTcpStream,
 {
+    let mut first_request = true;
+    let mut frame_log: Option<WebSocketFrameLog> = None;
+    let mut reorder = StreamReorder::new();
+    let mut closing = false;
+
     loop {
-        let data = match queue.next().await {
-            Some(StreamMessage::Data(data)) => data,
+        // once closing, flush what's already queued and only wait briefly on out of order packets
+        let message = if !closing {
+            queue.next().await
+        } else if reorder.is_waiting() {
+            tokio::time::timeout(END_DRAIN_TIMEOUT, queue.next())
+                .await
+                .unwrap_or(None)
+        } else {
+            queue.try_next().unwrap_or(None)
+        };
+
+        let data = match message {
+            Some(StreamMessage::Data(seq, data)) => match reorder.push(seq, data) {
+                Ok(data) => data.concat(),
+                Err(e) => {
+                    warn!("aborting stream: {}", e);
+                    let _ = sink.shutdown().await;
+                    active_streams.write_or_recover().remove(&stream_id);
+                    return;
+                }
+            },
+            Some(StreamMessage::Close) if !closing => {
+                closing = true;
+                continue;
+            }
             None | Some(StreamMessage::Close) => {
                 warn!("closing stream");
                 let _ = sink.shutdown().await.map_err(|e| {
                     error!("failed to shutdown: {:?}", e);
                 });
+                if closing {
+                    active_streams.write_or_recover().remove(&stream_id);
+                }
                 return;
             }
         };
 
-        sink.write_all(&data)
-            .await
-            .expect("failed to write packet data to local tcp socket");
+        // waiting on an earlier packet
+        if data.is_empty() {
+            continue;
+        }
+
+        // only log frames when someone will see them
+        if first_request
+            && log::log_enabled!(log::Level::Debug)
+            && StreamKind::detect(&data) == StreamKind::WebSocketUpgrade
+        {
+            is_websocket.store(true, Ordering::Relaxed);
+            frame_log = Some(WebSocketFrameLog::new("remote -> local"));
+        }
+        first_request = false;
+        if let Some(frame_log) = frame_log.as_mut() {
+            frame_log.feed(&data);
+        }
+
+        let data = match rewriter.as_mut() {
+            Some(rewriter) => rewriter.rewrite(data),
+            None => data,
+        };
+        if data.is_empty() {
+            // still buffering a request head
+            continue;
+        }
+
+        if let Err(e) = sink.write_all(&data).await {
+            warn!("failed to write to local service: {:?}", e);
+            active_streams.write_or_recover().remove(&stream_id);
+            return;
+        }
         debug!("wrote to local service: {:?}", data.len());
 
+        if let Some(raw_in) = &raw_in {
+            raw_in.record(&stream_id, &data);
+        }
+
         let _ = introspect.send(data).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\nAuthorization: Basic dXNlcjpwYXNz\r\n\r\n";
+
+    #[test]
+    fn gated_head_waits_for_the_whole_head() {
+        let mut gated = GatedHead::default();
+        assert!(!gated.push(Some(0), HEAD[..20].to_vec()).unwrap());
+        assert!(!has_basic_auth(gated.head(), "user:pass"));
+
+        assert!(gated.push(Some(1), HEAD[20..].to_vec()).unwrap());
+        assert!(has_basic_auth(gated.head(), "user:pass"));
+        assert_eq!(gated.into_packets().len(), 2);
+    }
+
+    #[test]
+    fn gated_head_reorders() {
+        let mut gated = GatedHead::default();
+        assert!(!gated.push(Some(1), HEAD[20..].to_vec()).unwrap());
+        assert!(gated.push(Some(0), HEAD[..20].to_vec()).unwrap());
+        assert_eq!(gated.head(), HEAD);
+
+        // handed on as they arrived
+        let seqs: Vec<Option<u32>> = gated.into_packets().iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, vec![Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn gated_head_is_capped() {
+        let mut gated = GatedHead::default();
+        let mut head = b"GET / HTTP/1.1\r\nX-Large: ".to_vec();
+        head.extend(vec![b'a'; MAX_GATED_HEAD]);
+        assert!(gated.push(None, head).unwrap());
+        assert!(!has_basic_auth(gated.head(), "user:pass"));
+    }
+
+    #[test]
+    fn gated_head_not_http() {
+        let mut gated = GatedHead::default();
+        assert!(gated.push(None, b"\x16\x03\x01 tls".to_vec()).unwrap());
+    }
+
+    #[test]
+    fn unauthorized_response_closes() {
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut response = httparse::Response::new(&mut headers);
+        assert!(response
+            .parse(HTTP_UNAUTHORIZED_RESPONSE)
+            .unwrap()
+            .is_complete());
+        assert_eq!(response.code, Some(401));
+        assert!(response
+            .headers
+            .iter()
+            .any(|h| h.name.eq_ignore_ascii_case("connection") && h.value == b"close"));
+    }
+
+    #[test]
+    fn methods_allowed() {
+        let config = Config::from_args(&["tunnelto", "--tunnel-only-methods", "GET, head"]);
+        assert!(is_method_allowed(&config, HEAD));
+        assert!(is_method_allowed(&config, b"HEAD / HTTP/1.1\r\n\r\n"));
+        assert!(!is_method_allowed(&config, b"POST / HTTP/1.1\r\n\r\n"));
+        assert!(!is_method_allowed(&config, b"GETS / HTTP/1.1\r\n\r\n"));
+
+        // everything without the option
+        let config = Config::from_args(&["tunnelto"]);
+        assert!(is_method_allowed(&config, b"DELETE / HTTP/1.1\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn disallowed_method_gets_a_405() {
+        let config = Config::from_args(&["tunnelto", "--tunnel-only-methods", "GET,HEAD"]);
+        let stream_id = StreamId::generate();
+        let (tunnel_tx, mut tunnel_rx) = unbounded();
+        reject_method(&config, tunnel_tx, stream_id.clone()).await;
+
+        let data = match tunnel_rx.next().await {
+            Some(ControlPacket::Data(id, _, data)) if id == stream_id => data,
+            other => panic!("expected a 405, got {:?}", other),
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut response = httparse::Response::new(&mut headers);
+        let head_len = match response.parse(&data).unwrap() {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => panic!("partial 405"),
+        };
+        assert_eq!(response.code, Some(405));
+
+        let header = |name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .map(|h| std::str::from_utf8(h.value).unwrap().to_string())
+        };
+        assert_eq!(header("allow").as_deref(), Some("GET, HEAD"));
+        assert_eq!(header("connection").as_deref(), Some("close"));
+        assert_eq!(
+            header("content-length"),
+            Some((data.len() - head_len).to_string())
+        );
+
+        // the rest of the stream never reaches the local service
+        assert!(config
+            .active_streams
+            .read_or_recover()
+            .contains_key(&stream_id));
+    }
+
+    #[tokio::test]
+    async fn response_timeout_discards_the_rest() {
+        let active_streams: ActiveStreams = Arc::new(RwLock::new(HashMap::new()));
+        let stream_id = StreamId::generate();
+        let (remote_tx, _remote_rx) = unbounded::<StreamMessage>();
+        active_streams
+            .write_or_recover()
+            .insert(stream_id.clone(), remote_tx.clone());
+
+        // a local service that never answers
+        let (local, _service) = tokio::io::duplex(64);
+        let (stream, _sink) = split(local);
+        let (tunnel_tx, mut tunnel_rx) = unbounded();
+        let (introspect, _) = unbounded();
+        process_local_tcp(
+            stream,
+            active_streams.clone(),
+            tunnel_tx,
+            stream_id.clone(),
+            introspect,
+            Some(Duration::from_millis(10)),
+            Arc::new(AtomicBool::new(false)),
+            None,
+            1024,
+            true,
+        )
+        .await;
+
+        match tunnel_rx.next().await {
+            Some(ControlPacket::Data(id, _, data)) => {
+                assert_eq!(id, stream_id);
+                assert_eq!(data, HTTP_GATEWAY_TIMEOUT_RESPONSE);
+            }
+            other => panic!("expected a 504, got {:?}", other),
+        }
+        assert!(matches!(tunnel_rx.next().await, Some(ControlPacket::End(id)) if id == stream_id));
+
+        // later packets go to the discard sink instead of the old forwarder
+        let mut discard = active_streams
+            .read_or_recover()
+            .get(&stream_id)
+            .cloned()
+            .expect("stream still routed");
+        assert!(!discard.same_receiver(&remote_tx));
+        discard
+            .send(StreamMessage::Data(Some(1), b"more".to_vec()))
+            .await
+            .unwrap();
+        discard.send(StreamMessage::Close).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!active_streams.read_or_recover().contains_key(&stream_id));
+    }
+
+    async fn read_until_local_closes(
+        reply: &[u8],
+        max_frame_size: usize,
+        server_stream_end: bool,
+    ) -> Vec<ControlPacket> {
+        let active_streams: ActiveStreams = Arc::new(RwLock::new(HashMap::new()));
+        let stream_id = StreamId::generate();
+        let (remote_tx, _remote_rx) = unbounded::<StreamMessage>();
+        active_streams
+            .write_or_recover()
+            .insert(stream_id.clone(), remote_tx);
+
+        let (local, mut service) = tokio::io::duplex(64 * 1024);
+        service.write_all(reply).await.unwrap();
+        drop(service);
+
+        let (stream, _sink) = split(local);
+        let (tunnel_tx, tunnel_rx) = unbounded();
+        let (introspect, _) = unbounded();
+        process_local_tcp(
+            stream,
+            active_streams.clone(),
+            tunnel_tx,
+            stream_id.clone(),
+            introspect,
+            None,
+            Arc::new(AtomicBool::new(false)),
+            None,
+            max_frame_size,
+            server_stream_end,
+        )
+        .await;
+
+        assert!(!active_streams.read_or_recover().contains_key(&stream_id));
+        tunnel_rx.collect().await
+    }
+
+    #[tokio::test]
+    async fn local_eof_ends_the_stream() {
+        let packets = read_until_local_closes(b"bye", 1024, true).await;
+        assert!(matches!(packets.first(), Some(ControlPacket::Data(..))));
+        assert!(matches!(packets.last(), Some(ControlPacket::End(_))));
+    }
+
+    #[tokio::test]
+    async fn local_eof_without_server_stream_end() {
+        let packets = read_until_local_closes(b"bye", 1024, false).await;
+        assert!(!packets
+            .iter()
+            .any(|packet| matches!(packet, ControlPacket::End(_))));
+    }
+
+    #[tokio::test]
+    async fn reads_are_split_at_the_max_frame_size() {
+        let reply: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let packets = read_until_local_closes(&reply, 1000, true).await;
+
+        let sizes: Vec<usize> = packets
+            .iter()
+            .filter_map(|packet| match packet {
+                ControlPacket::Data(_, _, data) => Some(data.len()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sizes, vec![1000, 1000, 500]);
+
+        let data: Vec<u8> = packets
+            .into_iter()
+            .filter_map(|packet| match packet {
+                ControlPacket::Data(_, _, data) => Some(data),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(data, reply);
+    }
+
+    /// The error of a TLS handshake with a local service that answers with `reply`
+    async fn tls_handshake_error(reply: &'static [u8]) -> std::io::Error {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut hello = [0; 1024];
+            let _ = socket.read(&mut hello).await;
+            let _ = socket.write_all(reply).await;
+        });
+
+        let local_tcp = TcpStream::connect(addr).await.unwrap();
+        let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        connect_tls(dnsname, local_tcp).await.err().unwrap()
+    }
+
+    #[tokio::test]
+    async fn plaintext_reply_to_tls_is_detected() {
+        let error = tls_handshake_error(
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(is_plaintext_handshake_error(&error), "{:?}", error);
+
+        // a service that just hangs up isn't speaking plaintext
+        let error = tls_handshake_error(b"").await;
+        assert!(!is_plaintext_handshake_error(&error), "{:?}", error);
+    }
+}
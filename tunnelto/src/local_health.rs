@@ -0,0 +1,180 @@
+use super::*;
+use std::collections::HashSet;
+
+/// How often a down local service is polled
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+lazy_static::lazy_static! {
+    /// Forward urls with a poller running, so refused streams don't start more
+    static ref POLLING: std::sync::Mutex<HashSet<String>> = std::sync::Mutex::new(HashSet::new());
+}
+
+/// Before opening the tunnel: warn if nothing is listening locally yet,
+/// or with `--wait-for-local` wait until something is
+pub async fn check_on_startup(config: &Config) {
+    let forward_url = config.forward_url();
+    let error = match local::probe(config).await {
+        Ok(()) => return,
+        Err(e) => e,
+    };
+    debug!("local service probe failed: {:?}", error);
+
+    if !config.wait_for_local {
+        eprintln!(
+            "{} {}",
+            "LOCAL SERVICE IS NOT UP YET".yellow(),
+            format!("(nothing is accepting connections at {})", forward_url).dimmed()
+        );
+        return;
+    }
+
+    eprintln!(
+        "{} {}",
+        "WAITING FOR LOCAL SERVICE".yellow(),
+        format!("({})", forward_url).dimmed()
+    );
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        if local::probe(config).await.is_ok() {
+            eprintln!(
+                "{} {}",
+                "LOCAL SERVICE IS UP".green(),
+                format!("({})", forward_url).dimmed()
+            );
+            return;
+        }
+    }
+}
+
+/// A stream was refused because the local service is down: poll its `--local-path-health`
+/// in the background, and tell the user once it's back up
+pub fn service_down(config: &Config) {
+    let path = match &config.local_path_health {
+        Some(path) if config.unix_socket.is_none() => path.clone(),
+        _ => return,
+    };
+
+    let forward_url = config.forward_url();
+    if !POLLING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(forward_url.clone())
+    {
+        return;
+    }
+
+    let url = format!("{}/{}", forward_url, path.trim_start_matches('/'));
+    tokio::spawn(async move {
+        poll_until_healthy(&url, POLL_INTERVAL).await;
+        POLLING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&forward_url);
+    });
+}
+
+/// Poll `url` every `interval` until it answers without a server error,
+/// returning that status (or `None` if polling couldn't start)
+async fn poll_until_healthy(url: &str, interval: Duration) -> Option<u16> {
+    // local services commonly use self-signed certificates
+    let client = match reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .timeout(interval)
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("failed to create local health check client: {:?}", e);
+            return None;
+        }
+    };
+
+    info!("polling local health check: {}", url);
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match client.get(url).send().await {
+            Ok(response) if !response.status().is_server_error() => {
+                eprintln!(
+                    "{} {}",
+                    "LOCAL SERVICE IS BACK".green(),
+                    format!("({} responded {})", url, response.status().as_u16()).dimmed()
+                );
+                return Some(response.status().as_u16());
+            }
+            Ok(response) => debug!("local health check: {}", response.status()),
+            Err(e) => debug!("local health check failed: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A local service answering each connection with the next of `statuses`
+    async fn local_service(statuses: Vec<&'static str>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn reports_the_service_back_once_healthy() {
+        let addr = local_service(vec![
+            "502 Bad Gateway",
+            "503 Service Unavailable",
+            "404 Not Found",
+        ])
+        .await;
+        let url = format!("http://{}/health", addr);
+
+        // a 404 is still a service that's up
+        let poll = poll_until_healthy(&url, Duration::from_millis(10));
+        let status = tokio::time::timeout(Duration::from_secs(5), poll).await;
+        assert_eq!(status.expect("the service came back"), Some(404));
+    }
+
+    #[tokio::test]
+    async fn keeps_polling_while_refused() {
+        // nothing listening on the port until later
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}/health", port);
+
+        let poll =
+            tokio::spawn(async move { poll_until_healthy(&url, Duration::from_millis(10)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!poll.is_finished());
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 1024];
+        let _ = stream.read(&mut buf).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let status = tokio::time::timeout(Duration::from_secs(5), poll).await;
+        assert_eq!(status.expect("the service came back").unwrap(), Some(200));
+    }
+}
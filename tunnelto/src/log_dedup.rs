@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long identical lines are collapsed for
+const DEDUP_WINDOW: Duration = Duration::from_secs(1);
+
+/// Upper bound on distinct lines we keep windows for
+const MAX_TRACKED_LINES: usize = 64;
+
+lazy_static::lazy_static! {
+    pub static ref LOG_DEDUP: LogDedup = LogDedup::new(DEDUP_WINDOW, MAX_TRACKED_LINES);
+}
+
+struct Window {
+    started: Instant,
+    suppressed: usize,
+}
+
+/// Collapses repeated high-frequency log lines into a count per time window
+pub struct LogDedup {
+    window: Duration,
+    capacity: usize,
+    lines: Mutex<HashMap<&'static str, Window>>,
+}
+
+impl LogDedup {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            lines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `Some(suppressed)` if the line should be emitted now, where `suppressed`
+    /// is the number of identical lines swallowed during the previous window.
+    pub fn check(&self, line: &'static str) -> Option<usize> {
+        let now = Instant::now();
+        let mut lines = match self.lines.lock() {
+            Ok(lines) => lines,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some(window) = lines.get_mut(line) {
+            if now.duration_since(window.started) < self.window {
+                window.suppressed += 1;
+                return None;
+            }
+
+            let suppressed = window.suppressed;
+            window.started = now;
+            window.suppressed = 0;
+            return Some(suppressed);
+        }
+
+        // stay bounded: forget expired windows first, then everything
+        if lines.len() >= self.capacity {
+            let window = self.window;
+            lines.retain(|_, w| now.duration_since(w.started) < window);
+            if lines.len() >= self.capacity {
+                lines.clear();
+            }
+        }
+
+        lines.insert(
+            line,
+            Window {
+                started: now,
+                suppressed: 0,
+            },
+        );
+        Some(0)
+    }
+}
+
+/// Log a high-frequency line at info level, at most once per dedup window
+pub fn info(line: &'static str) {
+    match LOG_DEDUP.check(line) {
+        Some(0) => log::info!("{}", line),
+        Some(suppressed) => log::info!("{} (repeated {} times)", line, suppressed),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_lines_are_collapsed() {
+        let dedup = LogDedup::new(Duration::from_millis(50), 8);
+        assert_eq!(dedup.check("new data"), Some(0));
+        for _ in 0..5 {
+            assert_eq!(dedup.check("new data"), None);
+        }
+
+        // other lines have windows of their own
+        assert_eq!(dedup.check("forwarded"), Some(0));
+
+        // the next window reports what the last one swallowed
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(dedup.check("new data"), Some(5));
+        assert_eq!(dedup.check("new data"), None);
+    }
+
+    #[test]
+    fn tracked_lines_stay_bounded() {
+        const LINES: [&str; 3] = ["a", "b", "c"];
+        let dedup = LogDedup::new(Duration::from_secs(60), 2);
+        for line in LINES.iter() {
+            assert_eq!(dedup.check(line), Some(0));
+        }
+        assert!(dedup.lines.lock().unwrap().len() <= 2);
+
+        // forgotten lines start over rather than staying suppressed
+        assert_eq!(dedup.check("a"), Some(0));
+    }
+}
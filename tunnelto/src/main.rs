@@ -1,51 +1,88 @@
-use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::{SinkExt, StreamExt};
 
 use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
 use human_panic::setup_panic;
-pub use log::{debug, error, info, warn};
+pub use log::{debug, error, info, trace, warn};
 
 use std::collections::HashMap;
 use std::env;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 mod cli_ui;
 mod config;
 mod error;
+mod http;
 mod introspect;
 mod local;
+mod local_health;
+mod log_dedup;
 mod update;
+mod vanity;
 pub use self::error::*;
 
 pub use config::*;
 pub use tunnelto_lib::*;
 
-use crate::cli_ui::CliInterface;
+use crate::cli_ui::{CliInterface, ServedBy};
 use colored::Colorize;
 use futures::future::Either;
 use std::time::Duration;
 use tokio::sync::Mutex;
 
-pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
+/// Set on Ctrl-C: new streams are refused while the active ones drain
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
 lazy_static::lazy_static! {
-    pub static ref ACTIVE_STREAMS:ActiveStreams = Arc::new(RwLock::new(HashMap::new()));
-    pub static ref RECONNECT_TOKEN: Arc<Mutex<Option<ReconnectToken>>> = Arc::new(Mutex::new(None));
+    /// Tells each control connection to close its websocket once streams drained
+    static ref CLOSE_CONTROL: tokio::sync::Notify = tokio::sync::Notify::new();
+    /// Shuts down like Ctrl-C, but exits with a failure (i.e: `--tls-fail-fast`)
+    pub static ref FAIL_FAST: tokio::sync::Notify = tokio::sync::Notify::new();
+}
+
+pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
+pub type SharedReconnectToken = Arc<Mutex<Option<ReconnectToken>>>;
+
+/// Take a lock even if a task panicked while holding it, so one bad stream
+/// can't take down every other stream
+pub trait RecoverLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RecoverLock<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(|e| {
+            warn!("recovering poisoned lock");
+            e.into_inner()
+        })
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(|e| {
+            warn!("recovering poisoned lock");
+            e.into_inner()
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
-    Data(Vec<u8>),
+    /// data from the server, with its sequence number if the server numbers them
+    Data(Option<u32>, Vec<u8>),
     Close,
 }
 
 #[tokio::main]
 async fn main() {
-    let mut config = match Config::get() {
+    let config = match Config::get() {
         Ok(config) => config,
         Err(_) => return,
     };
@@ -54,16 +91,131 @@ async fn main() {
 
     update::check().await;
 
-    let introspect_dash_addr = introspect::start_introspect_web_dashboard(config.clone());
+    if let Some(name) = config.sub_domain_check.clone() {
+        vanity::suggest(&config, &name).await;
+        return;
+    }
+
+    let tunnels = config.tunnels();
+
+    let shutdown_grace = Duration::from_secs(config.shutdown_grace);
+    let active_streams: Vec<ActiveStreams> =
+        tunnels.iter().map(|t| t.active_streams.clone()).collect();
+    tokio::spawn(async move {
+        let code = tokio::select! {
+            signal = tokio::signal::ctrl_c() => match signal {
+                Ok(()) => 0,
+                Err(_) => return,
+            },
+            _ = FAIL_FAST.notified() => 1,
+        };
+
+        SHUTTING_DOWN.store(true, Ordering::SeqCst);
+        drain_active_streams(&active_streams, shutdown_grace).await;
+
+        // give the writers a moment to send their close frames
+        CLOSE_CONTROL.notify_waiters();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        std::process::exit(code);
+    });
+
+    // replays go to the first tunnel
+    let introspect_dash_addr = match introspect::start_introspect_web_dashboard(tunnels[0].clone())
+    {
+        Some(addr) => addr,
+        None => return,
+    };
+
+    if !config.forwards.is_empty() {
+        eprintln!(
+            "Local inspect dashboard: {}",
+            config.dashboard_url(introspect_dash_addr.port()).magenta()
+        );
+        if config.open_dashboard {
+            cli_ui::open_in_browser(&config.dashboard_url(introspect_dash_addr.port()));
+        }
+    }
+
+    for tunnel in &tunnels {
+        local_health::check_on_startup(tunnel).await;
+    }
+
+    let supervisors = tunnels
+        .into_iter()
+        .map(|tunnel| supervise_tunnel(tunnel, introspect_dash_addr));
+    futures::future::join_all(supervisors).await;
+}
+
+/// Give up after the server closes on us mid-handshake this many times in a row
+const MAX_HANDSHAKE_CLOSES: u32 = 3;
+
+/// Packets for the server, kept across control reconnects so open streams can resume
+struct TunnelQueue {
+    tx: UnboundedSender<ControlPacket>,
+    rx: UnboundedReceiver<ControlPacket>,
+    /// the packet being written, kept for the next connection if this one drops first
+    pending: Option<ControlPacket>,
+    /// when the last control connection dropped
+    disconnected_at: Option<std::time::Instant>,
+}
+
+impl TunnelQueue {
+    fn new() -> Self {
+        let (tx, rx) = unbounded();
+        TunnelQueue {
+            tx,
+            rx,
+            pending: None,
+            disconnected_at: None,
+        }
+    }
+
+    async fn next(&mut self) -> Option<ControlPacket> {
+        match self.pending.take() {
+            Some(packet) => Some(packet),
+            None => self.rx.next().await,
+        }
+    }
+
+    /// Drop anything buffered for streams we gave up on
+    fn clear(&mut self) {
+        self.pending = None;
+        while let Ok(Some(_)) = self.rx.try_next() {}
+    }
+}
+
+/// Keep a tunnel open, restarting its control connection until a fatal error
+async fn supervise_tunnel(mut config: Config, introspect_dash_addr: SocketAddr) {
+    let mut handshake_closes = 0;
+    let mut queue = TunnelQueue::new();
 
     loop {
         let (restart_tx, mut restart_rx) = unbounded();
-        let wormhole = run_wormhole(config.clone(), introspect_dash_addr.clone(), restart_tx);
-        let result = futures::future::select(Box::pin(wormhole), restart_rx.next()).await;
+        let result = {
+            let wormhole =
+                run_wormhole(config.clone(), introspect_dash_addr, restart_tx, &mut queue);
+            match futures::future::select(Box::pin(wormhole), restart_rx.next()).await {
+                Either::Left((result, _)) => Either::Left(result),
+                Either::Right((error, _)) => Either::Right(error),
+            }
+        };
         config.first_run = false;
+        queue
+            .disconnected_at
+            .get_or_insert_with(std::time::Instant::now);
+
+        let closed = matches!(result, Either::Left(Err(Error::HandshakeClosed)));
+        let retry_close = retry_handshake_close(&mut handshake_closes, closed);
 
         match result {
-            Either::Left((Err(e), _)) => match e {
+            Either::Left(Err(e)) => match e {
+                Error::HandshakeClosed if retry_close => {
+                    error!(
+                        "Control error: {:?} ({}/{}). Retrying in 5 seconds.",
+                        e, handshake_closes, MAX_HANDSHAKE_CLOSES
+                    );
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
                 Error::WebSocketError(_) | Error::NoResponseFromServer | Error::Timeout => {
                     error!("Control error: {:?}. Retrying in 5 seconds.", e);
                     tokio::time::sleep(Duration::from_secs(5)).await;
@@ -94,7 +246,7 @@ async fn main() {
                     return;
                 }
             },
-            Either::Right((Some(e), _)) => {
+            Either::Right(Some(e)) => {
                 warn!("restarting in 3 seconds...from error: {:?}", e);
                 tokio::time::sleep(Duration::from_secs(3)).await;
             }
@@ -105,11 +257,67 @@ async fn main() {
     }
 }
 
+/// Count a control connection ending, `closed` if the server closed it
+/// mid-handshake. Whether to retry that: only while it happened fewer than
+/// `MAX_HANDSHAKE_CLOSES` times in a row.
+fn retry_handshake_close(handshake_closes: &mut u32, closed: bool) -> bool {
+    if !closed {
+        *handshake_closes = 0;
+        return false;
+    }
+
+    *handshake_closes += 1;
+    *handshake_closes < MAX_HANDSHAKE_CLOSES
+}
+
+fn active_stream_count(active_streams: &[ActiveStreams]) -> usize {
+    active_streams
+        .iter()
+        .map(|s| s.read_or_recover().len())
+        .sum()
+}
+
+/// Wait for active streams to complete, for at most `grace`.
+/// Returns how many were still open when it gave up.
+async fn drain_active_streams(active_streams: &[ActiveStreams], grace: Duration) -> usize {
+    let deadline = tokio::time::Instant::now() + grace;
+
+    while tokio::time::Instant::now() < deadline {
+        let remaining = active_stream_count(active_streams);
+        if remaining == 0 {
+            return 0;
+        }
+
+        debug!("waiting on {} active streams to finish", remaining);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let remaining = active_stream_count(active_streams);
+    if remaining > 0 {
+        warn!("shutdown grace period elapsed, closing active streams");
+    }
+    remaining
+}
+
+/// Close local streams that outlived the resume window, the server has dropped them
+async fn close_stale_streams(config: &Config, queue: &mut TunnelQueue) {
+    let streams: Vec<_> = config.active_streams.write_or_recover().drain().collect();
+    if !streams.is_empty() {
+        warn!("reconnect took too long, closing {} streams", streams.len());
+    }
+
+    for (_, mut tx) in streams {
+        let _ = tx.send(StreamMessage::Close).await;
+    }
+    queue.clear();
+}
+
 /// Setup the tunnel to our control server
 async fn run_wormhole(
     config: Config,
     introspect_web_addr: SocketAddr,
     mut restart_tx: UnboundedSender<Option<Error>>,
+    queue: &mut TunnelQueue,
 ) -> Result<(), Error> {
     let interface = CliInterface::start(config.clone(), introspect_web_addr);
     tokio::time::sleep(std::time::Duration::from_millis(500)).await;
@@ -117,38 +325,91 @@ async fn run_wormhole(
         websocket,
         sub_domain,
         hostname,
+        reserved_until,
+        format,
+        remote_port,
+        served_by,
     } = connect_to_wormhole(&config).await?;
 
-    interface.did_connect(&sub_domain, &hostname);
+    interface.did_connect(
+        &sub_domain,
+        &hostname,
+        reserved_until,
+        remote_port,
+        &served_by,
+    );
+
+    // resume streams from a brief drop, the server holds them for the same window
+    if let Some(disconnected_at) = queue.disconnected_at.take() {
+        if disconnected_at.elapsed() > Duration::from_secs(STREAM_RESUME_WINDOW) {
+            close_stale_streams(&config, queue).await;
+        } else {
+            info!(
+                "resuming {} streams",
+                config.active_streams.read_or_recover().len()
+            );
+        }
+    }
 
     // split reading and writing
-    let (mut ws_sink, mut ws_stream) = websocket.split();
+    let (mut ws_sink, ws_stream) = websocket.split();
 
     // tunnel channel
-    let (tunnel_tx, mut tunnel_rx) = unbounded::<ControlPacket>();
+    let tunnel_tx = queue.tx.clone();
 
     // continuously write to websocket tunnel
-    let mut restart = restart_tx.clone();
-    tokio::spawn(async move {
+    let writer = async move {
+        let mut probe = tokio::time::interval(Duration::from_secs(PING_INTERVAL));
         loop {
-            let packet = match tunnel_rx.next().await {
+            let next = tokio::select! {
+                next = queue.next() => next,
+                _ = probe.tick() => Some(ControlPacket::Probe(PingTimestamp::now())),
+                _ = CLOSE_CONTROL.notified() => {
+                    debug!("shutting down, closing control connection");
+                    let _ = ws_sink.send(Message::Close(None)).await;
+                    return Ok(());
+                }
+            };
+
+            let packet = match next {
                 Some(data) => data,
                 None => {
                     warn!("control flow didn't send anything!");
-                    let _ = restart.send(Some(Error::Timeout)).await;
-                    return;
+                    return Err(Error::Timeout);
                 }
             };
 
-            if let Err(e) = ws_sink.send(Message::binary(packet.serialize())).await {
+            // held until it's written: a dropped connection can cancel the write
+            // midway, and a probe re-sent later would measure the reconnect
+            let message = Message::binary(packet.clone().encode(format));
+            if !matches!(packet, ControlPacket::Probe(_)) {
+                queue.pending = Some(packet);
+            }
+
+            if let Err(e) = ws_sink.send(message).await {
                 warn!("failed to write message to tunnel websocket: {:?}", e);
-                let _ = restart.send(Some(Error::WebSocketError(e))).await;
-                return;
+                return Err(Error::WebSocketError(e));
             }
+            queue.pending = None;
         }
-    });
+    };
 
     // continuously read from websocket tunnel
+    let reader = read_wormhole(config, ws_stream, tunnel_tx, &mut restart_tx);
+
+    match futures::future::select(Box::pin(writer), Box::pin(reader)).await {
+        Either::Left((result, _)) => result,
+        Either::Right((result, _)) => result,
+    }
+}
+
+async fn read_wormhole(
+    config: Config,
+    mut ws_stream: futures::stream::SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    tunnel_tx: UnboundedSender<ControlPacket>,
+    restart_tx: &mut UnboundedSender<Option<Error>>,
+) -> Result<(), Error> {
+    let mut gated_heads = HashMap::new();
 
     loop {
         match ws_stream.next().await {
@@ -161,6 +422,7 @@ async fn run_wormhole(
                 let packet = process_control_flow_message(
                     config.clone(),
                     tunnel_tx.clone(),
+                    &mut gated_heads,
                     message.into_data(),
                 )
                 .await
@@ -186,20 +448,44 @@ struct Wormhole {
     websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
     sub_domain: String,
     hostname: String,
+    reserved_until: Option<u64>,
+    /// how to write packets for this server
+    format: WireFormat,
+    /// the public port of a raw tcp tunnel
+    remote_port: Option<u16>,
+    served_by: ServedBy,
 }
 
 async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
-    let (mut websocket, _) = tokio_tungstenite::connect_async(&config.control_url).await?;
+    let mut request = config.control_url.as_str().into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(CONTROL_SUBPROTOCOL),
+    );
+    if let Some(region) = config.region.as_ref() {
+        if let Ok(value) = HeaderValue::from_str(region) {
+            request.headers_mut().insert(REGION_HEADER, value);
+        }
+    }
+    let (mut websocket, response) = tokio_tungstenite::connect_async(request).await?;
+
+    // servers from before subprotocols don't answer with one
+    if let Some(subprotocol) = response.headers().get("Sec-WebSocket-Protocol") {
+        let subprotocol = String::from_utf8_lossy(subprotocol.as_bytes()).to_string();
+        if subprotocol != CONTROL_SUBPROTOCOL {
+            return Err(Error::SubprotocolMismatch(subprotocol));
+        }
+    }
 
     // send our Client Hello message
-    let client_hello = match config.secret_key.clone() {
+    let mut client_hello = match config.secret_key.clone() {
         Some(secret_key) => ClientHello::generate(
             config.sub_domain.clone(),
             ClientType::Auth { key: secret_key },
         ),
         None => {
             // if we have a reconnect token, use it.
-            if let Some(reconnect) = RECONNECT_TOKEN.lock().await.clone() {
+            if let Some(reconnect) = config.reconnect_token.lock().await.clone() {
                 ClientHello::reconnect(reconnect)
             } else {
                 ClientHello::generate(config.sub_domain.clone(), ClientType::Anonymous)
@@ -207,6 +493,12 @@ async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
         }
     };
 
+    client_hello.takeover = config.takeover;
+    client_hello.compression = config.compress;
+    client_hello.tcp = config.tcp;
+    client_hello.requested_port = config.remote_port;
+    client_hello.region = config.region.clone();
+
     info!("connecting to wormhole...");
 
     let hello = serde_json::to_vec(&client_hello).unwrap();
@@ -215,48 +507,135 @@ async fn connect_to_wormhole(config: &Config) -> Result<Wormhole, Error> {
         .await
         .expect("Failed to send client hello to wormhole server.");
 
-    // wait for Server hello
-    let server_hello_data = websocket
-        .next()
-        .await
-        .ok_or(Error::NoResponseFromServer)??
-        .into_data();
-    let server_hello = serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
-        error!("Couldn't parse server_hello from {:?}", e);
-        Error::ServerReplyInvalid
-    })?;
+    let server_hello = read_server_hello(&mut websocket).await?;
 
-    let (sub_domain, hostname) = match server_hello {
+    let (sub_domain, hostname, reserved_until, format, remote_port, served_by) = match server_hello
+    {
         ServerHello::Success {
             sub_domain,
             client_id,
             hostname,
+            reserved_until,
+            compression,
+            protocol_version,
+            remote_port,
+            max_frame_size,
+            features,
+            instance_id,
+            region,
         } => {
             info!("Server accepted our connection. I am client_{}", client_id);
-            (sub_domain, hostname)
+            info!(
+                "served by instance {:?} in region {:?}",
+                instance_id, region
+            );
+            debug!("server features: {:?}", features);
+            let supports = |name| feature::supported(features.as_deref(), name);
+
+            // servers from before raw tcp tunnels would give us an http one
+            if config.tcp && (remote_port.is_none() || !supports(feature::RAW_TCP)) {
+                return Err(Error::ServerError(
+                    "This server does not support raw tcp tunnels.".to_string(),
+                ));
+            }
+            let compression = compression && supports(feature::COMPRESSION);
+            if config.compress && !compression {
+                warn!("server does not support compression, sending uncompressed");
+            }
+            config
+                .max_frame_size
+                .store(max_frame_size, Ordering::SeqCst);
+            config
+                .server_stream_end
+                .store(supports(feature::STREAM_END), Ordering::SeqCst);
+            let format = WireFormat {
+                compression,
+                sequenced: protocol_version >= SEQUENCED_PROTOCOL_VERSION
+                    && supports(feature::SEQUENCED),
+            };
+            let served_by = ServedBy {
+                instance_id,
+                region,
+            };
+            (
+                sub_domain,
+                hostname,
+                reserved_until,
+                format,
+                remote_port,
+                served_by,
+            )
         }
         ServerHello::AuthFailed => {
             return Err(Error::AuthenticationFailed);
         }
+        ServerHello::AuthFailedWithReason { reason } => {
+            return Err(Error::AuthenticationRefused(reason));
+        }
         ServerHello::InvalidSubDomain => {
             return Err(Error::InvalidSubDomain);
         }
         ServerHello::SubDomainInUse => {
             return Err(Error::SubDomainInUse);
         }
-        ServerHello::Error(error) => return Err(Error::ServerError(error)),
+        ServerHello::IncompatibleVersion { min, max } => {
+            return Err(Error::IncompatibleVersion {
+                ours: PROTOCOL_VERSION,
+                min,
+                max,
+            })
+        }
+        ServerHello::Error(error) => {
+            return Err(match ServerHello::outdated_client(&error) {
+                Some((min, upgrade_url)) => Error::OutdatedClient {
+                    ours: PROTOCOL_VERSION,
+                    min,
+                    upgrade_url,
+                },
+                None => Error::ServerError(error),
+            })
+        }
     };
 
     Ok(Wormhole {
         websocket,
         sub_domain,
         hostname,
+        reserved_until,
+        format,
+        remote_port,
+        served_by,
+    })
+}
+
+/// Wait for the server hello, sent as either a binary or a text frame
+async fn read_server_hello<S>(websocket: &mut S) -> Result<ServerHello, Error>
+where
+    S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let server_hello_data = loop {
+        match websocket
+            .next()
+            .await
+            .ok_or(Error::NoResponseFromServer)??
+        {
+            Message::Binary(data) => break data,
+            Message::Text(text) => break text.into_bytes(),
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Close(_) => return Err(Error::HandshakeClosed),
+        }
+    };
+
+    serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
+        error!("Couldn't parse server_hello from {:?}", e);
+        Error::ServerReplyInvalid
     })
 }
 
 async fn process_control_flow_message(
     config: Config,
     mut tunnel_tx: UnboundedSender<ControlPacket>,
+    gated_heads: &mut HashMap<StreamId, local::GatedHead>,
     payload: Vec<u8>,
 ) -> Result<ControlPacket, Box<dyn std::error::Error>> {
     let control_packet = ControlPacket::deserialize(&payload)?;
@@ -265,40 +644,118 @@ async fn process_control_flow_message(
         ControlPacket::Init(stream_id) => {
             info!("stream[{:?}] -> init", stream_id.to_string());
         }
-        ControlPacket::Ping(reconnect_token) => {
+        ControlPacket::Ping(reconnect_token, timestamp) => {
             log::info!("got ping. reconnect_token={}", reconnect_token.is_some());
 
             if let Some(reconnect) = reconnect_token {
-                let _ = RECONNECT_TOKEN.lock().await.replace(reconnect.clone());
+                let _ = config
+                    .reconnect_token
+                    .lock()
+                    .await
+                    .replace(reconnect.clone());
             }
-            let _ = tunnel_tx.send(ControlPacket::Ping(None)).await;
+            // echo the timestamp back so the server can measure rtt
+            let _ = tunnel_tx.send(ControlPacket::Ping(None, *timestamp)).await;
+        }
+        ControlPacket::ProbeReply(timestamp) => {
+            cli_ui::report_rtt(&config, timestamp.rtt());
+        }
+        ControlPacket::Refused(_) | ControlPacket::Probe(_) => {
+            return Err("unexpected control packet".into())
+        }
+        ControlPacket::EndOfMessage(stream_id) => {
+            debug!("stream[{:?}] -> end of message", stream_id.to_string());
         }
-        ControlPacket::Refused(_) => return Err("unexpected control packet".into()),
         ControlPacket::End(stream_id) => {
             // find the stream
             let stream_id = stream_id.clone();
 
             info!("got end stream [{:?}]", &stream_id);
-
-            tokio::spawn(async move {
-                let stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
-                if let Some(mut tx) = stream {
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    let _ = tx.send(StreamMessage::Close).await.map_err(|e| {
-                        error!("failed to send stream close: {:?}", e);
-                    });
-                    ACTIVE_STREAMS.write().unwrap().remove(&stream_id);
-                }
-            });
+            gated_heads.remove(&stream_id);
+
+            // queued after any data still in flight, the stream drains then removes itself
+            let stream = config
+                .active_streams
+                .read_or_recover()
+                .get(&stream_id)
+                .cloned();
+            if let Some(mut tx) = stream {
+                let _ = tx.send(StreamMessage::Close).await.map_err(|e| {
+                    error!("failed to send stream close: {:?}", e);
+                });
+            }
         }
-        ControlPacket::Data(stream_id, data) => {
-            info!(
+        ControlPacket::Data(stream_id, seq, data) => {
+            trace!(
                 "stream[{:?}] -> new data: {:?}",
                 stream_id.to_string(),
                 data.len()
             );
+            log_dedup::info("stream -> new data");
+
+            // an empty payload is a keep-alive, it must not open or close a stream
+            if data.is_empty() {
+                return Ok(control_packet.clone());
+            }
+
+            if !config
+                .active_streams
+                .read_or_recover()
+                .contains_key(stream_id)
+            {
+                if SHUTTING_DOWN.load(Ordering::SeqCst) {
+                    debug!("shutting down, refusing new stream");
+                    gated_heads.remove(stream_id);
+                    tunnel_tx
+                        .send(ControlPacket::Refused(stream_id.clone()))
+                        .await?;
+                    return Ok(control_packet.clone());
+                }
+
+                if local::is_gated(&config) {
+                    let ready = gated_heads
+                        .entry(stream_id.clone())
+                        .or_default()
+                        .push(*seq, data.clone());
+                    match ready {
+                        // still waiting on the rest of the request head
+                        Ok(false) => return Ok(control_packet.clone()),
+                        Ok(true) => {}
+                        Err(e) => {
+                            warn!("dropping gated stream: {}", e);
+                            gated_heads.remove(stream_id);
+                            tunnel_tx
+                                .send(ControlPacket::Refused(stream_id.clone()))
+                                .await?;
+                            return Ok(control_packet.clone());
+                        }
+                    }
+
+                    let gated = gated_heads.remove(stream_id).unwrap_or_default();
+                    if !local::is_authorized(&config, gated.head()) {
+                        local::reject_unauthorized(&config, tunnel_tx.clone(), stream_id.clone())
+                            .await;
+                    } else if !local::is_method_allowed(&config, gated.head()) {
+                        local::reject_method(&config, tunnel_tx.clone(), stream_id.clone()).await;
+                    } else {
+                        match local::setup_new_stream(
+                            config.clone(),
+                            tunnel_tx.clone(),
+                            stream_id.clone(),
+                        )
+                        .await
+                        {
+                            Some(mut tx) => {
+                                for (seq, data) in gated.into_packets() {
+                                    let _ = tx.send(StreamMessage::Data(seq, data)).await;
+                                }
+                            }
+                            None => error!("failed to open local tunnel"),
+                        }
+                    }
+                    return Ok(control_packet.clone());
+                }
 
-            if !ACTIVE_STREAMS.read().unwrap().contains_key(&stream_id) {
                 if local::setup_new_stream(config.clone(), tunnel_tx.clone(), stream_id.clone())
                     .await
                     .is_none()
@@ -308,12 +765,26 @@ async fn process_control_flow_message(
             }
 
             // find the right stream
-            let active_stream = ACTIVE_STREAMS.read().unwrap().get(&stream_id).cloned();
+            let active_stream = config
+                .active_streams
+                .read_or_recover()
+                .get(stream_id)
+                .cloned();
 
             // forward data to it
             if let Some(mut tx) = active_stream {
-                tx.send(StreamMessage::Data(data.clone())).await?;
-                info!("forwarded to local tcp ({})", stream_id.to_string());
+                // the local stream may have just closed on its own
+                if tx
+                    .send(StreamMessage::Data(*seq, data.clone()))
+                    .await
+                    .is_err()
+                {
+                    warn!("local stream closed, dropping data");
+                    config.active_streams.write_or_recover().remove(stream_id);
+                    return Ok(control_packet.clone());
+                }
+                trace!("forwarded to local tcp ({})", stream_id.to_string());
+                log_dedup::info("forwarded to local tcp");
             } else {
                 error!("got data but no stream to send it to.");
                 let _ = tunnel_tx
@@ -325,3 +796,103 @@ async fn process_control_flow_message(
 
     Ok(control_packet.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_closes_retry_then_give_up() {
+        let mut handshake_closes = 0;
+        for _ in 1..MAX_HANDSHAKE_CLOSES {
+            assert!(retry_handshake_close(&mut handshake_closes, true));
+        }
+        assert!(!retry_handshake_close(&mut handshake_closes, true));
+    }
+
+    #[test]
+    fn handshake_closes_count_only_in_a_row() {
+        let mut handshake_closes = 0;
+        for _ in 0..MAX_HANDSHAKE_CLOSES * 2 {
+            assert!(retry_handshake_close(&mut handshake_closes, true));
+            // e.g: a timeout, or a connection that got through
+            assert!(!retry_handshake_close(&mut handshake_closes, false));
+        }
+        assert_eq!(handshake_closes, 0);
+    }
+
+    fn stream(active_streams: &ActiveStreams) -> StreamId {
+        let id = StreamId::generate();
+        let (tx, _rx) = unbounded();
+        active_streams.write_or_recover().insert(id.clone(), tx);
+        id
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_returns_once_streams_finish() {
+        let active_streams = ActiveStreams::default();
+        let id = stream(&active_streams);
+
+        let closing = active_streams.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            closing.write_or_recover().remove(&id);
+        });
+
+        let start = tokio::time::Instant::now();
+        let remaining = drain_active_streams(&[active_streams], Duration::from_secs(30)).await;
+        assert_eq!(remaining, 0);
+        assert!(
+            start.elapsed() < Duration::from_secs(3),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_gives_up_at_the_grace_deadline() {
+        let active_streams = ActiveStreams::default();
+        let idle = ActiveStreams::default();
+        stream(&active_streams);
+        stream(&active_streams);
+
+        let start = tokio::time::Instant::now();
+        let remaining = drain_active_streams(&[idle, active_streams], Duration::from_secs(5)).await;
+        assert_eq!(remaining, 2);
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        assert!(
+            start.elapsed() < Duration::from_secs(6),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    async fn server_hello(messages: Vec<Message>) -> Result<ServerHello, Error> {
+        let mut websocket = futures::stream::iter(messages.into_iter().map(Ok));
+        read_server_hello(&mut websocket).await
+    }
+
+    #[tokio::test]
+    async fn server_hello_as_binary_or_text() {
+        let hello = serde_json::to_string(&ServerHello::SubDomainInUse).unwrap();
+
+        let binary = server_hello(vec![Message::Binary(hello.clone().into_bytes())]).await;
+        assert!(matches!(binary, Ok(ServerHello::SubDomainInUse)));
+
+        // keep-alives before it are skipped
+        let text = server_hello(vec![Message::Ping(vec![]), Message::Text(hello)]).await;
+        assert!(matches!(text, Ok(ServerHello::SubDomainInUse)));
+    }
+
+    #[tokio::test]
+    async fn server_hello_missing_or_invalid() {
+        let closed = server_hello(vec![Message::Close(None)]).await;
+        assert!(matches!(closed, Err(Error::HandshakeClosed)));
+
+        let ended = server_hello(vec![]).await;
+        assert!(matches!(ended, Err(Error::NoResponseFromServer)));
+
+        let invalid = server_hello(vec![Message::Text("{".to_string())]).await;
+        assert!(matches!(invalid, Err(Error::ServerReplyInvalid)));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::Config;
+use colored::Colorize;
+use tunnelto_lib::{sub_domain_to_ascii, SubDomainCheck, SubDomainCheckResult};
+
+/// Print which variants of `base` are available to claim
+pub async fn suggest(config: &Config, base: &str) {
+    match check(config, base).await {
+        Ok(result) if result.available.is_empty() => {
+            eprintln!(
+                "{}",
+                format!("No available sub-domains found for '{}'.", base).yellow()
+            );
+        }
+        Ok(result) => {
+            eprintln!("Available sub-domains:");
+            for sub_domain in result.available {
+                eprintln!("    {}", sub_domain.green());
+            }
+        }
+        Err(e) => eprintln!("Error: {}", format!("{}", e).red()),
+    }
+}
+
+async fn check(
+    config: &Config,
+    base: &str,
+) -> Result<SubDomainCheckResult, Box<dyn std::error::Error>> {
+    let base = sub_domain_to_ascii(base)?;
+    let query = SubDomainCheck {
+        sub_domains: SubDomainCheck::suggestions(&base),
+        key: config.secret_key.clone(),
+    };
+
+    let result = reqwest::Client::new()
+        .post(format!("{}/subdomain_check", config.control_api_url))
+        .json(&query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    /// A control server marking the sub-domains `taken` says are in use,
+    /// returning its api url and the sub-domains it was asked about
+    fn stub_server(taken: fn(&str) -> bool) -> (String, Arc<Mutex<Vec<String>>>) {
+        let asked = Arc::new(Mutex::new(vec![]));
+        let seen = asked.clone();
+        let route = warp::post()
+            .and(warp::path("subdomain_check"))
+            .and(warp::body::json())
+            .map(move |query: SubDomainCheck| {
+                seen.lock().unwrap().extend(query.sub_domains.clone());
+                let available = query
+                    .sub_domains
+                    .into_iter()
+                    .filter(|s| !taken(s))
+                    .collect();
+                warp::reply::json(&SubDomainCheckResult { available })
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), asked)
+    }
+
+    fn config(control_api_url: String) -> Config {
+        let mut config = Config::from_args(&["tunnelto"]);
+        config.control_api_url = control_api_url;
+        config
+    }
+
+    #[tokio::test]
+    async fn suggests_only_free_variants() {
+        let (url, asked) = stub_server(|s| ["myapp", "myapp-1", "myapp-dev"].contains(&s));
+        let result = check(&config(url), "myapp").await.unwrap();
+        assert_eq!(result.available[..2], ["myapp-2", "myapp-app"]);
+        assert_eq!(result.available.len(), 3);
+        assert!(result.available[2].starts_with("myapp-"));
+
+        let asked = asked.lock().unwrap();
+        assert_eq!(asked.len(), SubDomainCheck::suggestions("myapp").len());
+        assert_eq!(asked[0], "myapp");
+    }
+
+    #[tokio::test]
+    async fn none_free() {
+        let (url, _) = stub_server(|_| true);
+        assert!(check(&config(url), "myapp")
+            .await
+            .unwrap()
+            .available
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_base_or_server_error() {
+        let (url, asked) = stub_server(|_| false);
+        assert!(check(&config(url.clone()), "a..b").await.is_err());
+        assert!(asked.lock().unwrap().is_empty());
+
+        // i.e: an older server without the endpoint
+        let missing = config(format!("{}/missing", url));
+        assert!(check(&missing, "myapp").await.is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::channel::mpsc::unbounded;
+use futures::{SinkExt, StreamExt};
+use tunnelto_lib::{ControlPacket, StreamId, MAX_FRAME_SIZE};
+
+/// From a tiny request up to a large upload chunk
+const PAYLOAD_SIZES: &[usize] = &[64, 1024, 4 * 1024, 64 * 1024, 1024 * 1024];
+
+/// The client reads local streams 4kb at a time
+const STREAM_CHUNK: usize = 4 * 1024;
+
+fn serialize(c: &mut Criterion) {
+    let stream_id = StreamId::generate();
+    let mut group = c.benchmark_group("serialize");
+
+    for size in PAYLOAD_SIZES {
+        let payload = vec![0xAB; *size];
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &payload, |b, payload| {
+            b.iter(|| ControlPacket::Data(stream_id.clone(), None, payload.clone()).serialize())
+        });
+    }
+    group.finish();
+}
+
+fn deserialize(c: &mut Criterion) {
+    let stream_id = StreamId::generate();
+    let mut group = c.benchmark_group("deserialize");
+
+    for size in PAYLOAD_SIZES {
+        let packet = ControlPacket::Data(stream_id.clone(), None, vec![0xAB; *size]).serialize();
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &packet, |b, packet| {
+            b.iter(|| ControlPacket::deserialize(black_box(packet)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// A stream body sent as data packets over an in-memory tunnel:
+/// serialized by the "client", deserialized and reassembled by the "server"
+fn stream_throughput(c: &mut Criterion) {
+    let stream_id = StreamId::generate();
+    let mut group = c.benchmark_group("stream_throughput");
+
+    for size in &[64 * 1024, 1024 * 1024] {
+        let body = vec![0xAB; *size];
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &body, |b, body| {
+            b.iter(|| {
+                futures::executor::block_on(async {
+                    let (mut tx, mut rx) = unbounded::<Vec<u8>>();
+
+                    for chunk in body.chunks(STREAM_CHUNK) {
+                        let packet = ControlPacket::Data(stream_id.clone(), None, chunk.to_vec());
+                        tx.send(packet.serialize()).await.unwrap();
+                    }
+                    tx.send(ControlPacket::End(stream_id.clone()).serialize())
+                        .await
+                        .unwrap();
+
+                    let mut received = Vec::with_capacity(body.len());
+                    while let Some(message) = rx.next().await {
+                        match ControlPacket::deserialize(&message).unwrap() {
+                            ControlPacket::Data(_, _, data) => received.extend(data),
+                            _ => break,
+                        }
+                    }
+                    received
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A 1mb upload read off the remote stream a buffer at a time, 1kb being the old server default
+fn read_buffer_size(c: &mut Criterion) {
+    let stream_id = StreamId::generate();
+    let body = vec![0xAB; 1024 * 1024];
+    let mut group = c.benchmark_group("read_buffer_size");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+
+    for buffer_size in &[1024, 4 * 1024, 16 * 1024, 64 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            buffer_size,
+            |b, buffer_size| {
+                b.iter(|| {
+                    let mut seq = tunnelto_lib::StreamSequence::default();
+                    let mut received = 0;
+                    for read in body.chunks(*buffer_size) {
+                        let packets =
+                            ControlPacket::chunk(&stream_id, &mut seq, read, MAX_FRAME_SIZE);
+                        for packet in packets {
+                            let message = packet.serialize();
+                            if let ControlPacket::Data(_, _, data) =
+                                ControlPacket::deserialize(black_box(&message)).unwrap()
+                            {
+                                received += data.len();
+                            }
+                        }
+                    }
+                    received
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    serialize,
+    deserialize,
+    stream_throughput,
+    read_buffer_size
+);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("invalid DataPacket, missing stream id")]
+    MissingStreamId,
+
+    #[error("invalid timed Ping, missing timestamp")]
+    MissingPingTimestamp,
+
+    #[error("invalid Ping, reconnect token is not utf8 or longer than {max} bytes")]
+    InvalidReconnectToken { max: usize },
+
+    #[error("invalid {0} packet, unexpected payload")]
+    UnexpectedPayload(&'static str),
+
+    #[error("invalid sequenced DataPacket, missing sequence number")]
+    MissingSequence,
+
+    #[error("invalid control byte in DataPacket: {0:#04x}")]
+    InvalidControlByte(u8),
+
+    #[error("failed to inflate compressed DataPacket: {0}")]
+    Inflate(#[from] std::io::Error),
+
+    #[error("compressed DataPacket inflates past the limit")]
+    InflatedTooLarge,
+
+    #[error("stream is missing data packets {expected} to {received}")]
+    StreamGap { expected: u32, received: u32 },
+
+    #[error("invalid internationalized sub-domain: {0}")]
+    InvalidSubDomain(String),
+
+    #[error("sub-domain labels must be non-empty")]
+    EmptySubDomainLabel,
+}
@@ -2,6 +2,9 @@ use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 
+mod error;
+pub use self::error::ProtocolError;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
 pub struct SecretKey(pub String);
@@ -34,14 +37,120 @@ pub enum ServerHello {
         sub_domain: String,
         hostname: String,
         client_id: ClientId,
+        /// unix timestamp (in seconds) when a time-limited reservation of the sub-domain expires
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reserved_until: Option<u64>,
+        /// the client asked for compression and the server will compress too,
+        /// see `ControlPacket::serialize_compressed`
+        #[serde(default)]
+        compression: bool,
+        /// the server's `PROTOCOL_VERSION`, servers from before this field are version 1
+        #[serde(default = "legacy_server_version")]
+        protocol_version: u8,
+        /// the public port of a raw tcp tunnel
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_port: Option<u16>,
+        /// the largest data payload the server wants per packet, see `ControlPacket::chunk`
+        #[serde(default = "legacy_max_frame_size")]
+        max_frame_size: usize,
+        /// the optional capabilities of this server, see `feature`,
+        /// None from servers from before this field
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        features: Option<Vec<String>>,
+        /// the server instance and region serving this tunnel, if it knows them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<String>,
     },
     SubDomainInUse,
     InvalidSubDomain,
     AuthFailed,
+    /// `AuthFailed` with why, for clients that set `ClientHello::auth_failure_reasons`
+    AuthFailedWithReason {
+        reason: String,
+    },
+    /// The client's `protocol_version` is outside of what the server speaks
+    IncompatibleVersion {
+        min: u8,
+        max: u8,
+    },
     Error(String),
 }
 
+/// The wire protocol version spoken by this build, sent in the `ClientHello`
+/// and `ServerHello::Success`
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Peers from this version on number their data packets, see `StreamReorder`
+pub const SEQUENCED_PROTOCOL_VERSION: u8 = 2;
+
+/// Sent with the control connection's upgrade request for `ClientHello::region`,
+/// the fleet's edge proxy routes it to an instance in that region when there is one
+pub const REGION_HEADER: &str = "fly-prefer-region";
+
+/// The WebSocket subprotocol of the control channel (`Sec-WebSocket-Protocol`)
+pub const CONTROL_SUBPROTOCOL: &str = "tunnelto.v1";
+
+/// Capabilities a server advertises in `ServerHello::Success`, clients ignore ones they don't know
+pub mod feature {
+    /// the server can compress data packets, see `ControlPacket::serialize_compressed`
+    pub const COMPRESSION: &str = "compression";
+    /// the server numbers data packets, see `SEQUENCED_PROTOCOL_VERSION`
+    pub const SEQUENCED: &str = "sequenced";
+    /// the server has ports for raw tcp tunnels, see `ClientHello::tcp`
+    pub const RAW_TCP: &str = "raw_tcp";
+    /// the server takes `ControlPacket::End` from the client, closing the end user's connection
+    pub const STREAM_END: &str = "stream_end";
+
+    /// What a server from before advertising is taken to support, for these
+    /// the other `ServerHello::Success` fields tell. Anything newer is never assumed.
+    pub const LEGACY: &[&str] = &[COMPRESSION, SEQUENCED, RAW_TCP];
+
+    /// The server advertised `feature`, or predates advertising (`None`) and `feature` is in `LEGACY`
+    pub fn supported(features: Option<&[String]>, feature: &str) -> bool {
+        match features {
+            Some(features) => features.iter().any(|f| f == feature),
+            None => LEGACY.contains(&feature),
+        }
+    }
+}
+
+fn legacy_server_version() -> u8 {
+    1
+}
+
+fn legacy_max_frame_size() -> usize {
+    MAX_FRAME_SIZE
+}
+
+/// The oldest client protocol the server still accepts,
+/// clients from before versioning send none and are version 0
+pub const MIN_PROTOCOL_VERSION: u8 = 0;
+
+/// Wording of the `ServerHello::Error` sent to clients below `MIN_PROTOCOL_VERSION`
+const OUTDATED_PREFIX: &str = "Protocol version ";
+const OUTDATED_UPGRADE: &str = " or newer is required, please upgrade: ";
+
 impl ServerHello {
+    /// Reject a client older than `min`. A plain `Error` since outdated clients may
+    /// predate `IncompatibleVersion`, newer ones read it back with `outdated_client`
+    pub fn outdated(min: u8, upgrade_url: &str) -> Self {
+        ServerHello::Error(format!(
+            "{}{}{}{}",
+            OUTDATED_PREFIX, min, OUTDATED_UPGRADE, upgrade_url
+        ))
+    }
+
+    /// The minimum version and upgrade url of an `outdated` rejection
+    pub fn outdated_client(error: &str) -> Option<(u8, String)> {
+        let rest = error.strip_prefix(OUTDATED_PREFIX)?;
+        let mut parts = rest.splitn(2, OUTDATED_UPGRADE);
+        let min = parts.next()?.parse().ok()?;
+        let upgrade_url = parts.next()?.to_string();
+        Some((min, upgrade_url))
+    }
+
     #[allow(unused)]
     pub fn random_domain() -> String {
         let mut rng = rand::thread_rng();
@@ -58,6 +167,77 @@ impl ServerHello {
     }
 }
 
+/// Convert a requested sub-domain into its ASCII form, punycode-encoding
+/// internationalized names, i.e: "café" => "xn--caf-dma".
+/// Nested sub-domains (i.e. "a.acme") are allowed, but no empty labels.
+/// The name is normalized first, see `normalize_sub_domain`.
+pub fn sub_domain_to_ascii(sub_domain: &str) -> Result<String, ProtocolError> {
+    let ascii = idna::domain_to_ascii(&normalize_sub_domain(sub_domain))
+        .map_err(|e| ProtocolError::InvalidSubDomain(format!("{:?}", e)))?;
+
+    if ascii.is_empty() || ascii.split('.').any(|label| label.is_empty()) {
+        return Err(ProtocolError::EmptySubDomainLabel);
+    }
+
+    Ok(ascii)
+}
+
+/// Collapse consecutive hyphens and trim leading/trailing ones in each label,
+/// i.e: "-my--app-" => "my-app". Punycode labels ("xn--...") are left alone.
+pub fn normalize_sub_domain(sub_domain: &str) -> String {
+    sub_domain
+        .split('.')
+        .map(|label| {
+            if label.to_lowercase().starts_with("xn--") {
+                return label.to_string();
+            }
+
+            let mut normalized = String::with_capacity(label.len());
+            for c in label.chars() {
+                if c == '-' && (normalized.is_empty() || normalized.ends_with('-')) {
+                    continue;
+                }
+                normalized.push(c);
+            }
+            normalized.trim_end_matches('-').to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Convert an ASCII (possibly punycode) sub-domain back into unicode for display
+pub fn sub_domain_to_unicode(sub_domain: &str) -> String {
+    idna::domain_to_unicode(sub_domain).0
+}
+
+/// Ask the control server which of the given sub-domains are available
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubDomainCheck {
+    pub sub_domains: Vec<String>,
+    pub key: Option<SecretKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubDomainCheckResult {
+    pub available: Vec<String>,
+}
+
+impl SubDomainCheck {
+    pub const MAX_SUB_DOMAINS: usize = 10;
+
+    /// The requested name followed by a few nearby variants
+    pub fn suggestions(base: &str) -> Vec<String> {
+        vec![
+            base.to_string(),
+            format!("{}-1", base),
+            format!("{}-2", base),
+            format!("{}-dev", base),
+            format!("{}-app", base),
+            ServerHello::prefixed_random_domain(base),
+        ]
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientHello {
     /// deprecated: just send some garbage
@@ -65,6 +245,27 @@ pub struct ClientHello {
     pub sub_domain: Option<String>,
     pub client_type: ClientType,
     pub reconnect_token: Option<ReconnectToken>,
+    /// disconnect a stale tunnel of our account holding the sub-domain, instead of failing as in-use
+    #[serde(default)]
+    pub takeover: bool,
+    /// see `PROTOCOL_VERSION`
+    #[serde(default)]
+    pub protocol_version: u8,
+    /// ask for compressed data packets, see `ControlPacket::encode`
+    #[serde(default)]
+    pub compression: bool,
+    /// tunnel raw tcp on a dedicated public port instead of http by host
+    #[serde(default)]
+    pub tcp: bool,
+    /// the public port for a raw tcp tunnel, any free one if not set
+    #[serde(default)]
+    pub requested_port: Option<u16>,
+    /// the client understands `ServerHello::AuthFailedWithReason`
+    #[serde(default)]
+    pub auth_failure_reasons: bool,
+    /// the region the client would like to be served from, see `REGION_HEADER`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
 }
 
 impl ClientHello {
@@ -74,6 +275,13 @@ impl ClientHello {
             client_type: typ,
             sub_domain,
             reconnect_token: None,
+            takeover: false,
+            protocol_version: PROTOCOL_VERSION,
+            compression: false,
+            tcp: false,
+            requested_port: None,
+            auth_failure_reasons: true,
+            region: None,
         }
     }
 
@@ -83,6 +291,13 @@ impl ClientHello {
             sub_domain: None,
             client_type: ClientType::Anonymous,
             reconnect_token: Some(reconnect_token),
+            takeover: false,
+            protocol_version: PROTOCOL_VERSION,
+            compression: false,
+            tcp: false,
+            requested_port: None,
+            auth_failure_reasons: true,
+            region: None,
         }
     }
 }
@@ -137,69 +352,836 @@ impl StreamId {
 #[derive(Debug, Clone)]
 pub enum ControlPacket {
     Init(StreamId),
-    Data(StreamId, Vec<u8>),
+    /// Stream bytes, with the stream's sequence number if the sender numbers them.
+    /// An empty payload is a no-op keep-alive, never an end of stream:
+    /// streams end with `End` (or the local side closing)
+    Data(StreamId, Option<u32>, Vec<u8>),
     Refused(StreamId),
+    /// The stream ended. Sent client -> server only if the server supports `feature::STREAM_END`
     End(StreamId),
-    Ping(Option<ReconnectToken>),
+    /// A ping, optionally carrying a reconnect token and a send timestamp the
+    /// receiver echoes back for measuring round trip time
+    Ping(Option<ReconnectToken>, Option<PingTimestamp>),
+    /// Marks the end of a logical (i.e. HTTP) message within a stream's data.
+    /// Only sent client -> server: older servers skip unknown packets.
+    EndOfMessage(StreamId),
+    /// A client's timed ping for the server to echo back as a `ProbeReply`,
+    /// so the client can measure round trip time too. Sent as a ping with its own
+    /// sentinel: older servers take it for a ping with a token, and ignore it.
+    Probe(PingTimestamp),
+    /// The server's echo of a `Probe`
+    ProbeReply(PingTimestamp),
 }
 
 pub const PING_INTERVAL: u64 = 30;
 
+/// Seconds both ends hold open streams while a dropped control connection reconnects
+pub const STREAM_RESUME_WINDOW: u64 = 15;
+
+/// The most stream bytes a single data packet carries unless the server advertises
+/// otherwise, larger payloads are split by `ControlPacket::chunk` so websocket messages stay bounded
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Data payloads smaller than this aren't worth compressing
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Reconnect tokens are a few hundred bytes, anything much longer is corrupt
+const MAX_RECONNECT_TOKEN_LEN: usize = 2048;
+
+/// A compressed data packet may not inflate past this, even if the peer asks it to
+const MAX_INFLATED_DATA: usize = 16 * 1024 * 1024;
+
+/// How packets are written for a peer, as negotiated in the handshake
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WireFormat {
+    /// deflate data payloads of at least `COMPRESSION_THRESHOLD` bytes
+    pub compression: bool,
+    /// keep the sequence numbers of data packets, see `SEQUENCED_PROTOCOL_VERSION`
+    pub sequenced: bool,
+}
+
+/// Numbers the data packets of one stream, wrapping around past `u32::MAX`
+#[derive(Debug, Default)]
+pub struct StreamSequence(u32);
+
+impl Iterator for StreamSequence {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let seq = self.0;
+        self.0 = self.0.wrapping_add(1);
+        Some(seq)
+    }
+}
+
+/// Puts the data packets of one stream back in order, dropping duplicates,
+/// i.e. ones re-sent after a control reconnect. Unsequenced data passes straight through.
+#[derive(Debug)]
+pub struct StreamReorder<T> {
+    next: u32,
+    pending: std::collections::BTreeMap<u32, T>,
+}
+
+impl<T> Default for StreamReorder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> StreamReorder<T> {
+    /// A packet this far ahead means the ones in between were lost
+    pub const WINDOW: u32 = 64;
+
+    pub fn new() -> Self {
+        StreamReorder {
+            next: 0,
+            pending: std::collections::BTreeMap::new(),
+        }
+    }
+
+    /// Some data is held back on an earlier packet
+    pub fn is_waiting(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The data that's now in order, empty while waiting on an earlier packet.
+    /// A gap wider than `WINDOW` can't be filled anymore, so the stream is
+    /// broken and must be aborted: skipping over it would corrupt the data.
+    pub fn push(&mut self, seq: Option<u32>, item: T) -> Result<Vec<T>, ProtocolError> {
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return Ok(vec![item]),
+        };
+
+        // sequence numbers wrap, anything in the half behind `next` was already delivered
+        let ahead = seq.wrapping_sub(self.next);
+        if (ahead as i32) < 0 {
+            return Ok(vec![]);
+        }
+
+        if ahead > Self::WINDOW {
+            return Err(ProtocolError::StreamGap {
+                expected: self.next,
+                received: seq,
+            });
+        }
+
+        self.pending.insert(seq, item);
+        let mut ready = vec![];
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.push(item);
+            self.next = self.next.wrapping_add(1);
+        }
+        Ok(ready)
+    }
+}
+
+/// Milliseconds since the unix epoch at which a ping was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingTimestamp(pub u64);
+
+impl PingTimestamp {
+    pub fn now() -> Self {
+        let millis = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        PingTimestamp(millis)
+    }
+
+    /// Round trip time of an echoed timestamp we sent
+    pub fn rtt(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(Self::now().0.saturating_sub(self.0))
+    }
+}
+
+/// Headers the tunnel must forward untouched: WebSocket upgrades (and their
+/// `permessage-deflate` negotiation) are end-to-end between the remote client
+/// and the local server, so any header rewriting must skip these.
+pub const PRESERVED_HEADERS: &[&str] = &[
+    "connection",
+    "upgrade",
+    "sec-websocket-key",
+    "sec-websocket-accept",
+    "sec-websocket-version",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+];
+
+/// Check if a header must be forwarded as-is, see `PRESERVED_HEADERS`
+pub fn is_preserved_header(name: &str) -> bool {
+    PRESERVED_HEADERS
+        .iter()
+        .any(|preserved| preserved.eq_ignore_ascii_case(name))
+}
+
+/// The protocol a stream speaks, sniffed from it's first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Http1,
+    Http2Preface,
+    WebSocketUpgrade,
+    Tls,
+    Unknown,
+}
+
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+impl StreamKind {
+    /// Classify the first bytes of a stream. A request head that isn't complete yet
+    /// is still `Http1` if its request line parses.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(HTTP2_PREFACE) {
+            return StreamKind::Http2Preface;
+        }
+
+        // a TLS handshake record: content type 22, protocol major version 3
+        if data.len() >= 3 && data[0] == 0x16 && data[1] == 0x03 {
+            return StreamKind::Tls;
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 100];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(data) {
+            Ok(httparse::Status::Complete(_)) => {
+                let is_upgrade = request.headers.iter().any(|h| {
+                    h.name.eq_ignore_ascii_case("upgrade")
+                        && std::str::from_utf8(h.value)
+                            .map_or(false, |v| v.trim().eq_ignore_ascii_case("websocket"))
+                });
+
+                if is_upgrade {
+                    StreamKind::WebSocketUpgrade
+                } else {
+                    StreamKind::Http1
+                }
+            }
+            Ok(httparse::Status::Partial) if request.version.is_some() => StreamKind::Http1,
+            _ => StreamKind::Unknown,
+        }
+    }
+}
+
 const EMPTY_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
 const TOKEN_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
+const TIMED_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02]);
+const PROBE_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03]);
+const PROBE_REPLY_STREAM: StreamId = StreamId([0xF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04]);
 
 impl ControlPacket {
+    /// Data packets for a stream's bytes, at most `max_frame_size` each and numbered by `seq`.
+    /// The receiver reassembles them simply by writing them out in order.
+    pub fn chunk(
+        stream_id: &StreamId,
+        seq: &mut StreamSequence,
+        data: &[u8],
+        max_frame_size: usize,
+    ) -> Vec<Self> {
+        data.chunks(max_frame_size.max(1))
+            .map(|chunk| ControlPacket::Data(stream_id.clone(), seq.next(), chunk.to_vec()))
+            .collect()
+    }
+
+    /// Serialize for a peer that negotiated nothing, see `encode`
     pub fn serialize(self) -> Vec<u8> {
+        self.encode(WireFormat::default())
+    }
+
+    /// Data packets are `[control][stream id: 8][seq: 4, if sequenced][payload]`,
+    /// with the control byte telling if it's sequenced and/or compressed.
+    /// `deserialize` reads any of them back into `Data`.
+    pub fn encode(self, format: WireFormat) -> Vec<u8> {
         match self {
             ControlPacket::Init(sid) => [vec![0x01], sid.0.to_vec()].concat(),
-            ControlPacket::Data(sid, data) => [vec![0x02], sid.0.to_vec(), data].concat(),
+            ControlPacket::Data(sid, seq, data) => {
+                let seq = seq.filter(|_| format.sequenced);
+                let (compressed, data) =
+                    if format.compression && data.len() >= COMPRESSION_THRESHOLD {
+                        match deflate(&data) {
+                            Ok(compressed) if compressed.len() < data.len() => (true, compressed),
+                            // already compressed bodies (i.e. images) don't shrink
+                            _ => (false, data),
+                        }
+                    } else {
+                        (false, data)
+                    };
+
+                let control = match (compressed, seq) {
+                    (false, None) => 0x02,
+                    (true, None) => 0x07,
+                    (false, Some(_)) => 0x08,
+                    (true, Some(_)) => 0x09,
+                };
+                let seq = seq.map_or(vec![], |seq| seq.to_be_bytes().to_vec());
+                [vec![control], sid.0.to_vec(), seq, data].concat()
+            }
             ControlPacket::Refused(sid) => [vec![0x03], sid.0.to_vec()].concat(),
             ControlPacket::End(sid) => [vec![0x04], sid.0.to_vec()].concat(),
-            ControlPacket::Ping(tok) => {
-                let data = tok.map_or(EMPTY_STREAM.0.to_vec(), |t| {
-                    vec![TOKEN_STREAM.0.to_vec(), t.0.into_bytes()].concat()
-                });
+            ControlPacket::Ping(tok, timestamp) => {
+                let data = match (tok, timestamp) {
+                    (None, None) => EMPTY_STREAM.0.to_vec(),
+                    (Some(t), None) => [TOKEN_STREAM.0.to_vec(), t.0.into_bytes()].concat(),
+                    (tok, Some(ts)) => [
+                        TIMED_STREAM.0.to_vec(),
+                        ts.0.to_be_bytes().to_vec(),
+                        tok.map_or(vec![], |t| t.0.into_bytes()),
+                    ]
+                    .concat(),
+                };
                 [vec![0x05], data].concat()
             }
+            ControlPacket::EndOfMessage(sid) => [vec![0x06], sid.0.to_vec()].concat(),
+            ControlPacket::Probe(ts) => [
+                vec![0x05],
+                PROBE_STREAM.0.to_vec(),
+                ts.0.to_be_bytes().to_vec(),
+            ]
+            .concat(),
+            ControlPacket::ProbeReply(ts) => [
+                vec![0x05],
+                PROBE_REPLY_STREAM.0.to_vec(),
+                ts.0.to_be_bytes().to_vec(),
+            ]
+            .concat(),
         }
     }
 
-    pub fn packet_type(&self) -> &str {
+    pub fn packet_type(&self) -> &'static str {
         match &self {
-            ControlPacket::Ping(_) => "PING",
+            ControlPacket::Ping(_, _) => "PING",
             ControlPacket::Init(_) => "INIT STREAM",
-            ControlPacket::Data(_, _) => "STREAM DATA",
+            ControlPacket::Data(_, _, _) => "STREAM DATA",
             ControlPacket::Refused(_) => "REFUSED",
             ControlPacket::End(_) => "END STREAM",
+            ControlPacket::EndOfMessage(_) => "END OF MESSAGE",
+            ControlPacket::Probe(_) => "PROBE",
+            ControlPacket::ProbeReply(_) => "PROBE REPLY",
         }
     }
 
-    pub fn deserialize(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn deserialize(data: &[u8]) -> Result<Self, ProtocolError> {
         if data.len() < 9 {
-            return Err("invalid DataPacket, missing stream id".into());
+            return Err(ProtocolError::MissingStreamId);
         }
 
         let mut stream_id = [0u8; 8];
         stream_id.clone_from_slice(&data[1..9]);
         let stream_id = StreamId(stream_id);
 
+        // packets that are only a stream id must end there
+        let no_payload = |packet: ControlPacket| {
+            if data.len() > 9 {
+                return Err(ProtocolError::UnexpectedPayload(packet.packet_type()));
+            }
+            Ok(packet)
+        };
+
         let packet = match data[0] {
-            0x01 => ControlPacket::Init(stream_id),
-            0x02 => ControlPacket::Data(stream_id, data[9..].to_vec()),
-            0x03 => ControlPacket::Refused(stream_id),
-            0x04 => ControlPacket::End(stream_id),
+            0x01 => no_payload(ControlPacket::Init(stream_id))?,
+            0x02 => ControlPacket::Data(stream_id, None, data[9..].to_vec()),
+            0x03 => no_payload(ControlPacket::Refused(stream_id))?,
+            0x04 => no_payload(ControlPacket::End(stream_id))?,
             0x05 => {
                 if stream_id == EMPTY_STREAM {
-                    ControlPacket::Ping(None)
+                    no_payload(ControlPacket::Ping(None, None))?
+                } else if stream_id == PROBE_STREAM {
+                    ControlPacket::Probe(read_ping_timestamp(data)?)
+                } else if stream_id == PROBE_REPLY_STREAM {
+                    ControlPacket::ProbeReply(read_ping_timestamp(data)?)
+                } else if stream_id == TIMED_STREAM {
+                    let timestamp = read_ping_timestamp(data)?;
+                    let token = if data.len() > 17 {
+                        Some(read_reconnect_token(&data[17..])?)
+                    } else {
+                        None
+                    };
+
+                    ControlPacket::Ping(token, Some(timestamp))
+                } else if stream_id == TOKEN_STREAM {
+                    ControlPacket::Ping(Some(read_reconnect_token(&data[9..])?), None)
+                } else {
+                    // a newer peer's kind of ping, it's still a ping
+                    ControlPacket::Ping(None, None)
+                }
+            }
+            0x06 => no_payload(ControlPacket::EndOfMessage(stream_id))?,
+            0x07 => ControlPacket::Data(stream_id, None, inflate(&data[9..])?),
+            0x08 | 0x09 => {
+                if data.len() < 13 {
+                    return Err(ProtocolError::MissingSequence);
+                }
+
+                let mut seq = [0u8; 4];
+                seq.clone_from_slice(&data[9..13]);
+                let seq = Some(u32::from_be_bytes(seq));
+                if data[0] == 0x09 {
+                    ControlPacket::Data(stream_id, seq, inflate(&data[13..])?)
                 } else {
-                    ControlPacket::Ping(Some(ReconnectToken(
-                        String::from_utf8_lossy(&data[9..]).to_string(),
-                    )))
+                    ControlPacket::Data(stream_id, seq, data[13..].to_vec())
                 }
             }
-            _ => return Err("invalid control byte in DataPacket".into()),
+            control => return Err(ProtocolError::InvalidControlByte(control)),
         };
 
         Ok(packet)
     }
 }
+
+/// The timestamp following the sentinel of a timed ping
+fn read_ping_timestamp(data: &[u8]) -> Result<PingTimestamp, ProtocolError> {
+    if data.len() < 17 {
+        return Err(ProtocolError::MissingPingTimestamp);
+    }
+
+    let mut timestamp = [0u8; 8];
+    timestamp.clone_from_slice(&data[9..17]);
+    Ok(PingTimestamp(u64::from_be_bytes(timestamp)))
+}
+
+fn read_reconnect_token(data: &[u8]) -> Result<ReconnectToken, ProtocolError> {
+    if data.len() > MAX_RECONNECT_TOKEN_LEN {
+        return Err(ProtocolError::InvalidReconnectToken {
+            max: MAX_RECONNECT_TOKEN_LEN,
+        });
+    }
+
+    let token = std::str::from_utf8(data).map_err(|_| ProtocolError::InvalidReconnectToken {
+        max: MAX_RECONNECT_TOKEN_LEN,
+    })?;
+    Ok(ReconnectToken(token.to_string()))
+}
+
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    use std::io::Read;
+
+    let mut inflated = Vec::new();
+    flate2::read::DeflateDecoder::new(data)
+        .take(MAX_INFLATED_DATA as u64 + 1)
+        .read_to_end(&mut inflated)?;
+
+    if inflated.len() > MAX_INFLATED_DATA {
+        return Err(ProtocolError::InflatedTooLarge);
+    }
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQUENCED: WireFormat = WireFormat {
+        compression: false,
+        sequenced: true,
+    };
+    const COMPRESSED: WireFormat = WireFormat {
+        compression: true,
+        sequenced: true,
+    };
+
+    fn round_trip(packet: ControlPacket, format: WireFormat) -> ControlPacket {
+        ControlPacket::deserialize(&packet.encode(format)).unwrap()
+    }
+
+    fn stream_packet(control: u8, stream_id: &StreamId, rest: &[u8]) -> Vec<u8> {
+        [vec![control], stream_id.0.to_vec(), rest.to_vec()].concat()
+    }
+
+    #[test]
+    fn stream_packets_round_trip() {
+        let sid = StreamId::generate();
+        assert!(
+            matches!(round_trip(ControlPacket::Init(sid.clone()), WireFormat::default()), ControlPacket::Init(s) if s == sid)
+        );
+        assert!(
+            matches!(round_trip(ControlPacket::Refused(sid.clone()), WireFormat::default()), ControlPacket::Refused(s) if s == sid)
+        );
+        assert!(
+            matches!(round_trip(ControlPacket::End(sid.clone()), WireFormat::default()), ControlPacket::End(s) if s == sid)
+        );
+        assert!(
+            matches!(round_trip(ControlPacket::EndOfMessage(sid.clone()), WireFormat::default()), ControlPacket::EndOfMessage(s) if s == sid)
+        );
+    }
+
+    #[test]
+    fn data_round_trips() {
+        let sid = StreamId::generate();
+        let small = b"hello".to_vec();
+        let large = vec![b'a'; 4 * COMPRESSION_THRESHOLD];
+
+        for data in [small, large].iter() {
+            match round_trip(
+                ControlPacket::Data(sid.clone(), Some(7), data.clone()),
+                WireFormat::default(),
+            ) {
+                ControlPacket::Data(s, None, d) => assert!(s == sid && &d == data),
+                other => panic!("unexpected {:?}", other),
+            }
+
+            for format in [SEQUENCED, COMPRESSED].iter() {
+                match round_trip(
+                    ControlPacket::Data(sid.clone(), Some(7), data.clone()),
+                    *format,
+                ) {
+                    ControlPacket::Data(s, Some(7), d) => assert!(s == sid && &d == data),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compression_only_when_it_helps() {
+        let sid = StreamId::generate();
+        let compressible = vec![b'a'; 4 * COMPRESSION_THRESHOLD];
+        let encoded =
+            ControlPacket::Data(sid.clone(), None, compressible.clone()).encode(COMPRESSED);
+        assert_eq!(encoded[0], 0x07);
+        assert!(encoded.len() < compressible.len());
+
+        let small = ControlPacket::Data(sid, None, b"hi".to_vec()).encode(COMPRESSED);
+        assert_eq!(small[0], 0x02);
+    }
+
+    #[test]
+    fn pings_round_trip() {
+        let token = ReconnectToken("token".to_string());
+        let ts = PingTimestamp(1234);
+
+        assert!(matches!(
+            round_trip(ControlPacket::Ping(None, None), WireFormat::default()),
+            ControlPacket::Ping(None, None)
+        ));
+        assert!(matches!(
+            round_trip(ControlPacket::Ping(Some(token.clone()), None), WireFormat::default()),
+            ControlPacket::Ping(Some(t), None) if t.0 == "token"
+        ));
+        assert!(matches!(
+            round_trip(ControlPacket::Ping(None, Some(ts)), WireFormat::default()),
+            ControlPacket::Ping(None, Some(t)) if t == ts
+        ));
+        assert!(matches!(
+            round_trip(ControlPacket::Ping(Some(token), Some(ts)), WireFormat::default()),
+            ControlPacket::Ping(Some(t), Some(s)) if t.0 == "token" && s == ts
+        ));
+        assert!(matches!(
+            round_trip(ControlPacket::Probe(ts), WireFormat::default()),
+            ControlPacket::Probe(t) if t == ts
+        ));
+        assert!(matches!(
+            round_trip(ControlPacket::ProbeReply(ts), WireFormat::default()),
+            ControlPacket::ProbeReply(t) if t == ts
+        ));
+    }
+
+    #[test]
+    fn ping_rtt() {
+        let sent = PingTimestamp(PingTimestamp::now().0 - 250);
+        let rtt = sent.rtt();
+        assert!(rtt >= std::time::Duration::from_millis(250), "{:?}", rtt);
+        assert!(rtt < std::time::Duration::from_secs(60), "{:?}", rtt);
+
+        // a timestamp from a clock ahead of ours isn't a negative rtt
+        assert_eq!(PingTimestamp(u64::MAX).rtt(), std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn unknown_ping_is_still_a_ping() {
+        let sid = StreamId([0xF, 0, 0, 0, 0, 0, 0, 0x7F]);
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x05, &sid, b"whatever")),
+            Ok(ControlPacket::Ping(None, None))
+        ));
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let sid = StreamId::generate();
+
+        assert!(matches!(
+            ControlPacket::deserialize(&[0x01, 0, 0]),
+            Err(ProtocolError::MissingStreamId)
+        ));
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x04, &sid, b"x")),
+            Err(ProtocolError::UnexpectedPayload("END STREAM"))
+        ));
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x08, &sid, &[0, 0])),
+            Err(ProtocolError::MissingSequence)
+        ));
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x05, &PROBE_STREAM, &[0, 0])),
+            Err(ProtocolError::MissingPingTimestamp)
+        ));
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(
+                0x05,
+                &TOKEN_STREAM,
+                &vec![b'a'; MAX_RECONNECT_TOKEN_LEN + 1]
+            )),
+            Err(ProtocolError::InvalidReconnectToken { .. })
+        ));
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x05, &TOKEN_STREAM, &[0xff, 0xfe])),
+            Err(ProtocolError::InvalidReconnectToken { .. })
+        ));
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x07, &sid, b"not deflate data")),
+            Err(ProtocolError::Inflate(_))
+        ));
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x42, &sid, &[])),
+            Err(ProtocolError::InvalidControlByte(0x42))
+        ));
+    }
+
+    #[test]
+    fn inflate_is_capped() {
+        let sid = StreamId::generate();
+        let bomb = deflate(&vec![0u8; MAX_INFLATED_DATA + 1]).unwrap();
+        assert!(matches!(
+            ControlPacket::deserialize(&stream_packet(0x07, &sid, &bomb)),
+            Err(ProtocolError::InflatedTooLarge)
+        ));
+    }
+
+    #[test]
+    fn sub_domain_errors() {
+        assert!(matches!(
+            sub_domain_to_ascii("a..b"),
+            Err(ProtocolError::EmptySubDomainLabel)
+        ));
+    }
+
+    #[test]
+    fn idn_sub_domains_round_trip() {
+        for (unicode, ascii) in &[
+            ("café", "xn--caf-dma"),
+            ("bücher.shop", "xn--bcher-kva.shop"),
+            ("plain", "plain"),
+        ] {
+            assert_eq!(&sub_domain_to_ascii(unicode).unwrap(), ascii);
+            assert_eq!(&sub_domain_to_unicode(ascii), unicode);
+        }
+
+        // already encoded names are left as they are
+        assert_eq!(sub_domain_to_ascii("xn--caf-dma").unwrap(), "xn--caf-dma");
+    }
+
+    #[test]
+    fn sub_domain_hyphens_are_normalized() {
+        assert_eq!(normalize_sub_domain("-my--app-"), "my-app");
+        assert_eq!(normalize_sub_domain("a---b.--c--"), "a-b.c");
+        assert_eq!(normalize_sub_domain("my-app"), "my-app");
+        assert_eq!(normalize_sub_domain("xn--caf-dma"), "xn--caf-dma");
+        assert_eq!(sub_domain_to_ascii("--café--").unwrap(), "xn--caf-dma");
+        assert!(matches!(
+            sub_domain_to_ascii("---"),
+            Err(ProtocolError::EmptySubDomainLabel)
+        ));
+    }
+
+    #[test]
+    fn chunk_splits_at_max_frame_size() {
+        let sid = StreamId::generate();
+        let mut seq = StreamSequence::default();
+        let data: Vec<u8> = (0..10u8).collect();
+
+        let packets = ControlPacket::chunk(&sid, &mut seq, &data, 4);
+        let chunks: Vec<(Option<u32>, Vec<u8>)> = packets
+            .into_iter()
+            .map(|packet| match packet {
+                ControlPacket::Data(s, seq, d) if s == sid => (seq, d),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (Some(0), vec![0, 1, 2, 3]),
+                (Some(1), vec![4, 5, 6, 7]),
+                (Some(2), vec![8, 9]),
+            ]
+        );
+
+        // numbering carries on across calls
+        match ControlPacket::chunk(&sid, &mut seq, b"x", 4).as_slice() {
+            [ControlPacket::Data(_, Some(3), d)] => assert_eq!(d, b"x"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunk_edge_cases() {
+        let sid = StreamId::generate();
+        let mut seq = StreamSequence::default();
+        assert!(ControlPacket::chunk(&sid, &mut seq, &[], 4).is_empty());
+        // a zero frame size can't loop forever
+        assert_eq!(ControlPacket::chunk(&sid, &mut seq, b"ab", 0).len(), 2);
+    }
+
+    #[test]
+    fn detect_stream_kind() {
+        assert_eq!(
+            StreamKind::detect(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            StreamKind::Http1
+        );
+        // the head isn't complete yet, but the request line parses
+        assert_eq!(
+            StreamKind::detect(b"POST /upload HTTP/1.1\r\nHost: a\r\n"),
+            StreamKind::Http1
+        );
+        assert_eq!(
+            StreamKind::detect(
+                b"GET /ws HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: WebSocket \r\n\r\n"
+            ),
+            StreamKind::WebSocketUpgrade
+        );
+        assert_eq!(
+            StreamKind::detect(b"GET / HTTP/1.1\r\nUpgrade: h2c\r\n\r\n"),
+            StreamKind::Http1
+        );
+        assert_eq!(
+            StreamKind::detect(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00"),
+            StreamKind::Http2Preface
+        );
+        assert_eq!(
+            StreamKind::detect(&[0x16, 0x03, 0x01, 0x02, 0x00]),
+            StreamKind::Tls
+        );
+        assert_eq!(StreamKind::detect(b"\x00\x01garbage"), StreamKind::Unknown);
+        assert_eq!(StreamKind::detect(b""), StreamKind::Unknown);
+    }
+
+    #[test]
+    fn reorder_in_order_and_unsequenced() {
+        let mut reorder = StreamReorder::new();
+        assert_eq!(reorder.push(Some(0), 'a').unwrap(), vec!['a']);
+        assert_eq!(reorder.push(Some(1), 'b').unwrap(), vec!['b']);
+        assert_eq!(reorder.push(None, 'x').unwrap(), vec!['x']);
+        assert!(!reorder.is_waiting());
+    }
+
+    #[test]
+    fn reorder_holds_back_until_the_gap_fills() {
+        let mut reorder = StreamReorder::new();
+        assert!(reorder.push(Some(2), 'c').unwrap().is_empty());
+        assert!(reorder.push(Some(1), 'b').unwrap().is_empty());
+        assert!(reorder.is_waiting());
+        assert_eq!(reorder.push(Some(0), 'a').unwrap(), vec!['a', 'b', 'c']);
+        assert!(!reorder.is_waiting());
+    }
+
+    #[test]
+    fn reorder_drops_duplicates() {
+        let mut reorder = StreamReorder::new();
+        assert_eq!(reorder.push(Some(0), 'a').unwrap(), vec!['a']);
+        assert!(reorder.push(Some(0), 'a').unwrap().is_empty());
+        assert!(reorder.push(Some(2), 'c').unwrap().is_empty());
+        assert!(reorder.push(Some(2), 'c').unwrap().is_empty());
+        assert_eq!(reorder.push(Some(1), 'b').unwrap(), vec!['b', 'c']);
+    }
+
+    #[test]
+    fn reorder_gap_past_window_is_an_error() {
+        let mut reorder = StreamReorder::new();
+        assert_eq!(reorder.push(Some(0), 'a').unwrap(), vec!['a']);
+
+        // at the edge of the window it still waits
+        let edge = StreamReorder::<char>::WINDOW + 1;
+        assert!(reorder.push(Some(edge), 'z').unwrap().is_empty());
+
+        assert!(matches!(
+            reorder.push(Some(edge + 1), 'y'),
+            Err(ProtocolError::StreamGap {
+                expected: 1,
+                received
+            }) if received == edge + 1
+        ));
+    }
+
+    #[test]
+    fn reorder_across_sequence_wraparound() {
+        let mut reorder = StreamReorder {
+            next: u32::MAX - 1,
+            pending: std::collections::BTreeMap::new(),
+        };
+        assert!(reorder.push(Some(0), 'c').unwrap().is_empty());
+        assert_eq!(reorder.push(Some(u32::MAX - 1), 'a').unwrap(), vec!['a']);
+        assert_eq!(reorder.push(Some(u32::MAX), 'b').unwrap(), vec!['b', 'c']);
+
+        // re-sent packets from before the wrap are still duplicates
+        assert!(reorder.push(Some(u32::MAX), 'b').unwrap().is_empty());
+        assert_eq!(reorder.push(Some(1), 'd').unwrap(), vec!['d']);
+    }
+
+    #[test]
+    fn outdated_server_hello_round_trips() {
+        let hello = ServerHello::outdated(3, "https://tunnelto.dev/#install");
+        let data = serde_json::to_vec(&hello).unwrap();
+        assert_eq!(
+            String::from_utf8(data.clone()).unwrap(),
+            r#"{"error":"Protocol version 3 or newer is required, please upgrade: https://tunnelto.dev/#install"}"#
+        );
+
+        // as the client reads it back
+        let error = match serde_json::from_slice::<ServerHello>(&data).unwrap() {
+            ServerHello::Error(error) => error,
+            other => panic!("expected an error, got {:?}", other),
+        };
+        assert_eq!(
+            ServerHello::outdated_client(&error),
+            Some((3, "https://tunnelto.dev/#install".to_string()))
+        );
+
+        assert_eq!(ServerHello::outdated_client("some other error"), None);
+        assert_eq!(
+            ServerHello::outdated_client(
+                "Protocol version x or newer is required, please upgrade: "
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn feature_supported() {
+        let advertised = vec![feature::COMPRESSION.to_string()];
+        assert!(feature::supported(
+            Some(advertised.as_slice()),
+            feature::COMPRESSION
+        ));
+        assert!(!feature::supported(
+            Some(advertised.as_slice()),
+            feature::RAW_TCP
+        ));
+
+        // an empty list supports nothing
+        assert!(!feature::supported(Some(&[]), feature::COMPRESSION));
+
+        // legacy servers
+        assert!(feature::supported(None, feature::SEQUENCED));
+        assert!(feature::supported(None, feature::RAW_TCP));
+        assert!(!feature::supported(None, feature::STREAM_END));
+    }
+
+    #[test]
+    fn legacy_server_hello_has_no_features() {
+        let hello: ServerHello = serde_json::from_str(
+            r#"{"success":{"sub_domain":"a","hostname":"a.tunnelto.dev","client_id":"c"}}"#,
+        )
+        .unwrap();
+        match hello {
+            ServerHello::Success { features, .. } => assert_eq!(features, None),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::tls::{CertResolver, CERTIFICATE};
+use crate::CONFIG;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, Order, OrderStatus,
+};
+use serde::Deserialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// How often we check whether the cert is due for renewal
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Without any cert to serve, failed issuances are retried after this, doubling up to `MAX_RETRY_DELAY`
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// Renew well before the 90 days let's encrypt certs are valid for
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+/// Time for the challenge TXT records to reach the authoritative name servers
+const DNS_PROPAGATION_DELAY: Duration = Duration::from_secs(30);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_POLLS: usize = 60;
+
+/// Obtaining a wildcard cert via acme DNS-01 challenges
+pub struct AcmeConfig {
+    pub directory_url: String,
+    pub contact_email: Option<String>,
+
+    /// Keeps the account and the issued cert across restarts, to stay clear of rate limits
+    pub cache_dir: PathBuf,
+
+    /// The only DNS provider for now
+    pub cloudflare: CloudflareConfig,
+}
+
+pub struct CloudflareConfig {
+    pub api_token: String,
+    pub zone_id: String,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("acme request failed")]
+    Acme(#[from] instant_acme::Error),
+
+    #[error("failed to create csr")]
+    Csr(#[from] rcgen::RcgenError),
+
+    #[error("failed to update challenge dns record")]
+    Dns(#[from] reqwest::Error),
+
+    #[error("dns provider refused the change")]
+    DnsRefused,
+
+    #[error("failed to access the acme cache")]
+    Cache(#[from] std::io::Error),
+
+    #[error("invalid cached acme account")]
+    InvalidAccount(#[from] serde_json::Error),
+
+    #[error("the order failed: {0}")]
+    Order(String),
+
+    #[error("invalid issued cert: {0}")]
+    InvalidCert(String),
+}
+
+/// Load the cached cert, then keep it issued and renewed in the background
+pub fn spawn_renewal() {
+    let acme = match CONFIG.acme.as_ref() {
+        Some(acme) => acme,
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        if let Err(error) = load_cached(acme, &CERTIFICATE) {
+            tracing::warn!(?error, "no usable cached cert, issuing a new one");
+        }
+
+        let mut failures = 0;
+        loop {
+            if needs_renewal(acme, &CERTIFICATE) {
+                match issue(acme).await {
+                    Ok(()) => {
+                        tracing::info!(host = %CONFIG.tunnel_host, "issued tls cert");
+                        failures = 0;
+                    }
+                    Err(error) => {
+                        tracing::error!(?error, "failed to issue tls cert, will retry");
+                        failures += 1;
+                    }
+                }
+            }
+
+            // every tls handshake fails until there's a cert, don't wait long on the next try
+            let delay = if failures > 0 && !CERTIFICATE.is_loaded() {
+                retry_delay(failures)
+            } else {
+                CHECK_INTERVAL
+            };
+            tokio::time::sleep(delay).await;
+        }
+    });
+}
+
+/// The backoff after `failures` failed issuances in a row
+fn retry_delay(failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1).min(16));
+    MIN_RETRY_DELAY
+        .checked_mul(factor)
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+fn cert_path(acme: &AcmeConfig) -> PathBuf {
+    acme.cache_dir.join("cert.pem")
+}
+
+fn key_path(acme: &AcmeConfig) -> PathBuf {
+    acme.cache_dir.join("key.pem")
+}
+
+fn load_cached(acme: &AcmeConfig, certificate: &CertResolver) -> Result<(), Error> {
+    let cert_pem = std::fs::read(cert_path(acme))?;
+    let key_pem = std::fs::read(key_path(acme))?;
+    certificate
+        .set(&cert_pem, &key_pem)
+        .map_err(Error::InvalidCert)
+}
+
+/// Due when nothing is served (i.e. the cached cert was missing or corrupt), or it's old
+fn needs_renewal(acme: &AcmeConfig, certificate: &CertResolver) -> bool {
+    if !certificate.is_loaded() {
+        return true;
+    }
+
+    let issued_at = match std::fs::metadata(cert_path(acme)).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return true,
+    };
+
+    SystemTime::now()
+        .duration_since(issued_at)
+        .map(|age| age >= RENEW_AFTER)
+        .unwrap_or(false)
+}
+
+async fn account(acme: &AcmeConfig) -> Result<Account, Error> {
+    let path = acme.cache_dir.join("account.json");
+    if let Ok(cached) = std::fs::read(&path) {
+        let credentials: AccountCredentials = serde_json::from_slice(&cached)?;
+        return Ok(Account::from_credentials(credentials)?);
+    }
+
+    let contact = acme
+        .contact_email
+        .iter()
+        .map(|email| format!("mailto:{}", email))
+        .collect::<Vec<_>>();
+    let contact = contact.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let account = Account::create(
+        &NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        &acme.directory_url,
+        None,
+    )
+    .await?;
+
+    std::fs::create_dir_all(&acme.cache_dir)?;
+    write_private(&path, &serde_json::to_vec(&account.credentials())?)?;
+    Ok(account)
+}
+
+/// Order a cert for `*.<tunnel_host>` and `<tunnel_host>`, and serve it
+async fn issue(acme: &AcmeConfig) -> Result<(), Error> {
+    let names = vec![
+        format!("*.{}", CONFIG.tunnel_host),
+        CONFIG.tunnel_host.clone(),
+    ];
+    let identifiers = names
+        .iter()
+        .map(|name| Identifier::Dns(name.clone()))
+        .collect::<Vec<_>>();
+
+    let account = account(acme).await?;
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &identifiers,
+        })
+        .await?;
+
+    let mut records = vec![];
+    let result = complete_challenges(acme, &mut order, &mut records).await;
+
+    // the challenge records are only needed until the order is validated
+    for record_id in records {
+        if let Err(error) = delete_txt_record(&acme.cloudflare, &record_id).await {
+            tracing::warn!(?error, %record_id, "failed to delete challenge record");
+        }
+    }
+    result?;
+
+    let mut params = rcgen::CertificateParams::new(names);
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    let cert = rcgen::Certificate::from_params(params)?;
+    order.finalize(&cert.serialize_request_der()?).await?;
+
+    let mut chain = None;
+    for _ in 0..MAX_POLLS {
+        if let Some(pem) = order.certificate().await? {
+            chain = Some(pem);
+            break;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    let cert_pem = chain.ok_or(Error::Order("timed out waiting for the cert".into()))?;
+    let key_pem = cert.serialize_private_key_pem();
+
+    CERTIFICATE
+        .set(cert_pem.as_bytes(), key_pem.as_bytes())
+        .map_err(Error::InvalidCert)?;
+
+    std::fs::create_dir_all(&acme.cache_dir)?;
+    write_private(&key_path(acme), key_pem.as_bytes())?;
+    std::fs::write(cert_path(acme), cert_pem)?;
+    Ok(())
+}
+
+/// Write a file only we may read, as it holds a private key
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+
+        // the mode only applies to new files, older caches may be readable by others
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+
+    options.open(path)?.write_all(data)
+}
+
+/// Publish a TXT record per authorization and wait for the order to be ready.
+/// The created records are pushed to `records` even on failure, for cleanup.
+async fn complete_challenges(
+    acme: &AcmeConfig,
+    order: &mut Order,
+    records: &mut Vec<String>,
+) -> Result<(), Error> {
+    let mut ready = vec![];
+    for authorization in order.authorizations().await? {
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => return Err(Error::Order(format!("authorization is {:?}", status))),
+        }
+
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.r#type == ChallengeType::Dns01)
+            .ok_or(Error::Order("no dns-01 challenge offered".into()))?;
+
+        let Identifier::Dns(domain) = &authorization.identifier;
+        let value = order.key_authorization(challenge).dns_value();
+        let name = format!("_acme-challenge.{}", domain);
+        records.push(create_txt_record(&acme.cloudflare, &name, &value).await?);
+        ready.push(challenge.url.clone());
+    }
+
+    tokio::time::sleep(DNS_PROPAGATION_DELAY).await;
+    for url in ready {
+        order.set_challenge_ready(&url).await?;
+    }
+
+    for _ in 0..MAX_POLLS {
+        match order.refresh().await?.status {
+            OrderStatus::Ready => return Ok(()),
+            OrderStatus::Pending | OrderStatus::Processing => {}
+            status => return Err(Error::Order(format!("order is {:?}", status))),
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    Err(Error::Order("timed out waiting for validation".into()))
+}
+
+const CLOUDFLARE_API: &str = "https://api.cloudflare.com/client/v4";
+
+#[derive(Deserialize)]
+struct CloudflareResponse {
+    success: bool,
+    result: Option<CloudflareRecord>,
+}
+
+#[derive(Deserialize)]
+struct CloudflareRecord {
+    id: String,
+}
+
+async fn create_txt_record(
+    cloudflare: &CloudflareConfig,
+    name: &str,
+    content: &str,
+) -> Result<String, Error> {
+    let response: CloudflareResponse = reqwest::Client::new()
+        .post(format!(
+            "{}/zones/{}/dns_records",
+            CLOUDFLARE_API, cloudflare.zone_id
+        ))
+        .bearer_auth(&cloudflare.api_token)
+        .json(&serde_json::json!({
+            "type": "TXT",
+            "name": name,
+            "content": content,
+            "ttl": 120,
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    match response.result {
+        Some(record) if response.success => Ok(record.id),
+        _ => Err(Error::DnsRefused),
+    }
+}
+
+async fn delete_txt_record(cloudflare: &CloudflareConfig, record_id: &str) -> Result<(), Error> {
+    reqwest::Client::new()
+        .delete(format!(
+            "{}/zones/{}/dns_records/{}",
+            CLOUDFLARE_API, cloudflare.zone_id, record_id
+        ))
+        .bearer_auth(&cloudflare.api_token)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_backs_off_to_the_max() {
+        assert_eq!(retry_delay(1), MIN_RETRY_DELAY);
+        assert_eq!(retry_delay(2), MIN_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(3), MIN_RETRY_DELAY * 4);
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    fn cache_config(cache_dir: PathBuf) -> AcmeConfig {
+        AcmeConfig {
+            directory_url: instant_acme::LetsEncrypt::Staging.url().to_string(),
+            contact_email: None,
+            cache_dir,
+            cloudflare: CloudflareConfig {
+                api_token: "token".to_string(),
+                zone_id: "zone".to_string(),
+            },
+        }
+    }
+
+    fn temp_cache() -> AcmeConfig {
+        let dir = std::env::temp_dir().join(format!("tunnelto-acme-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        cache_config(dir)
+    }
+
+    fn cache_cert(acme: &AcmeConfig) {
+        let cert = rcgen::generate_simple_self_signed(vec!["*.tunnel.test".to_string()]).unwrap();
+        std::fs::write(cert_path(acme), cert.serialize_pem().unwrap()).unwrap();
+        write_private(&key_path(acme), cert.serialize_private_key_pem().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn cached_cert_is_served() {
+        let acme = temp_cache();
+        cache_cert(&acme);
+
+        let certificate = CertResolver::default();
+        assert!(needs_renewal(&acme, &certificate));
+        load_cached(&acme, &certificate).unwrap();
+        assert!(certificate.is_loaded());
+        assert!(!needs_renewal(&acme, &certificate));
+
+        std::fs::remove_dir_all(&acme.cache_dir).unwrap();
+    }
+
+    #[test]
+    fn missing_or_corrupt_cache_is_issued_again() {
+        let acme = temp_cache();
+        let certificate = CertResolver::default();
+
+        assert!(matches!(
+            load_cached(&acme, &certificate),
+            Err(Error::Cache(_))
+        ));
+
+        std::fs::write(cert_path(&acme), b"corrupt").unwrap();
+        std::fs::write(key_path(&acme), b"corrupt").unwrap();
+        assert!(matches!(
+            load_cached(&acme, &certificate),
+            Err(Error::InvalidCert(_))
+        ));
+        assert!(needs_renewal(&acme, &certificate));
+
+        std::fs::remove_dir_all(&acme.cache_dir).unwrap();
+    }
+
+    #[test]
+    fn old_cert_is_renewed() {
+        let acme = temp_cache();
+        cache_cert(&acme);
+        let certificate = CertResolver::default();
+        load_cached(&acme, &certificate).unwrap();
+
+        let issued_at = SystemTime::now() - RENEW_AFTER;
+        std::fs::File::options()
+            .write(true)
+            .open(cert_path(&acme))
+            .unwrap()
+            .set_modified(issued_at)
+            .unwrap();
+        assert!(needs_renewal(&acme, &certificate));
+
+        std::fs::remove_dir_all(&acme.cache_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("tunnelto-acme-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let new_key = dir.join("key.pem");
+        write_private(&new_key, b"new").unwrap();
+        assert_eq!(mode(&new_key), 0o600);
+        assert_eq!(std::fs::read(&new_key).unwrap(), b"new");
+
+        // a key cached before is tightened too
+        let old_key = dir.join("old.pem");
+        std::fs::write(&old_key, b"old key that is longer").unwrap();
+        std::fs::set_permissions(&old_key, std::fs::Permissions::from_mode(0o644)).unwrap();
+        write_private(&old_key, b"renewed").unwrap();
+        assert_eq!(mode(&old_key), 0o600);
+        assert_eq!(std::fs::read(&old_key).unwrap(), b"renewed");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,17 +2,31 @@
 pub struct ActiveStream {
     pub id: StreamId,
     pub client: ConnectedClient,
-    pub tx: UnboundedSender<StreamMessage>,
+    pub tx: Sender<StreamMessage>,
+    pub state: Arc<StreamState>,
+}
+
+/// Shared between both directions of a remote stream
+#[derive(Debug, Default)]
+pub struct StreamState {
+    /// any response bytes were written to the end user
+    pub response_started: AtomicBool,
+    /// the id the request was re-issued under after a control reconnect
+    pub retry_id: Mutex<Option<StreamId>>,
+    /// packets for the client were dropped with its control connection,
+    /// so the stream has a gap and can't be resumed
+    pub packets_lost: AtomicBool,
 }
 
 impl ActiveStream {
-    pub fn new(client: ConnectedClient) -> (Self, UnboundedReceiver<StreamMessage>) {
-        let (tx, rx) = unbounded();
+    pub fn new(client: ConnectedClient) -> (Self, Receiver<StreamMessage>) {
+        let (tx, rx) = channel(CONFIG.channel_capacity);
         (
             ActiveStream {
                 id: StreamId::generate(),
                 client,
                 tx,
+                state: Arc::new(StreamState::default()),
             },
             rx,
         )
@@ -22,9 +36,15 @@ impl ActiveStream {
 pub type ActiveStreams = Arc<DashMap<StreamId, ActiveStream>>;
 
 use super::*;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
-    Data(Vec<u8>),
+    /// data from the client, with its sequence number if the client numbers them
+    Data(Option<u32>, Vec<u8>),
     TunnelRefused,
     NoClientTunnel,
+    /// the client ended the stream, i.e. after answering it without the local service
+    End,
 }
@@ -0,0 +1,195 @@
+use crate::auth::AuthService;
+use crate::{AUTH_DB_SERVICE, CONFIG};
+use serde::Deserialize;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+struct CreateKey {
+    /// add a key to this account, or create a new one
+    #[serde(default)]
+    account_id: Option<Uuid>,
+    /// mark the account as subscribed, so it may reserve and use sub-domains
+    #[serde(default)]
+    good_standing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReserveDomain {
+    account_id: Uuid,
+    sub_domain: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CustomHostname {
+    /// i.e: dash.theircompany.com, CNAME'd to this instance
+    hostname: String,
+    sub_domain: String,
+}
+
+/// `POST /admin/keys`, `POST /admin/domains` and `POST /admin/hostnames`
+/// to provision accounts of a private instance.
+/// They require `Authorization: Bearer <ADMIN_TOKEN>` and don't exist without `ADMIN_TOKEN`.
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let keys = warp::post()
+        .and(warp::path!("admin" / "keys"))
+        .and(authorized())
+        .and(warp::body::json())
+        .and_then(create_key);
+
+    let domains = warp::post()
+        .and(warp::path!("admin" / "domains"))
+        .and(authorized())
+        .and(warp::body::json())
+        .and_then(reserve_domain);
+
+    let hostnames = warp::post()
+        .and(warp::path!("admin" / "hostnames"))
+        .and(authorized())
+        .and(warp::body::json())
+        .and_then(map_hostname);
+
+    keys.or(domains).or(hostnames)
+}
+
+fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|authorization: Option<String>| async move {
+            let token = match &CONFIG.admin_token {
+                Some(token) => token,
+                None => return Err(warp::reject::not_found()),
+            };
+
+            match authorization
+                .as_deref()
+                .and_then(|a| a.strip_prefix("Bearer "))
+            {
+                Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(()),
+                _ => {
+                    tracing::warn!("unauthorized admin request");
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn create_key(request: CreateKey) -> Result<impl Reply, Rejection> {
+    match AUTH_DB_SERVICE
+        .create_auth_key(request.account_id, request.good_standing)
+        .await
+    {
+        Ok((account_id, key)) => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "account_id": account_id,
+                "key": key,
+            })),
+            StatusCode::CREATED,
+        )),
+        Err(error) => {
+            tracing::error!(?error, "failed to create auth key");
+            Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to create auth key",
+            ))
+        }
+    }
+}
+
+async fn reserve_domain(request: ReserveDomain) -> Result<impl Reply, Rejection> {
+    // stored the way clients' requested sub-domains are looked up,
+    // patterns (`prefix-*` or `*.namespace`) by their name part
+    let requested = request.sub_domain.to_lowercase();
+    let (name, pattern) = if let Some(prefix) = requested.strip_suffix("-*") {
+        (prefix, "{}-*")
+    } else if let Some(namespace) = requested.strip_prefix("*.") {
+        (namespace, "*.{}")
+    } else {
+        (requested.as_str(), "{}")
+    };
+    let sub_domain = match tunnelto_lib::sub_domain_to_ascii(name) {
+        Ok(name) => pattern.replace("{}", &name),
+        Err(error) => {
+            return Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid sub-domain: {}", error),
+            ))
+        }
+    };
+
+    match AUTH_DB_SERVICE
+        .reserve_sub_domain(request.account_id, &sub_domain)
+        .await
+    {
+        Ok(true) => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "account_id": request.account_id,
+                "sub_domain": sub_domain,
+            })),
+            StatusCode::CREATED,
+        )),
+        Ok(false) => Ok(error_reply(
+            StatusCode::CONFLICT,
+            "sub-domain is reserved by another account",
+        )),
+        Err(error) => {
+            tracing::error!(?error, "failed to reserve sub-domain");
+            Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to reserve sub-domain",
+            ))
+        }
+    }
+}
+
+async fn map_hostname(request: CustomHostname) -> Result<impl Reply, Rejection> {
+    let hostname = match crate::remote::parse_host(request.hostname.trim_end_matches('.')) {
+        Some(hostname) => hostname.to_lowercase(),
+        None => return Ok(error_reply(StatusCode::BAD_REQUEST, "invalid hostname")),
+    };
+    let sub_domain = match tunnelto_lib::sub_domain_to_ascii(&request.sub_domain.to_lowercase()) {
+        Ok(sub_domain) => sub_domain,
+        Err(error) => {
+            return Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                &format!("invalid sub-domain: {}", error),
+            ))
+        }
+    };
+
+    match AUTH_DB_SERVICE
+        .set_custom_hostname(&hostname, &sub_domain)
+        .await
+    {
+        Ok(()) => {
+            crate::custom_hosts::invalidate(&hostname);
+            tracing::info!(%hostname, %sub_domain, "mapped custom hostname");
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "hostname": hostname,
+                    "sub_domain": sub_domain,
+                })),
+                StatusCode::CREATED,
+            ))
+        }
+        Err(error) => {
+            tracing::error!(?error, "failed to map custom hostname");
+            Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to map custom hostname",
+            ))
+        }
+    }
+}
+
+fn error_reply(status: StatusCode, error: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": error })),
+        status,
+    )
+}
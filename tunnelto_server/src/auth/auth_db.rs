@@ -1,5 +1,8 @@
 use rusoto_core::{Client, HttpClient, Region};
-use rusoto_dynamodb::{AttributeValue, DynamoDb, DynamoDbClient, GetItemError, GetItemInput};
+use rusoto_dynamodb::{
+    AttributeValue, DynamoDb, DynamoDbClient, GetItemError, GetItemInput, PutItemError,
+    PutItemInput, UpdateItemError, UpdateItemInput,
+};
 
 use super::AuthResult;
 use crate::auth::AuthService;
@@ -9,6 +12,7 @@ use sha2::Digest;
 use std::collections::HashMap;
 use std::str::FromStr;
 use thiserror::Error;
+use tunnelto_lib::ClientId;
 use uuid::Uuid;
 
 pub struct AuthDbService {
@@ -31,6 +35,7 @@ mod domain_db {
     pub const TABLE_NAME: &'static str = "tunnelto_domains";
     pub const PRIMARY_KEY: &'static str = "subdomain";
     pub const ACCOUNT_ID: &'static str = "account_id";
+    pub const EXPIRES_AT: &'static str = "expires_at";
 }
 
 mod key_db {
@@ -43,6 +48,25 @@ mod record_db {
     pub const TABLE_NAME: &'static str = "tunnelto_record";
     pub const PRIMARY_KEY: &'static str = "account_id";
     pub const SUBSCRIPTION_ID: &'static str = "subscription_id";
+    pub const SUB_DOMAIN_PREFIX: &'static str = "subdomain_prefix";
+    pub const NOT_FOUND_PAGE: &'static str = "not_found_page";
+    pub const MAX_TUNNELS: &'static str = "max_tunnels";
+}
+
+/// Simultaneous tunnels per account, unless its record sets `max_tunnels`
+const FREE_TUNNEL_LIMIT: usize = 1;
+const PRO_TUNNEL_LIMIT: usize = 5;
+
+mod custom_host_db {
+    pub const TABLE_NAME: &'static str = "tunnelto_custom_hosts";
+    pub const PRIMARY_KEY: &'static str = "hostname";
+    pub const SUB_DOMAIN: &'static str = "subdomain";
+}
+
+mod usage_db {
+    pub const TABLE_NAME: &'static str = "tunnelto_usage";
+    pub const PRIMARY_KEY: &'static str = "client_id";
+    pub const BYTES: &'static str = "bytes";
 }
 
 fn key_id(auth_key: &str) -> String {
@@ -55,6 +79,12 @@ pub enum Error {
     #[error("failed to get domain item")]
     AuthDbGetItem(#[from] rusoto_core::RusotoError<GetItemError>),
 
+    #[error("failed to update item")]
+    UsageDbUpdateItem(#[from] rusoto_core::RusotoError<UpdateItemError>),
+
+    #[error("failed to put item")]
+    AuthDbPutItem(#[from] rusoto_core::RusotoError<PutItemError>),
+
     #[error("The authentication key is invalid")]
     AccountNotFound,
 
@@ -82,7 +112,21 @@ impl AuthService for AuthDbService {
 
         tracing::info!(account=%authenticated_account_id.to_string(), requested_subdomain=%subdomain, is_pro=%is_pro_account, "authenticated client");
 
-        if let Some(account_id) = self.get_account_id_for_subdomain(subdomain).await? {
+        if crate::CONFIG.enforce_sub_domain_prefix {
+            if let Some(prefix) = self.get_sub_domain_prefix(authenticated_account_id).await? {
+                if !subdomain.starts_with(&prefix) {
+                    tracing::info!(account=%authenticated_account_id.to_string(), %prefix, "outside of account prefix");
+                    return Ok(AuthResult::Forbidden);
+                }
+            }
+        }
+
+        let reserved_by = match self.get_account_id_for_subdomain(subdomain).await? {
+            Some(account_id) => Some(account_id),
+            None => self.get_account_id_for_namespace(subdomain).await?,
+        };
+
+        if let Some(account_id) = reserved_by {
             // check you reserved it
             if authenticated_account_id != account_id {
                 tracing::info!(account=%authenticated_account_id.to_string(), "reserved by other");
@@ -104,6 +148,237 @@ impl AuthService for AuthDbService {
             Ok(AuthResult::PaymentRequired)
         }
     }
+
+    async fn create_auth_key(
+        &self,
+        account_id: Option<Uuid>,
+        good_standing: bool,
+    ) -> Result<(Uuid, String), Error> {
+        let account_id = account_id.unwrap_or_else(Uuid::new_v4);
+        let auth_key = super::generate_auth_key();
+
+        let mut item = HashMap::new();
+        item.insert(
+            key_db::PRIMARY_KEY.to_string(),
+            AttributeValue {
+                s: Some(key_id(&auth_key)),
+                ..Default::default()
+            },
+        );
+        item.insert(
+            key_db::ACCOUNT_ID.to_string(),
+            AttributeValue {
+                s: Some(account_id.to_string()),
+                ..Default::default()
+            },
+        );
+        self.client
+            .put_item(PutItemInput {
+                table_name: key_db::TABLE_NAME.to_string(),
+                item,
+                ..Default::default()
+            })
+            .await?;
+
+        // a subscription is what makes an account pro, keep any real one
+        if good_standing {
+            let mut input = UpdateItemInput {
+                table_name: record_db::TABLE_NAME.to_string(),
+                update_expression: Some(format!(
+                    "SET {0} = if_not_exists({0}, :subscription)",
+                    record_db::SUBSCRIPTION_ID
+                )),
+                ..Default::default()
+            };
+            input.key = {
+                let mut item = HashMap::new();
+                item.insert(
+                    record_db::PRIMARY_KEY.to_string(),
+                    AttributeValue {
+                        s: Some(account_id.to_string()),
+                        ..Default::default()
+                    },
+                );
+                item
+            };
+            input.expression_attribute_values = {
+                let mut values = HashMap::new();
+                values.insert(
+                    ":subscription".to_string(),
+                    AttributeValue {
+                        s: Some("admin".to_string()),
+                        ..Default::default()
+                    },
+                );
+                Some(values)
+            };
+            self.client.update_item(input).await?;
+        }
+
+        tracing::info!(account=%account_id.to_string(), %good_standing, "created auth key");
+        Ok((account_id, auth_key))
+    }
+
+    async fn reserve_sub_domain(&self, account_id: Uuid, subdomain: &str) -> Result<bool, Error> {
+        let mut item = HashMap::new();
+        item.insert(
+            domain_db::PRIMARY_KEY.to_string(),
+            AttributeValue {
+                s: Some(subdomain.to_string()),
+                ..Default::default()
+            },
+        );
+        item.insert(
+            domain_db::ACCOUNT_ID.to_string(),
+            AttributeValue {
+                s: Some(account_id.to_string()),
+                ..Default::default()
+            },
+        );
+
+        let mut input = PutItemInput {
+            table_name: domain_db::TABLE_NAME.to_string(),
+            item,
+            condition_expression: Some(format!(
+                "attribute_not_exists({}) OR {} = :account",
+                domain_db::PRIMARY_KEY,
+                domain_db::ACCOUNT_ID
+            )),
+            ..Default::default()
+        };
+        input.expression_attribute_values = {
+            let mut values = HashMap::new();
+            values.insert(
+                ":account".to_string(),
+                AttributeValue {
+                    s: Some(account_id.to_string()),
+                    ..Default::default()
+                },
+            );
+            Some(values)
+        };
+
+        match self.client.put_item(input).await {
+            Ok(_) => {
+                tracing::info!(account=%account_id.to_string(), %subdomain, "reserved sub-domain");
+                Ok(true)
+            }
+            Err(rusoto_core::RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => {
+                Ok(false)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// When the reservation of this sub-domain expires, for time-limited reservations
+    async fn get_reservation_expiry(&self, subdomain: &str) -> Result<Option<u64>, Error> {
+        let mut input = GetItemInput {
+            table_name: domain_db::TABLE_NAME.to_string(),
+            ..Default::default()
+        };
+        input.key = {
+            let mut item = HashMap::new();
+            item.insert(
+                domain_db::PRIMARY_KEY.to_string(),
+                AttributeValue {
+                    s: Some(subdomain.to_string()),
+                    ..Default::default()
+                },
+            );
+            item
+        };
+
+        let result = self.client.get_item(input).await?;
+        let expires_at = result
+            .item
+            .unwrap_or(HashMap::new())
+            .get(domain_db::EXPIRES_AT)
+            .cloned()
+            .unwrap_or(AttributeValue::default())
+            .n
+            .and_then(|n| n.parse::<u64>().ok());
+
+        Ok(expires_at)
+    }
+
+    /// The account of an auth key, and how many tunnels it may have open at once
+    async fn get_tunnel_limit(&self, auth_key: &String) -> Result<(Uuid, usize), Error> {
+        let account_id = self.get_account_id_for_auth_key(auth_key).await?;
+
+        let mut input = GetItemInput {
+            table_name: record_db::TABLE_NAME.to_string(),
+            ..Default::default()
+        };
+        input.key = {
+            let mut item = HashMap::new();
+            item.insert(
+                record_db::PRIMARY_KEY.to_string(),
+                AttributeValue {
+                    s: Some(account_id.to_string()),
+                    ..Default::default()
+                },
+            );
+            item
+        };
+
+        let result = self.client.get_item(input).await?;
+        let result = result.item.unwrap_or(HashMap::new());
+
+        let max_tunnels = result
+            .get(record_db::MAX_TUNNELS)
+            .cloned()
+            .unwrap_or(AttributeValue::default())
+            .n
+            .and_then(|n| n.parse::<usize>().ok());
+        let is_pro_account = result
+            .get(record_db::SUBSCRIPTION_ID)
+            .map_or(false, |s| s.s.is_some());
+
+        let limit = match max_tunnels {
+            Some(max_tunnels) => max_tunnels,
+            None if is_pro_account => PRO_TUNNEL_LIMIT,
+            None => FREE_TUNNEL_LIMIT,
+        };
+        Ok((account_id, limit))
+    }
+
+    /// Atomically add `bytes` to the running usage total of a client
+    async fn add_usage(&self, client_id: &ClientId, bytes: u64) -> Result<(), Error> {
+        let mut input = UpdateItemInput {
+            table_name: usage_db::TABLE_NAME.to_string(),
+            update_expression: Some(format!("ADD {} :bytes", usage_db::BYTES)),
+            ..Default::default()
+        };
+        input.key = {
+            let mut item = HashMap::new();
+            item.insert(
+                usage_db::PRIMARY_KEY.to_string(),
+                AttributeValue {
+                    s: Some(client_id.to_string()),
+                    ..Default::default()
+                },
+            );
+            item
+        };
+        input.expression_attribute_values = {
+            let mut values = HashMap::new();
+            values.insert(
+                ":bytes".to_string(),
+                AttributeValue {
+                    n: Some(bytes.to_string()),
+                    ..Default::default()
+                },
+            );
+            Some(values)
+        };
+
+        self.client.update_item(input).await?;
+        Ok(())
+    }
+
+    fn is_invalid_key(error: &Error) -> bool {
+        matches!(error, Error::AccountNotFound | Error::InvalidAccountId(_))
+    }
 }
 
 impl AuthDbService {
@@ -170,6 +445,144 @@ impl AuthDbService {
         Ok(subscription_id.is_some())
     }
 
+    /// The sub-domain a custom hostname (i.e. `dash.theircompany.com`) is mapped to
+    pub async fn get_sub_domain_for_hostname(
+        &self,
+        hostname: &str,
+    ) -> Result<Option<String>, Error> {
+        let mut input = GetItemInput {
+            table_name: custom_host_db::TABLE_NAME.to_string(),
+            ..Default::default()
+        };
+        input.key = {
+            let mut item = HashMap::new();
+            item.insert(
+                custom_host_db::PRIMARY_KEY.to_string(),
+                AttributeValue {
+                    s: Some(hostname.to_string()),
+                    ..Default::default()
+                },
+            );
+            item
+        };
+
+        let result = self.client.get_item(input).await?;
+        let sub_domain = result
+            .item
+            .unwrap_or(HashMap::new())
+            .get(custom_host_db::SUB_DOMAIN)
+            .cloned()
+            .unwrap_or(AttributeValue::default())
+            .s;
+
+        Ok(sub_domain)
+    }
+
+    /// Route a custom hostname to a sub-domain, replacing any previous mapping
+    pub async fn set_custom_hostname(&self, hostname: &str, sub_domain: &str) -> Result<(), Error> {
+        let mut item = HashMap::new();
+        item.insert(
+            custom_host_db::PRIMARY_KEY.to_string(),
+            AttributeValue {
+                s: Some(hostname.to_string()),
+                ..Default::default()
+            },
+        );
+        item.insert(
+            custom_host_db::SUB_DOMAIN.to_string(),
+            AttributeValue {
+                s: Some(sub_domain.to_string()),
+                ..Default::default()
+            },
+        );
+
+        self.client
+            .put_item(PutItemInput {
+                table_name: custom_host_db::TABLE_NAME.to_string(),
+                item,
+                ..Default::default()
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// The custom "tunnel offline" page of the account that reserved this sub-domain
+    pub async fn get_not_found_page(&self, subdomain: &str) -> Result<Option<String>, Error> {
+        let account_id = match self.get_account_id_for_subdomain(subdomain).await? {
+            Some(account_id) => account_id,
+            None => return Ok(None),
+        };
+
+        let mut input = GetItemInput {
+            table_name: record_db::TABLE_NAME.to_string(),
+            ..Default::default()
+        };
+        input.key = {
+            let mut item = HashMap::new();
+            item.insert(
+                record_db::PRIMARY_KEY.to_string(),
+                AttributeValue {
+                    s: Some(account_id.to_string()),
+                    ..Default::default()
+                },
+            );
+            item
+        };
+
+        let result = self.client.get_item(input).await?;
+        let page = result
+            .item
+            .unwrap_or(HashMap::new())
+            .get(record_db::NOT_FOUND_PAGE)
+            .cloned()
+            .unwrap_or(AttributeValue::default())
+            .s;
+
+        Ok(page)
+    }
+
+    async fn get_sub_domain_prefix(&self, account_id: Uuid) -> Result<Option<String>, Error> {
+        let mut input = GetItemInput {
+            table_name: record_db::TABLE_NAME.to_string(),
+            ..Default::default()
+        };
+        input.key = {
+            let mut item = HashMap::new();
+            item.insert(
+                record_db::PRIMARY_KEY.to_string(),
+                AttributeValue {
+                    s: Some(account_id.to_string()),
+                    ..Default::default()
+                },
+            );
+            item
+        };
+
+        let result = self.client.get_item(input).await?;
+        let prefix = result
+            .item
+            .unwrap_or(HashMap::new())
+            .get(record_db::SUB_DOMAIN_PREFIX)
+            .cloned()
+            .unwrap_or(AttributeValue::default())
+            .s;
+
+        Ok(prefix)
+    }
+
+    /// The owner of the most specific prefix (i.e. `foo-*`) or wildcard namespace
+    /// (i.e. `*.acme`) reservation covering this sub-domain
+    async fn get_account_id_for_namespace(&self, subdomain: &str) -> Result<Option<Uuid>, Error> {
+        for namespace in super::reservation_patterns(subdomain) {
+            if let Some(account_id) = self.get_account_id_for_subdomain(&namespace).await? {
+                tracing::debug!(%namespace, %subdomain, "matched pattern reservation");
+                return Ok(Some(account_id));
+            }
+        }
+
+        Ok(None)
+    }
+
     async fn get_account_id_for_subdomain(&self, subdomain: &str) -> Result<Option<Uuid>, Error> {
         let mut input = GetItemInput {
             table_name: domain_db::TABLE_NAME.to_string(),
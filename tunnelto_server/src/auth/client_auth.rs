@@ -1,15 +1,26 @@
-use crate::auth::reconnect_token::ReconnectTokenPayload;
+use crate::auth::reconnect_token::{self, ReconnectTokenPayload};
 use crate::auth::{AuthResult, AuthService};
-use crate::{ReconnectToken, CONFIG};
+use crate::{ConnectedClient, ReconnectToken, CONFIG};
 use futures::{SinkExt, StreamExt};
 use tracing::error;
-use tunnelto_lib::{ClientHello, ClientId, ClientType, ServerHello};
+use tunnelto_lib::{
+    ClientHello, ClientId, ClientType, SecretKey, ServerHello, WireFormat, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, SEQUENCED_PROTOCOL_VERSION,
+};
 use warp::filters::ws::{Message, WebSocket};
 
 pub struct ClientHandshake {
     pub id: ClientId,
     pub sub_domain: String,
     pub is_anonymous: bool,
+    pub reserved_until: Option<u64>,
+    /// the account of an authenticated client
+    pub account_id: Option<String>,
+    /// how to write packets for this client
+    pub format: WireFormat,
+    /// the client wants a raw tcp tunnel, optionally on `requested_port`
+    pub tcp: bool,
+    pub requested_port: Option<u16>,
 }
 
 #[tracing::instrument(skip(websocket))]
@@ -17,23 +28,40 @@ pub async fn auth_client_handshake(
     mut websocket: WebSocket,
 ) -> Option<(WebSocket, ClientHandshake)> {
     let client_hello_data = match websocket.next().await {
-        Some(Ok(msg)) => msg,
+        // accept the hello as either a binary or a text frame
+        Some(Ok(msg)) if msg.is_binary() || msg.is_text() => msg,
+        Some(Ok(msg)) => {
+            error!(?msg, "client init message is not a data frame");
+            return None;
+        }
         _ => {
             error!("no client init message");
             return None;
         }
     };
 
-    auth_client(client_hello_data.as_bytes(), websocket).await
+    auth_client(
+        &*crate::AUTH_DB_SERVICE,
+        CONFIG.allow_anonymous,
+        client_hello_data.as_bytes(),
+        websocket,
+    )
+    .await
 }
 
-#[tracing::instrument(skip(client_hello_data, websocket))]
-async fn auth_client(
+#[tracing::instrument(skip(service, client_hello_data, websocket))]
+async fn auth_client<S>(
+    service: &S,
+    allow_anonymous: bool,
     client_hello_data: &[u8],
     mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
+) -> Option<(WebSocket, ClientHandshake)>
+where
+    S: AuthService<AuthKey = String> + Sync,
+    S::Error: std::fmt::Debug,
+{
     // parse the client hello
-    let client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
+    let mut client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
         Ok(ch) => ch,
         Err(error) => {
             error!(?error, "invalid client hello");
@@ -43,41 +71,91 @@ async fn auth_client(
         }
     };
 
-    let (auth_key, client_id, requested_sub_domain) = match client_hello.client_type {
-        ClientType::Anonymous => {
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+    // never true while the minimum is 0, it's raised as old protocols are dropped
+    #[allow(clippy::absurd_extreme_comparisons)]
+    if client_hello.protocol_version < MIN_PROTOCOL_VERSION {
+        tracing::info!(version=%client_hello.protocol_version, "outdated client protocol version");
+        let data = serde_json::to_vec(&ServerHello::outdated(
+            MIN_PROTOCOL_VERSION,
+            &CONFIG.upgrade_url,
+        ))
+        .unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    }
+
+    if client_hello.protocol_version > PROTOCOL_VERSION {
+        tracing::info!(version=%client_hello.protocol_version, "incompatible client protocol version");
+        let data = serde_json::to_vec(&ServerHello::IncompatibleVersion {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        })
+        .unwrap_or_default();
+        let _ = websocket.send(Message::binary(data)).await;
+        return None;
+    }
+
+    // the edge proxy places clients by `REGION_HEADER`, ending up elsewhere means
+    // the region has no instances (or none that are up)
+    if let (Some(wanted), Some(region)) = (client_hello.region.as_ref(), CONFIG.region.as_ref()) {
+        if !wanted.eq_ignore_ascii_case(region) {
+            tracing::info!(%wanted, %region, "client requested another region");
+        }
+    }
+
+    let format = WireFormat {
+        compression: client_hello.compression,
+        sequenced: client_hello.protocol_version >= SEQUENCED_PROTOCOL_VERSION,
+    };
+
+    let (auth_key, client_id, requested_sub_domain, stale) = match client_hello.client_type.clone()
+    {
+        ClientType::Anonymous if !allow_anonymous => {
+            let reason = "This server requires an authentication key, set one with `--key`.";
+            let hello = auth_failed(client_hello.auth_failure_reasons, reason);
+            let data = serde_json::to_vec(&hello).unwrap_or_default();
             let _ = websocket.send(Message::binary(data)).await;
             return None;
+        }
+        ClientType::Anonymous => {
+            if let Some(verified) = verify_reconnect_token(client_hello.reconnect_token.take()) {
+                return handle_reconnect_token(verified, websocket, format, &client_hello).await;
+            }
 
-            // // determine the client and subdomain
-            // let (client_id, sub_domain) =
-            //     match (client_hello.reconnect_token, client_hello.sub_domain) {
-            //         (Some(token), _) => {
-            //             return handle_reconnect_token(token, websocket).await;
-            //         }
-            //         (None, Some(sd)) => (
-            //             ClientId::generate(),
-            //             ServerHello::prefixed_random_domain(&sd),
-            //         ),
-            //         (None, None) => (ClientId::generate(), ServerHello::random_domain()),
-            //     };
-
-            // return Some((
-            //     websocket,
-            //     ClientHandshake {
-            //         id: client_id,
-            //         sub_domain,
-            //         is_anonymous: true,
-            //     },
-            // ));
-        }
-        ClientType::Auth { key } => match client_hello.sub_domain {
+            // determine the subdomain, anonymous clients never get the exact name they ask for
+            let sub_domain = match client_hello.sub_domain.take() {
+                Some(sd) => match tunnelto_lib::sub_domain_to_ascii(&sd.to_lowercase()) {
+                    Ok(sd) if sd.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') => {
+                        ServerHello::prefixed_random_domain(&sd)
+                    }
+                    _ => crate::word_list::random_sub_domain().await,
+                },
+                None => crate::word_list::random_sub_domain().await,
+            };
+
+            tracing::info!(subdomain=%sub_domain, "anonymous client");
+            return Some((
+                websocket,
+                ClientHandshake {
+                    id: ClientId::generate(),
+                    sub_domain,
+                    is_anonymous: true,
+                    reserved_until: None,
+                    account_id: None,
+                    format,
+                    tcp: client_hello.tcp,
+                    requested_port: client_hello.requested_port,
+                },
+            ));
+        }
+        ClientType::Auth { key } => match client_hello.sub_domain.take() {
             Some(requested_sub_domain) => {
                 let client_id = key.client_id();
-                let (ws, sub_domain) = match sanitize_sub_domain_and_pre_validate(
+                let (ws, sub_domain, stale) = match sanitize_sub_domain_and_pre_validate(
                     websocket,
                     requested_sub_domain,
                     &client_id,
+                    client_hello.takeover,
                 )
                 .await
                 {
@@ -86,15 +164,17 @@ async fn auth_client(
                 };
                 websocket = ws;
 
-                (key, client_id, sub_domain)
+                (key, client_id, sub_domain, stale)
             }
             None => {
-                if let Some(token) = client_hello.reconnect_token {
-                    return handle_reconnect_token(token, websocket).await;
+                if let Some(verified) = verify_reconnect_token(client_hello.reconnect_token.take())
+                {
+                    return handle_reconnect_token(verified, websocket, format, &client_hello)
+                        .await;
                 } else {
-                    let sub_domain = ServerHello::random_domain();
+                    let sub_domain = crate::word_list::random_sub_domain().await;
                     let client_id = key.client_id();
-                    (key, client_id, sub_domain)
+                    (key, client_id, sub_domain, None)
                 }
             }
         },
@@ -103,17 +183,51 @@ async fn auth_client(
     tracing::info!(requested_sub_domain=%requested_sub_domain, "will auth sub domain");
 
     // next authenticate the sub-domain
-    let sub_domain = match crate::AUTH_DB_SERVICE
+    let (sub_domain, reserved_until) = match service
         .auth_sub_domain(&auth_key.0, &requested_sub_domain)
         .await
     {
-        Ok(AuthResult::Available) | Ok(AuthResult::ReservedByYou) => requested_sub_domain,
-        Ok(AuthResult::ReservedByYouButDelinquent) | Ok(AuthResult::PaymentRequired) => {
+        Ok(AuthResult::Available) => {
+            // only a tunnel with our own key can be taken over from an unreserved sub-domain
+            if stale.as_ref().map_or(false, |stale| stale.id != client_id) {
+                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
+                let _ = websocket.send(Message::binary(data)).await;
+                return None;
+            }
+            (requested_sub_domain, None)
+        }
+        Ok(AuthResult::ReservedByYou) => {
+            let reserved_until = service
+                .get_reservation_expiry(&requested_sub_domain)
+                .await
+                .unwrap_or_else(|error| {
+                    error!(?error, "failed to get reservation expiry");
+                    None
+                });
+            (requested_sub_domain, reserved_until)
+        }
+        Ok(AuthResult::ReservedByYouButDelinquent) => {
+            tracing::info!(requested_sub_domain=%requested_sub_domain, "delinquent");
+            let reason = "Your subscription is not active, so your reserved sub-domain is unavailable. Check your billing at https://dashboard.tunnelto.dev.";
+            let hello = auth_failed(client_hello.auth_failure_reasons, reason);
+            let data = serde_json::to_vec(&hello).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+        Ok(AuthResult::PaymentRequired) => {
             // note: delinquent payments get a random suffix
             // ServerHello::prefixed_random_domain(&requested_sub_domain)
             // TODO: create free trial domain
             tracing::info!(requested_sub_domain=%requested_sub_domain, "payment required");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+            let reason = "Custom sub-domains require a subscription, get one at https://dashboard.tunnelto.dev.";
+            let hello = auth_failed(client_hello.auth_failure_reasons, reason);
+            let data = serde_json::to_vec(&hello).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+        Ok(AuthResult::Forbidden) => {
+            tracing::info!(requested_sub_domain=%requested_sub_domain, "outside account prefix");
+            let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
             let _ = websocket.send(Message::binary(data)).await;
             return None;
         }
@@ -124,7 +238,8 @@ async fn auth_client(
         }
         Err(error) => {
             error!(?error, "error auth-ing user");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+            let hello = auth_failed(client_hello.auth_failure_reasons, key_error::<S>(&error));
+            let data = serde_json::to_vec(&hello).unwrap_or_default();
             let _ = websocket.send(Message::binary(data)).await;
             return None;
         }
@@ -132,26 +247,99 @@ async fn auth_client(
 
     tracing::info!(subdomain=%sub_domain, "did auth sub_domain");
 
+    // the account may only have so many tunnels open at once. Fails closed like
+    // `auth_sub_domain` above, an unknown limit would be no limit at all.
+    let account_id = match service.get_tunnel_limit(&auth_key.0).await {
+        Ok((account_id, limit)) => {
+            let account_id = account_id.to_string();
+            // a reconnect or takeover replaces the tunnel on this host, it doesn't add one
+            if !crate::Connections::reserve_account_slot(&account_id, &sub_domain, limit) {
+                tracing::info!(account=%account_id, %limit, "too many tunnels");
+                let data = serde_json::to_vec(&ServerHello::Error(format!(
+                    "Your plan allows {} simultaneous tunnel(s). Close one to open another.",
+                    limit
+                )))
+                .unwrap_or_default();
+                let _ = websocket.send(Message::binary(data)).await;
+                return None;
+            }
+            account_id
+        }
+        Err(error) => {
+            error!(?error, "failed to get account tunnel limit");
+            let hello = auth_failed(client_hello.auth_failure_reasons, key_error::<S>(&error));
+            let data = serde_json::to_vec(&hello).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+    };
+
+    if let Some(stale) = stale {
+        crate::Connections::evict(&stale, Some(&account_id));
+    }
+
     Some((
         websocket,
         ClientHandshake {
             id: client_id,
             sub_domain,
             is_anonymous: false,
+            reserved_until,
+            account_id: Some(account_id),
+            format,
+            tcp: client_hello.tcp,
+            requested_port: client_hello.requested_port,
         },
     ))
 }
 
-#[tracing::instrument(skip(token, websocket))]
+/// Why a lookup for the client's key failed, to tell the client
+fn key_error<S: AuthService>(error: &S::Error) -> &'static str {
+    if S::is_invalid_key(error) {
+        "The authentication key is invalid."
+    } else {
+        "The server could not verify your key, try again later."
+    }
+}
+
+/// The reason is only sent to clients that can read it, older ones get a plain `AuthFailed`
+fn auth_failed(with_reason: bool, reason: &str) -> ServerHello {
+    if with_reason {
+        ServerHello::AuthFailedWithReason {
+            reason: reason.to_string(),
+        }
+    } else {
+        ServerHello::AuthFailed
+    }
+}
+
+/// An expired token is dropped, so the client starts over on a new sub-domain
+fn verify_reconnect_token(
+    token: Option<ReconnectToken>,
+) -> Option<Result<ReconnectTokenPayload, reconnect_token::Error>> {
+    match ReconnectTokenPayload::verify(token?, &CONFIG.master_sig_key) {
+        Err(reconnect_token::Error::Expired) => {
+            tracing::info!("reconnect token expired, assigning a new sub-domain");
+            None
+        }
+        verified => Some(verified),
+    }
+}
+
+#[tracing::instrument(skip(verified, websocket, client_hello))]
 async fn handle_reconnect_token(
-    token: ReconnectToken,
+    verified: Result<ReconnectTokenPayload, reconnect_token::Error>,
     mut websocket: WebSocket,
+    format: WireFormat,
+    client_hello: &ClientHello,
 ) -> Option<(WebSocket, ClientHandshake)> {
-    let payload = match ReconnectTokenPayload::verify(token, &CONFIG.master_sig_key) {
+    let payload = match verified {
         Ok(payload) => payload,
         Err(error) => {
             error!(?error, "invalid reconnect token");
-            let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
+            let reason = "The reconnect token is invalid, restart tunnelto for a new sub-domain.";
+            let hello = auth_failed(client_hello.auth_failure_reasons, reason);
+            let data = serde_json::to_vec(&hello).unwrap_or_default();
             let _ = websocket.send(Message::binary(data)).await;
             return None;
         }
@@ -168,25 +356,43 @@ async fn handle_reconnect_token(
             id: payload.client_id,
             sub_domain: payload.sub_domain,
             is_anonymous: true,
+            reserved_until: None,
+            account_id: None,
+            format,
+            tcp: client_hello.tcp,
+            requested_port: client_hello.requested_port,
         },
     ))
 }
 
+/// Returns the sanitized sub-domain, and the stale tunnel to evict from it when taking over
 async fn sanitize_sub_domain_and_pre_validate(
     mut websocket: WebSocket,
     requested_sub_domain: String,
     client_id: &ClientId,
-) -> Option<(WebSocket, String)> {
-    // ignore uppercase
-    let sub_domain = requested_sub_domain.to_lowercase();
+    takeover: bool,
+) -> Option<(WebSocket, String, Option<ConnectedClient>)> {
+    // ignore uppercase, punycode-encode internationalized names
+    let sub_domain = match tunnelto_lib::sub_domain_to_ascii(&requested_sub_domain.to_lowercase()) {
+        Ok(sub_domain) => sub_domain,
+        Err(error) => {
+            error!(
+                ?error,
+                "invalid client hello: sub-domain is not a valid IDN label"
+            );
+            let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
+            let _ = websocket.send(Message::binary(data)).await;
+            return None;
+        }
+    };
 
     if sub_domain
         .chars()
-        .filter(|c| !(c.is_alphanumeric() || c == &'-'))
+        .filter(|c| !(c.is_ascii_alphanumeric() || c == &'-' || c == &'.'))
         .count()
         > 0
     {
-        error!("invalid client hello: only alphanumeric/hyphen chars allowed!");
+        error!("invalid client hello: only alphanumeric/hyphen/dot chars allowed!");
         let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
         let _ = websocket.send(Message::binary(data)).await;
         return None;
@@ -200,12 +406,23 @@ async fn sanitize_sub_domain_and_pre_validate(
         return None;
     }
 
+    // a stale tunnel can only be taken over on the instance it's connected to,
+    // ownership of the sub-domain is checked once we've authenticated
+    let stale = if takeover {
+        crate::Connections::find_by_host(&sub_domain)
+    } else {
+        None
+    };
+
     // ensure this sub-domain isn't taken
     // check all instances
     match crate::network::instance_for_host(&sub_domain).await {
         Err(crate::network::Error::DoesNotServeHost) => {}
         Ok((_, existing_client)) => {
-            if &existing_client != client_id {
+            let is_stale = stale
+                .as_ref()
+                .map_or(false, |stale| stale.id == existing_client);
+            if &existing_client != client_id && !is_stale {
                 error!("invalid client hello: requested sub domain in use already!");
                 let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
                 let _ = websocket.send(Message::binary(data)).await;
@@ -217,5 +434,388 @@ async fn sanitize_sub_domain_and_pre_validate(
         }
     }
 
-    Some((websocket, sub_domain))
+    Some((websocket, sub_domain, stale))
+}
+
+/// Check if a sub-domain could be claimed right now (by the owner of `key`, if given)
+pub async fn is_sub_domain_available(requested_sub_domain: &str, key: Option<&SecretKey>) -> bool {
+    let sub_domain = match tunnelto_lib::sub_domain_to_ascii(&requested_sub_domain.to_lowercase()) {
+        Ok(sub_domain) => sub_domain,
+        Err(_) => return false,
+    };
+
+    if sub_domain
+        .chars()
+        .any(|c| !(c.is_ascii_alphanumeric() || c == '-' || c == '.'))
+        || CONFIG.blocked_sub_domains.contains(&sub_domain)
+    {
+        return false;
+    }
+
+    let client_id = key.map(|k| k.client_id());
+    let in_use_by = match crate::network::instance_for_host(&sub_domain).await {
+        Ok((_, existing_client)) => Some(existing_client),
+        Err(_) => crate::Connections::client_for_host(&sub_domain),
+    };
+    if in_use_by.map_or(false, |existing| Some(existing) != client_id) {
+        return false;
+    }
+
+    let key = match key {
+        Some(key) => key,
+        None => return true,
+    };
+
+    match crate::AUTH_DB_SERVICE
+        .auth_sub_domain(&key.0, &sub_domain)
+        .await
+    {
+        Ok(AuthResult::Available) | Ok(AuthResult::ReservedByYou) => true,
+        Ok(_) => false,
+        Err(error) => {
+            error!(?error, "error checking sub domain availability");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::in_memory::InMemoryAuthService;
+    use std::sync::Arc;
+    use tunnelto_lib::{ControlPacket, SecretKey};
+    use uuid::Uuid;
+    use warp::Filter;
+
+    const KEY: &str = "the-auth-key";
+
+    /// Run `auth_client` against `service` on a test websocket, returning the handshake
+    /// or the server hello the client was sent instead
+    async fn handshake(
+        service: InMemoryAuthService,
+        hello: ClientHello,
+    ) -> Result<ClientHandshake, ServerHello> {
+        let service = Arc::new(service);
+        let data = serde_json::to_vec(&hello).unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let route = warp::ws().map(move |ws: warp::ws::Ws| {
+            let (service, data, tx) = (service.clone(), data.clone(), tx.clone());
+            ws.on_upgrade(move |websocket| async move {
+                let handshake = auth_client(&*service, true, &data, websocket).await;
+                let _ = tx.send(handshake.map(|(_, handshake)| handshake));
+            })
+        });
+        let mut client = warp::test::ws().handshake(route).await.unwrap();
+
+        match rx.recv().await.unwrap() {
+            Some(handshake) => Ok(handshake),
+            None => {
+                let msg = client.recv().await.unwrap();
+                Err(serde_json::from_slice(msg.as_bytes()).unwrap())
+            }
+        }
+    }
+
+    fn auth_hello(sub_domain: &str) -> ClientHello {
+        let mut hello = ClientHello::generate(
+            Some(sub_domain.to_string()),
+            ClientType::Auth {
+                key: SecretKey(KEY.to_string()),
+            },
+        );
+        hello.auth_failure_reasons = true;
+        hello
+    }
+
+    fn reason(hello: ServerHello) -> String {
+        match hello {
+            ServerHello::AuthFailedWithReason { reason } => reason,
+            other => panic!("expected a reason, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn available_to_good_standing() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .good_standing(account)
+            .build();
+
+        let handshake = handshake(service, auth_hello("Available-Ok"))
+            .await
+            .unwrap_or_else(|hello| panic!("unexpected {:?}", hello));
+        assert_eq!(handshake.sub_domain, "available-ok");
+        assert_eq!(handshake.account_id, Some(account.to_string()));
+        assert!(!handshake.is_anonymous);
+    }
+
+    #[tokio::test]
+    async fn reserved_by_you() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .good_standing(account)
+            .reservation("mine-reserved", account)
+            .build();
+
+        let handshake = handshake(service, auth_hello("mine-reserved"))
+            .await
+            .unwrap_or_else(|hello| panic!("unexpected {:?}", hello));
+        assert_eq!(handshake.sub_domain, "mine-reserved");
+        assert_eq!(handshake.reserved_until, None);
+    }
+
+    #[tokio::test]
+    async fn reserved_by_you_but_delinquent() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .reservation("mine-delinquent", account)
+            .build();
+
+        let hello = handshake(service, auth_hello("mine-delinquent"))
+            .await
+            .err()
+            .unwrap();
+        assert!(reason(hello).contains("subscription is not active"));
+    }
+
+    #[tokio::test]
+    async fn payment_required() {
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, Uuid::new_v4())
+            .build();
+
+        let hello = handshake(service, auth_hello("wants-custom"))
+            .await
+            .err()
+            .unwrap();
+        assert!(reason(hello).contains("require a subscription"));
+    }
+
+    #[tokio::test]
+    async fn forbidden_outside_prefix() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .good_standing(account)
+            .sub_domain_prefix(account, "acme-")
+            .build();
+
+        let hello = handshake(service, auth_hello("other-name"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(hello, ServerHello::InvalidSubDomain));
+    }
+
+    #[tokio::test]
+    async fn reserved_by_other() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .good_standing(account)
+            .reservation("taken-name", Uuid::new_v4())
+            .build();
+
+        let hello = handshake(service, auth_hello("taken-name"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(hello, ServerHello::SubDomainInUse));
+    }
+
+    #[tokio::test]
+    async fn reserved_by_other_namespace() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .good_standing(account)
+            .reservation("*.their-namespace", Uuid::new_v4())
+            .build();
+
+        let hello = handshake(service, auth_hello("api.their-namespace"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(hello, ServerHello::SubDomainInUse));
+    }
+
+    #[tokio::test]
+    async fn invalid_key() {
+        let service = InMemoryAuthService::builder().build();
+
+        let hello = handshake(service, auth_hello("any-name"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(reason(hello), "The authentication key is invalid.");
+    }
+
+    #[tokio::test]
+    async fn over_tunnel_limit() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .good_standing(account)
+            .tunnel_limit(account, 0)
+            .build();
+
+        let hello = handshake(service, auth_hello("one-too-many"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(hello, ServerHello::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn tunnel_limit_counts_pending_handshakes() {
+        let account = Uuid::new_v4();
+        let service = || {
+            InMemoryAuthService::builder()
+                .auth_key(KEY, account)
+                .good_standing(account)
+                .tunnel_limit(account, 1)
+                .build()
+        };
+
+        // the first tunnel holds the slot before it's connected
+        let first = handshake(service(), auth_hello("slot-one")).await;
+        assert!(first.is_ok());
+        let hello = handshake(service(), auth_hello("slot-two"))
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(hello, ServerHello::Error(_)));
+
+        // the same host again doesn't take another slot
+        assert!(handshake(service(), auth_hello("slot-one")).await.is_ok());
+
+        crate::Connections::release_account_slot(&account.to_string(), "slot-one");
+        assert!(handshake(service(), auth_hello("slot-two")).await.is_ok());
+    }
+
+    fn reconnect_hello(expires: chrono::DateTime<chrono::Utc>) -> (ClientId, ClientHello) {
+        let client_id = ClientId::generate();
+        let token = ReconnectTokenPayload {
+            sub_domain: "reconnecting".to_string(),
+            client_id: client_id.clone(),
+            expires,
+        }
+        .into_token(&CONFIG.master_sig_key)
+        .unwrap();
+
+        let mut hello = ClientHello::generate(
+            None,
+            ClientType::Auth {
+                key: SecretKey(KEY.to_string()),
+            },
+        );
+        hello.reconnect_token = Some(token);
+        hello.auth_failure_reasons = true;
+        (client_id, hello)
+    }
+
+    #[tokio::test]
+    async fn reconnect_token_keeps_sub_domain() {
+        let (client_id, hello) = reconnect_hello(chrono::Utc::now() + chrono::Duration::minutes(2));
+
+        // the token alone is enough, the key is never looked up
+        let handshake = handshake(InMemoryAuthService::builder().build(), hello)
+            .await
+            .unwrap_or_else(|hello| panic!("unexpected {:?}", hello));
+        assert_eq!(handshake.sub_domain, "reconnecting");
+        assert_eq!(handshake.id, client_id);
+    }
+
+    #[tokio::test]
+    async fn reconnect_token_expired_gets_a_new_sub_domain() {
+        let account = Uuid::new_v4();
+        let service = InMemoryAuthService::builder()
+            .auth_key(KEY, account)
+            .good_standing(account)
+            .build();
+        let (client_id, hello) = reconnect_hello(chrono::Utc::now() - chrono::Duration::seconds(1));
+
+        let handshake = handshake(service, hello)
+            .await
+            .unwrap_or_else(|hello| panic!("unexpected {:?}", hello));
+        assert_ne!(handshake.sub_domain, "reconnecting");
+        assert_ne!(handshake.id, client_id);
+        assert_eq!(handshake.id, SecretKey(KEY.to_string()).client_id());
+    }
+
+    #[tokio::test]
+    async fn reconnect_token_invalid() {
+        let (_, mut hello) = reconnect_hello(chrono::Utc::now() + chrono::Duration::minutes(2));
+        let forged = ReconnectTokenPayload {
+            sub_domain: "reconnecting".to_string(),
+            client_id: ClientId::generate(),
+            expires: chrono::Utc::now() + chrono::Duration::minutes(2),
+        };
+        hello.reconnect_token = Some(forged.into_token(&crate::auth::SigKey::generate()).unwrap());
+
+        let hello = handshake(InMemoryAuthService::builder().build(), hello)
+            .await
+            .err()
+            .unwrap();
+        assert!(reason(hello).contains("token is invalid"));
+    }
+
+    /// The token an anonymous tunnel is pinged with, as its client reads it off the wire
+    fn pinged_token(handshake: &ClientHandshake) -> ReconnectToken {
+        let (tx, _rx) = futures::channel::mpsc::channel(1);
+        let client = ConnectedClient {
+            id: handshake.id.clone(),
+            host: handshake.sub_domain.clone(),
+            is_anonymous: handshake.is_anonymous,
+            account_id: None,
+            format: handshake.format,
+            tx,
+        };
+        let ping = crate::control_server::client_ping(&client).encode(client.format);
+        match ControlPacket::deserialize(&ping) {
+            Ok(ControlPacket::Ping(Some(token), Some(_))) => token,
+            other => panic!("expected a ping with a token, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn anonymous_client_reconnects_with_its_pinged_token() {
+        let anonymous = || InMemoryAuthService::builder().build();
+        let first = handshake(
+            anonymous(),
+            ClientHello::generate(None, ClientType::Anonymous),
+        )
+        .await
+        .unwrap_or_else(|hello| panic!("unexpected {:?}", hello));
+        assert!(first.is_anonymous);
+
+        // the connection drops, the client comes back with the last token it was pinged
+        let token = pinged_token(&first);
+        let reconnected = handshake(anonymous(), ClientHello::reconnect(token))
+            .await
+            .unwrap_or_else(|hello| panic!("unexpected {:?}", hello));
+        assert_eq!(reconnected.sub_domain, first.sub_domain);
+        assert_eq!(reconnected.id, first.id);
+        assert!(reconnected.is_anonymous);
+
+        // it was gone too long, so it starts over rather than failing
+        let expired = ReconnectTokenPayload {
+            sub_domain: first.sub_domain.clone(),
+            client_id: first.id.clone(),
+            expires: chrono::Utc::now() - chrono::Duration::seconds(1),
+        }
+        .into_token(&CONFIG.master_sig_key)
+        .unwrap();
+        let restarted = handshake(anonymous(), ClientHello::reconnect(expired))
+            .await
+            .unwrap_or_else(|hello| panic!("unexpected {:?}", hello));
+        assert_ne!(restarted.sub_domain, first.sub_domain);
+        assert_ne!(restarted.id, first.id);
+        assert!(restarted.is_anonymous);
+    }
 }
@@ -0,0 +1,225 @@
+use super::AuthResult;
+use crate::auth::AuthService;
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use thiserror::Error;
+use tunnelto_lib::ClientId;
+use uuid::Uuid;
+
+/// An in-memory auth service with seeded accounts for testing the auth flows
+/// without a real database. It follows the same contract as `AuthDbService`:
+///     - an unknown auth key is an error
+///     - a sub-domain outside the account's prefix (if one is seeded) is `Forbidden`
+///     - a sub-domain reserved by another account is `ReservedByOther`, including
+///       any nested sub-domain of a wildcard namespace (i.e. `*.acme`) or name matching
+///       a prefix (i.e. `foo-*`) they reserved
+///     - a sub-domain reserved by you is `ReservedByYou`, or `ReservedByYouButDelinquent`
+///       if the account is not in good standing
+///     - an unreserved sub-domain is `Available` for accounts in good standing,
+///       otherwise `PaymentRequired`
+///     - an account may open any number of tunnels unless given a limit
+///     - usage adds up per client, see `usage`
+#[derive(Debug, Default)]
+pub struct InMemoryAuthService {
+    accounts: RwLock<HashMap<String, Uuid>>,
+    reservations: RwLock<HashMap<String, Uuid>>,
+    good_standing: RwLock<HashSet<Uuid>>,
+    prefixes: HashMap<Uuid, String>,
+    tunnel_limits: HashMap<Uuid, usize>,
+    usage: RwLock<HashMap<ClientId, u64>>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("The authentication key is invalid")]
+    AccountNotFound,
+}
+
+impl InMemoryAuthService {
+    pub fn builder() -> InMemoryAuthServiceBuilder {
+        InMemoryAuthServiceBuilder::default()
+    }
+
+    /// The usage total added for a client so far
+    pub fn usage(&self, client_id: &ClientId) -> u64 {
+        self.usage
+            .read()
+            .unwrap()
+            .get(client_id)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryAuthServiceBuilder {
+    inner: InMemoryAuthService,
+}
+
+impl InMemoryAuthServiceBuilder {
+    /// Seed an auth key belonging to `account_id`
+    pub fn auth_key(mut self, auth_key: &str, account_id: Uuid) -> Self {
+        self.inner
+            .accounts
+            .get_mut()
+            .unwrap()
+            .insert(auth_key.to_string(), account_id);
+        self
+    }
+
+    /// Reserve `sub_domain` for `account_id`, a whole namespace with `*.namespace`,
+    /// or every name starting with a prefix with `prefix-*`
+    pub fn reservation(mut self, sub_domain: &str, account_id: Uuid) -> Self {
+        self.inner
+            .reservations
+            .get_mut()
+            .unwrap()
+            .insert(sub_domain.to_string(), account_id);
+        self
+    }
+
+    /// Mark `account_id` as having an active subscription
+    pub fn good_standing(mut self, account_id: Uuid) -> Self {
+        self.inner
+            .good_standing
+            .get_mut()
+            .unwrap()
+            .insert(account_id);
+        self
+    }
+
+    /// Require the sub-domains of `account_id` to start with `prefix`, as if
+    /// `ENFORCE_SUB_DOMAIN_PREFIX` were set
+    pub fn sub_domain_prefix(mut self, account_id: Uuid, prefix: &str) -> Self {
+        self.inner.prefixes.insert(account_id, prefix.to_string());
+        self
+    }
+
+    /// Allow `account_id` at most `limit` simultaneous tunnels
+    pub fn tunnel_limit(mut self, account_id: Uuid, limit: usize) -> Self {
+        self.inner.tunnel_limits.insert(account_id, limit);
+        self
+    }
+
+    pub fn build(self) -> InMemoryAuthService {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl AuthService for InMemoryAuthService {
+    type Error = Error;
+    type AuthKey = String;
+
+    async fn auth_sub_domain(
+        &self,
+        auth_key: &String,
+        subdomain: &str,
+    ) -> Result<AuthResult, Error> {
+        let authenticated_account_id = *self
+            .accounts
+            .read()
+            .unwrap()
+            .get(auth_key)
+            .ok_or(Error::AccountNotFound)?;
+        let is_pro_account = self
+            .good_standing
+            .read()
+            .unwrap()
+            .contains(&authenticated_account_id);
+
+        if let Some(prefix) = self.prefixes.get(&authenticated_account_id) {
+            if !subdomain.starts_with(prefix.as_str()) {
+                return Ok(AuthResult::Forbidden);
+            }
+        }
+
+        let reservations = self.reservations.read().unwrap();
+        let reserved_by = reservations.get(subdomain).or_else(|| {
+            super::reservation_patterns(subdomain)
+                .iter()
+                .find_map(|namespace| reservations.get(namespace))
+        });
+
+        if let Some(account_id) = reserved_by {
+            if &authenticated_account_id != account_id {
+                return Ok(AuthResult::ReservedByOther);
+            }
+
+            if !is_pro_account {
+                return Ok(AuthResult::ReservedByYouButDelinquent);
+            }
+
+            return Ok(AuthResult::ReservedByYou);
+        }
+
+        if is_pro_account {
+            Ok(AuthResult::Available)
+        } else {
+            Ok(AuthResult::PaymentRequired)
+        }
+    }
+
+    async fn create_auth_key(
+        &self,
+        account_id: Option<Uuid>,
+        good_standing: bool,
+    ) -> Result<(Uuid, String), Error> {
+        let account_id = account_id.unwrap_or_else(Uuid::new_v4);
+        let auth_key = super::generate_auth_key();
+
+        self.accounts
+            .write()
+            .unwrap()
+            .insert(auth_key.clone(), account_id);
+        if good_standing {
+            self.good_standing.write().unwrap().insert(account_id);
+        }
+        Ok((account_id, auth_key))
+    }
+
+    async fn reserve_sub_domain(&self, account_id: Uuid, subdomain: &str) -> Result<bool, Error> {
+        let mut reservations = self.reservations.write().unwrap();
+        match reservations.get(subdomain) {
+            Some(owner) if *owner != account_id => Ok(false),
+            _ => {
+                reservations.insert(subdomain.to_string(), account_id);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn get_reservation_expiry(&self, _subdomain: &str) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    async fn get_tunnel_limit(&self, auth_key: &String) -> Result<(Uuid, usize), Error> {
+        let account_id = *self
+            .accounts
+            .read()
+            .unwrap()
+            .get(auth_key)
+            .ok_or(Error::AccountNotFound)?;
+        let limit = self
+            .tunnel_limits
+            .get(&account_id)
+            .copied()
+            .unwrap_or(usize::MAX);
+        Ok((account_id, limit))
+    }
+
+    async fn add_usage(&self, client_id: &ClientId, bytes: u64) -> Result<(), Error> {
+        *self
+            .usage
+            .write()
+            .unwrap()
+            .entry(client_id.clone())
+            .or_insert(0) += bytes;
+        Ok(())
+    }
+
+    fn is_invalid_key(error: &Error) -> bool {
+        matches!(error, Error::AccountNotFound)
+    }
+}
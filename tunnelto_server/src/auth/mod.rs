@@ -3,9 +3,13 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::fmt::Formatter;
+use tunnelto_lib::ClientId;
+use uuid::Uuid;
 
 pub mod auth_db;
 pub mod client_auth;
+#[cfg(test)]
+pub mod in_memory;
 pub mod reconnect_token;
 
 #[derive(Clone)]
@@ -61,6 +65,73 @@ pub trait AuthService {
         auth_key: &Self::AuthKey,
         subdomain: &str,
     ) -> Result<AuthResult, Self::Error>;
+
+    /// Create a new auth key, for a new account unless `account_id` is given.
+    /// Returns the account and the key, only its hash is stored.
+    async fn create_auth_key(
+        &self,
+        account_id: Option<Uuid>,
+        good_standing: bool,
+    ) -> Result<(Uuid, String), Self::Error>;
+
+    /// Reserve a sub-domain for an account, false if another account already reserved it
+    async fn reserve_sub_domain(
+        &self,
+        account_id: Uuid,
+        subdomain: &str,
+    ) -> Result<bool, Self::Error>;
+
+    /// When the reservation of this sub-domain expires, for time-limited reservations
+    async fn get_reservation_expiry(&self, subdomain: &str) -> Result<Option<u64>, Self::Error>;
+
+    /// The account of an auth key, and how many tunnels it may have open at once
+    async fn get_tunnel_limit(
+        &self,
+        auth_key: &Self::AuthKey,
+    ) -> Result<(Uuid, usize), Self::Error>;
+
+    /// Add `bytes` to the running usage total of a client, see `usage.rs`
+    async fn add_usage(&self, client_id: &ClientId, bytes: u64) -> Result<(), Self::Error>;
+
+    /// The error means the key itself is bad, rather than the lookup failing
+    fn is_invalid_key(_error: &Self::Error) -> bool {
+        false
+    }
+}
+
+/// A new random auth key
+pub fn generate_auth_key() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// The wildcard namespace reservations that could cover a nested sub-domain,
+/// longest suffix first, i.e: "a.b.acme" => ["*.b.acme", "*.acme"]
+pub fn wildcard_namespaces(sub_domain: &str) -> Vec<String> {
+    sub_domain
+        .match_indices('.')
+        .map(|(idx, _)| format!("*{}", &sub_domain[idx..]))
+        .collect()
+}
+
+/// The prefix reservations that could cover a single-label sub-domain,
+/// longest prefix first, i.e: "foo-staging-2" => ["foo-staging-*", "foo-*"]
+pub fn prefix_patterns(sub_domain: &str) -> Vec<String> {
+    if sub_domain.contains('.') {
+        return vec![];
+    }
+
+    sub_domain
+        .rmatch_indices('-')
+        .filter(|(idx, _)| *idx > 0)
+        .map(|(idx, _)| format!("{}-*", &sub_domain[..idx]))
+        .collect()
+}
+
+/// Every pattern reservation that could cover a sub-domain, most specific first
+pub fn reservation_patterns(sub_domain: &str) -> Vec<String> {
+    let mut patterns = prefix_patterns(sub_domain);
+    patterns.extend(wildcard_namespaces(sub_domain));
+    patterns
 }
 
 /// A result for authenticating a subdomain
@@ -70,6 +141,8 @@ pub enum AuthResult {
     ReservedByYouButDelinquent,
     PaymentRequired,
     Available,
+    /// The sub-domain is outside the account's required prefix
+    Forbidden,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -88,4 +161,73 @@ impl AuthService for NoAuth {
     ) -> Result<AuthResult, Self::Error> {
         Ok(AuthResult::Available)
     }
+
+    /// Without auth there are no accounts to provision
+    async fn create_auth_key(
+        &self,
+        _account_id: Option<Uuid>,
+        _good_standing: bool,
+    ) -> Result<(Uuid, String), Self::Error> {
+        Err(())
+    }
+
+    async fn reserve_sub_domain(
+        &self,
+        _account_id: Uuid,
+        _subdomain: &str,
+    ) -> Result<bool, Self::Error> {
+        Err(())
+    }
+
+    async fn get_reservation_expiry(&self, _subdomain: &str) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+
+    async fn get_tunnel_limit(
+        &self,
+        _auth_key: &Self::AuthKey,
+    ) -> Result<(Uuid, usize), Self::Error> {
+        Err(())
+    }
+
+    /// Without auth there is nowhere to keep usage
+    async fn add_usage(&self, _client_id: &ClientId, _bytes: u64) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_namespaces_longest_first() {
+        assert_eq!(
+            wildcard_namespaces("a.b.acme"),
+            vec!["*.b.acme".to_string(), "*.acme".to_string()]
+        );
+        assert_eq!(wildcard_namespaces("api.acme"), vec!["*.acme".to_string()]);
+        assert!(wildcard_namespaces("acme").is_empty());
+    }
+
+    #[test]
+    fn prefix_patterns_longest_first() {
+        assert_eq!(
+            prefix_patterns("foo-staging-2"),
+            vec!["foo-staging-*".to_string(), "foo-*".to_string()]
+        );
+        assert!(prefix_patterns("foo").is_empty());
+        // nested sub-domains are only covered by namespaces
+        assert!(prefix_patterns("foo-1.acme").is_empty());
+        assert!(prefix_patterns("-foo").is_empty());
+    }
+
+    #[test]
+    fn reservation_patterns_prefixes_then_namespaces() {
+        assert_eq!(reservation_patterns("foo-2"), vec!["foo-*".to_string()]);
+        assert_eq!(
+            reservation_patterns("api.foo-2"),
+            vec!["*.foo-2".to_string()]
+        );
+    }
 }
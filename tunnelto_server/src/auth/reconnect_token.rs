@@ -57,3 +57,83 @@ struct ReconnectTokenInner {
     payload: String,
     sig: Signature,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn payload(expires: DateTime<Utc>) -> ReconnectTokenPayload {
+        ReconnectTokenPayload {
+            sub_domain: "foo".to_string(),
+            client_id: ClientId::generate(),
+            expires,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = SigKey::generate();
+        let payload = payload(Utc::now() + Duration::minutes(2));
+
+        let token = payload.into_token(&key).unwrap();
+        let verified = ReconnectTokenPayload::verify(token, &key).unwrap();
+        assert_eq!(verified.sub_domain, payload.sub_domain);
+        assert_eq!(verified.client_id, payload.client_id);
+        assert_eq!(verified.expires, payload.expires);
+    }
+
+    #[test]
+    fn other_key_is_invalid_signature() {
+        let token = payload(Utc::now() + Duration::minutes(2))
+            .into_token(&SigKey::generate())
+            .unwrap();
+        assert!(matches!(
+            ReconnectTokenPayload::verify(token, &SigKey::generate()),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn tampered_payload_is_invalid_signature() {
+        let key = SigKey::generate();
+        let token = payload(Utc::now() + Duration::minutes(2))
+            .into_token(&key)
+            .unwrap();
+
+        let mut inner: ReconnectTokenInner =
+            serde_json::from_slice(&base64::decode(token.0.as_str()).unwrap()).unwrap();
+        inner.payload = inner.payload.replace("\"foo\"", "\"bar\"");
+        let token = ReconnectToken(base64::encode(&serde_json::to_vec(&inner).unwrap()));
+
+        assert!(matches!(
+            ReconnectTokenPayload::verify(token, &key),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn expired() {
+        let key = SigKey::generate();
+        let token = payload(Utc::now() - Duration::seconds(1))
+            .into_token(&key)
+            .unwrap();
+        assert!(matches!(
+            ReconnectTokenPayload::verify(token, &key),
+            Err(Error::Expired)
+        ));
+    }
+
+    #[test]
+    fn garbage() {
+        let key = SigKey::generate();
+        assert!(matches!(
+            ReconnectTokenPayload::verify(ReconnectToken("not base64!".to_string()), &key),
+            Err(Error::Base64(_))
+        ));
+        assert!(matches!(
+            ReconnectTokenPayload::verify(ReconnectToken(base64::encode("{}")), &key),
+            Err(Error::Json(_))
+        ));
+    }
+}
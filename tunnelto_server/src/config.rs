@@ -1,6 +1,10 @@
+use crate::acme::{AcmeConfig, CloudflareConfig};
 use crate::auth::SigKey;
+use crate::word_list::WordList;
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::time::Duration;
+use tokio_rustls::TlsAcceptor;
 use uuid::Uuid;
 
 /// Global service configuration
@@ -35,11 +39,85 @@ pub struct Config {
     /// The identifier for this instance of the server
     pub instance_id: String,
 
+    /// The region this instance runs in, told to clients so they can verify their placement
+    pub region: Option<String>,
+
     /// Blocked IP addresses
     pub blocked_ips: Vec<IpAddr>,
 
     /// The host on which we create tunnels on
     pub tunnel_host: String,
+
+    /// Require sub-domains to start with the account's prefix (if it has one)
+    pub enforce_sub_domain_prefix: bool,
+
+    /// Persist per-client bandwidth usage to the db
+    pub record_usage: bool,
+
+    /// How many accepted remote sockets may be processed at once,
+    /// further connections wait in the listen backlog
+    pub max_concurrent_accepts: usize,
+
+    /// Lowercase request headers to strip before forwarding, a trailing `*` matches a prefix:
+    /// i.e:    keep-alive,proxy-*
+    /// `hop-by-hop` expands to the standard hop-by-hop headers
+    pub strip_headers: Vec<String>,
+
+    /// Adjective-noun words for random sub-domains (i.e. happy-otter) instead of alphanumeric ones
+    pub sub_domain_words: Option<WordList>,
+
+    /// The WebSocket subprotocol clients must offer on the control channel.
+    /// Clients offering none predate subprotocols and are still accepted.
+    pub control_subprotocol: String,
+
+    /// Public ports for raw tcp tunnels, one per tunnel, i.e: 20000-20099.
+    /// Raw tcp tunnels are refused when not set.
+    pub tcp_port_range: Option<(u16, u16)>,
+
+    /// The largest data payload clients should put in one packet, advertised in the handshake
+    pub max_frame_size: usize,
+
+    /// How much is read from a remote stream at once, each read becomes at least one data packet.
+    /// Every open stream holds a buffer this large, so 10k streams at 16kb use ~160mb.
+    pub remote_read_buffer_size: usize,
+
+    /// How many packets may queue for a client's websocket, or for a remote stream.
+    /// A slow websocket throttles its streams instead of buffering in memory, while a remote
+    /// stream falling this far behind is closed so it can't hold up the rest of its client.
+    pub channel_capacity: usize,
+
+    /// The most control connections one source ip may open per `handshake_rate_window`,
+    /// unlimited when not set
+    pub handshake_rate_limit: Option<usize>,
+    pub handshake_rate_window: Duration,
+
+    /// Where clients below the minimum protocol version are told to upgrade
+    pub upgrade_url: String,
+
+    /// Remote connections must send a complete request head within this long
+    pub request_head_timeout: Duration,
+
+    /// Bearer token for the admin api (see `admin.rs`), disabled when not set
+    pub admin_token: Option<String>,
+
+    /// Let clients without an auth key open tunnels on random sub-domains,
+    /// i.e. for a self-hosted instance without an auth backend
+    pub allow_anonymous: bool,
+
+    /// Route a customer's own hostnames (see `custom_hosts.rs`), each unknown
+    /// `Host` is looked up in the auth db
+    pub custom_hosts: bool,
+
+    /// A proxy in front of us sets `Fly-Client-IP` / `X-Forwarded-For` (and `X-Forwarded-Proto`),
+    /// otherwise those headers come from the end user and can't be trusted.
+    /// Defaults to on when running on fly.io.
+    pub trusted_proxy: bool,
+
+    /// Terminate tls for remote streams ourselves (see `tls.rs`), instead of behind a proxy
+    pub tls_acceptor: Option<TlsAcceptor>,
+
+    /// Obtain and renew the tls cert for `*.<tunnel_host>` automatically
+    pub acme: Option<AcmeConfig>,
 }
 
 impl Config {
@@ -75,6 +153,79 @@ impl Config {
             .unwrap_or(vec![]);
 
         let tunnel_host = std::env::var("TUNNEL_HOST").unwrap_or("tunnelto.dev".to_string());
+        let enforce_sub_domain_prefix = std::env::var("ENFORCE_SUB_DOMAIN_PREFIX").is_ok();
+        let record_usage = std::env::var("RECORD_USAGE").is_ok();
+        let max_concurrent_accepts = std::env::var("MAX_CONCURRENT_ACCEPTS")
+            .map(|s| {
+                s.parse().unwrap_or_else(|_| {
+                    panic!("invalid ENV MAX_CONCURRENT_ACCEPTS={}", s);
+                })
+            })
+            .unwrap_or(1024);
+        let strip_headers = std::env::var("STRIP_HEADERS")
+            .map(|s| parse_strip_headers(&s))
+            .unwrap_or(vec![]);
+        let sub_domain_words = std::env::var("SUB_DOMAIN_WORDS_FILE")
+            .ok()
+            .map(|path| WordList::load(&path));
+        let tcp_port_range = std::env::var("TCP_PORT_RANGE").ok().map(|s| {
+            parse_port_range(&s).unwrap_or_else(|| panic!("invalid ENV TCP_PORT_RANGE={}", s))
+        });
+        let max_frame_size = std::env::var("MAX_FRAME_SIZE")
+            .map(|s| match s.parse() {
+                Ok(size) if size > 0 => size,
+                _ => panic!("invalid ENV MAX_FRAME_SIZE={}", s),
+            })
+            .unwrap_or(tunnelto_lib::MAX_FRAME_SIZE);
+        let remote_read_buffer_size = std::env::var("REMOTE_READ_BUFFER_SIZE")
+            .map(|s| match s.parse() {
+                Ok(size) if size > 0 => size,
+                _ => panic!("invalid ENV REMOTE_READ_BUFFER_SIZE={}", s),
+            })
+            .unwrap_or(16 * 1024);
+        let channel_capacity = std::env::var("CHANNEL_CAPACITY")
+            .map(|s| match s.parse() {
+                Ok(capacity) if capacity > 0 => capacity,
+                _ => panic!("invalid ENV CHANNEL_CAPACITY={}", s),
+            })
+            .unwrap_or(32);
+        let handshake_rate_limit = std::env::var("HANDSHAKE_RATE_LIMIT").ok().map(|s| {
+            s.parse()
+                .unwrap_or_else(|_| panic!("invalid ENV HANDSHAKE_RATE_LIMIT={}", s))
+        });
+        let handshake_rate_window = std::env::var("HANDSHAKE_RATE_WINDOW_SECS")
+            .map(|s| {
+                s.parse()
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|_| panic!("invalid ENV HANDSHAKE_RATE_WINDOW_SECS={}", s))
+            })
+            .unwrap_or(Duration::from_secs(60));
+        let request_head_timeout = std::env::var("REQUEST_HEAD_TIMEOUT_SECS")
+            .map(|s| {
+                s.parse()
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|_| panic!("invalid ENV REQUEST_HEAD_TIMEOUT_SECS={}", s))
+            })
+            .unwrap_or(Duration::from_secs(10));
+        let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+        let allow_anonymous = std::env::var("ALLOW_ANONYMOUS")
+            .map(|s| s == "1" || s.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let custom_hosts = std::env::var("CUSTOM_HOSTS")
+            .map(|s| s == "1" || s.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+        let trusted_proxy = match std::env::var("TRUSTED_PROXY") {
+            Ok(s) if s == "1" || s.eq_ignore_ascii_case("true") => true,
+            Ok(s) if s == "0" || s.eq_ignore_ascii_case("false") => false,
+            Ok(s) => panic!("invalid ENV TRUSTED_PROXY={}", s),
+            Err(_) => std::env::var("FLY_APP_NAME").is_ok(),
+        };
+        let upgrade_url =
+            std::env::var("UPGRADE_URL").unwrap_or("https://tunnelto.dev".to_string());
+        let acme = acme_from_env();
+        let tls_acceptor = crate::tls::acceptor_from_env(acme.is_some());
+        let control_subprotocol = std::env::var("CONTROL_SUBPROTOCOL")
+            .unwrap_or(tunnelto_lib::CONTROL_SUBPROTOCOL.to_string());
 
         Config {
             allowed_hosts,
@@ -86,10 +237,107 @@ impl Config {
             gossip_dns_host,
             honeycomb_api_key,
             instance_id,
+            region: std::env::var("FLY_REGION").ok().filter(|r| !r.is_empty()),
             blocked_ips,
             tunnel_host,
+            enforce_sub_domain_prefix,
+            record_usage,
+            max_concurrent_accepts,
+            strip_headers,
+            sub_domain_words,
+            control_subprotocol,
+            tcp_port_range,
+            max_frame_size,
+            remote_read_buffer_size,
+            channel_capacity,
+            handshake_rate_limit,
+            handshake_rate_window,
+            upgrade_url,
+            request_head_timeout,
+            admin_token,
+            allow_anonymous,
+            custom_hosts,
+            trusted_proxy,
+            tls_acceptor,
+            acme,
+        }
+    }
+
+    /// What this server supports, advertised to clients in the handshake
+    pub fn features(&self) -> Vec<String> {
+        let mut features = vec![
+            tunnelto_lib::feature::COMPRESSION.to_string(),
+            tunnelto_lib::feature::SEQUENCED.to_string(),
+            tunnelto_lib::feature::STREAM_END.to_string(),
+        ];
+        if self.tcp_port_range.is_some() {
+            features.push(tunnelto_lib::feature::RAW_TCP.to_string());
+        }
+        features
+    }
+}
+
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-*",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+fn parse_strip_headers(s: &str) -> Vec<String> {
+    let mut headers = vec![];
+    for name in s.split(",").map(|n| n.trim().to_lowercase()) {
+        match name.as_str() {
+            "" => {}
+            "hop-by-hop" => headers.extend(HOP_BY_HOP_HEADERS.iter().map(|h| h.to_string())),
+            _ => headers.push(name),
         }
     }
+    headers
+}
+
+/// i.e: "20000-20099"
+fn parse_port_range(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, '-');
+    let start = parts.next()?.trim().parse().ok()?;
+    let end = parts.next()?.trim().parse().ok()?;
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn acme_from_env() -> Option<AcmeConfig> {
+    let enabled = std::env::var("ACME_ENABLED")
+        .map(|s| s == "1" || s.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    if !enabled {
+        return None;
+    }
+
+    let provider = std::env::var("ACME_DNS_PROVIDER").unwrap_or("cloudflare".to_string());
+    if provider != "cloudflare" {
+        panic!("invalid ENV ACME_DNS_PROVIDER={}", provider);
+    }
+
+    let required = |var: &'static str| {
+        std::env::var(var).unwrap_or_else(|_| panic!("missing ENV {} for ACME_ENABLED", var))
+    };
+
+    Some(AcmeConfig {
+        directory_url: std::env::var("ACME_DIRECTORY_URL")
+            .unwrap_or(instant_acme::LetsEncrypt::Production.url().to_string()),
+        contact_email: std::env::var("ACME_EMAIL").ok(),
+        cache_dir: std::env::var("ACME_CACHE_DIR")
+            .unwrap_or("acme".to_string())
+            .into(),
+        cloudflare: CloudflareConfig {
+            api_token: required("CLOUDFLARE_API_TOKEN"),
+            zone_id: required("CLOUDFLARE_ZONE_ID"),
+        },
+    })
 }
 
 fn get_port(var: &'static str, default: u16) -> u16 {
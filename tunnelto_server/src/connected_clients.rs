@@ -1,5 +1,6 @@
 use super::*;
 use dashmap::DashMap;
+use std::collections::HashSet;
 use std::fmt::Formatter;
 
 #[derive(Clone)]
@@ -7,7 +8,11 @@ pub struct ConnectedClient {
     pub id: ClientId,
     pub host: String,
     pub is_anonymous: bool,
-    pub tx: UnboundedSender<ControlPacket>,
+    /// the account of an authenticated client
+    pub account_id: Option<String>,
+    /// how to write packets for this client
+    pub format: WireFormat,
+    pub tx: Sender<ControlPacket>,
 }
 
 impl std::fmt::Debug for ConnectedClient {
@@ -23,6 +28,8 @@ impl std::fmt::Debug for ConnectedClient {
 pub struct Connections {
     clients: Arc<DashMap<ClientId, ConnectedClient>>,
     hosts: Arc<DashMap<String, ConnectedClient>>,
+    /// the hosts each account has a tunnel on
+    accounts: Arc<DashMap<String, HashSet<String>>>,
 }
 
 impl Connections {
@@ -30,6 +37,7 @@ impl Connections {
         Self {
             clients: Arc::new(DashMap::new()),
             hosts: Arc::new(DashMap::new()),
+            accounts: Arc::new(DashMap::new()),
         }
     }
 
@@ -40,19 +48,35 @@ impl Connections {
     }
 
     pub fn remove(client: &ConnectedClient) {
-        client.tx.close_channel();
+        Self::disconnect(client, false)
+    }
+
+    fn disconnect(client: &ConnectedClient, keep_account_slot: bool) {
+        // closes the channel for every clone of the sender
+        client.tx.clone().close_channel();
 
-        // ensure another client isn't using this host
+        // ensure another client (or a newer connection of this client) isn't using this host
         if CONNECTIONS
             .hosts
             .get(&client.host)
-            .map_or(false, |c| c.id == client.id)
+            .map_or(false, |c| c.tx.same_receiver(&client.tx))
         {
             tracing::debug!("dropping sub-domain: {}", &client.host);
             CONNECTIONS.hosts.remove(&client.host);
+
+            if let Some(account_id) = client.account_id.as_ref().filter(|_| !keep_account_slot) {
+                if let Some(mut hosts) = CONNECTIONS.accounts.get_mut(account_id) {
+                    hosts.remove(&client.host);
+                }
+                CONNECTIONS
+                    .accounts
+                    .remove_if(account_id, |_, hosts| hosts.is_empty());
+            }
         };
 
-        CONNECTIONS.clients.remove(&client.id);
+        CONNECTIONS
+            .clients
+            .remove_if(&client.id, |_, c| c.tx.same_receiver(&client.tx));
         tracing::debug!("rm client: {}", &client.id);
 
         // // drop all the streams
@@ -80,10 +104,215 @@ impl Connections {
         CONNECTIONS.hosts.get(host).map(|c| c.value().clone())
     }
 
+    /// Disconnect a stale tunnel so a new connection can take over its host. When the new
+    /// connection is of the same account its slot for the host stays taken.
+    pub fn evict(stale: &ConnectedClient, taken_by: Option<&str>) {
+        tracing::info!(client_id=%stale.id, host=%stale.host, "evicting stale tunnel");
+        let same_account = taken_by.is_some() && stale.account_id.as_deref() == taken_by;
+        Self::disconnect(stale, same_account);
+    }
+
+    /// The hosts the account currently has tunnels on (or slots reserved for)
+    pub fn account_hosts(account_id: &str) -> HashSet<String> {
+        CONNECTIONS
+            .accounts
+            .get(account_id)
+            .map(|hosts| hosts.value().clone())
+            .unwrap_or_default()
+    }
+
+    /// Take one of the account's `limit` tunnel slots for `host`, false if they're all taken.
+    /// Checked and taken under the account's entry, so concurrent handshakes can't both
+    /// get the last one. A host the account already holds (i.e. a reconnect or takeover)
+    /// takes no other slot.
+    pub fn reserve_account_slot(account_id: &str, host: &str, limit: usize) -> bool {
+        let reserved = {
+            let mut hosts = CONNECTIONS
+                .accounts
+                .entry(account_id.to_string())
+                .or_default();
+            if hosts.contains(host) {
+                true
+            } else if hosts.len() >= limit {
+                false
+            } else {
+                hosts.insert(host.to_string());
+                true
+            }
+        };
+
+        if !reserved {
+            CONNECTIONS
+                .accounts
+                .remove_if(account_id, |_, hosts| hosts.is_empty());
+        }
+        reserved
+    }
+
+    /// Give back a slot from `reserve_account_slot` when the tunnel didn't open after all,
+    /// unless a tunnel of the account is on the host
+    pub fn release_account_slot(account_id: &str, host: &str) {
+        let in_use = CONNECTIONS
+            .hosts
+            .get(host)
+            .map_or(false, |c| c.account_id.as_deref() == Some(account_id));
+        if in_use {
+            return;
+        }
+
+        if let Some(mut hosts) = CONNECTIONS.accounts.get_mut(account_id) {
+            hosts.remove(host);
+        }
+        CONNECTIONS
+            .accounts
+            .remove_if(account_id, |_, hosts| hosts.is_empty());
+    }
+
+    /// Re-claim the client's host, unless a newer tunnel (i.e. a takeover or a reconnect
+    /// of the same client) has taken it over since
+    pub fn refresh(client: &ConnectedClient) {
+        let is_current = |c: &ConnectedClient| c.id == client.id && c.tx.same_receiver(&client.tx);
+
+        let owns_host = CONNECTIONS
+            .hosts
+            .get(&client.host)
+            .map_or(true, |c| is_current(c.value()));
+        if !owns_host {
+            tracing::debug!(client_id=%client.id, host=%client.host, "not refreshing a taken over host");
+            return;
+        }
+
+        let owns_id = CONNECTIONS
+            .clients
+            .get(&client.id)
+            .map_or(true, |c| is_current(c.value()));
+        if !owns_id {
+            return;
+        }
+
+        Self::add(client.clone());
+    }
+
     pub fn add(client: ConnectedClient) {
+        if let Some(account_id) = &client.account_id {
+            CONNECTIONS
+                .accounts
+                .entry(account_id.clone())
+                .or_default()
+                .insert(client.host.clone());
+        }
         CONNECTIONS
             .clients
             .insert(client.id.clone(), client.clone());
         CONNECTIONS.hosts.insert(client.host.clone(), client);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(host: &str, account_id: &str) -> ConnectedClient {
+        let (tx, _rx) = channel(8);
+        ConnectedClient {
+            id: ClientId::generate(),
+            host: host.to_string(),
+            is_anonymous: false,
+            account_id: Some(account_id.to_string()),
+            format: WireFormat::default(),
+            tx,
+        }
+    }
+
+    #[test]
+    fn account_slots_are_limited() {
+        let account = "account-slots-are-limited";
+        assert!(Connections::reserve_account_slot(account, "slots-a", 2));
+        assert!(Connections::reserve_account_slot(account, "slots-b", 2));
+        assert!(!Connections::reserve_account_slot(account, "slots-c", 2));
+        assert!(Connections::reserve_account_slot(account, "slots-a", 2));
+
+        Connections::release_account_slot(account, "slots-a");
+        Connections::release_account_slot(account, "slots-b");
+        assert!(Connections::account_hosts(account).is_empty());
+        assert!(!CONNECTIONS.accounts.contains_key(account));
+
+        // no empty entry is left behind by a rejection
+        assert!(!Connections::reserve_account_slot(account, "slots-a", 0));
+        assert!(!CONNECTIONS.accounts.contains_key(account));
+    }
+
+    #[test]
+    fn connected_host_keeps_its_slot() {
+        let account = "connected-host-keeps-its-slot";
+        let connected = client("slots-connected", account);
+        Connections::add(connected.clone());
+
+        Connections::release_account_slot(account, "slots-connected");
+        assert!(Connections::account_hosts(account).contains("slots-connected"));
+
+        // taken over by the same account
+        Connections::evict(&connected, Some(account));
+        assert!(Connections::account_hosts(account).contains("slots-connected"));
+        Connections::release_account_slot(account, "slots-connected");
+        assert!(Connections::account_hosts(account).is_empty());
+    }
+
+    #[test]
+    fn evicted_by_other_account_frees_the_slot() {
+        let account = "evicted-by-other-account";
+        let connected = client("slots-other", account);
+        Connections::add(connected.clone());
+
+        Connections::evict(&connected, Some("someone-else"));
+        assert!(Connections::account_hosts(account).is_empty());
+    }
+
+    #[test]
+    fn refresh_reclaims_a_dropped_host() {
+        let client = client("refresh-reclaims", "refresh-reclaims-account");
+        Connections::refresh(&client);
+        assert!(Connections::find_by_host(&client.host)
+            .map_or(false, |c| c.tx.same_receiver(&client.tx)));
+        assert!(Connections::get(&client.id).is_some());
+
+        Connections::remove(&client);
+    }
+
+    #[test]
+    fn refresh_leaves_a_taken_over_host() {
+        let stale = client("refresh-taken-over", "refresh-taken-over-account");
+        Connections::add(stale.clone());
+
+        let newer = client("refresh-taken-over", "refresh-taken-over-account");
+        Connections::evict(&stale, newer.account_id.as_deref());
+        Connections::add(newer.clone());
+
+        // a late pong of the stale tunnel
+        Connections::refresh(&stale);
+        assert!(
+            Connections::find_by_host(&stale.host).map_or(false, |c| c.tx.same_receiver(&newer.tx))
+        );
+        assert!(Connections::get(&stale.id).is_none());
+
+        Connections::remove(&newer);
+    }
+
+    #[test]
+    fn refresh_leaves_a_reconnected_client_id() {
+        let stale = client("refresh-reconnected-old", "refresh-reconnected-account");
+        Connections::add(stale.clone());
+
+        // the same client reconnected on another host
+        let mut newer = client("refresh-reconnected-new", "refresh-reconnected-account");
+        newer.id = stale.id.clone();
+        Connections::remove(&stale);
+        Connections::add(newer.clone());
+
+        Connections::refresh(&stale);
+        assert!(Connections::find_by_host(&stale.host).is_none());
+        assert!(Connections::get(&stale.id).map_or(false, |c| c.tx.same_receiver(&newer.tx)));
+
+        Connections::remove(&newer);
+    }
+}
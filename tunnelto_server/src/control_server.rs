@@ -4,30 +4,77 @@ use crate::client_auth::ClientHandshake;
 use chrono::Utc;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 use tracing::{error, Instrument};
-use warp::Rejection;
+use warp::{Rejection, Reply};
 
 pub fn spawn<A: Into<SocketAddr>>(addr: A) {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Health Check #2 triggered");
         "ok"
     });
-    let client_conn = warp::path("wormhole").and(client_ip()).and(warp::ws()).map(
-        move |client_ip: IpAddr, ws: Ws| {
-            ws.on_upgrade(move |w| {
-                async move { handle_new_connection(client_ip, w).await }
-                    .instrument(observability::remote_trace("handle_websocket"))
-            })
-        },
-    );
+    let client_conn = warp::path("wormhole")
+        .and(client_ip())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::ws())
+        .map(
+            move |client_ip: IpAddr, protocols: Option<String>, ws: Ws| {
+                let subprotocol = match negotiate_subprotocol(protocols.as_deref()) {
+                    Ok(subprotocol) => subprotocol,
+                    Err(_) => {
+                        tracing::info!(?protocols, "client offered no supported subprotocol");
+                        return warp::reply::with_status(
+                            "unsupported websocket subprotocol",
+                            warp::http::StatusCode::BAD_REQUEST,
+                        )
+                        .into_response();
+                    }
+                };
+
+                let reply = ws.on_upgrade(move |w| {
+                    async move { handle_new_connection(client_ip, w).await }
+                        .instrument(observability::remote_trace("handle_websocket"))
+                });
+                match subprotocol {
+                    Some(subprotocol) => {
+                        warp::reply::with_header(reply, "sec-websocket-protocol", subprotocol)
+                            .into_response()
+                    }
+                    None => reply.into_response(),
+                }
+            },
+        );
 
-    let routes = client_conn.or(health_check);
+    let sub_domain_check = warp::post()
+        .and(warp::path("subdomain_check"))
+        .and(warp::body::json())
+        .and_then(handle_sub_domain_check);
+
+    let routes = client_conn
+        .or(health_check)
+        .or(sub_domain_check)
+        .or(crate::admin::routes());
 
     // spawn our websocket control server
     tokio::spawn(warp::serve(routes).run(addr.into()));
 }
 
+/// Pick our subprotocol from the client's offer, clients from before subprotocols offer none
+fn negotiate_subprotocol(offered: Option<&str>) -> Result<Option<String>, ()> {
+    let offered = match offered {
+        Some(offered) => offered,
+        None => return Ok(None),
+    };
+
+    offered
+        .split(',')
+        .map(str::trim)
+        .find(|protocol| *protocol == CONFIG.control_subprotocol)
+        .map(|protocol| Some(protocol.to_string()))
+        .ok_or(())
+}
+
 fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
     warp::any()
         .and(warp::header::optional("Fly-Client-IP"))
@@ -35,26 +82,56 @@ fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
         .and(warp::addr::remote())
         .map(
             |client_ip: Option<String>, fwd: Option<String>, remote: Option<SocketAddr>| {
-                let client_ip = client_ip.map(|s| IpAddr::from_str(&s).ok()).flatten();
-                let fwd = fwd
-                    .map(|s| {
-                        s.split(",")
-                            .into_iter()
-                            .next()
-                            .map(IpAddr::from_str)
-                            .map(Result::ok)
-                            .flatten()
-                    })
-                    .flatten();
-                let remote = remote.map(|r| r.ip());
-                client_ip
-                    .or(fwd)
-                    .or(remote)
-                    .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+                source_ip(
+                    CONFIG.trusted_proxy,
+                    client_ip.as_deref(),
+                    fwd.as_deref(),
+                    remote,
+                )
             },
         )
 }
 
+/// The ip a control connection comes from. The proxy headers are only
+/// believed from a trusted proxy, anyone else could pick their ip (i.e. to
+/// dodge the handshake rate limit or the block list).
+fn source_ip(
+    trusted_proxy: bool,
+    client_ip: Option<&str>,
+    fwd: Option<&str>,
+    remote: Option<SocketAddr>,
+) -> IpAddr {
+    let remote = remote.map(|r| r.ip());
+    if !trusted_proxy {
+        return remote.unwrap_or(IpAddr::from([0, 0, 0, 0]));
+    }
+
+    let client_ip = client_ip.and_then(|s| IpAddr::from_str(s.trim()).ok());
+    // the proxy appends the address it saw, earlier hops are whatever the client sent
+    let fwd = fwd
+        .and_then(|s| s.rsplit(',').next())
+        .and_then(|s| IpAddr::from_str(s.trim()).ok());
+    client_ip
+        .or(fwd)
+        .or(remote)
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+}
+
+async fn handle_sub_domain_check(query: SubDomainCheck) -> Result<impl warp::Reply, Rejection> {
+    let mut available = vec![];
+    for sub_domain in query
+        .sub_domains
+        .into_iter()
+        .take(SubDomainCheck::MAX_SUB_DOMAINS)
+    {
+        if client_auth::is_sub_domain_available(&sub_domain, query.key.as_ref()).await {
+            available.push(sub_domain);
+        }
+    }
+
+    Ok(warp::reply::json(&SubDomainCheckResult { available }))
+}
+
 #[tracing::instrument(skip(websocket))]
 async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
     // check if this client is blocked
@@ -64,22 +141,43 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
         return;
     }
 
-    let (websocket, handshake) = match try_client_handshake(websocket).await {
+    if !rate_limit::allow_handshake(client_ip) {
+        tracing::warn!(
+            ?client_ip,
+            "too many connections from client ip, denying connection"
+        );
+        let _ = websocket.close().await;
+        return;
+    }
+
+    let (websocket, handshake, raw_listener) = match try_client_handshake(websocket).await {
         Some(ws) => ws,
         None => return,
     };
 
     tracing::info!(client_ip=%client_ip, subdomain=%handshake.sub_domain, "open tunnel");
 
-    let (tx, rx) = unbounded::<ControlPacket>();
+    let (tx, rx) = channel::<ControlPacket>(CONFIG.channel_capacity);
     let mut client = ConnectedClient {
         id: handshake.id,
         host: handshake.sub_domain,
         is_anonymous: handshake.is_anonymous,
+        account_id: handshake.account_id,
+        format: handshake.format,
         tx,
     };
     Connections::add(client.clone());
 
+    if let Some((port, listener)) = raw_listener {
+        let client = client.clone();
+        tokio::spawn(
+            async move {
+                raw_tcp::serve(port, listener, client).await;
+            }
+            .instrument(observability::remote_trace("raw_tcp")),
+        );
+    }
+
     let (sink, stream) = websocket.split();
 
     let client_clone = client.clone();
@@ -106,21 +204,7 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
             loop {
                 tracing::trace!("sending ping");
 
-                // create a new reconnect token for anonymous clients
-                let reconnect_token = if client.is_anonymous {
-                    ReconnectTokenPayload {
-                        sub_domain: client.host.clone(),
-                        client_id: client.id.clone(),
-                        expires: Utc::now() + chrono::Duration::minutes(2),
-                    }
-                    .into_token(&CONFIG.master_sig_key)
-                    .map_err(|e| error!("unable to create reconnect token: {:?}", e))
-                    .ok()
-                } else {
-                    None
-                };
-
-                match client.tx.send(ControlPacket::Ping(reconnect_token)).await {
+                match client.tx.send(client_ping(&client)).await {
                     Ok(_) => {}
                     Err(e) => {
                         tracing::debug!("Failed to send ping: {:?}, removing client", e);
@@ -136,22 +220,72 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
     );
 }
 
+/// A ping, carrying a new reconnect token for anonymous clients
+pub fn client_ping(client: &ConnectedClient) -> ControlPacket {
+    let reconnect_token = if client.is_anonymous {
+        ReconnectTokenPayload {
+            sub_domain: client.host.clone(),
+            client_id: client.id.clone(),
+            expires: Utc::now() + chrono::Duration::minutes(2),
+        }
+        .into_token(&CONFIG.master_sig_key)
+        .map_err(|e| error!("unable to create reconnect token: {:?}", e))
+        .ok()
+    } else {
+        None
+    };
+
+    ControlPacket::Ping(reconnect_token, Some(PingTimestamp::now()))
+}
+
 #[tracing::instrument(skip(websocket))]
-async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, ClientHandshake)> {
+async fn try_client_handshake(
+    websocket: WebSocket,
+) -> Option<(WebSocket, ClientHandshake, Option<raw_tcp::RawListener>)> {
     // Authenticate client handshake
     let (mut websocket, client_handshake) = client_auth::auth_client_handshake(websocket).await?;
 
+    // raw tcp tunnels get their own public port
+    let raw_listener = if client_handshake.tcp {
+        match raw_tcp::bind(client_handshake.requested_port).await {
+            Some(bound) => Some(bound),
+            None => {
+                tracing::info!(requested_port=?client_handshake.requested_port, "no raw tcp port");
+                let error = match (CONFIG.tcp_port_range, client_handshake.requested_port) {
+                    (None, _) => "This server does not support raw tcp tunnels.".to_string(),
+                    (Some(_), Some(port)) => format!("Port {} is not available.", port),
+                    (Some(_), None) => "No raw tcp ports are available.".to_string(),
+                };
+                let data = serde_json::to_vec(&ServerHello::Error(error)).unwrap_or_default();
+                let _ = websocket.send(Message::binary(data)).await;
+                release_account_slot(&client_handshake);
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+
     // Send server hello success
     let data = serde_json::to_vec(&ServerHello::Success {
         sub_domain: client_handshake.sub_domain.clone(),
         hostname: format!("{}.{}", &client_handshake.sub_domain, CONFIG.tunnel_host),
         client_id: client_handshake.id.clone(),
+        reserved_until: client_handshake.reserved_until,
+        compression: client_handshake.format.compression,
+        protocol_version: PROTOCOL_VERSION,
+        remote_port: raw_listener.as_ref().map(|(port, _)| *port),
+        max_frame_size: CONFIG.max_frame_size,
+        features: Some(CONFIG.features()),
+        instance_id: Some(CONFIG.instance_id.clone()),
+        region: CONFIG.region.clone(),
     })
     .unwrap_or_default();
 
     let send_result = websocket.send(Message::binary(data)).await;
     if let Err(error) = send_result {
         error!(?error, "aborting...failed to write server hello");
+        release_account_slot(&client_handshake);
         return None;
     }
 
@@ -164,7 +298,14 @@ async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, Client
             ""
         }
     );
-    Some((websocket, client_handshake))
+    Some((websocket, client_handshake, raw_listener))
+}
+
+/// The tunnel won't open after all, free the account's slot `auth_client` reserved for it
+fn release_account_slot(handshake: &ClientHandshake) {
+    if let Some(account_id) = &handshake.account_id {
+        Connections::release_account_slot(account_id, &handshake.sub_domain);
+    }
 }
 
 /// Send the client a "stream init" message
@@ -187,7 +328,12 @@ pub async fn send_client_stream_init(mut stream: ActiveStream) {
 
 /// Process client control messages
 #[tracing::instrument(skip(client_conn))]
-async fn process_client_messages(client: ConnectedClient, mut client_conn: SplitStream<WebSocket>) {
+async fn process_client_messages<S>(client: ConnectedClient, mut client_conn: S)
+where
+    S: futures::Stream<Item = Result<Message, warp::Error>> + Unpin,
+{
+    let mut malformed = MalformedPackets::default();
+
     loop {
         let result = client_conn.next().await;
 
@@ -213,26 +359,62 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
             Ok(packet) => packet,
             Err(error) => {
                 error!(?error, "invalid data packet");
+                if malformed.exceeded() {
+                    tracing::warn!(?client.id, "too many malformed packets, dropping client");
+                    Connections::remove(&client);
+                    return;
+                }
                 continue;
             }
         };
 
         let (stream_id, message) = match packet {
-            ControlPacket::Data(stream_id, data) => {
+            // an empty payload is a keep-alive, it must not look like an eof to the stream
+            ControlPacket::Data(_, _, data) if data.is_empty() => continue,
+            ControlPacket::Data(stream_id, seq, data) => {
                 tracing::debug!(?stream_id, num_bytes=?data.len(),"forwarding to stream");
-                (stream_id, StreamMessage::Data(data))
+                if CONFIG.record_usage {
+                    crate::usage::record(&client.id, data.len());
+                }
+                (stream_id, StreamMessage::Data(seq, data))
             }
             ControlPacket::Refused(stream_id) => {
                 tracing::debug!("tunnel says: refused");
                 (stream_id, StreamMessage::TunnelRefused)
             }
-            ControlPacket::Init(_) | ControlPacket::End(_) => {
+            ControlPacket::End(stream_id) => {
+                tracing::debug!(?stream_id, "tunnel says: end");
+                (stream_id, StreamMessage::End)
+            }
+            ControlPacket::Init(_) => {
                 error!("invalid protocol control::init message");
                 continue;
             }
-            ControlPacket::Ping(_) => {
-                tracing::trace!("pong");
-                Connections::add(client.clone());
+            ControlPacket::EndOfMessage(stream_id) => {
+                tracing::trace!(?stream_id, "end of message");
+                continue;
+            }
+            ControlPacket::Probe(timestamp) => {
+                let _ = client
+                    .tx
+                    .clone()
+                    .send(ControlPacket::ProbeReply(timestamp))
+                    .await;
+                continue;
+            }
+            ControlPacket::ProbeReply(_) => {
+                error!("invalid protocol control::probe_reply message");
+                continue;
+            }
+            ControlPacket::Ping(_, timestamp) => {
+                match timestamp {
+                    Some(timestamp) => tracing::debug!(rtt_ms=%timestamp.rtt().as_millis(), "pong"),
+                    None => tracing::trace!("pong"),
+                }
+                // an evicted tunnel must not re-claim its host
+                if !client.tx.is_closed() {
+                    Connections::refresh(&client);
+                }
                 continue;
             }
         };
@@ -240,26 +422,79 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
         let stream = ACTIVE_STREAMS.get(&stream_id).map(|s| s.value().clone());
 
         if let Some(mut stream) = stream {
-            let _ = stream.tx.send(message).await.map_err(|error| {
-                tracing::trace!(?error, "Failed to send to stream tx");
-            });
+            match stream.tx.try_send(message) {
+                Ok(()) => {}
+                // waiting on one slow end user would stall every stream of this client
+                Err(error) if error.is_full() => {
+                    tracing::warn!(?stream_id, "stream fell behind, closing it");
+                    close_stalled_stream(&client, &stream).await;
+                }
+                Err(error) => tracing::trace!(?error, "Failed to send to stream tx"),
+            }
         }
     }
 }
 
+/// Close a stream whose queue is full, the end user gets what was already queued
+async fn close_stalled_stream(client: &ConnectedClient, stream: &ActiveStream) {
+    ACTIVE_STREAMS.remove(&stream.id);
+    stream.tx.clone().close_channel();
+    let _ = client
+        .tx
+        .clone()
+        .send(ControlPacket::End(stream.id.clone()))
+        .await;
+}
+
+/// A client sending more malformed packets than this per window is dropped
+const MAX_MALFORMED_PACKETS: usize = 10;
+const MALFORMED_PACKETS_WINDOW: Duration = Duration::from_secs(60);
+
+/// Counts a client's malformed packets over a fixed window
+#[derive(Default)]
+struct MalformedPackets {
+    count: usize,
+    window_start: Option<std::time::Instant>,
+}
+
+impl MalformedPackets {
+    /// Count one, true once the client sent too many
+    fn exceeded(&mut self) -> bool {
+        let now = std::time::Instant::now();
+        match self.window_start {
+            Some(start) if now.duration_since(start) < MALFORMED_PACKETS_WINDOW => self.count += 1,
+            _ => {
+                self.window_start = Some(now);
+                self.count = 1;
+            }
+        }
+        self.count > MAX_MALFORMED_PACKETS
+    }
+}
+
 #[tracing::instrument(skip(sink, queue))]
 async fn tunnel_client(
     client: ConnectedClient,
     mut sink: SplitSink<WebSocket, Message>,
-    mut queue: UnboundedReceiver<ControlPacket>,
+    mut queue: Receiver<ControlPacket>,
 ) {
     loop {
         match queue.next().await {
             Some(packet) => {
-                let result = sink.send(Message::binary(packet.serialize())).await;
+                let stream_id = lost_stream_id(&packet);
+                let result = sink
+                    .send(Message::binary(packet.encode(client.format)))
+                    .await;
                 if let Err(error) = result {
                     tracing::trace!(?error, "client disconnected: aborting.");
                     Connections::remove(&client);
+
+                    // neither this packet nor the ones queued behind it reach the client
+                    mark_packets_lost(stream_id);
+                    queue.close();
+                    while let Ok(Some(packet)) = queue.try_next() {
+                        mark_packets_lost(lost_stream_id(&packet));
+                    }
                     return;
                 }
             }
@@ -270,3 +505,189 @@ async fn tunnel_client(
         };
     }
 }
+
+/// The stream left with a gap if this packet never reaches the client
+fn lost_stream_id(packet: &ControlPacket) -> Option<StreamId> {
+    match packet {
+        ControlPacket::Data(_, _, data) if data.is_empty() => None,
+        ControlPacket::Init(stream_id)
+        | ControlPacket::Data(stream_id, _, _)
+        | ControlPacket::End(stream_id) => Some(stream_id.clone()),
+        _ => None,
+    }
+}
+
+/// See `StreamState::packets_lost`
+fn mark_packets_lost(stream_id: Option<StreamId>) {
+    if let Some(stream) = stream_id.and_then(|id| ACTIVE_STREAMS.get(&id)) {
+        stream.state.packets_lost.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_packets_exceeded_after_max() {
+        let mut malformed = MalformedPackets::default();
+        for _ in 0..MAX_MALFORMED_PACKETS {
+            assert!(!malformed.exceeded());
+        }
+        assert!(malformed.exceeded());
+    }
+
+    #[test]
+    fn malformed_packets_window_resets() {
+        let mut malformed = MalformedPackets::default();
+        for _ in 0..MAX_MALFORMED_PACKETS {
+            assert!(!malformed.exceeded());
+        }
+
+        // the window started long enough ago
+        malformed.window_start = std::time::Instant::now().checked_sub(MALFORMED_PACKETS_WINDOW);
+        assert!(!malformed.exceeded());
+        assert_eq!(malformed.count, 1);
+    }
+
+    #[test]
+    fn lost_packets_mark_their_stream() {
+        let (tx, _rx) = channel(8);
+        let client = ConnectedClient {
+            id: ClientId::generate(),
+            host: "lost-packets".to_string(),
+            is_anonymous: true,
+            account_id: None,
+            format: WireFormat::default(),
+            tx,
+        };
+        let (stream, _stream_rx) = ActiveStream::new(client);
+        ACTIVE_STREAMS.insert(stream.id.clone(), stream.clone());
+
+        // keep-alives and pings leave no gap
+        let keep_alive = ControlPacket::Data(stream.id.clone(), None, vec![]);
+        assert!(lost_stream_id(&keep_alive).is_none());
+        assert!(lost_stream_id(&ControlPacket::Ping(None, None)).is_none());
+        mark_packets_lost(lost_stream_id(&keep_alive));
+        assert!(!stream.state.packets_lost.load(Ordering::SeqCst));
+
+        let data = ControlPacket::Data(stream.id.clone(), Some(3), b"hi".to_vec());
+        mark_packets_lost(lost_stream_id(&data));
+        assert!(stream.state.packets_lost.load(Ordering::SeqCst));
+
+        ACTIVE_STREAMS.remove(&stream.id);
+    }
+
+    #[tokio::test]
+    async fn stalled_stream_is_closed_alone() {
+        let (tx, mut client_rx) = channel(8);
+        let client = ConnectedClient {
+            id: ClientId::generate(),
+            host: "stalled-stream".to_string(),
+            is_anonymous: true,
+            account_id: None,
+            format: WireFormat::default(),
+            tx,
+        };
+        let (stalled, mut stalled_rx) = ActiveStream::new(client.clone());
+        let (other, _other_rx) = ActiveStream::new(client.clone());
+        ACTIVE_STREAMS.insert(stalled.id.clone(), stalled.clone());
+        ACTIVE_STREAMS.insert(other.id.clone(), other.clone());
+
+        close_stalled_stream(&client, &stalled).await;
+        assert!(!ACTIVE_STREAMS.contains_key(&stalled.id));
+        assert!(ACTIVE_STREAMS.contains_key(&other.id));
+        assert!(stalled_rx.next().await.is_none());
+        assert!(matches!(
+            client_rx.next().await,
+            Some(ControlPacket::End(id)) if id == stalled.id
+        ));
+
+        ACTIVE_STREAMS.remove(&other.id);
+    }
+
+    #[test]
+    fn subprotocol_negotiation() {
+        let ours = tunnelto_lib::CONTROL_SUBPROTOCOL;
+
+        // clients from before subprotocols
+        assert_eq!(negotiate_subprotocol(None), Ok(None));
+
+        assert_eq!(
+            negotiate_subprotocol(Some(ours)),
+            Ok(Some(ours.to_string()))
+        );
+        assert_eq!(
+            negotiate_subprotocol(Some(&format!("chat, {} ,other", ours))),
+            Ok(Some(ours.to_string()))
+        );
+        assert_eq!(negotiate_subprotocol(Some("chat, other")), Err(()));
+        assert_eq!(negotiate_subprotocol(Some("")), Err(()));
+    }
+
+    #[tokio::test]
+    async fn empty_data_is_a_keep_alive() {
+        let (tx, _client_rx) = channel(8);
+        let client = ConnectedClient {
+            id: ClientId::generate(),
+            host: "keep-alive".to_string(),
+            is_anonymous: true,
+            account_id: None,
+            format: WireFormat::default(),
+            tx,
+        };
+        let (stream, mut stream_rx) = ActiveStream::new(client.clone());
+        ACTIVE_STREAMS.insert(stream.id.clone(), stream.clone());
+
+        let packets = vec![
+            ControlPacket::Data(stream.id.clone(), None, vec![]),
+            ControlPacket::Data(stream.id.clone(), None, b"hi".to_vec()),
+            ControlPacket::Data(stream.id.clone(), None, vec![]),
+        ];
+        let messages = packets
+            .into_iter()
+            .map(|packet| Ok::<_, warp::Error>(Message::binary(packet.serialize())));
+        process_client_messages(client, futures::stream::iter(messages)).await;
+
+        // only the data reached the stream, and nothing ended it
+        assert!(matches!(
+            stream_rx.try_next(),
+            Ok(Some(StreamMessage::Data(None, data))) if data == b"hi"
+        ));
+        assert!(stream_rx.try_next().is_err());
+        assert!(ACTIVE_STREAMS.contains_key(&stream.id));
+
+        ACTIVE_STREAMS.remove(&stream.id);
+    }
+
+    #[test]
+    fn source_ip_ignores_proxy_headers_unless_trusted() {
+        let remote = Some(SocketAddr::from(([10, 0, 0, 1], 4000)));
+        assert_eq!(
+            source_ip(false, Some("1.1.1.1"), Some("2.2.2.2"), remote),
+            IpAddr::from([10, 0, 0, 1])
+        );
+        assert_eq!(
+            source_ip(false, None, None, None),
+            IpAddr::from([0, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn source_ip_from_trusted_proxy() {
+        let remote = Some(SocketAddr::from(([10, 0, 0, 1], 4000)));
+        assert_eq!(
+            source_ip(true, Some("1.1.1.1"), Some("2.2.2.2"), remote),
+            IpAddr::from([1, 1, 1, 1])
+        );
+        // a forged first hop is skipped for the one the proxy appended
+        assert_eq!(
+            source_ip(true, None, Some("6.6.6.6, 2.2.2.2"), remote),
+            IpAddr::from([2, 2, 2, 2])
+        );
+        assert_eq!(
+            source_ip(true, Some("garbage"), None, remote),
+            IpAddr::from([10, 0, 0, 1])
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{AUTH_DB_SERVICE, CONFIG};
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+/// How long a looked up custom hostname (or its absence) is trusted
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How often expired lookups are dropped from the cache
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Unknown hostnames remembered at most, past that random traffic is looked up every time
+const MAX_UNKNOWN_HOSTS: usize = 10_000;
+
+lazy_static::lazy_static! {
+    /// custom hostname => sub-domain
+    static ref CACHE: DashMap<String, (String, Instant)> = DashMap::new();
+    /// hostnames that aren't mapped
+    static ref UNKNOWN: DashMap<String, Instant> = DashMap::new();
+}
+
+/// The sub-domain a customer's own hostname (i.e. `dash.theircompany.com`) routes to
+pub async fn resolve(host: &str) -> Option<String> {
+    if !CONFIG.custom_hosts {
+        return None;
+    }
+
+    let hostname = crate::remote::parse_host(host)?.to_lowercase();
+
+    if let Some(cached) = CACHE.get(&hostname) {
+        let (sub_domain, at) = cached.value();
+        if at.elapsed() < CACHE_TTL {
+            return Some(sub_domain.clone());
+        }
+    }
+    if let Some(at) = UNKNOWN.get(&hostname) {
+        if at.elapsed() < CACHE_TTL {
+            return None;
+        }
+    }
+
+    let sub_domain = match AUTH_DB_SERVICE.get_sub_domain_for_hostname(&hostname).await {
+        Ok(sub_domain) => sub_domain,
+        Err(error) => {
+            tracing::error!(?error, %hostname, "failed to look up custom hostname");
+            return None;
+        }
+    };
+
+    tracing::debug!(%hostname, ?sub_domain, "looked up custom hostname");
+    remember(hostname, sub_domain.clone(), Instant::now());
+    sub_domain
+}
+
+fn remember(hostname: String, sub_domain: Option<String>, at: Instant) {
+    match sub_domain {
+        Some(sub_domain) => {
+            UNKNOWN.remove(&hostname);
+            CACHE.insert(hostname, (sub_domain, at));
+        }
+        None => {
+            CACHE.remove(&hostname);
+            if UNKNOWN.len() < MAX_UNKNOWN_HOSTS || UNKNOWN.contains_key(&hostname) {
+                UNKNOWN.insert(hostname, at);
+            }
+        }
+    }
+}
+
+/// Forget a hostname after its mapping changed
+pub fn invalidate(hostname: &str) {
+    CACHE.remove(hostname);
+    UNKNOWN.remove(hostname);
+}
+
+fn prune() {
+    CACHE.retain(|_, (_, at)| at.elapsed() < CACHE_TTL);
+    UNKNOWN.retain(|_, at| at.elapsed() < CACHE_TTL);
+}
+
+/// Periodically drop expired lookups so the cache stays bounded
+pub fn spawn_prune() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            prune();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_lookups_and_caps_unknown_hosts() {
+        let now = Instant::now();
+        let expired = now - CACHE_TTL * 2;
+
+        remember("dash.example.com".into(), Some("dash".into()), now);
+        remember("old.example.com".into(), Some("old".into()), expired);
+        remember("gone.example.com".into(), None, expired);
+        assert_eq!(
+            CACHE.get("dash.example.com").map(|c| c.0.clone()),
+            Some("dash".to_string())
+        );
+
+        prune();
+        assert!(CACHE.contains_key("dash.example.com"));
+        assert!(!CACHE.contains_key("old.example.com"));
+        assert!(!UNKNOWN.contains_key("gone.example.com"));
+
+        for i in 0..MAX_UNKNOWN_HOSTS + 10 {
+            remember(format!("{}.random.example", i), None, now);
+        }
+        assert_eq!(UNKNOWN.len(), MAX_UNKNOWN_HOSTS);
+
+        // becoming known takes a hostname out of the negative cache
+        remember("0.random.example".into(), Some("zero".into()), now);
+        assert!(!UNKNOWN.contains_key("0.random.example"));
+
+        invalidate("dash.example.com");
+        invalidate("0.random.example");
+        assert!(!CACHE.contains_key("dash.example.com"));
+        assert!(!CACHE.contains_key("0.random.example"));
+        UNKNOWN.clear();
+    }
+}
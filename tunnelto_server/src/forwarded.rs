@@ -0,0 +1,270 @@
+/// Add headers to a complete http request head (ending in an empty line),
+/// keeping any the edge proxy in front of us already set
+pub fn inject_headers(head: &[u8], headers: &[(&str, String)]) -> Vec<u8> {
+    let mut lines: Vec<&[u8]> = head.split_inclusive(|b| *b == b'\n').collect();
+
+    // split off the empty line ending the head
+    let end = match lines.pop() {
+        Some(end) if end == b"\r\n" || end == b"\n" => end,
+        Some(end) => {
+            lines.push(end);
+            &b""[..]
+        }
+        None => return head.to_vec(),
+    };
+
+    let mut out = Vec::with_capacity(head.len() + 64);
+    for line in &lines {
+        out.extend_from_slice(line);
+    }
+
+    for (name, value) in headers {
+        if !has_header(lines.get(1..).unwrap_or_default(), name) {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+    }
+
+    out.extend_from_slice(end);
+    out
+}
+
+/// Set headers to our values, dropping any the request already has
+pub fn replace_headers(head: &[u8], headers: &[(&str, String)]) -> Vec<u8> {
+    let names: Vec<String> = headers
+        .iter()
+        .map(|(name, _)| name.to_lowercase())
+        .collect();
+    inject_headers(&strip_headers(head, &names), headers)
+}
+
+/// Append a value to a comma-separated header such as `X-Forwarded-For`,
+/// adding the header if the request doesn't have it yet
+pub fn append_header(head: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let lines: Vec<&[u8]> = head.split_inclusive(|b| *b == b'\n').collect();
+    let position = lines
+        .iter()
+        .skip(1)
+        .position(|line| has_header(&[*line], name))
+        .map(|i| i + 1);
+
+    let position = match position {
+        Some(position) => position,
+        None => return inject_headers(head, &[(name, value.to_string())]),
+    };
+
+    let mut out = Vec::with_capacity(head.len() + value.len() + 2);
+    for (i, line) in lines.iter().enumerate() {
+        if i != position {
+            out.extend_from_slice(line);
+            continue;
+        }
+
+        let existing = String::from_utf8_lossy(&line[name.len() + 1..]);
+        let existing = existing.trim();
+        if existing.is_empty() {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        } else {
+            out.extend_from_slice(format!("{}: {}, {}\r\n", name, existing, value).as_bytes());
+        }
+    }
+    out
+}
+
+/// Remove headers matching `names` (lowercase, a trailing `*` matches a prefix).
+/// `Transfer-Encoding` is always kept as bodies are forwarded as-is, and so are
+/// the websocket upgrade headers, see `tunnelto_lib::PRESERVED_HEADERS`
+pub fn strip_headers(head: &[u8], names: &[String]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(head.len());
+
+    for (i, line) in head.split_inclusive(|b| *b == b'\n').enumerate() {
+        let name = match line.iter().position(|b| *b == b':') {
+            Some(end) if i > 0 => String::from_utf8_lossy(&line[..end]).to_lowercase(),
+            _ => {
+                out.extend_from_slice(line);
+                continue;
+            }
+        };
+
+        let keep = name == "transfer-encoding"
+            || tunnelto_lib::is_preserved_header(&name)
+            || !names.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => &name == pattern,
+            });
+
+        if keep {
+            out.extend_from_slice(line);
+        }
+    }
+
+    out
+}
+
+fn has_header(lines: &[&[u8]], name: &str) -> bool {
+    lines.iter().any(|line| {
+        line.len() > name.len()
+            && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+            && line[name.len()] == b':'
+    })
+}
+
+/// The protocol the end user connected with: https when we terminated tls ourselves,
+/// what a trusted edge proxy says it terminated (https unless it says http), otherwise
+/// plain http straight to us
+pub fn forwarded_proto(
+    terminated_tls: bool,
+    trusted_proxy: bool,
+    proxy_proto: Option<&str>,
+) -> &'static str {
+    if terminated_tls {
+        return "https";
+    }
+    if !trusted_proxy {
+        return "http";
+    }
+    match proxy_proto {
+        Some(proto) if proto.trim().eq_ignore_ascii_case("http") => "http",
+        _ => "https",
+    }
+}
+
+/// The public port of a request, by its `forwarded_proto`
+pub fn forwarded_port(proto: &str) -> u16 {
+    if proto == "http" {
+        80
+    } else {
+        443
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &[u8] =
+        b"GET / HTTP/1.1\r\nHost: foo.tunnelto.dev\r\nX-Forwarded-Proto: https\r\n\r\n";
+
+    fn text(head: Vec<u8>) -> String {
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn inject_adds_missing_headers_before_the_end() {
+        let head = inject_headers(HEAD, &[("X-Forwarded-Port", "443".to_string())]);
+        assert_eq!(
+            text(head),
+            "GET / HTTP/1.1\r\nHost: foo.tunnelto.dev\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Port: 443\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn inject_keeps_existing_headers() {
+        let head = inject_headers(HEAD, &[("x-forwarded-proto", "http".to_string())]);
+        assert_eq!(head, HEAD);
+    }
+
+    #[test]
+    fn inject_into_incomplete_head() {
+        let head = inject_headers(
+            b"GET / HTTP/1.1\r\nHost: a\r\n",
+            &[("X-Forwarded-Port", "80".to_string())],
+        );
+        assert_eq!(
+            text(head),
+            "GET / HTTP/1.1\r\nHost: a\r\nX-Forwarded-Port: 80\r\n"
+        );
+    }
+
+    #[test]
+    fn replace_drops_the_end_users_headers() {
+        let head =
+            b"GET / HTTP/1.1\r\nX-Forwarded-Port: 8443\r\nHost: a\r\nx-forwarded-port: 1\r\n\r\n";
+        assert_eq!(
+            text(replace_headers(
+                head,
+                &[("X-Forwarded-Port", "80".to_string())]
+            )),
+            "GET / HTTP/1.1\r\nHost: a\r\nX-Forwarded-Port: 80\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn forwarded_proto_from_trusted_proxy() {
+        assert_eq!(forwarded_proto(false, true, Some("http")), "http");
+        assert_eq!(forwarded_proto(false, true, Some(" HTTP ")), "http");
+        assert_eq!(forwarded_proto(false, true, Some("https")), "https");
+        assert_eq!(forwarded_proto(false, true, None), "https");
+    }
+
+    #[test]
+    fn forwarded_proto_without_proxy() {
+        // whatever the end user claims
+        assert_eq!(forwarded_proto(true, true, Some("http")), "https");
+        assert_eq!(forwarded_proto(true, false, None), "https");
+        assert_eq!(forwarded_proto(false, false, Some("https")), "http");
+    }
+
+    #[test]
+    fn forwarded_port_by_proto() {
+        assert_eq!(forwarded_port("http"), 80);
+        assert_eq!(forwarded_port("https"), 443);
+    }
+
+    #[test]
+    fn append_to_existing_header() {
+        let head = b"GET / HTTP/1.1\r\nX-Forwarded-For: 1.1.1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(
+            text(append_header(head, "X-Forwarded-For", "2.2.2.2")),
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 1.1.1.1, 2.2.2.2\r\nHost: a\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn append_to_empty_header() {
+        let head = b"GET / HTTP/1.1\r\nx-forwarded-for:\r\n\r\n";
+        assert_eq!(
+            text(append_header(head, "X-Forwarded-For", "2.2.2.2")),
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 2.2.2.2\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn append_adds_missing_header() {
+        assert_eq!(
+            text(append_header(HEAD, "X-Forwarded-For", "2.2.2.2")),
+            "GET / HTTP/1.1\r\nHost: foo.tunnelto.dev\r\nX-Forwarded-Proto: https\r\nX-Forwarded-For: 2.2.2.2\r\n\r\n"
+        );
+    }
+
+    fn patterns(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn strip_by_name_and_prefix() {
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\nX-Secret: 1\r\nCf-Ray: 2\r\nCF-IPCountry: 3\r\nAccept: */*\r\n\r\n";
+        assert_eq!(
+            text(strip_headers(head, &patterns(&["x-secret", "cf-*"]))),
+            "GET / HTTP/1.1\r\nHost: a\r\nAccept: */*\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn strip_keeps_the_request_line_and_transfer_encoding() {
+        let head = b"POST /x:y HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            strip_headers(head, &patterns(&["post /x", "transfer-encoding"])),
+            head
+        );
+    }
+
+    #[test]
+    fn strip_keeps_preserved_headers() {
+        let head = b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: k\r\nSec-WebSocket-Extensions: permessage-deflate\r\nSec-Fetch-Mode: websocket\r\n\r\n";
+        let names = patterns(&["connection", "upgrade", "sec-*"]);
+        assert_eq!(
+            text(strip_headers(head, &names)),
+            "GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: k\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n"
+        );
+    }
+}
@@ -6,10 +6,12 @@ use dashmap::DashMap;
 use std::sync::Arc;
 pub use tunnelto_lib::*;
 
-use tokio::net::TcpListener;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use futures::stream::{SplitSink, SplitStream};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::stream::SplitSink;
 use lazy_static::lazy_static;
 
 mod connected_clients;
@@ -23,14 +25,26 @@ pub use self::auth::client_auth;
 
 pub use self::auth_db::AuthDbService;
 
+mod acme;
+mod admin;
 mod control_server;
+mod custom_hosts;
+mod forwarded;
 mod remote;
+mod remote_stream;
+use self::remote_stream::RemoteStream;
 
 mod config;
 pub use self::config::Config;
 mod network;
 
+mod metrics;
 mod observability;
+mod rate_limit;
+mod raw_tcp;
+mod tls;
+mod usage;
+mod word_list;
 
 use tracing::level_filters::LevelFilter;
 use tracing_honeycomb::libhoney;
@@ -48,6 +62,8 @@ lazy_static! {
 
     // To disable all authentication:
     // pub static ref AUTH_DB_SERVICE: crate::auth::NoAuth = crate::auth::NoAuth;
+
+    // The auth flows are tested against seeded in-memory accounts, see `auth::in_memory`
 }
 
 #[tokio::main]
@@ -89,6 +105,22 @@ async fn main() {
 
     tracing::info!("starting server!");
 
+    if CONFIG.record_usage {
+        usage::spawn_flush();
+    }
+
+    if CONFIG.handshake_rate_limit.is_some() {
+        rate_limit::spawn_prune();
+    }
+
+    if CONFIG.custom_hosts {
+        custom_hosts::spawn_prune();
+    }
+
+    if CONFIG.acme.is_some() {
+        acme::spawn_renewal();
+    }
+
     control_server::spawn(([0, 0, 0, 0], CONFIG.control_port));
     info!("started tunnelto server on 0.0.0.0:{}", CONFIG.control_port);
 
@@ -106,9 +138,27 @@ async fn main() {
         .await
         .expect("failed to bind");
 
+    // bound the sockets being processed so a connection storm can't spawn unbounded tasks
+    let accepts = Arc::new(Semaphore::new(CONFIG.max_concurrent_accepts));
+    accept_loop(listener, accepts, handle_remote_socket).await;
+}
+
+/// Accept sockets forever, at most as many being routed at once as `accepts` has permits.
+/// `handle` gets the socket's permit and drops it once the socket is routed.
+async fn accept_loop<F, Fut>(listener: TcpListener, accepts: Arc<Semaphore>, handle: F)
+where
+    F: Fn(TcpStream, SocketAddr, OwnedSemaphorePermit) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
     loop {
-        let socket = match listener.accept().await {
-            Ok((socket, _)) => socket,
+        let permit = accepts
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("accept semaphore closed");
+
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok((socket, peer_addr)) => (socket, peer_addr),
             _ => {
                 error!("failed to accept socket");
                 continue;
@@ -116,10 +166,96 @@ async fn main() {
         };
 
         tokio::spawn(
-            async move {
-                remote::accept_connection(socket).await;
-            }
-            .instrument(observability::remote_trace("remote_connect")),
+            handle(socket, peer_addr, permit)
+                .instrument(observability::remote_trace("remote_connect")),
         );
     }
 }
+
+async fn handle_remote_socket(
+    socket: TcpStream,
+    peer_addr: SocketAddr,
+    permit: OwnedSemaphorePermit,
+) {
+    let socket = match CONFIG.tls_acceptor.as_ref() {
+        Some(acceptor) => {
+            let handshake = acceptor.accept(socket);
+            match tokio::time::timeout(CONFIG.request_head_timeout, handshake).await {
+                Ok(Ok(tls)) => RemoteStream::new(tls, Some(peer_addr)),
+                Ok(Err(error)) => {
+                    tracing::debug!(?error, "tls handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!(?peer_addr, "tls handshake too slow");
+                    return;
+                }
+            }
+        }
+        None => RemoteStream::new(socket, Some(peer_addr)),
+    };
+
+    remote::accept_connection(socket, permit).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Accepts with 2 permits, counting the sockets handed to `handle`
+    async fn spawn_accept_loop<F, Fut>(handle: F) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        F: Fn(TcpStream, OwnedSemaphorePermit) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let counter = handled.clone();
+        let handle = move |socket: TcpStream, _: SocketAddr, permit: OwnedSemaphorePermit| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            handle(socket, permit)
+        };
+        tokio::spawn(accept_loop(listener, Arc::new(Semaphore::new(2)), handle));
+        (addr, handled)
+    }
+
+    async fn burst(addr: SocketAddr, n: usize) -> Vec<TcpStream> {
+        let mut sockets = vec![];
+        for _ in 0..n {
+            sockets.push(TcpStream::connect(addr).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sockets
+    }
+
+    #[tokio::test]
+    async fn burst_of_accepts_stays_bounded() {
+        // never done routing
+        let (addr, handled) = spawn_accept_loop(|socket, permit| async move {
+            let _held = (socket, permit);
+            std::future::pending::<()>().await
+        })
+        .await;
+
+        let _sockets = burst(addr, 5).await;
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn routed_sockets_release_their_permit() {
+        // routed, then tunneled for good
+        let (addr, handled) = spawn_accept_loop(|socket, permit| async move {
+            drop(permit);
+            let _held = socket;
+            std::future::pending::<()>().await
+        })
+        .await;
+
+        let _sockets = burst(addr, 5).await;
+        assert_eq!(handled.load(Ordering::SeqCst), 5);
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tunnelto_lib::StreamKind;
+
+static HTTP_STREAMS: AtomicU64 = AtomicU64::new(0);
+static WEBSOCKET_STREAMS: AtomicU64 = AtomicU64::new(0);
+static RAW_STREAMS: AtomicU64 = AtomicU64::new(0);
+
+/// The protocol a remote stream speaks, detected from it's first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamProtocol {
+    Http,
+    WebSocket,
+    Raw,
+}
+
+impl From<StreamKind> for StreamProtocol {
+    fn from(kind: StreamKind) -> Self {
+        match kind {
+            StreamKind::Http1 | StreamKind::Http2Preface => StreamProtocol::Http,
+            StreamKind::WebSocketUpgrade => StreamProtocol::WebSocket,
+            StreamKind::Tls | StreamKind::Unknown => StreamProtocol::Raw,
+        }
+    }
+}
+
+impl StreamProtocol {
+    fn counter(&self) -> &'static AtomicU64 {
+        match self {
+            StreamProtocol::Http => &HTTP_STREAMS,
+            StreamProtocol::WebSocket => &WEBSOCKET_STREAMS,
+            StreamProtocol::Raw => &RAW_STREAMS,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            StreamProtocol::Http => "http",
+            StreamProtocol::WebSocket => "websocket",
+            StreamProtocol::Raw => "raw",
+        }
+    }
+}
+
+pub fn record_stream(protocol: StreamProtocol) {
+    protocol.counter().fetch_add(1, Ordering::Relaxed);
+}
+
+/// Render our counters in the prometheus text format
+pub fn render() -> String {
+    let mut out = String::from(
+        "# HELP tunnelto_remote_streams_total Remote streams by detected protocol\n# TYPE tunnelto_remote_streams_total counter\n",
+    );
+    for protocol in &[
+        StreamProtocol::Http,
+        StreamProtocol::WebSocket,
+        StreamProtocol::Raw,
+    ] {
+        out.push_str(&format!(
+            "tunnelto_remote_streams_total{{protocol=\"{}\"}} {}\n",
+            protocol.label(),
+            protocol.counter().load(Ordering::Relaxed)
+        ));
+    }
+    out
+}
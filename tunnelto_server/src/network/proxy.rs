@@ -1,4 +1,5 @@
 use crate::network::Instance;
+use crate::remote_stream::RemoteStream;
 use std::net::SocketAddr;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
@@ -6,7 +7,7 @@ use tokio::net::TcpStream;
 const HTTP_ERROR_PROXYING_TUNNEL_RESPONSE: &'static [u8] =
     b"HTTP/1.1 500\r\nContent-Length: 28\r\n\r\nError: Error proxying tunnel";
 
-pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
+pub async fn proxy_stream(instance: Instance, mut stream: RemoteStream) {
     let addr = SocketAddr::new(instance.ip, crate::CONFIG.remote_port);
     let mut instance = match TcpStream::connect(addr).await {
         Ok(stream) => stream,
@@ -18,7 +19,7 @@ pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
     };
 
     let (mut i_read, mut i_write) = instance.split();
-    let (mut r_read, mut r_write) = stream.split();
+    let (mut r_read, mut r_write) = tokio::io::split(stream);
 
     let _ = futures::future::join(
         tokio::io::copy(&mut r_read, &mut i_write),
@@ -15,7 +15,11 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
         .and(warp::query::<HostQuery>())
         .map(|query| warp::reply::json(&handle_query(query)));
 
-    let routes = query_svc.or(health_check);
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .map(crate::metrics::render);
+
+    let routes = query_svc.or(health_check).or(metrics);
 
     // spawn our websocket control server
     tokio::spawn(warp::serve(routes).run(addr.into()));
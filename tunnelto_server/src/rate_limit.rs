@@ -0,0 +1,139 @@
+use crate::CONFIG;
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How often ips that went quiet are dropped from the limiter
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    /// recent handshake attempts per source ip, oldest first
+    static ref ATTEMPTS: DashMap<IpAddr, VecDeque<Instant>> = DashMap::new();
+}
+
+/// Count a handshake attempt from `ip`, false if it's over
+/// `HANDSHAKE_RATE_LIMIT` attempts within the sliding `HANDSHAKE_RATE_WINDOW`
+pub fn allow_handshake(ip: IpAddr) -> bool {
+    let limit = match CONFIG.handshake_rate_limit {
+        Some(limit) => limit,
+        None => return true,
+    };
+
+    allow(
+        &ATTEMPTS,
+        ip,
+        limit,
+        CONFIG.handshake_rate_window,
+        Instant::now(),
+    )
+}
+
+/// `allow_handshake` with the limiter state and clock passed in
+fn allow(
+    attempts: &DashMap<IpAddr, VecDeque<Instant>>,
+    ip: IpAddr,
+    limit: usize,
+    window: Duration,
+    now: Instant,
+) -> bool {
+    // an unknown ip would lump every such client together
+    if ip.is_unspecified() {
+        return true;
+    }
+
+    let mut attempts = attempts.entry(ip).or_default();
+    while attempts
+        .front()
+        .map_or(false, |at| now.duration_since(*at) >= window)
+    {
+        attempts.pop_front();
+    }
+
+    if attempts.len() >= limit {
+        return false;
+    }
+    attempts.push_back(now);
+    true
+}
+
+/// Periodically drop ips without attempts in the window so the limiter stays bounded
+pub fn spawn_prune() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            let now = Instant::now();
+            let window = CONFIG.handshake_rate_window;
+            ATTEMPTS.retain(|_, attempts| {
+                attempts
+                    .back()
+                    .map_or(false, |at| now.duration_since(*at) < window)
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn burst_is_limited() {
+        let attempts = DashMap::new();
+        let ip = IpAddr::from([1, 2, 3, 4]);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(allow(&attempts, ip, 3, WINDOW, now));
+        }
+        assert!(!allow(&attempts, ip, 3, WINDOW, now));
+        // rejected attempts don't count against the ip
+        assert_eq!(attempts.get(&ip).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn attempts_refill_as_the_window_slides() {
+        let attempts = DashMap::new();
+        let ip = IpAddr::from([1, 2, 3, 4]);
+        let start = Instant::now();
+
+        assert!(allow(&attempts, ip, 2, WINDOW, start));
+        assert!(allow(
+            &attempts,
+            ip,
+            2,
+            WINDOW,
+            start + Duration::from_secs(5)
+        ));
+        assert!(!allow(
+            &attempts,
+            ip,
+            2,
+            WINDOW,
+            start + Duration::from_secs(9)
+        ));
+
+        // the first attempt left the window, the second is still in it
+        assert!(allow(&attempts, ip, 2, WINDOW, start + WINDOW));
+        assert!(!allow(&attempts, ip, 2, WINDOW, start + WINDOW));
+    }
+
+    #[test]
+    fn ips_are_limited_separately() {
+        let attempts = DashMap::new();
+        let noisy = IpAddr::from([1, 2, 3, 4]);
+        let quiet = IpAddr::from([5, 6, 7, 8]);
+        let now = Instant::now();
+
+        assert!(allow(&attempts, noisy, 1, WINDOW, now));
+        assert!(!allow(&attempts, noisy, 1, WINDOW, now));
+        assert!(allow(&attempts, quiet, 1, WINDOW, now));
+
+        // unknown ips are never limited
+        let unknown = IpAddr::from([0, 0, 0, 0]);
+        assert!(allow(&attempts, unknown, 1, WINDOW, now));
+        assert!(allow(&attempts, unknown, 1, WINDOW, now));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{ConnectedClient, CONFIG};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// How often an idle listener checks that its client is still connected
+const CLIENT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A raw tcp tunnel's public port, and the listener on it
+pub type RawListener = (u16, TcpListener);
+
+/// Bind a public port from `TCP_PORT_RANGE` for a raw tcp tunnel,
+/// the requested one or else the first free one
+pub async fn bind(requested: Option<u16>) -> Option<RawListener> {
+    bind_in(CONFIG.tcp_port_range?, requested).await
+}
+
+async fn bind_in((start, end): (u16, u16), requested: Option<u16>) -> Option<RawListener> {
+    if let Some(port) = requested {
+        if port < start || port > end {
+            return None;
+        }
+        return TcpListener::bind(("0.0.0.0", port))
+            .await
+            .ok()
+            .map(|listener| (port, listener));
+    }
+
+    for port in start..=end {
+        if let Ok(listener) = TcpListener::bind(("0.0.0.0", port)).await {
+            return Some((port, listener));
+        }
+    }
+    None
+}
+
+/// Accept connections on a raw tcp tunnel's port until its client disconnects,
+/// the port is released when the listener drops
+#[tracing::instrument(skip(listener, client))]
+pub async fn serve(port: u16, listener: TcpListener, client: ConnectedClient) {
+    tracing::info!(%port, host=%client.host, "serving raw tcp tunnel");
+
+    loop {
+        if client.tx.is_closed() {
+            break;
+        }
+
+        let socket = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(error) => {
+                    tracing::error!(?error, "failed to accept raw tcp connection");
+                    continue;
+                }
+            },
+            _ = tokio::time::sleep(CLIENT_CHECK_INTERVAL) => continue,
+        };
+
+        crate::remote::accept_raw_connection(socket, client.clone());
+    }
+
+    tracing::info!(%port, "raw tcp tunnel closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActiveStream, StreamMessage, ACTIVE_STREAMS};
+    use futures::channel::mpsc::channel;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tunnelto_lib::{ClientId, ControlPacket, WireFormat};
+
+    /// A free port, held by the returned listener until it drops
+    async fn taken_port() -> (u16, TcpListener) {
+        let listener = TcpListener::bind(("0.0.0.0", 0)).await.unwrap();
+        (listener.local_addr().unwrap().port(), listener)
+    }
+
+    #[tokio::test]
+    async fn binds_a_free_port_in_the_range() {
+        let (port, taken) = taken_port().await;
+
+        // every port of the range is in use
+        assert!(bind_in((port, port), None).await.is_none());
+        assert!(bind_in((port, port), Some(port)).await.is_none());
+
+        drop(taken);
+        let (bound, _listener) = bind_in((port, port), None).await.unwrap();
+        assert_eq!(bound, port);
+    }
+
+    #[tokio::test]
+    async fn requested_port_must_be_in_the_range() {
+        let (port, taken) = taken_port().await;
+        drop(taken);
+
+        assert!(bind_in((port, port), Some(port.wrapping_add(1)))
+            .await
+            .is_none());
+        let (bound, _listener) = bind_in((port, port), Some(port)).await.unwrap();
+        assert_eq!(bound, port);
+    }
+
+    #[tokio::test]
+    async fn bytes_round_trip() {
+        let (tx, mut client_rx) = channel(8);
+        let client = ConnectedClient {
+            id: ClientId::generate(),
+            host: "raw-round-trip".to_string(),
+            is_anonymous: true,
+            account_id: None,
+            format: WireFormat::default(),
+            tx,
+        };
+        let (port, listener) = taken_port().await;
+        tokio::spawn(serve(port, listener, client.clone()));
+
+        let mut socket = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        socket.write_all(b"\x00\x01ping").await.unwrap();
+
+        let stream_id = match client_rx.next().await {
+            Some(ControlPacket::Init(stream_id)) => stream_id,
+            other => panic!("expected an init, got {:?}", other),
+        };
+        match client_rx.next().await {
+            Some(ControlPacket::Data(id, _, data)) => {
+                assert_eq!(id, stream_id);
+                assert_eq!(data, b"\x00\x01ping");
+            }
+            other => panic!("expected data, got {:?}", other),
+        }
+
+        // the client answers on the same stream
+        let mut stream: ActiveStream = ACTIVE_STREAMS.get(&stream_id).unwrap().value().clone();
+        stream
+            .tx
+            .send(StreamMessage::Data(None, b"pong\xff".to_vec()))
+            .await
+            .unwrap();
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong\xff");
+
+        stream.tx.send(StreamMessage::End).await.unwrap();
+        assert_eq!(socket.read(&mut buf).await.unwrap(), 0);
+        client.tx.clone().close_channel();
+    }
+}
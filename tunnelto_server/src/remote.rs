@@ -1,11 +1,17 @@
 use super::*;
+use crate::custom_hosts;
+use crate::forwarded;
+use crate::metrics::{self, StreamProtocol};
+use crate::remote_stream::RemoteStream;
+use std::sync::atomic::Ordering;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::OwnedSemaphorePermit;
 use tracing::debug;
 use tracing::{error, Instrument};
 
-async fn direct_to_control(mut incoming: TcpStream) {
+async fn direct_to_control(incoming: RemoteStream) {
     let mut control_socket =
         match TcpStream::connect(format!("localhost:{}", CONFIG.control_port)).await {
             Ok(s) => s,
@@ -16,7 +22,7 @@ async fn direct_to_control(mut incoming: TcpStream) {
         };
 
     let (mut control_r, mut control_w) = control_socket.split();
-    let (mut incoming_r, mut incoming_w) = incoming.split();
+    let (mut incoming_r, mut incoming_w) = tokio::io::split(incoming);
 
     let join_1 = tokio::io::copy(&mut control_r, &mut incoming_w);
     let join_2 = tokio::io::copy(&mut incoming_r, &mut control_w);
@@ -29,14 +35,17 @@ async fn direct_to_control(mut incoming: TcpStream) {
     }
 }
 
-#[tracing::instrument(skip(socket))]
-pub async fn accept_connection(socket: TcpStream) {
+/// Route a remote connection, `permit` is held until it's routed but not while it's tunneled
+#[tracing::instrument(skip(socket, permit))]
+pub async fn accept_connection(socket: RemoteStream, permit: OwnedSemaphorePermit) {
     // peek the host of the http request
     // if health check, then handle it and return
     let StreamWithPeekedHost {
         mut socket,
         host,
         forwarded_for,
+        head,
+        wants_json,
     } = match peek_http_request_host(socket).await {
         Some(s) => s,
         None => return,
@@ -50,17 +59,28 @@ pub async fn accept_connection(socket: TcpStream) {
         let _ = socket.write_all(HTTP_REDIRECT_RESPONSE).await;
         return;
     }
+    // a customer's own domain (i.e. CNAME'd to us) maps to a sub-domain
     let host = match validate_host_prefix(&host) {
+        Some(sub_domain) => Some(sub_domain),
+        None => custom_hosts::resolve(&host).await,
+    };
+    let host = match host {
         Some(sub_domain) => sub_domain,
         None => {
             error!("invalid host specified");
-            let _ = socket.write_all(HTTP_INVALID_HOST_RESPONSE).await;
+            let response = if wants_json {
+                json_error_response("400", "invalid_host")
+            } else {
+                HTTP_INVALID_HOST_RESPONSE.to_vec()
+            };
+            let _ = socket.write_all(&response).await;
             return;
         }
     };
 
     // Special case -- we redirect this tcp connection to the control server
     if host.as_str() == "wormhole" {
+        drop(permit);
         direct_to_control(socket).await;
         return;
     }
@@ -72,26 +92,47 @@ pub async fn accept_connection(socket: TcpStream) {
             // check other instances that may be serving this host
             match network::instance_for_host(&host).await {
                 Ok((instance, _)) => {
+                    drop(permit);
                     network::proxy_stream(instance, socket).await;
                     return;
                 }
                 Err(network::Error::DoesNotServeHost) => {
                     error!(%host, "no tunnel found");
-                    let _ = socket.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    let response = not_found_response(&host, wants_json).await;
+                    let _ = socket.write_all(&response).await;
                     return;
                 }
                 Err(error) => {
                     error!(%host, ?error, "failed to find instance");
-                    let _ = socket.write_all(HTTP_ERROR_LOCATING_HOST_RESPONSE).await;
+                    let response = if wants_json {
+                        json_error_response("500", "tunnel_lookup_failed")
+                    } else {
+                        HTTP_ERROR_LOCATING_HOST_RESPONSE.to_vec()
+                    };
+                    let _ = socket.write_all(&response).await;
                     return;
                 }
             }
         }
     };
 
+    // swap the peeked request head for one with our forwarded headers
+    let head = match head {
+        Some((len, head)) => {
+            let mut original = vec![0; len];
+            if let Err(e) = socket.read_exact(&mut original).await {
+                error!("failed to read peeked request head: {:?}", e);
+                return;
+            }
+            Some(head)
+        }
+        None => None,
+    };
+
     // allocate a new stream for this request
     let (active_stream, queue_rx) = ActiveStream::new(client.clone());
     let stream_id = active_stream.id.clone();
+    let state = active_stream.state.clone();
 
     tracing::debug!(
         stream_id = %active_stream.id.to_string(),
@@ -106,7 +147,7 @@ pub async fn accept_connection(socket: TcpStream) {
     let span = observability::remote_trace("process_tcp_stream");
     tokio::spawn(
         async move {
-            process_tcp_stream(active_stream, stream).await;
+            process_tcp_stream(active_stream, stream, head).await;
         }
         .instrument(span),
     );
@@ -115,35 +156,73 @@ pub async fn accept_connection(socket: TcpStream) {
     let span = observability::remote_trace("tunnel_to_stream");
     tokio::spawn(
         async move {
-            tunnel_to_stream(host, stream_id, sink, queue_rx).await;
+            tunnel_to_stream(host, stream_id, state, sink, queue_rx, wants_json, false).await;
         }
         .instrument(span),
     );
 }
 
-fn validate_host_prefix(host: &str) -> Option<String> {
+/// Tunnel a connection to a raw tcp tunnel's port through its client, byte for byte
+pub fn accept_raw_connection(socket: TcpStream, client: ConnectedClient) {
+    let peer_addr = socket.peer_addr().ok();
+    let socket = RemoteStream::new(socket, peer_addr);
+    let (active_stream, queue_rx) = ActiveStream::new(client.clone());
+    let stream_id = active_stream.id.clone();
+    let state = active_stream.state.clone();
+    let host = client.host.clone();
+
+    tracing::debug!(stream_id = %stream_id.to_string(), "new raw tcp stream connected");
+    metrics::record_stream(StreamProtocol::Raw);
+    let (stream, sink) = tokio::io::split(socket);
+    ACTIVE_STREAMS.insert(stream_id.clone(), active_stream.clone());
+
+    let span = observability::remote_trace("process_tcp_stream");
+    tokio::spawn(
+        async move {
+            process_tcp_stream(active_stream, stream, None).await;
+        }
+        .instrument(span),
+    );
+
+    let span = observability::remote_trace("tunnel_to_stream");
+    tokio::spawn(
+        async move {
+            tunnel_to_stream(host, stream_id, state, sink, queue_rx, false, true).await;
+        }
+        .instrument(span),
+    );
+}
+
+/// The host name of a Host header, without the port
+pub fn parse_host(host: &str) -> Option<String> {
     let url = format!("http://{}", host);
 
-    let host = match url::Url::parse(&url)
-        .map(|u| u.host().map(|h| h.to_owned()))
+    url::Url::parse(&url)
+        .map(|u| u.host().map(|h| h.to_string()))
         .unwrap_or(None)
-    {
-        Some(domain) => domain.to_string(),
+}
+
+fn validate_host_prefix(host: &str) -> Option<String> {
+    let host = match parse_host(host) {
+        Some(host) => host,
         None => {
             error!("invalid host header");
             return None;
         }
     };
 
-    let domain_segments = host.split(".").collect::<Vec<&str>>();
-    let prefix = &domain_segments[0];
-    let remaining = &domain_segments[1..].join(".");
-
-    if CONFIG.allowed_hosts.contains(remaining) {
-        Some(prefix.to_string())
-    } else {
-        None
-    }
+    // the sub-domain is everything before the longest allowed host suffix,
+    // so nested sub-domains (i.e. a.acme.tunnelto.dev) are supported
+    CONFIG
+        .allowed_hosts
+        .iter()
+        .filter(|allowed| {
+            host.len() > allowed.len() + 1
+                && host.ends_with(allowed.as_str())
+                && host.as_bytes()[host.len() - allowed.len() - 1] == b'.'
+        })
+        .max_by_key(|allowed| allowed.len())
+        .map(|allowed| host[..host.len() - allowed.len() - 1].to_string())
 }
 
 /// Response Constants
@@ -159,27 +238,83 @@ const HTTP_TUNNEL_REFUSED_RESPONSE: &'static [u8] =
 const HTTP_OK_RESPONSE: &'static [u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
 const HEALTH_CHECK_PATH: &'static [u8] = b"/0xDEADBEEF_HEALTH_CHECK";
 
+/// An error for API clients that asked for json, i.e: `{"error":"tunnel_not_found"}`
+fn json_error_response(status: &str, error: &str) -> Vec<u8> {
+    let body = serde_json::json!({ "error": error }).to_string();
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// The account's custom not-found page for this sub-domain, or our default
+async fn not_found_response(sub_domain: &str, wants_json: bool) -> Vec<u8> {
+    if wants_json {
+        return json_error_response("404", "tunnel_not_found");
+    }
+
+    let page = match AUTH_DB_SERVICE.get_not_found_page(sub_domain).await {
+        Ok(page) => page,
+        Err(error) => {
+            tracing::debug!(?error, "failed to get custom not found page");
+            None
+        }
+    };
+    not_found_page(page)
+}
+
+/// A 404 with the custom page as its body, if there is one
+fn not_found_page(page: Option<String>) -> Vec<u8> {
+    match page {
+        Some(page) => format!(
+            "HTTP/1.1 404\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+            page.len(),
+            page
+        )
+        .into_bytes(),
+        None => HTTP_NOT_FOUND_RESPONSE.to_vec(),
+    }
+}
+
 struct StreamWithPeekedHost {
-    socket: TcpStream,
+    socket: RemoteStream,
     host: String,
     forwarded_for: String,
+    /// the length of the peeked request head, and the head to send instead
+    head: Option<(usize, Vec<u8>)>,
+    /// the request accepts json, so our errors should be json too
+    wants_json: bool,
 }
 /// Filter incoming remote streams
 #[tracing::instrument(skip(socket))]
-async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeekedHost> {
+async fn peek_http_request_host(mut socket: RemoteStream) -> Option<StreamWithPeekedHost> {
     /// Note we return out if the host header is not found
     /// within the first 4kb of the request.
     const MAX_HEADER_PEAK: usize = 4096;
     let mut buf = vec![0; MAX_HEADER_PEAK]; //1kb
+    let peer_ip = socket.peer_addr().map(|addr| addr.ip().to_string());
 
     tracing::debug!("checking stream headers");
 
-    let n = match socket.peek(&mut buf).await {
-        Ok(n) => n,
-        Err(e) => {
+    // a head drip-fed a few bytes at a time (slow-loris) must complete within the budget
+    let n = match tokio::time::timeout(
+        CONFIG.request_head_timeout,
+        peek_head(&mut socket, &mut buf),
+    )
+    .await
+    {
+        Ok(Ok(n)) => n,
+        Ok(Err(e)) => {
             error!("failed to read from tcp socket to determine host: {:?}", e);
             return None;
         }
+        Err(_) => {
+            tracing::info!(?peer_ip, "request head too slow, dropping connection");
+            return None;
+        }
     };
 
     // make sure we're not peeking the same header bytes
@@ -189,14 +324,22 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
     }
 
     tracing::debug!("peeked {} stream bytes ", n);
+    let kind = StreamKind::detect(&buf[..n]);
+    let protocol = StreamProtocol::from(kind);
 
     let mut headers = [httparse::EMPTY_HEADER; 64]; // 30 seems like a generous # of headers
     let mut req = httparse::Request::new(&mut headers);
 
-    if let Err(e) = req.parse(&buf[..n]) {
-        error!("failed to parse incoming http bytes: {:?}", e);
-        return None;
-    }
+    // a head past our peek is forwarded as-is
+    let head_len = match req.parse(&buf[..n]) {
+        Ok(httparse::Status::Complete(len)) => Some(len),
+        Ok(httparse::Status::Partial) => None,
+        Err(e) => {
+            error!("failed to parse incoming http bytes: {:?}", e);
+            metrics::record_stream(protocol);
+            return None;
+        }
+    };
 
     // Handle the health check route
     if req.path.map(|s| s.as_bytes()) == Some(HEALTH_CHECK_PATH) {
@@ -228,12 +371,53 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
         .map(|h| std::str::from_utf8(h.value))
         .next()
     {
-        tracing::info!(host=%host, path=%req.path.unwrap_or_default(), "peek request");
+        metrics::record_stream(protocol);
+        tracing::info!(host=%host, path=%req.path.unwrap_or_default(), ?protocol, "peek request");
+
+        let wants_json = req.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("accept")
+                && String::from_utf8_lossy(h.value)
+                    .to_lowercase()
+                    .contains("application/json")
+        });
+
+        // a proxy can't add headers inside tls we terminate, so then (or with no trusted
+        // proxy at all) any forwarded headers are the end user's and are replaced with ours
+        let terminated_tls = CONFIG.tls_acceptor.is_some();
+        let from_proxy = CONFIG.trusted_proxy && !terminated_tls;
+        let proxy_proto = req
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("x-forwarded-proto"))
+            .and_then(|h| std::str::from_utf8(h.value).ok());
+        let forwarded_proto =
+            forwarded::forwarded_proto(terminated_tls, CONFIG.trusted_proxy, proxy_proto);
+        let forwarded_headers = [
+            (
+                "X-Forwarded-Port",
+                forwarded::forwarded_port(forwarded_proto).to_string(),
+            ),
+            ("X-Forwarded-Proto", forwarded_proto.to_string()),
+        ];
+        let head = head_len.map(|len| {
+            let mut head = forwarded::strip_headers(&buf[..len], &CONFIG.strip_headers);
+            head = if from_proxy {
+                forwarded::inject_headers(&head, &forwarded_headers)
+            } else {
+                forwarded::replace_headers(&head, &forwarded_headers)
+            };
+            if let Some(peer_ip) = &peer_ip {
+                head = forwarded::append_header(&head, "X-Forwarded-For", peer_ip);
+            }
+            (len, head)
+        });
 
         return Some(StreamWithPeekedHost {
             socket,
             host: host.to_string(),
             forwarded_for,
+            head,
+            wants_json,
         });
     }
 
@@ -241,82 +425,268 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
     None
 }
 
+/// Read ahead until the request head is complete, the buffer is full, or it isn't http at all.
+/// The bytes stay buffered in the stream, so they are still forwarded.
+async fn peek_head(socket: &mut RemoteStream, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut peeked = 0;
+    loop {
+        let bytes = socket.fill(buf.len()).await?;
+        let n = bytes.len();
+        buf[..n].copy_from_slice(bytes);
+
+        // the stream ended or there's no room left
+        if n == peeked || n == buf.len() {
+            return Ok(n);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        match httparse::Request::new(&mut headers).parse(&buf[..n]) {
+            Ok(httparse::Status::Partial) => {}
+            _ => return Ok(n),
+        }
+        peeked = n;
+    }
+}
+
 /// Process Messages from the control path in & out of the remote stream
 #[tracing::instrument(skip(tunnel_stream, tcp_stream))]
-async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: ReadHalf<TcpStream>) {
+async fn process_tcp_stream(
+    mut tunnel_stream: ActiveStream,
+    mut tcp_stream: ReadHalf<RemoteStream>,
+    head: Option<Vec<u8>>,
+) {
     // send initial control stream init to client
     control_server::send_client_stream_init(tunnel_stream.clone()).await;
+    let mut seq = StreamSequence::default();
 
-    // now read from stream and forward to clients
-    let mut buf = [0; 1024];
+    // an idempotent request that's only a head can be re-issued if the client
+    // reconnects before any of its response was written
+    let mut replay = head.as_ref().filter(|head| is_idempotent(head)).cloned();
+
+    // the request head we already read, with our forwarded headers
+    if let Some(head) = head {
+        if CONFIG.record_usage {
+            crate::usage::record(&tunnel_stream.client.id, head.len());
+        }
+        for packet in ControlPacket::chunk(&tunnel_stream.id, &mut seq, &head, MAX_FRAME_SIZE) {
+            if !send_to_client(&mut tunnel_stream, packet).await {
+                error!("failed to forward request head to disconnected client.");
+                let _ = tunnel_stream.tx.send(StreamMessage::NoClientTunnel).await;
+                return;
+            }
+        }
+    }
+
+    // now read from stream and forward to clients, verbatim: the public Host header
+    // is preserved and only the client may rewrite it (see its `--host-header`)
+    // reads larger than a frame are still chunked below
+    let mut buf = vec![0; CONFIG.remote_read_buffer_size];
 
     loop {
-        // client is no longer connected
-        if Connections::get(&tunnel_stream.client.id).is_none() {
-            debug!("client disconnected, closing stream");
-            let _ = tunnel_stream.tx.send(StreamMessage::NoClientTunnel).await;
-            tunnel_stream.tx.close_channel();
-            return;
+        // packets for the client were dropped with its old control connection, only
+        // a request that can be re-issued survives that
+        if tunnel_stream.state.packets_lost.load(Ordering::SeqCst)
+            && !tunnel_stream.client.tx.is_closed()
+        {
+            match replay.take() {
+                Some(head) if !tunnel_stream.state.response_started.load(Ordering::SeqCst) => {
+                    debug!("packets lost, retrying idempotent request");
+                    retry_request(&mut tunnel_stream, head).await;
+                }
+                _ => {
+                    debug!("packets lost, closing stream");
+                    abort_stream(&mut tunnel_stream).await;
+                    return;
+                }
+            }
         }
 
-        // read from stream
-        let n = match tcp_stream.read(&mut buf).await {
-            Ok(n) => n,
-            Err(e) => {
+        // client is no longer connected, give it a moment to reconnect
+        if tunnel_stream.client.tx.is_closed() {
+            match await_client_reconnect(&tunnel_stream.client.id).await {
+                Some(client) => {
+                    tunnel_stream.client = client;
+                    match replay.take() {
+                        Some(head)
+                            if !tunnel_stream.state.response_started.load(Ordering::SeqCst) =>
+                        {
+                            debug!("client reconnected, retrying idempotent request");
+                            retry_request(&mut tunnel_stream, head).await;
+                        }
+                        _ => debug!("client reconnected, resuming stream"),
+                    }
+                }
+                None => {
+                    debug!("client disconnected, closing stream");
+                    let _ = tunnel_stream.tx.send(StreamMessage::NoClientTunnel).await;
+                    tunnel_stream.tx.close_channel();
+                    return;
+                }
+            }
+        }
+
+        // read from stream, waking up now and then to notice a dropped client
+        let n = match tokio::time::timeout(CLIENT_CHECK_INTERVAL, tcp_stream.read(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => {
                 error!("failed to read from tcp socket: {:?}", e);
                 return;
             }
+            Err(_) => continue,
         };
 
         if n == 0 {
             debug!("stream ended");
-            let _ = tunnel_stream
-                .client
-                .tx
-                .send(ControlPacket::End(tunnel_stream.id.clone()))
-                .await
-                .map_err(|e| {
-                    error!("failed to send end signal: {:?}", e);
-                });
+            let packet = ControlPacket::End(tunnel_stream.id.clone());
+            if !send_to_client(&mut tunnel_stream, packet).await {
+                error!("failed to send end signal to disconnected client");
+            }
             return;
         }
 
         debug!("read {} bytes", n);
+        if CONFIG.record_usage {
+            crate::usage::record(&tunnel_stream.client.id, n);
+        }
 
-        let data = &buf[..n];
-        let packet = ControlPacket::Data(tunnel_stream.id.clone(), data.to_vec());
+        // more than the head was sent, so it's no longer safe to re-issue
+        replay = None;
 
-        match tunnel_stream.client.tx.send(packet.clone()).await {
-            Ok(_) => debug!(client_id = %tunnel_stream.client.id, "sent data packet to client"),
-            Err(_) => {
-                error!("failed to forward tcp packets to disconnected client. dropping client.");
-                Connections::remove(&tunnel_stream.client);
+        for packet in ControlPacket::chunk(&tunnel_stream.id, &mut seq, &buf[..n], MAX_FRAME_SIZE) {
+            if send_to_client(&mut tunnel_stream, packet).await {
+                debug!(client_id = %tunnel_stream.client.id, "sent data packet to client");
+            } else {
+                error!("failed to forward tcp packets to disconnected client.");
             }
         }
     }
 }
 
-#[tracing::instrument(skip(sink, stream_id, queue))]
+/// How often an idle remote stream checks that its client is still connected
+const CLIENT_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// GET and HEAD requests can be sent to the local service twice
+fn is_idempotent(head: &[u8]) -> bool {
+    head.starts_with(b"GET ") || head.starts_with(b"HEAD ")
+}
+
+/// Re-issue a request to a reconnected client under a new stream id,
+/// anything still arriving for the old id is dropped
+async fn retry_request(tunnel_stream: &mut ActiveStream, head: Vec<u8>) {
+    let old_id = std::mem::replace(&mut tunnel_stream.id, StreamId::generate());
+    ACTIVE_STREAMS.remove(&old_id);
+    tunnel_stream
+        .state
+        .packets_lost
+        .store(false, Ordering::SeqCst);
+    ACTIVE_STREAMS.insert(tunnel_stream.id.clone(), tunnel_stream.clone());
+    *tunnel_stream.state.retry_id.lock().unwrap() = Some(tunnel_stream.id.clone());
+
+    let _ = tunnel_stream
+        .client
+        .tx
+        .send(ControlPacket::End(old_id))
+        .await;
+    control_server::send_client_stream_init(tunnel_stream.clone()).await;
+
+    // only the head was sent before, so it's numbered the same on the new stream
+    let mut seq = StreamSequence::default();
+    for packet in ControlPacket::chunk(&tunnel_stream.id, &mut seq, &head, MAX_FRAME_SIZE) {
+        if !send_to_client(tunnel_stream, packet).await {
+            error!("failed to retry request on reconnected client.");
+            return;
+        }
+    }
+}
+
+/// End a stream that can't be resumed, for both the end user and the client
+async fn abort_stream(tunnel_stream: &mut ActiveStream) {
+    let _ = tunnel_stream.tx.send(StreamMessage::End).await;
+    let _ = tunnel_stream
+        .client
+        .tx
+        .send(ControlPacket::End(tunnel_stream.id.clone()))
+        .await;
+}
+
+/// Send a packet to the stream's client, following it across a brief control reconnect
+async fn send_to_client(tunnel_stream: &mut ActiveStream, packet: ControlPacket) -> bool {
+    if tunnel_stream.client.tx.send(packet.clone()).await.is_ok() {
+        return true;
+    }
+
+    Connections::remove(&tunnel_stream.client);
+    match await_client_reconnect(&tunnel_stream.client.id).await {
+        Some(client) => {
+            tunnel_stream.client = client;
+            tunnel_stream.client.tx.send(packet).await.is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Wait up to `STREAM_RESUME_WINDOW` for a client's control connection to come back
+async fn await_client_reconnect(client_id: &ClientId) -> Option<ConnectedClient> {
+    let deadline =
+        tokio::time::Instant::now() + std::time::Duration::from_secs(STREAM_RESUME_WINDOW);
+
+    loop {
+        let client = Connections::get(client_id).filter(|client| !client.tx.is_closed());
+        if client.is_some() {
+            return client;
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return None;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+    }
+}
+
+#[tracing::instrument(skip(sink, stream_id, state, queue))]
 async fn tunnel_to_stream(
     subdomain: String,
     stream_id: StreamId,
-    mut sink: WriteHalf<TcpStream>,
-    mut queue: UnboundedReceiver<StreamMessage>,
+    state: Arc<StreamState>,
+    mut sink: WriteHalf<RemoteStream>,
+    mut queue: Receiver<StreamMessage>,
+    wants_json: bool,
+    is_raw: bool,
 ) {
+    let mut reorder = StreamReorder::new();
+
     loop {
         let result = queue.next().await;
 
         let result = if let Some(message) = result {
             match message {
-                StreamMessage::Data(data) => Some(data),
+                StreamMessage::Data(seq, data) => match reorder.push(seq, data) {
+                    Ok(data) => Some(data.concat()),
+                    Err(error) => {
+                        tracing::warn!(?error, "aborting stream");
+                        None
+                    }
+                },
+                // raw tcp has no way to tell the end user, it just closes
+                StreamMessage::End => {
+                    tracing::debug!(?stream_id, "client ended stream");
+                    None
+                }
+                StreamMessage::TunnelRefused | StreamMessage::NoClientTunnel if is_raw => None,
                 StreamMessage::TunnelRefused => {
                     tracing::debug!(?stream_id, "tunnel refused");
-                    let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
+                    let response = if wants_json {
+                        json_error_response("500", "tunnel_refused")
+                    } else {
+                        HTTP_TUNNEL_REFUSED_RESPONSE.to_vec()
+                    };
+                    let _ = sink.write_all(&response).await;
                     None
                 }
                 StreamMessage::NoClientTunnel => {
                     tracing::info!(%subdomain, ?stream_id, "client tunnel not found");
-                    let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    let response = not_found_response(&subdomain, wants_json).await;
+                    let _ = sink.write_all(&response).await;
                     None
                 }
             }
@@ -333,10 +703,19 @@ async fn tunnel_to_stream(
                 });
 
                 ACTIVE_STREAMS.remove(&stream_id);
+                if let Some(retry_id) = state.retry_id.lock().unwrap().take() {
+                    ACTIVE_STREAMS.remove(&retry_id);
+                }
                 return;
             }
         };
 
+        // waiting on an earlier packet
+        if data.is_empty() {
+            continue;
+        }
+
+        state.response_started.store(true, Ordering::SeqCst);
         let result = sink.write_all(&data).await;
 
         if let Some(error) = result.err() {
@@ -345,3 +724,248 @@ async fn tunnel_to_stream(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    fn connected_client(id: ClientId) -> (ConnectedClient, Receiver<ControlPacket>) {
+        let (tx, rx) = channel(8);
+        let client = ConnectedClient {
+            id,
+            host: format!("reconnect-{}", StreamId::generate().to_string()),
+            is_anonymous: true,
+            account_id: None,
+            format: WireFormat::default(),
+            tx,
+        };
+        (client, rx)
+    }
+
+    /// A remote stream, and the end user's side of it
+    fn remote_stream() -> (RemoteStream, tokio::io::DuplexStream) {
+        let (remote, end_user) = tokio::io::duplex(64 * 1024);
+        (RemoteStream::new(remote, None), end_user)
+    }
+
+    /// Everything queued for the client so far
+    fn sent_packets(client_rx: &mut Receiver<ControlPacket>) -> Vec<ControlPacket> {
+        let mut packets = vec![];
+        while let Ok(Some(packet)) = client_rx.try_next() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    const RETRY_HEAD: &[u8] = b"GET / HTTP/1.1\r\nHost: retry.tunnelto.dev\r\n\r\n";
+
+    async fn peek(head: &[u8]) -> Option<StreamWithPeekedHost> {
+        let (socket, mut end_user) = remote_stream();
+        end_user.write_all(head).await.unwrap();
+        peek_http_request_host(socket).await
+    }
+
+    /// What the end user gets when the client refuses the stream
+    async fn refused_response(wants_json: bool) -> String {
+        let (socket, mut end_user) = remote_stream();
+        let (_, sink) = tokio::io::split(socket);
+        let (mut tx, queue) = channel(8);
+        tx.send(StreamMessage::TunnelRefused).await.unwrap();
+
+        let state = Arc::new(StreamState::default());
+        tunnel_to_stream(
+            "refused".to_string(),
+            StreamId::generate(),
+            state,
+            sink,
+            queue,
+            wants_json,
+            false,
+        )
+        .await;
+
+        let mut response = String::new();
+        end_user.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn client_reconnects_within_window() {
+        let id = ClientId::generate();
+        let (client, rx) = connected_client(id.clone());
+        Connections::add(client.clone());
+
+        // the control connection drops, a new one comes back shortly after
+        drop(rx);
+        assert!(client.tx.is_closed());
+        let (reconnected, _rx) = connected_client(id.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            Connections::add(reconnected);
+        });
+
+        let client = await_client_reconnect(&id)
+            .await
+            .expect("client reconnected");
+        assert!(!client.tx.is_closed());
+        Connections::remove(&client);
+    }
+
+    #[tokio::test]
+    async fn aborted_stream_ends_both_sides() {
+        let (client, mut client_rx) = connected_client(ClientId::generate());
+        let (mut stream, mut stream_rx) = ActiveStream::new(client);
+
+        abort_stream(&mut stream).await;
+        assert!(matches!(stream_rx.next().await, Some(StreamMessage::End)));
+        assert!(matches!(
+            client_rx.next().await,
+            Some(ControlPacket::End(id)) if id == stream.id
+        ));
+    }
+
+    #[test]
+    fn default_and_custom_not_found_pages() {
+        assert_eq!(not_found_page(None), HTTP_NOT_FOUND_RESPONSE);
+
+        // the length is in bytes, not characters
+        let page = "<h1>Back soon ☕</h1>";
+        assert_eq!(
+            String::from_utf8(not_found_page(Some(page.to_string()))).unwrap(),
+            format!(
+                "HTTP/1.1 404\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: 22\r\n\r\n{}",
+                page
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn not_found_is_json_when_asked() {
+        assert_eq!(
+            not_found_response("any-sub-domain", true).await,
+            json_error_response("404", "tunnel_not_found")
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_are_json_when_accepted() {
+        let json =
+            peek(b"GET / HTTP/1.1\r\nHost: a.tunnelto.dev\r\nAccept: application/json\r\n\r\n")
+                .await
+                .unwrap();
+        assert!(json.wants_json);
+        let html = peek(b"GET / HTTP/1.1\r\nHost: a.tunnelto.dev\r\nAccept: text/html\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(!html.wants_json);
+        let none = peek(b"GET / HTTP/1.1\r\nHost: a.tunnelto.dev\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(!none.wants_json);
+
+        assert_eq!(
+            refused_response(true).await,
+            "HTTP/1.1 500\r\nContent-Type: application/json\r\nContent-Length: 26\r\n\r\n{\"error\":\"tunnel_refused\"}"
+        );
+        assert_eq!(
+            refused_response(false).await.as_bytes(),
+            HTTP_TUNNEL_REFUSED_RESPONSE
+        );
+    }
+
+    #[tokio::test]
+    async fn lost_packets_before_the_response_retry_the_request() {
+        let (client, mut client_rx) = connected_client(ClientId::generate());
+        let (stream, _stream_rx) = ActiveStream::new(client);
+        let first_id = stream.id.clone();
+        let state = stream.state.clone();
+        ACTIVE_STREAMS.insert(first_id.clone(), stream.clone());
+
+        // the control connection flapped after the head was sent, the end user is done sending
+        state.packets_lost.store(true, Ordering::SeqCst);
+        let (socket, end_user) = remote_stream();
+        drop(end_user);
+        let (read, _write) = tokio::io::split(socket);
+        process_tcp_stream(stream, read, Some(RETRY_HEAD.to_vec())).await;
+
+        let mut packets = sent_packets(&mut client_rx).into_iter();
+        assert!(matches!(packets.next(), Some(ControlPacket::Init(id)) if id == first_id));
+        assert!(matches!(
+            packets.next(),
+            Some(ControlPacket::Data(id, _, head)) if id == first_id && head == RETRY_HEAD
+        ));
+        assert!(matches!(packets.next(), Some(ControlPacket::End(id)) if id == first_id));
+        let retry_id = match packets.next() {
+            Some(ControlPacket::Init(id)) => id,
+            other => panic!("expected the retry, got {:?}", other),
+        };
+        assert_ne!(retry_id, first_id);
+        assert!(matches!(
+            packets.next(),
+            Some(ControlPacket::Data(id, _, head)) if id == retry_id && head == RETRY_HEAD
+        ));
+        assert!(matches!(packets.next(), Some(ControlPacket::End(id)) if id == retry_id));
+        assert!(packets.next().is_none());
+
+        assert!(!state.packets_lost.load(Ordering::SeqCst));
+        assert_eq!(state.retry_id.lock().unwrap().as_ref(), Some(&retry_id));
+        assert!(!ACTIVE_STREAMS.contains_key(&first_id));
+        assert!(ACTIVE_STREAMS.remove(&retry_id).is_some());
+    }
+
+    #[tokio::test]
+    async fn lost_packets_after_the_response_started_abort_the_stream() {
+        let (client, mut client_rx) = connected_client(ClientId::generate());
+        let (stream, mut stream_rx) = ActiveStream::new(client);
+        let id = stream.id.clone();
+        let state = stream.state.clone();
+        ACTIVE_STREAMS.insert(id.clone(), stream.clone());
+
+        // part of the response already reached the end user, a retry would repeat it
+        state.response_started.store(true, Ordering::SeqCst);
+        state.packets_lost.store(true, Ordering::SeqCst);
+        let (socket, _end_user) = remote_stream();
+        let (read, _write) = tokio::io::split(socket);
+        process_tcp_stream(stream, read, Some(RETRY_HEAD.to_vec())).await;
+
+        let mut packets = sent_packets(&mut client_rx).into_iter();
+        assert!(matches!(packets.next(), Some(ControlPacket::Init(init)) if init == id));
+        assert!(
+            matches!(packets.next(), Some(ControlPacket::Data(data_id, _, _)) if data_id == id)
+        );
+        assert!(matches!(packets.next(), Some(ControlPacket::End(ended)) if ended == id));
+        assert!(packets.next().is_none());
+        assert!(matches!(stream_rx.next().await, Some(StreamMessage::End)));
+        assert!(state.retry_id.lock().unwrap().is_none());
+
+        ACTIVE_STREAMS.remove(&id);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_request_head_times_out() {
+        const HEAD: &[u8] = b"GET / HTTP/1.1\r\nHost: slow.tunnelto.dev\r\n\r\n";
+        let (socket, mut end_user) = remote_stream();
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        // a byte a second, well within any read timeout but not the head's overall budget
+        let drip = sent.clone();
+        tokio::spawn(async move {
+            for byte in HEAD {
+                if end_user.write_all(&[*byte]).await.is_err() {
+                    return;
+                }
+                drip.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        let start = tokio::time::Instant::now();
+        assert!(peek_http_request_host(socket).await.is_none());
+        assert_eq!(start.elapsed(), CONFIG.request_head_timeout);
+
+        let sent = sent.load(Ordering::SeqCst);
+        assert!(sent > 0 && sent < HEAD.len(), "sent {} bytes", sent);
+    }
+}
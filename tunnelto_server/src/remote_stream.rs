@@ -0,0 +1,75 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+pub trait RemoteIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RemoteIo for T {}
+
+/// A public connection, plain tcp or tls-terminated by us.
+/// Bytes read ahead with `fill` (i.e. to find the host) are still read out in order.
+pub struct RemoteStream {
+    inner: Box<dyn RemoteIo>,
+    peer_addr: Option<SocketAddr>,
+    buffered: Vec<u8>,
+}
+
+impl RemoteStream {
+    pub fn new<T: RemoteIo + 'static>(inner: T, peer_addr: Option<SocketAddr>) -> Self {
+        RemoteStream {
+            inner: Box::new(inner),
+            peer_addr,
+            buffered: vec![],
+        }
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Wait for more bytes, up to `max` read ahead in total, and return all read ahead so far.
+    /// It doesn't grow at the end of the stream.
+    pub async fn fill(&mut self, max: usize) -> io::Result<&[u8]> {
+        if self.buffered.len() < max {
+            let mut chunk = vec![0; max - self.buffered.len()];
+            let n = self.inner.read(&mut chunk).await?;
+            self.buffered.extend_from_slice(&chunk[..n]);
+        }
+        Ok(&self.buffered)
+    }
+}
+
+impl AsyncRead for RemoteStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let n = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..n]);
+            self.buffered.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RemoteStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
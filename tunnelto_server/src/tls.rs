@@ -0,0 +1,196 @@
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::sign::{self, CertifiedKey, SigningKey};
+use tokio_rustls::rustls::{
+    Certificate, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert, ServerConfig,
+    SignatureScheme,
+};
+use tokio_rustls::{webpki, TlsAcceptor};
+
+lazy_static::lazy_static! {
+    /// The cert served on the remote port, swapped in place when acme renews it
+    pub static ref CERTIFICATE: Arc<CertResolver> = Arc::new(CertResolver::default());
+}
+
+#[derive(Default)]
+pub struct CertResolver {
+    current: RwLock<Option<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Serve a new pem cert chain and key for all following handshakes
+    pub fn set(&self, cert_pem: &[u8], key_pem: &[u8]) -> Result<(), String> {
+        let cert_chain = certs(&mut &cert_pem[..]).map_err(|_| "bad pem certs".to_string())?;
+        if cert_chain.is_empty() {
+            return Err("no certs found".to_string());
+        }
+
+        let key = load_key(key_pem).ok_or("no private key found".to_string())?;
+        let key = sign::any_supported_type(&key).map_err(|_| "unsupported key".to_string())?;
+        if !key_matches(&cert_chain[0], key.as_ref()) {
+            return Err("the key doesn't match the cert".to_string());
+        }
+
+        let certified = CertifiedKey::new(cert_chain, Arc::new(key));
+        *self
+            .current
+            .write()
+            .map_err(|_| "cert lock poisoned".to_string())? = Some(certified);
+        Ok(())
+    }
+
+    /// A cert is being served
+    pub fn is_loaded(&self) -> bool {
+        self.current
+            .read()
+            .map_or(false, |current| current.is_some())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.current.read().ok()?.clone()
+    }
+}
+
+/// Terminate tls on the remote port with the pem cert chain and key at
+/// `TLS_CERT` and `TLS_KEY`, or with the cert acme issues (see `acme.rs`).
+/// Remote streams are plain tcp when neither is set.
+pub fn acceptor_from_env(acme_enabled: bool) -> Option<TlsAcceptor> {
+    match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        // handshakes fail until the first cert is issued
+        (Err(_), Err(_)) if acme_enabled => {}
+        _ if acme_enabled => {
+            panic!("invalid ENV: TLS_CERT and TLS_KEY can't be used with ACME_ENABLED")
+        }
+        (Ok(cert_path), Ok(key_path)) => {
+            let cert_pem = read(&cert_path);
+            let key_pem = read(&key_path);
+            CERTIFICATE.set(&cert_pem, &key_pem).unwrap_or_else(|e| {
+                panic!(
+                    "invalid ENV TLS_CERT={} TLS_KEY={}: {}",
+                    cert_path, key_path, e
+                )
+            });
+        }
+        (Err(_), Err(_)) => return None,
+        _ => panic!("invalid ENV: TLS_CERT and TLS_KEY must be set together"),
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = CERTIFICATE.clone();
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+
+    Some(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read(path: &str) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| panic!("failed to read {}: {:?}", path, e))
+}
+
+/// The key is the one the end-entity cert was issued for: it signs something the cert verifies
+fn key_matches(cert: &Certificate, key: &dyn SigningKey) -> bool {
+    const SCHEMES: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+    const MESSAGE: &[u8] = b"tunnelto cert and key check";
+
+    let cert = match webpki::EndEntityCert::from(&cert.0) {
+        Ok(cert) => cert,
+        Err(_) => return false,
+    };
+    let offered: Vec<_> = SCHEMES.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = match key.choose_scheme(&offered) {
+        Some(signer) => signer,
+        None => return false,
+    };
+    let algorithm = match SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+    {
+        Some((_, algorithm)) => *algorithm,
+        None => return false,
+    };
+
+    signer.sign(MESSAGE).map_or(false, |signature| {
+        cert.verify_signature(algorithm, MESSAGE, &signature)
+            .is_ok()
+    })
+}
+
+/// The first pkcs8 or rsa key in the pem
+fn load_key(pem: &[u8]) -> Option<PrivateKey> {
+    let key = pkcs8_private_keys(&mut &pem[..]).ok()?.into_iter().next();
+    key.or_else(|| rsa_private_keys(&mut &pem[..]).ok()?.into_iter().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_signed() -> (Vec<u8>, Vec<u8>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["tunnel.test".to_string()]).unwrap();
+        (
+            cert.serialize_pem().unwrap().into_bytes(),
+            cert.serialize_private_key_pem().into_bytes(),
+        )
+    }
+
+    #[test]
+    fn loads_a_cert_and_its_key() {
+        let (cert, key) = self_signed();
+        let resolver = CertResolver::default();
+        assert!(!resolver.is_loaded());
+
+        resolver.set(&cert, &key).unwrap();
+        assert!(resolver.is_loaded());
+    }
+
+    #[test]
+    fn rejects_a_key_of_another_cert() {
+        let (cert, _) = self_signed();
+        let (_, other_key) = self_signed();
+        let resolver = CertResolver::default();
+
+        assert_eq!(
+            resolver.set(&cert, &other_key),
+            Err("the key doesn't match the cert".to_string())
+        );
+        assert!(!resolver.is_loaded());
+    }
+
+    #[test]
+    fn rejects_invalid_pems() {
+        let (cert, key) = self_signed();
+        let resolver = CertResolver::default();
+
+        assert!(resolver.set(b"not a pem", &key).is_err());
+        assert!(resolver.set(&cert, b"not a pem").is_err());
+        // the pems swapped
+        assert!(resolver.set(&key, &cert).is_err());
+        assert!(!resolver.is_loaded());
+    }
+
+    #[test]
+    fn a_bad_renewal_keeps_the_current_cert() {
+        let (cert, key) = self_signed();
+        let (_, other_key) = self_signed();
+        let resolver = CertResolver::default();
+
+        resolver.set(&cert, &key).unwrap();
+        assert!(resolver.set(&cert, &other_key).is_err());
+        assert!(resolver.is_loaded());
+    }
+}
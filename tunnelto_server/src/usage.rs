@@ -0,0 +1,71 @@
+use crate::auth::AuthService;
+use crate::{ClientId, AUTH_DB_SERVICE};
+use dashmap::DashMap;
+use std::time::Duration;
+
+/// How often accumulated usage is flushed to the db
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref USAGE: DashMap<ClientId, u64> = DashMap::new();
+}
+
+/// Count bytes tunneled for a client, to be persisted on the next flush
+pub fn record(client_id: &ClientId, bytes: usize) {
+    *USAGE.entry(client_id.clone()).or_insert(0) += bytes as u64;
+}
+
+/// Periodically persist accumulated usage in batches rather than per packet
+pub fn spawn_flush() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            flush(&*AUTH_DB_SERVICE).await;
+        }
+    });
+}
+
+async fn flush<A>(service: &A)
+where
+    A: AuthService + Sync,
+    A::Error: std::fmt::Debug,
+{
+    let clients: Vec<ClientId> = USAGE.iter().map(|e| e.key().clone()).collect();
+
+    for client_id in clients {
+        let (client_id, bytes) = match USAGE.remove(&client_id) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        if let Err(error) = service.add_usage(&client_id, bytes).await {
+            tracing::error!(?error, %client_id, "failed to flush usage, will retry");
+            record(&client_id, bytes as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::in_memory::InMemoryAuthService;
+
+    #[tokio::test]
+    async fn usage_accumulates_across_flushes() {
+        let service = InMemoryAuthService::builder().build();
+        let client_id = ClientId::generate();
+
+        record(&client_id, 100);
+        record(&client_id, 50);
+        flush(&service).await;
+        assert_eq!(service.usage(&client_id), 150);
+        assert!(!USAGE.contains_key(&client_id));
+
+        flush(&service).await;
+        assert_eq!(service.usage(&client_id), 150);
+
+        record(&client_id, 25);
+        flush(&service).await;
+        assert_eq!(service.usage(&client_id), 175);
+    }
+}
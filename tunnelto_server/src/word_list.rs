@@ -0,0 +1,132 @@
+use crate::connected_clients::Connections;
+use crate::network;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+use tunnelto_lib::ServerHello;
+
+/// Give up on unused words after this many collisions and fall back to alphanumeric
+const MAX_ATTEMPTS: usize = 5;
+
+/// Words for memorable random sub-domains, i.e: happy-otter.
+/// Loaded from a json file: `{"adjectives": ["happy", ...], "nouns": ["otter", ...]}`
+#[derive(Debug, Deserialize)]
+pub struct WordList {
+    adjectives: Vec<String>,
+    nouns: Vec<String>,
+}
+
+impl WordList {
+    pub fn load(path: &str) -> Self {
+        let contents = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("failed to read word list {}: {:?}", path, e));
+        let list: WordList = serde_json::from_str(&contents)
+            .unwrap_or_else(|e| panic!("invalid word list {}: {:?}", path, e));
+
+        let list = WordList {
+            adjectives: sanitize(list.adjectives),
+            nouns: sanitize(list.nouns),
+        };
+        if list.adjectives.is_empty() || list.nouns.is_empty() {
+            panic!("word list {} needs at least one adjective and noun", path);
+        }
+        list
+    }
+
+    /// i.e: "happy-otter", with a number suffix (i.e: "happy-otter-42") after the first collision
+    fn generate(&self, attempt: usize) -> String {
+        let mut rng = rand::thread_rng();
+        let adjective = self
+            .adjectives
+            .choose(&mut rng)
+            .cloned()
+            .unwrap_or_default();
+        let noun = self.nouns.choose(&mut rng).cloned().unwrap_or_default();
+
+        if attempt == 0 {
+            format!("{}-{}", adjective, noun)
+        } else {
+            format!("{}-{}-{}", adjective, noun, rng.gen_range(10, 100))
+        }
+    }
+}
+
+/// Words are lowercased, and any with characters not allowed in a label are dropped
+fn sanitize(words: Vec<String>) -> Vec<String> {
+    words
+        .into_iter()
+        .map(|w| w.trim().to_lowercase())
+        .filter(|w| !w.is_empty() && w.chars().all(|c| c.is_ascii_alphanumeric()))
+        .collect()
+}
+
+/// A random sub-domain for a client that didn't ask for one, from the word list if configured
+pub async fn random_sub_domain() -> String {
+    let words = match &crate::CONFIG.sub_domain_words {
+        Some(words) => words,
+        None => return ServerHello::random_domain(),
+    };
+
+    for attempt in 0..MAX_ATTEMPTS {
+        let candidate = words.generate(attempt);
+        if Connections::find_by_host(&candidate).is_some() {
+            continue;
+        }
+
+        // a failed lookup can't tell us the name is taken
+        match network::instance_for_host(&candidate).await {
+            Ok(_) => continue,
+            Err(_) => return candidate,
+        }
+    }
+
+    tracing::warn!("word list sub-domains keep colliding, using a random one");
+    ServerHello::random_domain()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(adjectives: &[&str], nouns: &[&str]) -> WordList {
+        WordList {
+            adjectives: adjectives.iter().map(|w| w.to_string()).collect(),
+            nouns: nouns.iter().map(|w| w.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn generate_is_adjective_noun() {
+        let list = words(&["happy", "sleepy"], &["otter"]);
+        for _ in 0..20 {
+            let name = list.generate(0);
+            assert!(name == "happy-otter" || name == "sleepy-otter", "{}", name);
+        }
+    }
+
+    #[test]
+    fn retries_get_a_suffix() {
+        let list = words(&["happy"], &["otter"]);
+        assert_eq!(list.generate(0), "happy-otter");
+
+        for attempt in 1..MAX_ATTEMPTS {
+            let name = list.generate(attempt);
+            let suffix = name.strip_prefix("happy-otter-").expect(&name);
+            let suffix: u32 = suffix.parse().expect(&name);
+            assert!((10..100).contains(&suffix), "{}", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_drops_invalid_words() {
+        let words = vec![
+            " Happy ".to_string(),
+            "".to_string(),
+            "two words".to_string(),
+            "dash-ed".to_string(),
+            "café".to_string(),
+            "otter2".to_string(),
+        ];
+        assert_eq!(sanitize(words), vec!["happy", "otter2"]);
+    }
+}